@@ -0,0 +1,10932 @@
+use anyhow::Context;
+use async_trait::async_trait;
+use reqwest::Error;
+use rusqlite::{params, Connection, OpenFlags, OptionalExtension, Result};
+use serde::{Deserialize, Serialize};
+use base64::Engine;
+use flate2::write::{GzEncoder, ZlibEncoder};
+use flate2::Compression;
+use rand::Rng;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{BTreeMap, HashMap};
+use std::hash::{Hash, Hasher};
+use subtle::ConstantTimeEq;
+use std::io::{IsTerminal, Write};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard, OnceLock};
+use futures_util::{SinkExt, StreamExt};
+use tokio::sync::broadcast;
+use tokio::time::{self, Duration};
+use tracing::{error, info, warn};
+use warp::http::header::{
+    HeaderValue, CACHE_CONTROL, CONTENT_DISPOSITION, CONTENT_ENCODING, CONTENT_LENGTH, CONTENT_TYPE, ETAG, RETRY_AFTER, VARY, WWW_AUTHENTICATE,
+};
+use warp::http::StatusCode;
+use warp::hyper::body::{Bytes, HttpBody};
+use warp::hyper::Body;
+use warp::filters::BoxedFilter;
+use warp::reply::Response;
+use warp::ws::{Message, WebSocket};
+use warp::{Filter, Reply};
+
+// Default time between fetches, unless POLL_INTERVAL overrides it
+const POLL_INTERVAL: Duration = Duration::from_secs(20);
+
+// Identifies us to CoinGecko/Blockstream unless HTTP_USER_AGENT overrides it
+const DEFAULT_USER_AGENT: &str = concat!("bitcoin-explore/", env!("CARGO_PKG_VERSION"));
+
+// Bodies smaller than this cost more to compress than they save on the wire
+const COMPRESSION_MIN_BYTES: usize = 1024;
+
+// Rows read per query while streaming the export, bounding memory per chunk
+const EXPORT_PAGE_SIZE: i64 = 500;
+
+const METRICS_COLUMNS: &str =
+    "id, block_height, btc_price, timestamp, asset, source, fetch_latency_ms, block_hash, fee_1_block, fee_6_blocks, fee_144_blocks, price_spread";
+
+// Recorded in the source column so rows from different price feeds stay distinguishable
+const PRICE_SOURCE_COINGECKO: &str = "coingecko";
+const PRICE_SOURCE_COINBASE: &str = "coinbase";
+const PRICE_SOURCE_KRAKEN: &str = "kraken";
+// Source of rows priced by PRICE_AGGREGATION=median, with each feed's quote in source_prices
+const PRICE_SOURCE_MEDIAN: &str = "median";
+
+// Fewest answering feeds a median price is taken from
+const MIN_MEDIAN_SOURCES: usize = 2;
+
+// Source of rows saved without a price because every price fetch failed
+const PRICE_SOURCE_NONE: &str = "none";
+
+// Rows returned by /api/metrics when no limit is requested
+const DEFAULT_METRICS_LIMIT: u32 = 50;
+
+// Hard ceiling on rows per history query unless MAX_QUERY_LIMIT overrides it
+const DEFAULT_MAX_QUERY_LIMIT: u32 = 1000;
+
+// Most decimal places PRICE_DECIMALS may ask for before rounding stops being meaningful
+const MAX_PRICE_DECIMALS: u32 = 8;
+
+// Target and ceiling for the number of points /api/metrics/downsample returns
+const DEFAULT_DOWNSAMPLE_POINTS: usize = 500;
+const MAX_DOWNSAMPLE_POINTS: usize = 5000;
+
+// Browser and CDN cache lifetime for responses that only change on deploy, like the
+// OpenAPI document and version
+const STATIC_DOC_MAX_AGE: Duration = Duration::from_secs(300);
+
+// How far from a requested time /api/metrics/compare looks for a sample unless ?tolerance= is given
+const DEFAULT_COMPARE_TOLERANCE: Duration = Duration::from_secs(60 * 60);
+
+// Newest samples /api/metrics/gaps scans when no limit is given, about two days of polls
+const DEFAULT_GAP_SCAN_LIMIT: u32 = 10_000;
+
+// Upstream API roots, overridable with PRICE_API_BASE and CHAIN_API_BASE to point the
+// fetchers at a mock server or a caching proxy
+const DEFAULT_PRICE_API_BASE: &str = "https://api.coingecko.com/api/v3";
+const DEFAULT_CHAIN_API_BASE: &str = "https://blockstream.info/api";
+
+// Fallback price APIs listed in PRICE_SOURCES, overridable with COINBASE_API_BASE and KRAKEN_API_BASE
+const DEFAULT_COINBASE_API_BASE: &str = "https://api.coinbase.com/v2";
+const DEFAULT_KRAKEN_API_BASE: &str = "https://api.kraken.com/0/public";
+
+// CoinGecko id served when a request doesn't name an asset
+const DEFAULT_ASSET: &str = "bitcoin";
+
+// Quote currency always fetched, since btc_price holds the USD value
+const BASE_CURRENCY: &str = "usd";
+
+// CoinGecko's simple/price response, keyed by asset id
+type SimplePriceResponse = HashMap<String, CurrencyPrice>;
+
+// Any currency, USD included, can be missing or null while CoinGecko has an incident, so
+// a partial response still yields whatever prices it does have
+#[derive(Deserialize)]
+struct CurrencyPrice {
+    #[serde(default)]
+    usd: Option<f64>,
+    // Other requested vs_currencies, alongside extras like usd_24h_change
+    #[serde(flatten)]
+    other: HashMap<String, serde_json::Value>,
+}
+
+impl CurrencyPrice {
+    fn prices(&self, currencies: &[String]) -> BTreeMap<String, f64> {
+        currencies
+            .iter()
+            .filter_map(|currency| {
+                let value = if currency == BASE_CURRENCY {
+                    self.usd
+                } else {
+                    self.other.get(currency).and_then(serde_json::Value::as_f64)
+                };
+                value.map(|value| (currency.clone(), value))
+            })
+            .collect()
+    }
+}
+
+// Drops assets without a single requested price. A response with none at all, such as
+// CoinGecko's {"status": {...}} error body, isn't a price response and yields None.
+fn requested_prices(
+    response: SimplePriceResponse,
+    currencies: &[String],
+) -> Option<HashMap<String, BTreeMap<String, f64>>> {
+    let prices: HashMap<String, BTreeMap<String, f64>> = response
+        .into_iter()
+        .map(|(asset, price)| (asset, price.prices(currencies)))
+        .filter(|(_, quotes)| !quotes.is_empty())
+        .collect();
+    if prices.is_empty() {
+        None
+    } else {
+        Some(prices)
+    }
+}
+
+// Id of a row that isn't stored yet, such as one waiting in the write buffer. Both stores
+// number rows from 1.
+const UNSAVED_ID: i64 = 0;
+
+fn is_unsaved(id: &i64) -> bool {
+    *id == UNSAVED_ID
+}
+
+#[derive(Serialize, Clone)]
+pub struct Metrics {
+    // Left out until the row is stored, rather than sent as a placeholder
+    #[serde(skip_serializing_if = "is_unsaved")]
+    pub id: i64,
+    // Null for backfilled rows and for ticks where only the price fetch succeeded
+    pub block_height: Option<u64>,
+    // Hash of the block at block_height, so a reorg shows up as the same height with a new
+    // hash. Best effort: null when the hash fetch failed and for older rows.
+    pub block_hash: Option<String>,
+    // Null for ticks where only the block height fetch succeeded
+    pub btc_price: Option<f64>,
+    // Every fetched quote currency, stored one row per currency in the prices table
+    pub prices: BTreeMap<String, f64>,
+    pub timestamp: String,
+    pub asset: String,
+    pub source: String,
+    // Time spent on the tick's upstream fetches; null for backfilled and older rows
+    pub fetch_latency_ms: Option<u64>,
+    #[serde(flatten)]
+    pub fees: FeeEstimates,
+    #[serde(flatten)]
+    pub spread: PriceSpread,
+}
+
+// A sample as it's handed to the store, which assigns the id and timestamp
+#[derive(Clone, Default)]
+pub struct MetricsRow {
+    pub asset: String,
+    pub block_height: Option<u64>,
+    pub block_hash: Option<String>,
+    pub fees: FeeEstimates,
+    pub btc_price: Option<f64>,
+    pub prices: BTreeMap<String, f64>,
+    pub spread: PriceSpread,
+    pub source: String,
+    pub fetch_latency_ms: Option<u64>,
+}
+
+impl MetricsRow {
+    fn into_metrics(self, id: i64, timestamp: String) -> Metrics {
+        Metrics {
+            id,
+            block_height: self.block_height,
+            block_hash: self.block_hash,
+            btc_price: self.btc_price,
+            prices: self.prices,
+            timestamp,
+            asset: self.asset,
+            source: self.source,
+            fetch_latency_ms: self.fetch_latency_ms,
+            fees: self.fees,
+            spread: self.spread,
+        }
+    }
+}
+
+// Recommended fee rates in sat/vB for confirmation within 1, 6 and 144 blocks, from
+// Blockstream when FETCH_FEE_ESTIMATES=1. Best effort: each is null when not fetched or
+// missing from the response.
+#[derive(Serialize, Clone, Default, Debug, PartialEq)]
+pub struct FeeEstimates {
+    pub fee_1_block: Option<f64>,
+    pub fee_6_blocks: Option<f64>,
+    pub fee_144_blocks: Option<f64>,
+}
+
+// How far apart the feeds behind a PRICE_AGGREGATION=median price were. Empty for rows
+// priced by a single source.
+#[derive(Serialize, Clone, Default, Debug, PartialEq)]
+pub struct PriceSpread {
+    // Highest minus lowest feed's USD price
+    pub price_spread: Option<f64>,
+    // Each feed's USD price, written to the source_prices table. Kept for audit only, so
+    // it isn't read back into rows served by the API.
+    #[serde(skip)]
+    pub source_prices: BTreeMap<String, f64>,
+}
+
+// Newest stored row per asset, served by /api/metrics/latest without touching the DB
+type LatestMetrics = Arc<Mutex<HashMap<String, Metrics>>>;
+
+#[derive(Deserialize)]
+struct AssetQuery {
+    asset: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct StreamQuery {
+    asset: Option<String>,
+    // Stored samples sent before the live ones, capped at MAX_STREAM_BACKLOG
+    backlog: Option<u32>,
+}
+
+#[derive(Deserialize)]
+struct CountQuery {
+    asset: Option<String>,
+    from: Option<String>,
+    to: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct PruneQuery {
+    older_than_days: Option<u32>,
+}
+
+#[derive(Deserialize)]
+struct MetricsQuery {
+    asset: Option<String>,
+    limit: Option<u32>,
+    // IANA zone, e.g. America/New_York, the timestamps are shown in; UTC when absent
+    tz: Option<String>,
+}
+
+pub struct MetricsHistory {
+    pub metrics: Vec<Metrics>,
+    // Set when the requested limit was cut down to the server-side cap
+    pub truncated: bool,
+}
+
+#[derive(Serialize)]
+struct ErrorResponse {
+    error: String,
+}
+
+#[derive(Deserialize)]
+struct BucketsQuery {
+    interval: Option<String>,
+    from: Option<String>,
+    to: Option<String>,
+    asset: Option<String>,
+}
+
+#[derive(Serialize)]
+struct PriceBucket {
+    start: String,
+    open: f64,
+    high: f64,
+    low: f64,
+    close: f64,
+    avg: f64,
+    max_block_height: Option<u64>,
+    samples: u64,
+}
+
+#[derive(Deserialize)]
+struct DownsampleQuery {
+    points: Option<usize>,
+    from: Option<String>,
+    to: Option<String>,
+    asset: Option<String>,
+}
+
+#[derive(Serialize)]
+struct SeriesPoint {
+    id: i64,
+    timestamp: String,
+    btc_price: f64,
+    block_height: Option<u64>,
+}
+
+#[derive(Deserialize)]
+struct GapsQuery {
+    threshold: Option<String>,
+    asset: Option<String>,
+    // Newest samples to scan
+    limit: Option<u32>,
+}
+
+#[derive(Serialize, Debug, PartialEq)]
+struct Gap {
+    start: String,
+    end: String,
+    duration_secs: i64,
+}
+
+// Newest stored value of one quote currency
+#[derive(Serialize, Debug, PartialEq)]
+pub struct CurrencyQuote {
+    pub currency: String,
+    pub value: f64,
+    pub timestamp: String,
+}
+
+#[derive(Deserialize)]
+struct HighLowQuery {
+    window: Option<String>,
+    asset: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct CompareQuery {
+    a: Option<String>,
+    b: Option<String>,
+    asset: Option<String>,
+    // How far from each requested time the nearest sample may be, e.g. 15m
+    tolerance: Option<String>,
+}
+
+// Deltas run from `a` to `b` and are null when either sample lacks the field
+#[derive(Serialize)]
+struct Comparison {
+    a: Metrics,
+    b: Metrics,
+    price_delta: Option<f64>,
+    price_delta_pct: Option<f64>,
+    block_delta: Option<i64>,
+}
+
+impl Comparison {
+    fn new(a: Metrics, b: Metrics) -> Comparison {
+        let price_delta = a.btc_price.zip(b.btc_price).map(|(a, b)| b - a);
+        let price_delta_pct = a
+            .btc_price
+            .filter(|price| *price != 0.0)
+            .zip(price_delta)
+            .map(|(a, delta)| delta / a * 100.0);
+        let block_delta = a
+            .block_height
+            .zip(b.block_height)
+            .map(|(a, b)| b as i64 - a as i64);
+        Comparison {
+            a,
+            b,
+            price_delta,
+            price_delta_pct,
+            block_delta,
+        }
+    }
+}
+
+// Highest-priced stored sample, the earliest if several tie, and how far the newest priced
+// sample sits below it
+#[derive(Serialize)]
+struct AllTimeHigh {
+    ath: Metrics,
+    current_price: f64,
+    below_ath: f64,
+    below_ath_pct: f64,
+}
+
+impl AllTimeHigh {
+    fn new(ath: Metrics, current: &Metrics) -> Option<AllTimeHigh> {
+        let (ath_price, current_price) = (ath.btc_price?, current.btc_price?);
+        let below_ath = ath_price - current_price;
+        let below_ath_pct = if ath_price != 0.0 { below_ath / ath_price * 100.0 } else { 0.0 };
+        Some(AllTimeHigh {
+            ath,
+            current_price,
+            below_ath,
+            below_ath_pct,
+        })
+    }
+}
+
+// Prices and their timestamps are null when the window holds no priced samples
+#[derive(Serialize, Default, Debug, PartialEq)]
+struct HighLow {
+    window_secs: i64,
+    high: Option<f64>,
+    high_at: Option<String>,
+    low: Option<f64>,
+    low_at: Option<String>,
+}
+
+#[derive(Serialize, Default, Debug, PartialEq)]
+struct BlockTimeStats {
+    average_secs: Option<f64>,
+    median_secs: Option<f64>,
+    blocks: u64,
+}
+
+// Blocks between subsidy halvings, the era-one subsidy in sats, and the protocol's target
+// block time used for the halving ETA until two stored heights give a measured average
+const HALVING_INTERVAL: u64 = 210_000;
+const INITIAL_SUBSIDY_SATS: u64 = 50 * 100_000_000;
+const TARGET_BLOCK_SECS: f64 = 600.0;
+
+// reward_era counts from 1 for the original 50 BTC subsidy
+#[derive(Serialize, Debug, PartialEq)]
+struct HalvingEstimate {
+    block_height: u64,
+    reward_era: u64,
+    block_reward_btc: f64,
+    next_halving_block: u64,
+    blocks_remaining: u64,
+    average_block_secs: f64,
+    estimated_at: String,
+}
+
+// Consecutive failures before a source's breaker opens, and how long it stays open
+const DEFAULT_BREAKER_THRESHOLD: u32 = 5;
+const DEFAULT_BREAKER_COOLDOWN_SECS: i64 = 5 * 60;
+
+// Each failed probe doubles the cooldown, up to 2^this times the configured one
+const MAX_BREAKER_BACKOFF_DOUBLINGS: u32 = 3;
+
+#[derive(Clone, Copy)]
+pub struct BreakerConfig {
+    pub failure_threshold: u32,
+    pub cooldown: Duration,
+}
+
+impl BreakerConfig {
+    pub fn from_env() -> BreakerConfig {
+        let cooldown_secs = std::env::var("CIRCUIT_BREAKER_COOLDOWN")
+            .ok()
+            .and_then(|cooldown| parse_duration_secs(&cooldown))
+            .unwrap_or(DEFAULT_BREAKER_COOLDOWN_SECS);
+
+        BreakerConfig {
+            failure_threshold: env_parse::<u32>("CIRCUIT_BREAKER_THRESHOLD")
+                .unwrap_or(DEFAULT_BREAKER_THRESHOLD)
+                .max(1),
+            cooldown: Duration::from_secs(cooldown_secs as u64),
+        }
+    }
+}
+
+#[derive(Serialize, Clone, Copy, Default, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+enum BreakerState {
+    #[default]
+    Closed,
+    // Fetches are skipped until the cooldown runs out
+    Open,
+    // Cooldown over, the next fetch is a probe that either closes or reopens the breaker
+    HalfOpen,
+}
+
+// Stops polling a source that keeps failing instead of retrying it every tick
+#[derive(Serialize, Default)]
+struct CircuitBreaker {
+    state: BreakerState,
+    consecutive_failures: u32,
+    // Probes that failed since the breaker last closed, backing off the cooldown
+    failed_probes: u32,
+    retry_at: Option<String>,
+    #[serde(skip)]
+    open_until: Option<std::time::Instant>,
+}
+
+impl CircuitBreaker {
+    fn allow(&mut self, source: &str) -> bool {
+        match (self.state, self.open_until) {
+            (BreakerState::Open, Some(open_until)) if std::time::Instant::now() < open_until => false,
+            (BreakerState::Open, _) => {
+                info!("Circuit breaker for {} half-open, probing", source);
+                self.state = BreakerState::HalfOpen;
+                true
+            }
+            _ => true,
+        }
+    }
+
+    fn record(&mut self, source: &str, success: bool, config: &BreakerConfig) {
+        if success {
+            if self.state != BreakerState::Closed {
+                info!("Circuit breaker for {} closed", source);
+            }
+            *self = CircuitBreaker::default();
+            return;
+        }
+
+        self.consecutive_failures += 1;
+        if self.state == BreakerState::HalfOpen {
+            self.failed_probes += 1;
+        }
+        if self.state == BreakerState::HalfOpen || self.consecutive_failures >= config.failure_threshold {
+            let cooldown = config.cooldown * 2u32.pow(self.failed_probes.min(MAX_BREAKER_BACKOFF_DOUBLINGS));
+            warn!(
+                "Circuit breaker for {} open after {} consecutive failures, pausing fetches for {}s",
+                source,
+                self.consecutive_failures,
+                cooldown.as_secs()
+            );
+            self.state = BreakerState::Open;
+            self.open_until = Some(std::time::Instant::now() + cooldown);
+            self.retry_at = chrono::Duration::from_std(cooldown)
+                .ok()
+                .map(|cooldown| (chrono::Utc::now() + cooldown).format(TIMESTAMP_FORMAT).to_string());
+        }
+    }
+}
+
+#[derive(Serialize, Default)]
+struct SourceStatus {
+    last_success: Option<String>,
+    last_error: Option<String>,
+    last_error_at: Option<String>,
+    breaker: CircuitBreaker,
+}
+
+impl SourceStatus {
+    fn record<T, E: std::fmt::Display>(&mut self, source: &str, result: &Result<T, E>, breaker_config: &BreakerConfig) {
+        self.breaker.record(source, result.is_ok(), breaker_config);
+        match result {
+            Ok(_) => {
+                self.last_success = Some(now_timestamp());
+                self.last_error = None;
+                self.last_error_at = None;
+            }
+            Err(e) => {
+                self.last_error = Some(e.to_string());
+                self.last_error_at = Some(now_timestamp());
+            }
+        }
+    }
+}
+
+#[derive(Serialize, Default)]
+struct FetchStatus {
+    block_height: SourceStatus,
+    btc_price: SourceStatus,
+}
+
+// Monotonic counters about the collector itself, for alerting on failure rates
+#[derive(Default)]
+struct FetchCounters {
+    fetch_success: AtomicU64,
+    fetch_failure: AtomicU64,
+    // Bumped whenever a price comes from anything other than the primary source
+    price_fallback_used: AtomicU64,
+    db_write_errors: AtomicU64,
+    // Poll cycles that panicked and were restarted
+    poller_restarts: AtomicU64,
+    // Duration of the most recent attempt per source
+    block_height_latency_ms: AtomicU64,
+    btc_price_latency_ms: AtomicU64,
+}
+
+#[derive(Serialize)]
+struct FetchCountersSnapshot {
+    fetch_success: u64,
+    fetch_failure: u64,
+    price_fallback_used: u64,
+    db_write_errors: u64,
+    poller_restarts: u64,
+    block_height_latency_ms: u64,
+    btc_price_latency_ms: u64,
+}
+
+impl FetchCounters {
+    fn record<T, E>(&self, result: &Result<T, E>) {
+        let counter = if result.is_ok() {
+            &self.fetch_success
+        } else {
+            &self.fetch_failure
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn snapshot(&self) -> FetchCountersSnapshot {
+        FetchCountersSnapshot {
+            fetch_success: self.fetch_success.load(Ordering::Relaxed),
+            fetch_failure: self.fetch_failure.load(Ordering::Relaxed),
+            price_fallback_used: self.price_fallback_used.load(Ordering::Relaxed),
+            db_write_errors: self.db_write_errors.load(Ordering::Relaxed),
+            poller_restarts: self.poller_restarts.load(Ordering::Relaxed),
+            block_height_latency_ms: self.block_height_latency_ms.load(Ordering::Relaxed),
+            btc_price_latency_ms: self.btc_price_latency_ms.load(Ordering::Relaxed),
+        }
+    }
+
+    // Prometheus text exposition format
+    fn to_prometheus(&self) -> String {
+        let snapshot = self.snapshot();
+        let counters = [
+            ("fetch_success", "Successful upstream fetches", snapshot.fetch_success),
+            ("fetch_failure", "Failed upstream fetches", snapshot.fetch_failure),
+            ("price_fallback_used", "Prices taken from a fallback source", snapshot.price_fallback_used),
+            ("db_write_errors", "Failed metrics inserts", snapshot.db_write_errors),
+            ("poller_restarts", "Poll cycles restarted after a panic", snapshot.poller_restarts),
+        ];
+
+        let mut out = String::new();
+        for (name, help, value) in counters {
+            out.push_str(&format!(
+                "# HELP bitcoin_explore_{name}_total {help}\n# TYPE bitcoin_explore_{name}_total counter\nbitcoin_explore_{name}_total {value}\n"
+            ));
+        }
+
+        out.push_str(
+            "# HELP bitcoin_explore_fetch_latency_ms Duration of the latest upstream fetch\n# TYPE bitcoin_explore_fetch_latency_ms gauge\n",
+        );
+        for (source, value) in [
+            ("block_height", snapshot.block_height_latency_ms),
+            ("btc_price", snapshot.btc_price_latency_ms),
+        ] {
+            out.push_str(&format!("bitcoin_explore_fetch_latency_ms{{source=\"{source}\"}} {value}\n"));
+        }
+        out
+    }
+}
+
+// Build info baked in by build.rs
+#[derive(Serialize)]
+struct VersionInfo {
+    version: &'static str,
+    git_hash: Option<&'static str>,
+    built_at: Option<String>,
+}
+
+impl VersionInfo {
+    fn current() -> VersionInfo {
+        let built_at = env!("BUILD_UNIX_TIME")
+            .parse::<i64>()
+            .ok()
+            .and_then(|secs| chrono::DateTime::from_timestamp(secs, 0))
+            .map(|built_at| built_at.to_rfc3339());
+
+        VersionInfo {
+            version: env!("CARGO_PKG_VERSION"),
+            git_hash: option_env!("GIT_HASH"),
+            built_at,
+        }
+    }
+}
+
+// Live tip lookups for /api/health are cached so frequent probes don't hammer Blockstream
+const CHAIN_TIP_CACHE_TTL: Duration = Duration::from_secs(30);
+const CHAIN_TIP_TIMEOUT: Duration = Duration::from_secs(3);
+
+// Health turns degraded once the stored height trails the live tip by more than this
+const MAX_BLOCKS_BEHIND: u64 = 2;
+
+struct ChainTip {
+    http: reqwest::Client,
+    chain_api_base: String,
+    cached: Mutex<Option<(std::time::Instant, u64)>>,
+}
+
+impl ChainTip {
+    fn new(http: reqwest::Client, chain_api_base: String) -> ChainTip {
+        ChainTip {
+            http,
+            chain_api_base,
+            cached: Mutex::new(None),
+        }
+    }
+
+    // Best effort: any failure just means health can't report a delta
+    async fn height(&self) -> Option<u64> {
+        if let Some((fetched_at, height)) = *lock_or_recover(&self.cached) {
+            if fetched_at.elapsed() < CHAIN_TIP_CACHE_TTL {
+                return Some(height);
+            }
+        }
+
+        match time::timeout(CHAIN_TIP_TIMEOUT, fetch_block_height(&self.http, &self.chain_api_base)).await {
+            Ok(Ok(height)) => {
+                *lock_or_recover(&self.cached) = Some((std::time::Instant::now(), height));
+                Some(height)
+            }
+            Ok(Err(e)) => {
+                error!("Error fetching chain tip for health check: {}", e);
+                None
+            }
+            Err(_) => {
+                warn!("Timed out fetching chain tip for health check");
+                None
+            }
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct Health<'a> {
+    status: &'static str,
+    sources: &'a FetchStatus,
+    counters: FetchCountersSnapshot,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    chain_tip: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    blocks_behind: Option<u64>,
+}
+
+// When run() began. Uptime comes from the monotonic Instant so clock adjustments don't skew it.
+#[derive(Clone)]
+struct ProcessStart {
+    instant: std::time::Instant,
+    timestamp: String,
+}
+
+impl ProcessStart {
+    fn now() -> ProcessStart {
+        ProcessStart {
+            instant: std::time::Instant::now(),
+            timestamp: now_timestamp(),
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct Uptime<'a> {
+    uptime_secs: u64,
+    started_at: &'a str,
+}
+
+// Same format as SQLite's CURRENT_TIMESTAMP so it lines up with stored rows
+fn now_timestamp() -> String {
+    chrono::Utc::now().format("%Y-%m-%d %H:%M:%S").to_string()
+}
+
+fn parse_timestamp(timestamp: &str) -> Option<chrono::NaiveDateTime> {
+    chrono::NaiveDateTime::parse_from_str(timestamp, "%Y-%m-%d %H:%M:%S").ok()
+}
+
+const TIMESTAMP_FORMAT: &str = "%Y-%m-%d %H:%M:%S";
+
+// Accepts the stored "YYYY-MM-DD HH:MM:SS" form, RFC 3339, or a bare date, and
+// normalizes to the stored form so it compares correctly against the timestamp column
+fn normalize_timestamp(input: &str) -> Option<String> {
+    let input = input.trim();
+    if let Some(ts) = parse_timestamp(input) {
+        return Some(ts.format(TIMESTAMP_FORMAT).to_string());
+    }
+    if let Ok(ts) = chrono::DateTime::parse_from_rfc3339(input) {
+        return Some(ts.naive_utc().format(TIMESTAMP_FORMAT).to_string());
+    }
+    chrono::NaiveDate::parse_from_str(input, "%Y-%m-%d")
+        .ok()
+        .map(|date| date.format("%Y-%m-%d 00:00:00").to_string())
+}
+
+// Parses durations like "90s", "15m", "1h" or "7d" into seconds
+fn parse_duration_secs(input: &str) -> Option<i64> {
+    let input = input.trim();
+    let unit = input.chars().last()?;
+    let value: i64 = input[..input.len() - unit.len_utf8()].parse().ok().filter(|v| *v > 0)?;
+    let multiplier = match unit {
+        's' => 1,
+        'm' => 60,
+        'h' => 60 * 60,
+        'd' => 24 * 60 * 60,
+        _ => return None,
+    };
+    value.checked_mul(multiplier)
+}
+
+// Strips float noise like 63241.99999998 before a price is stored
+fn round_price(price: f64, decimals: u32) -> f64 {
+    let scale = 10f64.powi(decimals as i32);
+    (price * scale).round() / scale
+}
+
+// The configured POLL_INTERVAL, shared between the poll loop and the routes whose cache
+// lifetimes and staleness follow it, so a SIGHUP that changes it reaches all of them
+#[derive(Clone)]
+struct PollInterval(Arc<AtomicU64>);
+
+impl PollInterval {
+    fn new(interval: Duration) -> PollInterval {
+        PollInterval(Arc::new(AtomicU64::new(interval.as_millis() as u64)))
+    }
+
+    fn get(&self) -> Duration {
+        Duration::from_millis(self.0.load(Ordering::Relaxed))
+    }
+
+    fn set(&self, interval: Duration) {
+        self.0.store(interval.as_millis() as u64, Ordering::Relaxed);
+    }
+}
+
+impl Default for PollInterval {
+    fn default() -> PollInterval {
+        PollInterval::new(POLL_INTERVAL)
+    }
+}
+
+// Spreads each poll uniformly within ±pct of the base interval
+fn jittered_interval(base: Duration, jitter_pct: f64) -> Duration {
+    if jitter_pct <= 0.0 {
+        return base;
+    }
+    let factor = 1.0 + rand::thread_rng().gen_range(-jitter_pct..=jitter_pct) / 100.0;
+    base.mul_f64(factor)
+}
+
+// Delay before the first poll, up to pct of the base interval. Per-tick jitter only spreads
+// instances out over time, while a fleet started together would still share its first tick.
+fn startup_jitter(base: Duration, jitter_pct: f64) -> Duration {
+    if jitter_pct <= 0.0 {
+        return Duration::ZERO;
+    }
+    base.mul_f64(rand::thread_rng().gen_range(0.0..=jitter_pct) / 100.0)
+}
+
+// POLL_STRATEGY. Fixed fetches everything every tick; adaptive still fetches prices every
+// tick but refetches the block height less often the longer it has gone unchanged, reusing
+// the last one in between, since blocks only arrive about every ten minutes.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PollStrategy {
+    Fixed,
+    Adaptive,
+}
+
+impl PollStrategy {
+    pub fn from_env() -> PollStrategy {
+        match std::env::var("POLL_STRATEGY").as_deref().map(str::trim) {
+            Ok("adaptive") => PollStrategy::Adaptive,
+            Ok("fixed") | Ok("") | Err(_) => PollStrategy::Fixed,
+            Ok(other) => {
+                warn!("Ignoring unknown POLL_STRATEGY {:?}, polling everything every tick", other);
+                PollStrategy::Fixed
+            }
+        }
+    }
+}
+
+// Longest an adaptive poller goes without refetching the block height, which bounds how
+// late a new block shows up
+const ADAPTIVE_MAX_HEIGHT_INTERVAL: Duration = Duration::from_secs(120);
+
+// When the adaptive poller last fetched and last saw a new block height. The height is due
+// again once half the time since it changed has passed since the last fetch, so checks are
+// frequent right after a block and back off to ADAPTIVE_MAX_HEIGHT_INTERVAL.
+#[derive(Default)]
+struct HeightSchedule {
+    last_height: Option<u64>,
+    last_fetch: Option<std::time::Instant>,
+    last_change: Option<std::time::Instant>,
+}
+
+impl HeightSchedule {
+    // The last fetched height while it isn't due for a refetch yet
+    fn reusable(&self, now: std::time::Instant) -> Option<u64> {
+        let (last_fetch, last_change) = (self.last_fetch?, self.last_change?);
+        let wait = (now.saturating_duration_since(last_change) / 2).min(ADAPTIVE_MAX_HEIGHT_INTERVAL);
+        if now.saturating_duration_since(last_fetch) < wait {
+            self.last_height
+        } else {
+            None
+        }
+    }
+
+    fn record(&mut self, height: u64, now: std::time::Instant) {
+        if self.last_height != Some(height) {
+            self.last_height = Some(height);
+            self.last_change = Some(now);
+        }
+        self.last_fetch = Some(now);
+    }
+}
+
+fn lock_or_recover<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    match mutex.lock() {
+        Ok(guard) => guard,
+        Err(poisoned) => {
+            warn!("Mutex poisoned, recovering: {:?}", poisoned);
+            poisoned.into_inner()
+        }
+    }
+}
+
+pub struct AlertConfig {
+    pub above: Option<f64>,
+    pub below: Option<f64>,
+    pub webhook_url: String,
+}
+
+impl AlertConfig {
+    // Returns None when no thresholds are configured, which disables alerting entirely
+    pub fn from_env() -> Option<AlertConfig> {
+        let above = env_parse::<f64>("PRICE_ALERT_ABOVE");
+        let below = env_parse::<f64>("PRICE_ALERT_BELOW");
+        if above.is_none() && below.is_none() {
+            return None;
+        }
+
+        match std::env::var("ALERT_WEBHOOK_URL") {
+            Ok(webhook_url) if !webhook_url.is_empty() => Some(AlertConfig { above, below, webhook_url }),
+            _ => {
+                warn!("Price alert thresholds set but ALERT_WEBHOOK_URL is missing, alerts disabled");
+                None
+            }
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct PriceAlert {
+    direction: &'static str,
+    threshold: f64,
+    previous_price: f64,
+    price: f64,
+    timestamp: String,
+}
+
+pub struct BasicAuth {
+    pub username: String,
+    pub password: String,
+}
+
+impl BasicAuth {
+    // None unless both API_USERNAME and API_PASSWORD are set
+    pub fn from_env() -> Option<BasicAuth> {
+        let username = std::env::var("API_USERNAME").ok().filter(|v| !v.is_empty());
+        let password = std::env::var("API_PASSWORD").ok().filter(|v| !v.is_empty());
+
+        match (username, password) {
+            (Some(username), Some(password)) => Some(BasicAuth { username, password }),
+            (None, None) => None,
+            _ => {
+                warn!("Only one of API_USERNAME/API_PASSWORD is set, Basic Auth disabled");
+                None
+            }
+        }
+    }
+
+    fn matches(&self, authorization: &str) -> bool {
+        let encoded = match authorization.strip_prefix("Basic ") {
+            Some(encoded) => encoded.trim(),
+            None => return false,
+        };
+        let decoded = match base64::engine::general_purpose::STANDARD.decode(encoded) {
+            Ok(decoded) => decoded,
+            Err(_) => return false,
+        };
+        let credentials = match String::from_utf8(decoded) {
+            Ok(credentials) => credentials,
+            Err(_) => return false,
+        };
+
+        match credentials.split_once(':') {
+            // Both are compared either way, so timing doesn't tell which one was wrong
+            Some((username, password)) => {
+                secrets_match(username, &self.username) & secrets_match(password, &self.password)
+            }
+            None => false,
+        }
+    }
+}
+
+// Compares a provided credential in time that doesn't depend on where it first differs from
+// the expected one, so response timing can't be used to guess it byte by byte
+fn secrets_match(provided: &str, expected: &str) -> bool {
+    provided.as_bytes().ct_eq(expected.as_bytes()).into()
+}
+
+// Credentials for the protected routes: Basic Auth, an API_TOKEN bearer token, or both,
+// in which case either one is accepted
+pub struct ApiAuth {
+    basic: Option<BasicAuth>,
+    token: Option<String>,
+}
+
+impl ApiAuth {
+    // None when neither is configured, in which case nothing is auth-protected
+    pub fn new(basic: Option<BasicAuth>, token: Option<String>) -> Option<ApiAuth> {
+        if basic.is_none() && token.is_none() {
+            return None;
+        }
+        Some(ApiAuth { basic, token })
+    }
+
+    fn matches(&self, authorization: &str) -> bool {
+        if let (Some(token), Some(provided)) = (&self.token, authorization.strip_prefix("Bearer ")) {
+            if secrets_match(provided.trim(), token) {
+                return true;
+            }
+        }
+        self.basic.as_ref().is_some_and(|basic| basic.matches(authorization))
+    }
+
+    fn challenge(&self) -> &'static str {
+        if self.basic.is_some() {
+            "Basic realm=\"bitcoin-explore\""
+        } else {
+            "Bearer realm=\"bitcoin-explore\""
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Unauthorized {
+    challenge: &'static str,
+}
+
+impl warp::reject::Reject for Unauthorized {}
+
+// MAX_CONCURRENT_REQUESTS requests were already in flight
+#[derive(Debug)]
+struct Overloaded;
+
+impl warp::reject::Reject for Overloaded {}
+
+pub struct TlsConfig {
+    pub cert_path: String,
+    pub key_path: String,
+}
+
+impl TlsConfig {
+    // Both paths must be given together; a lone cert or key is a misconfiguration.
+    // TLS_CERT and TLS_KEY are accepted as shorter names for the same settings.
+    pub fn from_env() -> Result<Option<TlsConfig>, String> {
+        let var = |names: [&str; 2]| {
+            names
+                .iter()
+                .find_map(|name| std::env::var(name).ok().filter(|v| !v.is_empty()))
+        };
+        match (var(["TLS_CERT_PATH", "TLS_CERT"]), var(["TLS_KEY_PATH", "TLS_KEY"])) {
+            (Some(cert_path), Some(key_path)) => Ok(Some(TlsConfig { cert_path, key_path })),
+            (None, None) => Ok(None),
+            (Some(_), None) => Err("TLS_CERT_PATH is set but TLS_KEY_PATH is missing".to_string()),
+            (None, Some(_)) => Err("TLS_KEY_PATH is set but TLS_CERT_PATH is missing".to_string()),
+        }
+    }
+
+    // warp only opens the files once serving starts, and a failure there would stop the
+    // server task while polling carried on. Checked by run only when it serves the API, so a
+    // collector with SERVE_API=0 doesn't need the files.
+    fn check_readable(&self) -> Result<(), String> {
+        for path in [&self.cert_path, &self.key_path] {
+            std::fs::File::open(path).map_err(|e| format!("Cannot read {}: {}", path, e))?;
+        }
+        Ok(())
+    }
+}
+
+fn env_parse<T: std::str::FromStr>(key: &str) -> Option<T> {
+    parse_setting(key, std::env::var(key).ok())
+}
+
+// Warns about and ignores a value that doesn't parse, like env_parse, for a value that may
+// come from somewhere other than the process environment
+fn parse_setting<T: std::str::FromStr>(key: &str, value: Option<String>) -> Option<T> {
+    let value = value?;
+    match value.trim().parse() {
+        Ok(parsed) => Some(parsed),
+        Err(_) => {
+            warn!("Ignoring invalid value for {}: {:?}", key, value);
+            None
+        }
+    }
+}
+
+// Only a move from one side of a threshold to the other counts as a crossing, so
+// consecutive ticks that stay on the same side never fire twice.
+fn detect_price_crossings(config: &AlertConfig, previous_price: f64, price: f64) -> Vec<PriceAlert> {
+    let mut alerts = Vec::new();
+
+    if let Some(above) = config.above {
+        if previous_price < above && price >= above {
+            alerts.push(PriceAlert {
+                direction: "above",
+                threshold: above,
+                previous_price,
+                price,
+                timestamp: now_timestamp(),
+            });
+        }
+    }
+
+    if let Some(below) = config.below {
+        if previous_price >= below && price < below {
+            alerts.push(PriceAlert {
+                direction: "below",
+                threshold: below,
+                previous_price,
+                price,
+                timestamp: now_timestamp(),
+            });
+        }
+    }
+
+    alerts
+}
+
+async fn send_price_alert(client: &reqwest::Client, webhook_url: &str, alert: &PriceAlert) -> Result<(), Error> {
+    client
+        .post(webhook_url)
+        .json(alert)
+        .send()
+        .await?
+        .error_for_status()?;
+    Ok(())
+}
+
+// Somewhere outside the API to hand each saved sample to. Called from the fetch loop, so
+// implementations queue the sample and return rather than wait on the network.
+trait MetricsSink: Send + Sync {
+    fn publish(&self, metrics: &Metrics);
+}
+
+const DEFAULT_MQTT_TOPIC: &str = "bitcoin/metrics";
+// Publishes held while the broker is unreachable; once full, new samples are dropped
+#[cfg(feature = "mqtt")]
+const MQTT_QUEUE_CAPACITY: usize = 64;
+#[cfg(feature = "mqtt")]
+const MQTT_RECONNECT_DELAY: Duration = Duration::from_secs(5);
+#[cfg(feature = "mqtt")]
+const MQTT_DEFAULT_PORT: u16 = 1883;
+
+pub struct MqttConfig {
+    // mqtt://[user:password@]host[:port]
+    pub broker_url: String,
+    pub topic: String,
+}
+
+impl MqttConfig {
+    // Returns None when MQTT_BROKER_URL is unset, which disables publishing entirely
+    pub fn from_env() -> Option<MqttConfig> {
+        let broker_url = std::env::var("MQTT_BROKER_URL").ok().filter(|url| !url.is_empty())?;
+        let topic = std::env::var("MQTT_TOPIC")
+            .ok()
+            .filter(|topic| !topic.is_empty())
+            .unwrap_or_else(|| DEFAULT_MQTT_TOPIC.to_string());
+        Some(MqttConfig { broker_url, topic })
+    }
+}
+
+// Publishes each sample's JSON to the configured topic
+#[cfg(feature = "mqtt")]
+struct MqttSink {
+    client: rumqttc::AsyncClient,
+    topic: String,
+}
+
+#[cfg(feature = "mqtt")]
+impl MqttSink {
+    // Spawns the event loop that drives the connection. rumqttc reconnects on the poll
+    // after an error, so a broker outage only holds up publishes, never the fetch loop.
+    fn connect(config: &MqttConfig) -> Result<MqttSink, String> {
+        let url = reqwest::Url::parse(&config.broker_url).map_err(|e| format!("Invalid MQTT_BROKER_URL: {}", e))?;
+        if !matches!(url.scheme(), "mqtt" | "tcp") {
+            return Err(format!("Unsupported MQTT_BROKER_URL scheme {:?}, expected mqtt://", url.scheme()));
+        }
+        let host = url.host_str().ok_or("MQTT_BROKER_URL has no host")?;
+
+        let client_id = format!("bitcoin-explore-{}", std::process::id());
+        let mut options = rumqttc::MqttOptions::new(client_id, host, url.port().unwrap_or(MQTT_DEFAULT_PORT));
+        options.set_keep_alive(Duration::from_secs(30));
+        if !url.username().is_empty() {
+            options.set_credentials(url.username(), url.password().unwrap_or_default());
+        }
+
+        let (client, mut event_loop) = rumqttc::AsyncClient::new(options, MQTT_QUEUE_CAPACITY);
+        tokio::spawn(async move {
+            // Only the first failure of an outage is logged, not every reconnect attempt
+            let mut reported = false;
+            loop {
+                match event_loop.poll().await {
+                    Ok(rumqttc::Event::Incoming(rumqttc::Packet::ConnAck(_))) => {
+                        info!("Connected to MQTT broker");
+                        reported = false;
+                    }
+                    Ok(_) => {}
+                    // Every client handle is gone, nothing left to publish
+                    Err(rumqttc::ConnectionError::RequestsDone) => break,
+                    Err(e) => {
+                        if !reported {
+                            warn!("MQTT broker connection failed, retrying in the background: {}", e);
+                            reported = true;
+                        }
+                        time::sleep(MQTT_RECONNECT_DELAY).await;
+                    }
+                }
+            }
+        });
+
+        Ok(MqttSink {
+            client,
+            topic: config.topic.clone(),
+        })
+    }
+}
+
+#[cfg(feature = "mqtt")]
+impl MetricsSink for MqttSink {
+    fn publish(&self, metrics: &Metrics) {
+        let payload = match serde_json::to_vec(metrics) {
+            Ok(payload) => payload,
+            Err(e) => {
+                error!("Error serializing metrics for MQTT: {}", e);
+                return;
+            }
+        };
+        if let Err(e) = self.client.try_publish(&self.topic, rumqttc::QoS::AtLeastOnce, false, payload) {
+            warn!("Dropping MQTT publish for {}: {}", metrics.asset, e);
+        }
+    }
+}
+
+#[cfg(feature = "mqtt")]
+fn connect_sinks(mqtt: Option<&MqttConfig>) -> Result<Vec<Arc<dyn MetricsSink>>, String> {
+    let mut sinks: Vec<Arc<dyn MetricsSink>> = Vec::new();
+    if let Some(mqtt) = mqtt {
+        sinks.push(Arc::new(MqttSink::connect(mqtt)?));
+        info!("Publishing metrics to MQTT topic {}", mqtt.topic);
+    }
+    Ok(sinks)
+}
+
+#[cfg(not(feature = "mqtt"))]
+fn connect_sinks(mqtt: Option<&MqttConfig>) -> Result<Vec<Arc<dyn MetricsSink>>, String> {
+    match mqtt {
+        Some(_) => Err("MQTT_BROKER_URL is set but this build lacks the `mqtt` feature".to_string()),
+        None => Ok(Vec::new()),
+    }
+}
+
+// Upstream requests give up after this long, so a hung API costs one tick rather than
+// stalling the poll loop
+const HTTP_TIMEOUT: Duration = Duration::from_secs(10);
+const HTTP_CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+// Idle keep-alive connections kept per upstream host between ticks
+const HTTP_POOL_IDLE_PER_HOST: usize = 4;
+
+// Retries after the first attempt for errors that may clear up on their own
+const FETCH_RETRIES: u32 = 2;
+// Doubled after each retry
+const FETCH_RETRY_BACKOFF: Duration = Duration::from_millis(250);
+
+// One client for every upstream call, so they share the pool, user agent and timeouts
+fn build_http_client(user_agent: &str) -> reqwest::Client {
+    reqwest::Client::builder()
+        .user_agent(user_agent)
+        .timeout(HTTP_TIMEOUT)
+        .connect_timeout(HTTP_CONNECT_TIMEOUT)
+        .pool_max_idle_per_host(HTTP_POOL_IDLE_PER_HOST)
+        .build()
+        .expect("Failed to build HTTP client")
+}
+
+// Timeouts, refused connections, rate limits and 5xx answers are worth another try;
+// anything else would fail the same way again
+fn is_retryable(result: &std::result::Result<reqwest::Response, Error>) -> bool {
+    match result {
+        Ok(response) => {
+            let status = response.status();
+            status == StatusCode::TOO_MANY_REQUESTS || status.is_server_error()
+        }
+        Err(e) => e.is_timeout() || e.is_connect(),
+    }
+}
+
+// GETs the body as text, retrying transient failures with a doubling backoff. A non-2xx
+// answer that outlasts the retries is an error carrying the start of its body.
+async fn get_with_retry(client: &reqwest::Client, url: &str) -> Result<String, FetchError> {
+    let mut backoff = FETCH_RETRY_BACKOFF;
+    let mut retries = 0;
+    loop {
+        let result = client.get(url).send().await;
+        if retries == FETCH_RETRIES || !is_retryable(&result) {
+            let response = result?;
+            let status = response.status();
+            let body = response.text().await?;
+            if !status.is_success() {
+                return Err(FetchError::Status {
+                    url: url.to_string(),
+                    status,
+                    snippet: response_snippet(&body),
+                });
+            }
+            return Ok(body);
+        }
+        match &result {
+            Ok(response) => warn!("{} answered {}, retrying in {:?}", url, response.status(), backoff),
+            Err(e) => warn!("Request to {} failed: {}, retrying in {:?}", url, e, backoff),
+        }
+        time::sleep(backoff).await;
+        backoff *= 2;
+        retries += 1;
+    }
+}
+
+// How much of an unparseable upstream body to keep in logs
+const RESPONSE_SNIPPET_CHARS: usize = 200;
+
+#[derive(Debug)]
+enum FetchError {
+    Http(Error),
+    // Not attempted because the source's circuit breaker is open
+    CircuitOpen,
+    // The endpoint answered but not with the JSON we expect, e.g. an HTML error page
+    UnexpectedResponse {
+        url: String,
+        error: serde_json::Error,
+        snippet: String,
+    },
+    // Valid JSON, but none of the requested prices were in it
+    NoPrices {
+        url: String,
+    },
+    // The endpoint answered with a non-2xx status, e.g. during an upstream incident
+    Status {
+        url: String,
+        status: StatusCode,
+        snippet: String,
+    },
+    // A 2xx plain-text answer that isn't the value asked for, e.g. an empty body
+    InvalidBody {
+        url: String,
+        expected: &'static str,
+        snippet: String,
+    },
+    // PRICE_AGGREGATION=median heard back from fewer than MIN_MEDIAN_SOURCES feeds
+    TooFewSources {
+        answered: usize,
+        errors: Vec<String>,
+    },
+}
+
+impl FetchError {
+    // Stored with the fetch error so outage types can be told apart afterwards
+    fn kind(&self) -> &'static str {
+        match self {
+            FetchError::Http(e) if e.is_timeout() => "timeout",
+            FetchError::Http(_) => "network",
+            FetchError::CircuitOpen => "circuit_open",
+            FetchError::Status { .. } => "http_status",
+            FetchError::UnexpectedResponse { .. } | FetchError::InvalidBody { .. } => "invalid_body",
+            FetchError::NoPrices { .. } => "no_prices",
+            FetchError::TooFewSources { .. } => "too_few_sources",
+        }
+    }
+}
+
+impl std::fmt::Display for FetchError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            FetchError::Http(e) => write!(f, "{}", e),
+            FetchError::CircuitOpen => write!(f, "skipped while the circuit breaker is open"),
+            FetchError::UnexpectedResponse { url, error, snippet } => {
+                write!(f, "unexpected response format from {}: {} (body: {:?})", url, error, snippet)
+            }
+            FetchError::NoPrices { url } => write!(f, "no requested prices in response from {}", url),
+            FetchError::Status { url, status, snippet } => {
+                write!(f, "upstream {} returned HTTP {} (body: {:?})", url, status, snippet)
+            }
+            FetchError::InvalidBody { url, expected, snippet } if snippet.is_empty() => {
+                write!(f, "expected {} from {}, got an empty body", expected, url)
+            }
+            FetchError::InvalidBody { url, expected, snippet } => {
+                write!(f, "expected {} from {}, got {:?}", expected, url, snippet)
+            }
+            FetchError::TooFewSources { answered, errors } => write!(
+                f,
+                "{} of the price sources answered, a median needs {}: {}",
+                answered,
+                MIN_MEDIAN_SOURCES,
+                errors.join("; ")
+            ),
+        }
+    }
+}
+
+impl From<Error> for FetchError {
+    fn from(e: Error) -> Self {
+        FetchError::Http(e)
+    }
+}
+
+fn response_snippet(body: &str) -> String {
+    let body = body.trim();
+    match body.char_indices().nth(RESPONSE_SNIPPET_CHARS) {
+        Some((end, _)) => format!("{}...", &body[..end]),
+        None => body.to_string(),
+    }
+}
+
+// Reads the body as text first so a decode failure can report what was actually returned
+async fn fetch_with_retry<T: serde::de::DeserializeOwned>(client: &reqwest::Client, url: &str) -> Result<T, FetchError> {
+    let body = get_with_retry(client, url).await?;
+
+    serde_json::from_str(&body).map_err(|error| FetchError::UnexpectedResponse {
+        url: url.to_string(),
+        error,
+        snippet: response_snippet(&body),
+    })
+}
+
+// Roots of the upstream APIs, without a trailing slash
+#[derive(Clone)]
+struct ApiBases {
+    price: String,
+    chain: String,
+    coinbase: String,
+    kraken: String,
+}
+
+// Price feeds PRICE_SOURCES can list. CoinGecko prices every asset in one request; the
+// exchanges take one request per asset and currency and only know the assets in
+// exchange_symbol.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PriceSource {
+    CoinGecko,
+    Coinbase,
+    Kraken,
+}
+
+impl PriceSource {
+    fn name(self) -> &'static str {
+        match self {
+            PriceSource::CoinGecko => PRICE_SOURCE_COINGECKO,
+            PriceSource::Coinbase => PRICE_SOURCE_COINBASE,
+            PriceSource::Kraken => PRICE_SOURCE_KRAKEN,
+        }
+    }
+
+    fn parse(name: &str) -> Option<PriceSource> {
+        [PriceSource::CoinGecko, PriceSource::Coinbase, PriceSource::Kraken]
+            .into_iter()
+            .find(|source| source.name() == name)
+    }
+}
+
+// Ticker for a CoinGecko id on the exchanges, which quote by symbol. Kraken still calls
+// bitcoin XBT.
+fn exchange_symbol(source: PriceSource, asset: &str) -> Option<&'static str> {
+    let symbol = match asset {
+        "bitcoin" => "BTC",
+        "ethereum" => "ETH",
+        "litecoin" => "LTC",
+        "solana" => "SOL",
+        _ => return None,
+    };
+    Some(match (source, symbol) {
+        (PriceSource::Kraken, "BTC") => "XBT",
+        _ => symbol,
+    })
+}
+
+// Sources from a comma separated PRICE_SOURCES, in the order given. Unknown names are
+// skipped with a warning, and an empty result falls back to CoinGecko alone.
+pub fn parse_price_sources(raw: &str) -> Vec<PriceSource> {
+    let mut sources = Vec::new();
+    for name in raw.split(',').map(|name| name.trim().to_lowercase()).filter(|name| !name.is_empty()) {
+        match PriceSource::parse(&name) {
+            Some(source) if !sources.contains(&source) => sources.push(source),
+            Some(_) => {}
+            None => warn!("Ignoring unknown price source {:?} in PRICE_SOURCES", name),
+        }
+    }
+
+    if sources.is_empty() {
+        vec![PriceSource::CoinGecko]
+    } else {
+        sources
+    }
+}
+
+// PRICE_AGGREGATION. Single takes prices from the first of price_sources that answers;
+// median asks all of them every tick and stores the median of those that answered.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PriceAggregation {
+    Single,
+    Median,
+}
+
+impl PriceAggregation {
+    pub fn from_env() -> PriceAggregation {
+        match std::env::var("PRICE_AGGREGATION").as_deref().map(str::trim) {
+            Ok("median") => PriceAggregation::Median,
+            Ok("single") | Ok("") | Err(_) => PriceAggregation::Single,
+            Ok(other) => {
+                warn!("Ignoring unknown PRICE_AGGREGATION {:?}, using a single source", other);
+                PriceAggregation::Single
+            }
+        }
+    }
+}
+
+// Middle value, or the mean of the two middle values for an even count
+fn median(values: &mut [f64]) -> Option<f64> {
+    if values.is_empty() {
+        return None;
+    }
+    values.sort_by(f64::total_cmp);
+    let mid = values.len() / 2;
+    Some(if values.len() % 2 == 0 {
+        (values[mid - 1] + values[mid]) / 2.0
+    } else {
+        values[mid]
+    })
+}
+
+// Quotes per asset, then per currency, as one source returns them
+type AssetPrices = HashMap<String, BTreeMap<String, f64>>;
+
+// Per asset and currency median over the feeds that quoted it, leaving out quotes fewer
+// than MIN_MEDIAN_SOURCES feeds had. The spread is taken over the USD quotes behind each
+// asset's median.
+fn aggregate_median(quotes: &[(PriceSource, AssetPrices)]) -> (AssetPrices, HashMap<String, PriceSpread>) {
+    let mut by_quote: BTreeMap<(&str, &str), Vec<f64>> = BTreeMap::new();
+    let mut spreads: HashMap<String, PriceSpread> = HashMap::new();
+    for (source, prices) in quotes {
+        for (asset, currencies) in prices {
+            for (currency, value) in currencies {
+                by_quote.entry((asset, currency)).or_default().push(*value);
+                if currency == BASE_CURRENCY {
+                    spreads
+                        .entry(asset.clone())
+                        .or_default()
+                        .source_prices
+                        .insert(source.name().to_string(), *value);
+                }
+            }
+        }
+    }
+
+    let mut prices = AssetPrices::new();
+    for ((asset, currency), mut values) in by_quote {
+        if values.len() < MIN_MEDIAN_SOURCES {
+            continue;
+        }
+        if let Some(value) = median(&mut values) {
+            prices.entry(asset.to_string()).or_default().insert(currency.to_string(), value);
+        }
+    }
+    // Below the minimum there's no median USD price for the spread to describe
+    spreads.retain(|_, spread| spread.source_prices.len() >= MIN_MEDIAN_SOURCES);
+    for spread in spreads.values_mut() {
+        let (low, high) = spread
+            .source_prices
+            .values()
+            .fold((f64::INFINITY, f64::NEG_INFINITY), |(low, high), value| (low.min(*value), high.max(*value)));
+        spread.price_spread = Some(high - low);
+    }
+    (prices, spreads)
+}
+
+// Blockstream answers with a bare integer as text/plain
+async fn fetch_block_height(client: &reqwest::Client, chain_api_base: &str) -> Result<u64, FetchError> {
+    let url = format!("{}/blocks/tip/height", chain_api_base);
+    let body = get_with_retry(client, &url).await?;
+
+    body.trim().parse().map_err(|_| FetchError::InvalidBody {
+        url,
+        expected: "an integer block height",
+        snippet: response_snippet(&body),
+    })
+}
+
+// Looked up by height rather than from blocks/tip/hash: the tip can move between the two
+// requests, and pairing a height with the next block's hash would look like a reorg
+async fn fetch_block_hash(client: &reqwest::Client, chain_api_base: &str, height: u64) -> Result<String, FetchError> {
+    let url = format!("{}/block-height/{}", chain_api_base, height);
+    let body = get_with_retry(client, &url).await?;
+
+    let hash = body.trim();
+    if hash.len() != 64 || !hash.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return Err(FetchError::InvalidBody {
+            url,
+            expected: "a block hash",
+            snippet: response_snippet(&body),
+        });
+    }
+    Ok(hash.to_ascii_lowercase())
+}
+
+// Blockstream keys the estimates by confirmation target as a string, e.g. {"1": 25.2, "6": 12.1},
+// and leaves out targets it has no estimate for. Anything but a non-negative number for a
+// target counts as missing rather than failing the others.
+async fn fetch_fee_estimates(client: &reqwest::Client, chain_api_base: &str) -> Result<FeeEstimates, FetchError> {
+    let url = format!("{}/fee-estimates", chain_api_base);
+    let estimates: HashMap<String, serde_json::Value> = fetch_with_retry(client, &url).await?;
+
+    let target = |blocks: &str| {
+        estimates
+            .get(blocks)
+            .and_then(serde_json::Value::as_f64)
+            .filter(|rate| rate.is_finite() && *rate >= 0.0)
+    };
+    Ok(FeeEstimates {
+        fee_1_block: target("1"),
+        fee_6_blocks: target("6"),
+        fee_144_blocks: target("144"),
+    })
+}
+
+// One request covers every tracked asset; assets missing from the response are left out
+async fn fetch_prices(
+    client: &reqwest::Client,
+    price_api_base: &str,
+    assets: &[String],
+    currencies: &[String],
+) -> Result<HashMap<String, BTreeMap<String, f64>>, FetchError> {
+    let url = format!(
+        "{}/simple/price?ids={}&vs_currencies={}",
+        price_api_base,
+        assets.join(","),
+        currencies.join(",")
+    );
+    let response: SimplePriceResponse = fetch_with_retry(client, &url).await?;
+    requested_prices(response, currencies).ok_or(FetchError::NoPrices { url })
+}
+
+// Coinbase's prices/{BASE}-{QUOTE}/spot, with the amount as a decimal string
+#[derive(Deserialize)]
+struct CoinbaseSpot {
+    data: CoinbaseAmount,
+}
+
+#[derive(Deserialize)]
+struct CoinbaseAmount {
+    amount: String,
+}
+
+// Kraken's Ticker, keyed by its own pair name (XXBTZUSD for XBTUSD). Unknown pairs come
+// back as a 200 with only `error` filled in.
+#[derive(Deserialize)]
+struct KrakenTicker {
+    #[serde(default)]
+    result: HashMap<String, KrakenPair>,
+}
+
+#[derive(Deserialize)]
+struct KrakenPair {
+    // Last trade as [price, lot volume]
+    c: Vec<String>,
+}
+
+async fn fetch_exchange_quote(
+    client: &reqwest::Client,
+    source: PriceSource,
+    api_base: &str,
+    symbol: &str,
+    currency: &str,
+) -> Result<Option<f64>, FetchError> {
+    let quote = match source {
+        PriceSource::Coinbase => {
+            let url = format!("{}/prices/{}-{}/spot", api_base, symbol, currency.to_uppercase());
+            let spot: CoinbaseSpot = fetch_with_retry(client, &url).await?;
+            spot.data.amount.parse::<f64>().ok()
+        }
+        PriceSource::Kraken => {
+            let url = format!("{}/Ticker?pair={}{}", api_base, symbol, currency.to_uppercase());
+            let ticker: KrakenTicker = fetch_with_retry(client, &url).await?;
+            ticker
+                .result
+                .values()
+                .next()
+                .and_then(|pair| pair.c.first())
+                .and_then(|price| price.parse::<f64>().ok())
+        }
+        PriceSource::CoinGecko => None,
+    };
+    Ok(quote.filter(|price| price.is_finite() && *price > 0.0))
+}
+
+// Same shape as fetch_prices, one request per asset and currency. Pairs the exchange
+// doesn't list are left out; the error is only returned when nothing was priced.
+async fn fetch_exchange_prices(
+    client: &reqwest::Client,
+    source: PriceSource,
+    api_base: &str,
+    assets: &[String],
+    currencies: &[String],
+) -> Result<HashMap<String, BTreeMap<String, f64>>, FetchError> {
+    let mut prices: HashMap<String, BTreeMap<String, f64>> = HashMap::new();
+    let mut first_error = None;
+    for asset in assets {
+        let symbol = match exchange_symbol(source, asset) {
+            Some(symbol) => symbol,
+            None => continue,
+        };
+        for currency in currencies {
+            match fetch_exchange_quote(client, source, api_base, symbol, currency).await {
+                Ok(Some(price)) => {
+                    prices.entry(asset.clone()).or_default().insert(currency.clone(), price);
+                }
+                Ok(None) => {}
+                Err(e) => {
+                    first_error.get_or_insert(e);
+                }
+            }
+        }
+    }
+
+    if !prices.is_empty() {
+        return Ok(prices);
+    }
+    Err(first_error.unwrap_or_else(|| FetchError::NoPrices { url: api_base.to_string() }))
+}
+
+async fn fetch_prices_from(
+    client: &reqwest::Client,
+    source: PriceSource,
+    api_bases: &ApiBases,
+    assets: &[String],
+    currencies: &[String],
+) -> Result<HashMap<String, BTreeMap<String, f64>>, FetchError> {
+    match source {
+        PriceSource::CoinGecko => fetch_prices(client, &api_bases.price, assets, currencies).await,
+        PriceSource::Coinbase => fetch_exchange_prices(client, source, &api_bases.coinbase, assets, currencies).await,
+        PriceSource::Kraken => fetch_exchange_prices(client, source, &api_bases.kraken, assets, currencies).await,
+    }
+}
+
+// Daily [unix millis, price] points from CoinGecko's market_chart endpoint
+#[derive(Deserialize)]
+struct MarketChart {
+    prices: Vec<(f64, f64)>,
+}
+
+async fn fetch_price_history(
+    client: &reqwest::Client,
+    price_api_base: &str,
+    asset: &str,
+    days: u32,
+) -> Result<Vec<(String, f64)>, FetchError> {
+    let url = format!(
+        "{}/coins/{}/market_chart?vs_currency=usd&days={}&interval=daily",
+        price_api_base, asset, days
+    );
+    let chart: MarketChart = fetch_with_retry(client, &url).await?;
+
+    Ok(chart
+        .prices
+        .into_iter()
+        .filter_map(|(millis, price)| {
+            chrono::DateTime::from_timestamp_millis(millis as i64)
+                .map(|ts| (ts.format(TIMESTAMP_FORMAT).to_string(), price))
+        })
+        .collect())
+}
+
+// Comma separated vs_currencies from VS_CURRENCIES; usd is always included and listed first
+pub fn quote_currencies_from_env() -> Vec<String> {
+    let mut currencies = vec![BASE_CURRENCY.to_string()];
+    for currency in std::env::var("VS_CURRENCIES").unwrap_or_default().split(',') {
+        let currency = currency.trim().to_lowercase();
+        if !currency.is_empty() && !currencies.contains(&currency) {
+            currencies.push(currency);
+        }
+    }
+    currencies
+}
+
+// Comma separated CoinGecko ids from TRACKED_ASSETS, defaulting to bitcoin only
+pub fn tracked_assets_from_env() -> Vec<String> {
+    let assets: Vec<String> = std::env::var("TRACKED_ASSETS")
+        .unwrap_or_default()
+        .split(',')
+        .map(|asset| asset.trim().to_lowercase())
+        .filter(|asset| !asset.is_empty())
+        .collect();
+
+    if assets.is_empty() {
+        vec![DEFAULT_ASSET.to_string()]
+    } else {
+        assets
+    }
+}
+
+// Read-only connections opened alongside the writer unless SQLITE_READ_CONNECTIONS overrides it
+const DEFAULT_SQLITE_READ_CONNECTIONS: usize = 4;
+
+// How long a connection waits on a locked database before giving up with SQLITE_BUSY,
+// unless SQLITE_BUSY_TIMEOUT_MS overrides it
+const DEFAULT_SQLITE_BUSY_TIMEOUT: Duration = Duration::from_secs(5);
+
+const SQLITE_JOURNAL_MODES: [&str; 6] = ["DELETE", "TRUNCATE", "PERSIST", "MEMORY", "WAL", "OFF"];
+
+// SQLITE_SYNCHRONOUS settings, from fastest to most durable. OFF leaves flushing to the OS,
+// so a power loss or OS crash can corrupt the file. NORMAL is safe from corruption under
+// WAL but may lose the last commits to a power loss. FULL fsyncs every commit.
+const SQLITE_SYNCHRONOUS_MODES: [&str; 3] = ["OFF", "NORMAL", "FULL"];
+
+// What open_store does with a SQLite file that fails its startup integrity check, from ON_CORRUPT
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum OnCorrupt {
+    // Refuse to start, leaving the file untouched for manual recovery
+    Fail,
+    // Move the file aside as <path>.corrupt-<time> and start on an empty database
+    Reset,
+}
+
+impl OnCorrupt {
+    pub fn from_env() -> OnCorrupt {
+        match std::env::var("ON_CORRUPT").as_deref().map(str::trim) {
+            Ok("reset") => OnCorrupt::Reset,
+            Ok("fail") | Ok("") | Err(_) => OnCorrupt::Fail,
+            Ok(other) => {
+                warn!("Ignoring unknown ON_CORRUPT {:?}, refusing to start on a corrupt database", other);
+                OnCorrupt::Fail
+            }
+        }
+    }
+}
+
+fn is_db_corrupt(e: &rusqlite::Error) -> bool {
+    matches!(
+        e,
+        rusqlite::Error::SqliteFailure(err, _)
+            if err.code == rusqlite::ErrorCode::DatabaseCorrupt || err.code == rusqlite::ErrorCode::NotADatabase
+    )
+}
+
+// None for a healthy file. Otherwise what PRAGMA integrity_check reported, or the error it
+// failed with when the damage, or a file that isn't SQLite at all, stops it from running.
+fn integrity_problems(conn: &Connection) -> Result<Option<String>> {
+    let report = conn
+        .prepare("PRAGMA integrity_check")
+        .and_then(|mut stmt| stmt.query_map([], |row| row.get::<_, String>(0))?.collect::<Result<Vec<_>>>());
+    match report {
+        Ok(report) if report == ["ok"] => Ok(None),
+        Ok(report) => Ok(Some(report.join("; "))),
+        Err(e) if is_db_corrupt(&e) => Ok(Some(e.to_string())),
+        Err(e) => Err(e),
+    }
+}
+
+// Renames the database and any WAL or shared-memory file next to it, so they stay
+// together for inspection. Returns the new database path.
+fn move_corrupt_database(path: &str) -> std::io::Result<String> {
+    let aside = format!("{}.corrupt-{}", path, chrono::Utc::now().format("%Y%m%d-%H%M%S"));
+    std::fs::rename(path, &aside)?;
+    for suffix in ["-wal", "-shm"] {
+        let companion = format!("{}{}", path, suffix);
+        if std::path::Path::new(&companion).exists() {
+            std::fs::rename(&companion, format!("{}{}", aside, suffix))?;
+        }
+    }
+    Ok(aside)
+}
+
+// WAL lets the API read while the poller writes instead of blocking on the rollback
+// journal. It is paired with synchronous=NORMAL, which skips an fsync per commit: a
+// crash can't corrupt the database, but a power loss may drop the last few inserts.
+// SQLITE_JOURNAL_MODE=DELETE restores SQLite's default journal and FULL sync, and
+// SQLITE_SYNCHRONOUS overrides the sync level under either journal.
+fn configure_connection(conn: &Connection, journal_mode: &str, synchronous: Option<&str>, busy_timeout: Duration) -> Result<()> {
+    // Set first so even the journal_mode switch waits out another process's lock
+    conn.busy_timeout(busy_timeout)?;
+    let mode: String = conn.query_row(&format!("PRAGMA journal_mode = {}", journal_mode), [], |row| {
+        row.get(0)
+    })?;
+    if !mode.eq_ignore_ascii_case(journal_mode) {
+        warn!("SQLite kept journal_mode={} instead of {}", mode, journal_mode);
+    }
+
+    match synchronous {
+        Some(synchronous) => conn.execute_batch(&format!("PRAGMA synchronous = {}", synchronous))?,
+        None if mode.eq_ignore_ascii_case("wal") => conn.execute_batch("PRAGMA synchronous = NORMAL")?,
+        None => {}
+    }
+    // SQLite leaves REFERENCES unenforced unless asked
+    conn.execute_batch("PRAGMA foreign_keys = ON")?;
+    Ok(())
+}
+
+pub fn create_metrics_table(conn: &Connection) -> Result<()> {
+    // Create table if it doesn't exist
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS metrics (
+            id INTEGER PRIMARY KEY,
+            block_height INTEGER,
+            btc_price REAL,
+            timestamp DATETIME DEFAULT CURRENT_TIMESTAMP
+        )",
+        [],
+    )?;
+
+    // Tables created before multi-asset support only held bitcoin rows
+    if !column_exists(conn, "metrics", "asset")? {
+        conn.execute(
+            "ALTER TABLE metrics ADD COLUMN asset TEXT NOT NULL DEFAULT 'bitcoin'",
+            [],
+        )?;
+    }
+    conn.execute(
+        "CREATE INDEX IF NOT EXISTS idx_metrics_asset_id ON metrics (asset, id)",
+        [],
+    )?;
+    // Lets /api/metrics/ath read the top price straight off the index
+    conn.execute(
+        "CREATE INDEX IF NOT EXISTS idx_metrics_asset_price ON metrics (asset, btc_price)",
+        [],
+    )?;
+
+    // Every row before source attribution came from CoinGecko
+    if !column_exists(conn, "metrics", "source")? {
+        conn.execute(
+            "ALTER TABLE metrics ADD COLUMN source TEXT NOT NULL DEFAULT 'coingecko'",
+            [],
+        )?;
+    }
+
+    // Latency wasn't recorded before this column, so older rows stay null
+    if !column_exists(conn, "metrics", "fetch_latency_ms")? {
+        conn.execute("ALTER TABLE metrics ADD COLUMN fetch_latency_ms INTEGER", [])?;
+    }
+
+    if !column_exists(conn, "metrics", "block_hash")? {
+        conn.execute("ALTER TABLE metrics ADD COLUMN block_hash TEXT", [])?;
+    }
+
+    for column in ["fee_1_block", "fee_6_blocks", "fee_144_blocks"] {
+        if !column_exists(conn, "metrics", column)? {
+            conn.execute(&format!("ALTER TABLE metrics ADD COLUMN {} REAL", column), [])?;
+        }
+    }
+
+    // Only set under PRICE_AGGREGATION=median, so older rows stay null
+    if !column_exists(conn, "metrics", "price_spread")? {
+        conn.execute("ALTER TABLE metrics ADD COLUMN price_spread REAL", [])?;
+    }
+
+    // One row per quote currency per sample, so adding a currency needs no schema change
+    let prices_existed = conn
+        .query_row(
+            "SELECT COUNT(*) FROM sqlite_master WHERE type = 'table' AND name = 'prices'",
+            [],
+            |row| row.get::<_, i64>(0),
+        )?
+        > 0;
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS prices (
+            metric_id INTEGER NOT NULL REFERENCES metrics (id) ON DELETE CASCADE,
+            currency TEXT NOT NULL,
+            value REAL NOT NULL,
+            PRIMARY KEY (metric_id, currency)
+        )",
+        [],
+    )?;
+    // Rows from before the prices table only had the USD price
+    if !prices_existed {
+        conn.execute(
+            "INSERT INTO prices (metric_id, currency, value)
+             SELECT id, 'usd', btc_price FROM metrics WHERE btc_price IS NOT NULL",
+            [],
+        )?;
+    }
+
+    // Each feed's USD quote behind a median price, one row per feed
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS source_prices (
+            metric_id INTEGER NOT NULL REFERENCES metrics (id) ON DELETE CASCADE,
+            source TEXT NOT NULL,
+            value REAL NOT NULL,
+            PRIMARY KEY (metric_id, source)
+        )",
+        [],
+    )?;
+
+    // Notes on ticks that were only partially saved, one row per failed source
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS fetch_errors (
+            id INTEGER PRIMARY KEY,
+            source TEXT NOT NULL,
+            message TEXT NOT NULL,
+            timestamp DATETIME DEFAULT CURRENT_TIMESTAMP
+        )",
+        [],
+    )?;
+
+    // What went wrong, e.g. http_status or invalid_body; older rows only have the message
+    if !column_exists(conn, "fetch_errors", "kind")? {
+        conn.execute("ALTER TABLE fetch_errors ADD COLUMN kind TEXT", [])?;
+    }
+
+    // One OHLC row per asset per hour for samples past ROLLUP_AGE, see rollup_hourly
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS metrics_hourly (
+            asset TEXT NOT NULL,
+            hour TEXT NOT NULL,
+            open REAL NOT NULL,
+            high REAL NOT NULL,
+            low REAL NOT NULL,
+            close REAL NOT NULL,
+            avg REAL NOT NULL,
+            max_block_height INTEGER,
+            samples INTEGER NOT NULL,
+            PRIMARY KEY (asset, hour)
+        )",
+        [],
+    )?;
+    Ok(())
+}
+
+fn column_exists(conn: &Connection, table: &str, column: &str) -> Result<bool> {
+    let mut stmt = conn.prepare(&format!("PRAGMA table_info({})", table))?;
+    let names = stmt.query_map([], |row| row.get::<_, String>(1))?;
+
+    for name in names {
+        if name? == column {
+            return Ok(true);
+        }
+    }
+
+    Ok(false)
+}
+
+// Returns the id of the inserted row
+pub fn save_metrics(conn: &Connection, row: &MetricsRow) -> Result<i64> {
+    let tx = conn.unchecked_transaction()?;
+    tx.execute(
+        "INSERT INTO metrics (block_height, block_hash, fee_1_block, fee_6_blocks, fee_144_blocks, btc_price, price_spread, asset, source, fetch_latency_ms, timestamp)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, CURRENT_TIMESTAMP)",
+        params![
+            row.block_height,
+            row.block_hash,
+            row.fees.fee_1_block,
+            row.fees.fee_6_blocks,
+            row.fees.fee_144_blocks,
+            row.btc_price,
+            row.spread.price_spread,
+            row.asset,
+            row.source,
+            row.fetch_latency_ms
+        ],
+    )?;
+    let id = tx.last_insert_rowid();
+    save_prices(&tx, id, &row.prices)?;
+    save_source_prices(&tx, id, &row.spread.source_prices)?;
+    tx.commit()?;
+
+    Ok(id)
+}
+
+fn save_prices(conn: &Connection, metric_id: i64, prices: &BTreeMap<String, f64>) -> Result<()> {
+    let mut stmt = conn.prepare_cached("INSERT INTO prices (metric_id, currency, value) VALUES (?1, ?2, ?3)")?;
+    for (currency, value) in prices {
+        stmt.execute(params![metric_id, currency, value])?;
+    }
+    Ok(())
+}
+
+fn save_source_prices(conn: &Connection, metric_id: i64, source_prices: &BTreeMap<String, f64>) -> Result<()> {
+    let mut stmt = conn.prepare_cached("INSERT INTO source_prices (metric_id, source, value) VALUES (?1, ?2, ?3)")?;
+    for (source, value) in source_prices {
+        stmt.execute(params![metric_id, source, value])?;
+    }
+    Ok(())
+}
+
+// Bulk insert for backfill and imports: one transaction instead of a commit per row. Each
+// row keeps its own timestamp and ids are assigned by SQLite. Returns the number inserted.
+pub fn save_metrics_batch(conn: &mut Connection, metrics: &[Metrics]) -> Result<usize> {
+    let tx = conn.transaction()?;
+    {
+        let mut stmt = tx.prepare(
+            "INSERT INTO metrics (block_height, block_hash, fee_1_block, fee_6_blocks, fee_144_blocks, btc_price, price_spread, asset, source, fetch_latency_ms, timestamp)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11)",
+        )?;
+        for row in metrics {
+            stmt.execute(params![
+                row.block_height,
+                row.block_hash,
+                row.fees.fee_1_block,
+                row.fees.fee_6_blocks,
+                row.fees.fee_144_blocks,
+                row.btc_price,
+                row.spread.price_spread,
+                row.asset,
+                row.source,
+                row.fetch_latency_ms,
+                row.timestamp
+            ])?;
+            let id = tx.last_insert_rowid();
+            save_prices(&tx, id, &row.prices)?;
+            save_source_prices(&tx, id, &row.spread.source_prices)?;
+        }
+    }
+    tx.commit()?;
+
+    Ok(metrics.len())
+}
+
+pub fn save_fetch_error(conn: &Connection, source: &str, kind: &str, message: &str) -> Result<()> {
+    conn.execute(
+        "INSERT INTO fetch_errors (source, kind, message) VALUES (?1, ?2, ?3)",
+        params![source, kind, message],
+    )?;
+    Ok(())
+}
+
+// One entry per currency ever stored for the asset, from the newest row that has it.
+// Relies on SQLite filling bare columns from the row that produced the MAX.
+pub fn get_currencies(conn: &Connection, asset: &str) -> Result<Vec<CurrencyQuote>> {
+    let mut stmt = conn.prepare(
+        "SELECT p.currency, p.value, m.timestamp, MAX(p.metric_id)
+         FROM prices p JOIN metrics m ON m.id = p.metric_id
+         WHERE m.asset = ?1
+         GROUP BY p.currency
+         ORDER BY p.currency",
+    )?;
+    let rows = stmt.query_map(params![asset], |row| {
+        Ok(CurrencyQuote {
+            currency: row.get(0)?,
+            value: row.get(1)?,
+            timestamp: row.get(2)?,
+        })
+    })?;
+    rows.collect()
+}
+
+// Keeps the newest `max_rows` samples across all assets; prices go with them via ON DELETE CASCADE
+pub fn evict_oldest_metrics(conn: &Connection, max_rows: u64) -> Result<usize> {
+    conn.execute(
+        "DELETE FROM metrics WHERE id NOT IN (SELECT id FROM metrics ORDER BY id DESC LIMIT ?1)",
+        params![max_rows],
+    )
+}
+
+// None when the asset has no rows yet
+fn oldest_asset_timestamp(conn: &Connection, asset: &str) -> Result<Option<String>> {
+    conn.query_row("SELECT MIN(timestamp) FROM metrics WHERE asset = ?1", params![asset], |row| row.get(0))
+}
+
+// The height on the newest row that has one, across all assets
+fn last_block_height(conn: &Connection) -> Result<Option<u64>> {
+    conn.query_row(
+        "SELECT block_height FROM metrics WHERE block_height IS NOT NULL ORDER BY id DESC LIMIT 1",
+        [],
+        |row| row.get(0),
+    )
+    .optional()
+}
+
+// Either bound may be left open; both are inclusive like the range endpoints
+pub fn count_metrics(conn: &Connection, asset: &str, from: Option<&str>, to: Option<&str>) -> Result<i64> {
+    conn.query_row(
+        "SELECT COUNT(*) FROM metrics
+         WHERE asset = ?1 AND (?2 IS NULL OR timestamp >= ?2) AND (?3 IS NULL OR timestamp <= ?3)",
+        params![asset, from, to],
+        |row| row.get(0),
+    )
+}
+
+pub fn get_metrics_by_id(conn: &Connection, id: i64) -> Result<Metrics> {
+    query_metrics(conn, "WHERE id = ?1", "ASC", params![id])?
+        .into_iter()
+        .next()
+        .ok_or(rusqlite::Error::QueryReturnedNoRows)
+}
+
+// Runs `SELECT METRICS_COLUMNS FROM metrics {filter}` joined with each row's per-currency prices.
+// `order` must match the order the filter selects in so the joined rows of one sample stay together.
+fn query_metrics<P: rusqlite::Params>(conn: &Connection, filter: &str, order: &str, params: P) -> Result<Vec<Metrics>> {
+    let mut stmt = conn.prepare(&format!(
+        "SELECT m.*, p.currency, p.value
+         FROM (SELECT {} FROM metrics {}) m
+         LEFT JOIN prices p ON p.metric_id = m.id
+         ORDER BY m.id {}, p.currency",
+        METRICS_COLUMNS, filter, order
+    ))?;
+    let mut rows = stmt.query(params)?;
+
+    let mut metrics: Vec<Metrics> = Vec::new();
+    while let Some(row) = rows.next()? {
+        let id: i64 = row.get(0)?;
+        if metrics.last().map(|last| last.id) != Some(id) {
+            metrics.push(metrics_from_row(row)?);
+        }
+        if let (Some(last), Some(currency)) = (metrics.last_mut(), row.get::<_, Option<String>>("currency")?) {
+            last.prices.insert(currency, row.get("value")?);
+        }
+    }
+
+    Ok(metrics)
+}
+
+pub fn get_latest_metrics(conn: &Connection) -> Result<Vec<Metrics>, rusqlite::Error> {
+    query_metrics(
+        conn,
+        "WHERE id IN (SELECT MAX(id) FROM metrics GROUP BY asset) ORDER BY id ASC",
+        "ASC",
+        [],
+    )
+}
+
+// Expects the columns in METRICS_COLUMNS order
+fn metrics_from_row(row: &rusqlite::Row) -> Result<Metrics> {
+    Ok(Metrics {
+        id: row.get(0)?,
+        block_height: row.get(1)?,
+        btc_price: row.get(2)?,
+        prices: BTreeMap::new(),
+        timestamp: row.get(3)?,
+        asset: row.get(4)?,
+        source: row.get(5)?,
+        fetch_latency_ms: row.get(6)?,
+        block_hash: row.get(7)?,
+        fees: FeeEstimates {
+            fee_1_block: row.get(8)?,
+            fee_6_blocks: row.get(9)?,
+            fee_144_blocks: row.get(10)?,
+        },
+        spread: PriceSpread {
+            price_spread: row.get(11)?,
+            source_prices: BTreeMap::new(),
+        },
+    })
+}
+
+// The max_limit cap always wins over the requested limit
+pub fn get_metrics_history(
+    conn: &Connection,
+    asset: &str,
+    limit: Option<u32>,
+    max_limit: u32,
+) -> Result<MetricsHistory, rusqlite::Error> {
+    let requested = limit.unwrap_or(DEFAULT_METRICS_LIMIT);
+    let limit = requested.min(max_limit);
+
+    let metrics = query_metrics(
+        conn,
+        "WHERE asset = ?1 ORDER BY id DESC LIMIT ?2",
+        "DESC",
+        params![asset, limit],
+    )?;
+
+    Ok(MetricsHistory {
+        metrics,
+        truncated: requested > limit,
+    })
+}
+
+pub fn get_metrics_since(conn: &Connection, asset: &str, after_id: i64, limit: u32) -> Result<Vec<Metrics>, rusqlite::Error> {
+    query_metrics(
+        conn,
+        "WHERE asset = ?1 AND id > ?2 ORDER BY id ASC LIMIT ?3",
+        "ASC",
+        params![asset, after_id, limit],
+    )
+}
+
+pub fn get_metrics_before(conn: &Connection, asset: &str, before_id: i64, limit: u32) -> Result<Vec<Metrics>, rusqlite::Error> {
+    query_metrics(
+        conn,
+        "WHERE asset = ?1 AND id < ?2 ORDER BY id DESC LIMIT ?3",
+        "DESC",
+        params![asset, before_id, limit],
+    )
+}
+
+fn get_metrics_page(conn: &Connection, after_id: i64, limit: i64) -> Result<Vec<Metrics>, rusqlite::Error> {
+    query_metrics(conn, "WHERE id > ?1 ORDER BY id ASC LIMIT ?2", "ASC", params![after_id, limit])
+}
+
+// Every priced sample of an asset between two timestamps, oldest first
+fn get_price_series(conn: &Connection, asset: &str, from: &str, to: &str) -> Result<Vec<SeriesPoint>, rusqlite::Error> {
+    let mut stmt = conn.prepare(
+        "SELECT id, timestamp, btc_price, block_height FROM metrics
+         WHERE asset = ?1 AND btc_price IS NOT NULL AND timestamp >= ?2 AND timestamp <= ?3
+         ORDER BY timestamp ASC, id ASC",
+    )?;
+
+    let series_iter = stmt.query_map(params![asset, from, to], |row| {
+        Ok(SeriesPoint {
+            id: row.get(0)?,
+            timestamp: row.get(1)?,
+            btc_price: row.get(2)?,
+            block_height: row.get(3)?,
+        })
+    })?;
+
+    let mut series = Vec::new();
+    for point in series_iter {
+        series.push(point?);
+    }
+
+    Ok(series)
+}
+
+// Open/close come from the first/last row by id in each bucket
+fn get_price_buckets(
+    conn: &Connection,
+    asset: &str,
+    interval_secs: i64,
+    from: &str,
+    to: &str,
+) -> Result<Vec<PriceBucket>, rusqlite::Error> {
+    let mut stmt = conn.prepare(
+        "SELECT datetime(b.bucket * ?1, 'unixepoch'),
+                (SELECT btc_price FROM metrics WHERE id = b.first_id),
+                b.high, b.low,
+                (SELECT btc_price FROM metrics WHERE id = b.last_id),
+                b.avg, b.max_height, b.samples
+         FROM (
+             SELECT CAST(strftime('%s', timestamp) AS INTEGER) / ?1 AS bucket,
+                    MIN(id) AS first_id, MAX(id) AS last_id,
+                    MAX(btc_price) AS high, MIN(btc_price) AS low, AVG(btc_price) AS avg,
+                    MAX(block_height) AS max_height, COUNT(*) AS samples
+             FROM metrics
+             WHERE asset = ?2 AND btc_price IS NOT NULL AND timestamp >= ?3 AND timestamp <= ?4
+             GROUP BY bucket
+         ) b
+         ORDER BY b.bucket ASC",
+    )?;
+
+    let buckets_iter = stmt.query_map(params![interval_secs, asset, from, to], |row| {
+        Ok(PriceBucket {
+            start: row.get(0)?,
+            open: row.get(1)?,
+            high: row.get(2)?,
+            low: row.get(3)?,
+            close: row.get(4)?,
+            avg: row.get(5)?,
+            max_block_height: row.get(6)?,
+            samples: row.get(7)?,
+        })
+    })?;
+
+    let mut buckets = Vec::new();
+    for bucket in buckets_iter {
+        buckets.push(bucket?);
+    }
+
+    Ok(buckets)
+}
+
+// Rolls priced samples from before `cutoff` into hourly OHLC rows, opening and closing on
+// the first and last row by id like the buckets. Only hours after each asset's latest
+// rollup are scanned, and hours already rolled up are left alone, so with raw rows pruned
+// a rerun can't overwrite a full hour with what's left of it. Returns the hours added.
+pub fn rollup_hourly(conn: &Connection, cutoff: &str) -> Result<usize> {
+    conn.execute(
+        "INSERT OR IGNORE INTO metrics_hourly (asset, hour, open, high, low, close, avg, max_block_height, samples)
+         SELECT b.asset, b.hour,
+                (SELECT btc_price FROM metrics WHERE id = b.first_id),
+                b.high, b.low,
+                (SELECT btc_price FROM metrics WHERE id = b.last_id),
+                b.avg, b.max_height, b.samples
+         FROM (
+             SELECT asset, strftime('%Y-%m-%d %H:00:00', timestamp) AS hour,
+                    MIN(id) AS first_id, MAX(id) AS last_id,
+                    MAX(btc_price) AS high, MIN(btc_price) AS low, AVG(btc_price) AS avg,
+                    MAX(block_height) AS max_height, COUNT(*) AS samples
+             FROM metrics m
+             WHERE btc_price IS NOT NULL AND timestamp < ?1
+               AND timestamp >= COALESCE((SELECT MAX(hour) FROM metrics_hourly h WHERE h.asset = m.asset), '')
+             GROUP BY asset, hour
+         ) b",
+        params![cutoff],
+    )
+}
+
+// Deletes raw samples from before `cutoff`, along with their prices. Returns the rows deleted.
+pub fn prune_old_metrics(conn: &Connection, cutoff: &str) -> Result<usize> {
+    conn.execute("DELETE FROM metrics WHERE timestamp < ?1", params![cutoff])
+}
+
+fn get_hourly_rollups(conn: &Connection, asset: &str, from: &str, to: &str) -> Result<Vec<PriceBucket>, rusqlite::Error> {
+    let mut stmt = conn.prepare(
+        "SELECT hour, open, high, low, close, avg, max_block_height, samples FROM metrics_hourly
+         WHERE asset = ?1 AND hour >= ?2 AND hour <= ?3
+         ORDER BY hour ASC",
+    )?;
+
+    let rollups_iter = stmt.query_map(params![asset, from, to], |row| {
+        Ok(PriceBucket {
+            start: row.get(0)?,
+            open: row.get(1)?,
+            high: row.get(2)?,
+            low: row.get(3)?,
+            close: row.get(4)?,
+            avg: row.get(5)?,
+            max_block_height: row.get(6)?,
+            samples: row.get(7)?,
+        })
+    })?;
+
+    let mut rollups = Vec::new();
+    for rollup in rollups_iter {
+        rollups.push(rollup?);
+    }
+
+    Ok(rollups)
+}
+
+// First time each block height was observed, in ascending height order
+fn get_block_first_seen(conn: &Connection) -> Result<Vec<(u64, String)>, rusqlite::Error> {
+    let mut stmt = conn.prepare(
+        "SELECT block_height, MIN(timestamp) FROM metrics WHERE block_height IS NOT NULL
+         GROUP BY block_height ORDER BY block_height ASC",
+    )?;
+
+    let rows = stmt.query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?;
+
+    let mut first_seen = Vec::new();
+    for row in rows {
+        first_seen.push(row?);
+    }
+
+    Ok(first_seen)
+}
+
+// The newest `limit` sample times, oldest first
+fn get_sample_timestamps(conn: &Connection, asset: &str, limit: u32) -> Result<Vec<String>, rusqlite::Error> {
+    let mut stmt = conn.prepare(
+        "SELECT timestamp FROM (
+             SELECT timestamp FROM metrics WHERE asset = ?1 ORDER BY timestamp DESC LIMIT ?2
+         ) ORDER BY timestamp ASC",
+    )?;
+
+    let rows = stmt.query_map(params![asset, limit], |row| row.get(0))?;
+
+    let mut timestamps = Vec::new();
+    for row in rows {
+        timestamps.push(row?);
+    }
+
+    Ok(timestamps)
+}
+
+// The sample closest to `at` within `tolerance_secs` either side, so a time before the
+// history starts or inside a long gap finds nothing rather than some far-off row
+fn get_nearest_metrics(conn: &Connection, asset: &str, at: &str, tolerance_secs: i64) -> Result<Option<Metrics>> {
+    let at_time = match parse_timestamp(at) {
+        Some(at_time) => at_time,
+        None => return Ok(None),
+    };
+    let tolerance = chrono::Duration::seconds(tolerance_secs);
+    let earliest = (at_time - tolerance).format(TIMESTAMP_FORMAT).to_string();
+    let latest = (at_time + tolerance).format(TIMESTAMP_FORMAT).to_string();
+
+    let nearest = query_metrics(
+        conn,
+        "WHERE asset = ?1 AND timestamp >= ?2 AND timestamp <= ?3
+         ORDER BY ABS(julianday(timestamp) - julianday(?4)), id DESC LIMIT 1",
+        "ASC",
+        params![asset, earliest, latest, at],
+    )?;
+    Ok(nearest.into_iter().next())
+}
+
+// The highest-priced and the newest priced sample for an asset, None without any prices
+fn get_all_time_high(conn: &Connection, asset: &str) -> Result<Option<(Metrics, Metrics)>> {
+    let ath = query_metrics(
+        conn,
+        "WHERE asset = ?1 AND btc_price IS NOT NULL ORDER BY btc_price DESC, id ASC LIMIT 1",
+        "ASC",
+        params![asset],
+    )?;
+    let current = query_metrics(
+        conn,
+        "WHERE asset = ?1 AND btc_price IS NOT NULL ORDER BY id DESC LIMIT 1",
+        "ASC",
+        params![asset],
+    )?;
+    Ok(ath.into_iter().next().zip(current.into_iter().next()))
+}
+
+// Relies on SQLite filling bare columns from the row that produced the MAX/MIN
+fn get_high_low(conn: &Connection, asset: &str, since: &str, window_secs: i64) -> Result<HighLow, rusqlite::Error> {
+    let extreme = |aggregate: &str| {
+        conn.query_row(
+            &format!(
+                "SELECT {}(btc_price), timestamp FROM metrics
+                 WHERE asset = ?1 AND btc_price IS NOT NULL AND timestamp >= ?2",
+                aggregate
+            ),
+            params![asset, since],
+            |row| Ok((row.get::<_, Option<f64>>(0)?, row.get::<_, Option<String>>(1)?)),
+        )
+    };
+    let (high, high_at) = extreme("MAX")?;
+    let (low, low_at) = extreme("MIN")?;
+
+    Ok(HighLow {
+        window_secs,
+        high,
+        high_at,
+        low,
+        low_at,
+    })
+}
+
+// Spans between consecutive samples longer than the threshold, usually the service being
+// down. Unparseable timestamps are skipped rather than reported as gaps.
+fn find_gaps(timestamps: &[String], threshold_secs: i64) -> Vec<Gap> {
+    let points: Vec<(&String, chrono::NaiveDateTime)> = timestamps
+        .iter()
+        .filter_map(|ts| parse_timestamp(ts).map(|parsed| (ts, parsed)))
+        .collect();
+
+    points
+        .windows(2)
+        .filter_map(|pair| {
+            let (start, start_at) = pair[0];
+            let (end, end_at) = pair[1];
+            let duration_secs = (end_at - start_at).num_seconds();
+            (duration_secs > threshold_secs).then(|| Gap {
+                start: start.clone(),
+                end: end.clone(),
+                duration_secs,
+            })
+        })
+        .collect()
+}
+
+// The usual spacing between samples, as the median so the gaps themselves and the odd
+// retried poll don't drag it around. None with fewer than two distinct sample times.
+fn typical_interval_secs(timestamps: &[String]) -> Option<i64> {
+    let points: Vec<chrono::NaiveDateTime> = timestamps.iter().filter_map(|ts| parse_timestamp(ts)).collect();
+    let mut intervals: Vec<i64> = points
+        .windows(2)
+        .map(|pair| (pair[1] - pair[0]).num_seconds())
+        .filter(|secs| *secs > 0)
+        .collect();
+    if intervals.is_empty() {
+        return None;
+    }
+    intervals.sort_unstable();
+    Some(intervals[intervals.len() / 2])
+}
+
+// Divides the time between height changes by the number of blocks advanced, so a
+// jump of several blocks between two polls still counts as several intervals.
+fn compute_block_times(first_seen: &[(u64, String)]) -> BlockTimeStats {
+    let points: Vec<(u64, chrono::NaiveDateTime)> = first_seen
+        .iter()
+        .filter_map(|(height, ts)| parse_timestamp(ts).map(|ts| (*height, ts)))
+        .collect();
+
+    let mut per_block = Vec::new();
+    let mut total_secs = 0.0;
+    let mut blocks = 0;
+    for pair in points.windows(2) {
+        let (prev_height, prev_ts) = pair[0];
+        let (height, ts) = pair[1];
+        let secs = (ts - prev_ts).num_seconds();
+        if secs < 0 {
+            continue;
+        }
+        let advanced = height - prev_height;
+        per_block.push(secs as f64 / advanced as f64);
+        total_secs += secs as f64;
+        blocks += advanced;
+    }
+
+    if blocks == 0 {
+        return BlockTimeStats::default();
+    }
+
+    per_block.sort_by(|a, b| a.total_cmp(b));
+    let mid = per_block.len() / 2;
+    let median = if per_block.len() % 2 == 0 {
+        (per_block[mid - 1] + per_block[mid]) / 2.0
+    } else {
+        per_block[mid]
+    };
+
+    BlockTimeStats {
+        average_secs: Some(total_secs / blocks as f64),
+        median_secs: Some(median),
+        blocks,
+    }
+}
+
+// The next halving is the first multiple of HALVING_INTERVAL above `height`, so a
+// halving block itself already belongs to the new era
+fn compute_halving(height: u64, average_block_secs: Option<f64>, now: chrono::NaiveDateTime) -> HalvingEstimate {
+    let halvings = height / HALVING_INTERVAL;
+    let next_halving_block = (halvings + 1) * HALVING_INTERVAL;
+    let blocks_remaining = next_halving_block - height;
+    let average_block_secs = average_block_secs.filter(|secs| *secs > 0.0).unwrap_or(TARGET_BLOCK_SECS);
+    let subsidy_sats = INITIAL_SUBSIDY_SATS.checked_shr(halvings as u32).unwrap_or(0);
+    let eta = now + chrono::Duration::seconds((blocks_remaining as f64 * average_block_secs).round() as i64);
+
+    HalvingEstimate {
+        block_height: height,
+        reward_era: halvings + 1,
+        block_reward_btc: subsidy_sats as f64 / 100_000_000.0,
+        next_halving_block,
+        blocks_remaining,
+        average_block_secs,
+        estimated_at: eta.format(TIMESTAMP_FORMAT).to_string(),
+    }
+}
+
+#[derive(Debug)]
+pub enum StoreError {
+    Sqlite(rusqlite::Error),
+    #[cfg(feature = "postgres")]
+    Postgres(tokio_postgres::Error),
+}
+
+impl std::fmt::Display for StoreError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            StoreError::Sqlite(e) => write!(f, "{}", e),
+            #[cfg(feature = "postgres")]
+            StoreError::Postgres(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for StoreError {}
+
+impl From<rusqlite::Error> for StoreError {
+    fn from(e: rusqlite::Error) -> Self {
+        StoreError::Sqlite(e)
+    }
+}
+
+#[cfg(feature = "postgres")]
+impl From<tokio_postgres::Error> for StoreError {
+    fn from(e: tokio_postgres::Error) -> Self {
+        StoreError::Postgres(e)
+    }
+}
+
+impl StoreError {
+    // Transient lock contention, worth a client retry
+    fn is_busy(&self) -> bool {
+        match self {
+            StoreError::Sqlite(e) => is_db_busy(e),
+            #[cfg(feature = "postgres")]
+            StoreError::Postgres(_) => false,
+        }
+    }
+}
+
+// Storage for the core collect/read path. SQLite is the default; a postgres:// DATABASE_URL
+// selects the Postgres store when built with the `postgres` feature.
+#[async_trait]
+pub trait MetricsStore: Send + Sync {
+    async fn create_metrics_table(&self) -> Result<(), StoreError>;
+
+    // Returns the stored row as it will be served
+    async fn save_metrics(&self, row: &MetricsRow) -> Result<Metrics, StoreError>;
+
+    // Writes every row in one transaction, keeping each row's own timestamp
+    async fn save_metrics_batch(&self, rows: &[Metrics]) -> Result<usize, StoreError>;
+
+    async fn save_fetch_error(&self, source: &str, kind: &str, message: &str) -> Result<(), StoreError>;
+
+    // Deletes the oldest rows beyond `max_rows`, returning how many went
+    async fn evict_oldest(&self, max_rows: u64) -> Result<usize, StoreError>;
+
+    async fn get_metrics_history(
+        &self,
+        asset: &str,
+        limit: Option<u32>,
+        max_limit: u32,
+    ) -> Result<MetricsHistory, StoreError>;
+
+    // Rows after `after_id`, oldest first, so a client can catch up from the last id it saw
+    async fn get_metrics_since(&self, asset: &str, after_id: i64, limit: u32) -> Result<Vec<Metrics>, StoreError>;
+
+    // Rows before `before_id`, newest first, for paging back through history by id
+    async fn get_metrics_before(&self, asset: &str, before_id: i64, limit: u32) -> Result<Vec<Metrics>, StoreError>;
+
+    async fn count_metrics(&self, asset: &str, from: Option<&str>, to: Option<&str>) -> Result<i64, StoreError>;
+
+    async fn get_currencies(&self, asset: &str) -> Result<Vec<CurrencyQuote>, StoreError>;
+
+    async fn get_latest_metrics(&self) -> Result<Vec<Metrics>, StoreError>;
+
+    // The height on the newest stored row that has one, across all assets
+    async fn last_block_height(&self) -> Result<Option<u64>, StoreError>;
+}
+
+// Attempts closer together than this reuse the current connections, so a file that stays
+// corrupt isn't reopened on every query
+const SQLITE_REOPEN_MIN_INTERVAL: Duration = Duration::from_secs(30);
+
+// Enough to reopen a file-backed database the way open_store first opened it
+pub struct SqliteReopen {
+    pub path: String,
+    pub journal_mode: String,
+    pub synchronous: Option<String>,
+    pub busy_timeout: Duration,
+    // INTEGRITY_CHECK_ON_CORRUPT: logs what PRAGMA integrity_check makes of the reopened file
+    pub integrity_check: bool,
+}
+
+pub struct SqliteStore {
+    conn: Arc<Mutex<Connection>>,
+    // Serves the read methods when set, leaving `conn` to the poller's writes
+    readers: Option<Arc<ReadPool>>,
+    // Set for file databases, which are reopened after SQLITE_CORRUPT or SQLITE_NOTADB
+    reopen: Option<SqliteReopen>,
+    last_reopen: Mutex<Option<std::time::Instant>>,
+}
+
+impl SqliteStore {
+    pub fn new(conn: Arc<Mutex<Connection>>) -> SqliteStore {
+        SqliteStore {
+            conn,
+            readers: None,
+            reopen: None,
+            last_reopen: Mutex::new(None),
+        }
+    }
+
+    pub fn with_reopen(self, reopen: SqliteReopen) -> SqliteStore {
+        SqliteStore {
+            reopen: Some(reopen),
+            ..self
+        }
+    }
+
+    pub fn with_readers(self, readers: Arc<ReadPool>) -> SqliteStore {
+        SqliteStore {
+            readers: Some(readers),
+            ..self
+        }
+    }
+
+    fn read_conn(&self) -> MutexGuard<'_, Connection> {
+        match &self.readers {
+            Some(readers) => readers.get(),
+            None => lock_or_recover(&self.conn),
+        }
+    }
+
+    // Passes a query's result through, first reopening the connections when it failed
+    // because the file is corrupt. Callers release their connection before this runs.
+    fn recover<T>(&self, result: Result<T, rusqlite::Error>) -> Result<T, StoreError> {
+        if let (Err(e), Some(reopen)) = (&result, &self.reopen) {
+            if is_db_corrupt(e) {
+                self.reopen(reopen, e);
+            }
+        }
+        Ok(result?)
+    }
+
+    // The shared handles keep their Arcs, so routes querying SQLite directly pick up the
+    // new connections too. A file that still won't open leaves the old ones in place.
+    fn reopen(&self, reopen: &SqliteReopen, cause: &rusqlite::Error) {
+        {
+            let mut last_reopen = lock_or_recover(&self.last_reopen);
+            let now = std::time::Instant::now();
+            if last_reopen.is_some_and(|last| now.duration_since(last) < SQLITE_REOPEN_MIN_INTERVAL) {
+                return;
+            }
+            *last_reopen = Some(now);
+        }
+        warn!("Database {} reported {}, reopening it", reopen.path, cause);
+
+        let conn = Connection::open(&reopen.path).and_then(|conn| {
+            configure_connection(&conn, &reopen.journal_mode, reopen.synchronous.as_deref(), reopen.busy_timeout)?;
+            Ok(conn)
+        });
+        let conn = match conn {
+            Ok(conn) => conn,
+            Err(e) => {
+                error!("Failed to reopen database {}: {}", reopen.path, e);
+                return;
+            }
+        };
+        if reopen.integrity_check {
+            match integrity_problems(&conn) {
+                Ok(None) => info!("Reopened database {} passed its integrity check", reopen.path),
+                Ok(Some(problems)) => error!("Reopened database {} failed its integrity check: {}", reopen.path, problems),
+                Err(e) => error!("Failed to run an integrity check on {}: {}", reopen.path, e),
+            }
+        }
+
+        *lock_or_recover(&self.conn) = conn;
+        if let Some(readers) = &self.readers {
+            if let Err(e) = readers.reopen(&reopen.path, reopen.busy_timeout) {
+                error!("Failed to reopen read connections to {}: {}", reopen.path, e);
+            }
+        }
+        info!("Reopened database {}", reopen.path);
+    }
+}
+
+// Read-only connections for the API, so reads never queue behind the poller holding the
+// writer connection. Only useful in WAL mode, where readers and the writer run concurrently.
+pub struct ReadPool {
+    conns: Vec<Arc<Mutex<Connection>>>,
+    next: AtomicUsize,
+}
+
+impl ReadPool {
+    pub fn open(path: &str, size: usize, busy_timeout: Duration) -> Result<ReadPool> {
+        let conns = (0..size.max(1))
+            .map(|_| Ok(Arc::new(Mutex::new(ReadPool::open_reader(path, busy_timeout)?))))
+            .collect::<Result<Vec<_>>>()?;
+
+        Ok(ReadPool {
+            conns,
+            next: AtomicUsize::new(0),
+        })
+    }
+
+    fn open_reader(path: &str, busy_timeout: Duration) -> Result<Connection> {
+        let conn = Connection::open_with_flags(
+            path,
+            OpenFlags::SQLITE_OPEN_READ_ONLY | OpenFlags::SQLITE_OPEN_NO_MUTEX | OpenFlags::SQLITE_OPEN_URI,
+        )?;
+        conn.busy_timeout(busy_timeout)?;
+        Ok(conn)
+    }
+
+    // Swaps a fresh connection into every slot, in place so handed-out Arcs follow along
+    fn reopen(&self, path: &str, busy_timeout: Duration) -> Result<()> {
+        for slot in &self.conns {
+            let conn = ReadPool::open_reader(path, busy_timeout)?;
+            *lock_or_recover(slot) = conn;
+        }
+        Ok(())
+    }
+
+    // Takes the first idle connection, starting from a rotating offset; if all are busy,
+    // waits for the next one in turn
+    fn get(&self) -> MutexGuard<'_, Connection> {
+        let start = self.next.fetch_add(1, Ordering::Relaxed);
+        for offset in 0..self.conns.len() {
+            if let Ok(conn) = self.conns[(start + offset) % self.conns.len()].try_lock() {
+                return conn;
+            }
+        }
+        lock_or_recover(&self.conns[start % self.conns.len()])
+    }
+}
+
+// Direct SQLite handles for the routes and jobs that bypass MetricsStore
+pub struct SqliteConnections {
+    pub writer: Arc<Mutex<Connection>>,
+    // A pooled read-only connection when the pool is enabled, otherwise the writer
+    pub reader: Arc<Mutex<Connection>>,
+}
+
+#[async_trait]
+impl MetricsStore for SqliteStore {
+    async fn create_metrics_table(&self) -> Result<(), StoreError> {
+        let result = create_metrics_table(&lock_or_recover(&self.conn));
+        self.recover(result)
+    }
+
+    async fn save_metrics(&self, row: &MetricsRow) -> Result<Metrics, StoreError> {
+        let result = {
+            let conn = lock_or_recover(&self.conn);
+            save_metrics(&conn, row).and_then(|id| get_metrics_by_id(&conn, id))
+        };
+        self.recover(result)
+    }
+
+    async fn save_metrics_batch(&self, rows: &[Metrics]) -> Result<usize, StoreError> {
+        let result = save_metrics_batch(&mut lock_or_recover(&self.conn), rows);
+        self.recover(result)
+    }
+
+    async fn save_fetch_error(&self, source: &str, kind: &str, message: &str) -> Result<(), StoreError> {
+        let result = save_fetch_error(&lock_or_recover(&self.conn), source, kind, message);
+        self.recover(result)
+    }
+
+    async fn evict_oldest(&self, max_rows: u64) -> Result<usize, StoreError> {
+        let result = evict_oldest_metrics(&lock_or_recover(&self.conn), max_rows);
+        self.recover(result)
+    }
+
+    async fn get_metrics_history(
+        &self,
+        asset: &str,
+        limit: Option<u32>,
+        max_limit: u32,
+    ) -> Result<MetricsHistory, StoreError> {
+        let result = get_metrics_history(&self.read_conn(), asset, limit, max_limit);
+        self.recover(result)
+    }
+
+    async fn get_metrics_since(&self, asset: &str, after_id: i64, limit: u32) -> Result<Vec<Metrics>, StoreError> {
+        let result = get_metrics_since(&self.read_conn(), asset, after_id, limit);
+        self.recover(result)
+    }
+
+    async fn get_metrics_before(&self, asset: &str, before_id: i64, limit: u32) -> Result<Vec<Metrics>, StoreError> {
+        let result = get_metrics_before(&self.read_conn(), asset, before_id, limit);
+        self.recover(result)
+    }
+
+    async fn count_metrics(&self, asset: &str, from: Option<&str>, to: Option<&str>) -> Result<i64, StoreError> {
+        let result = count_metrics(&self.read_conn(), asset, from, to);
+        self.recover(result)
+    }
+
+    async fn get_currencies(&self, asset: &str) -> Result<Vec<CurrencyQuote>, StoreError> {
+        let result = get_currencies(&self.read_conn(), asset);
+        self.recover(result)
+    }
+
+    async fn get_latest_metrics(&self) -> Result<Vec<Metrics>, StoreError> {
+        let result = get_latest_metrics(&self.read_conn());
+        self.recover(result)
+    }
+
+    async fn last_block_height(&self) -> Result<Option<u64>, StoreError> {
+        let result = last_block_height(&self.read_conn());
+        self.recover(result)
+    }
+}
+
+#[cfg(feature = "postgres")]
+const POSTGRES_METRICS_COLUMNS: &str = "id, block_height, btc_price, to_char(timestamp, 'YYYY-MM-DD HH24:MI:SS'), asset, source, \
+     fetch_latency_ms, block_hash, fee_1_block, fee_6_blocks, fee_144_blocks, price_spread";
+
+#[cfg(feature = "postgres")]
+struct PostgresStore {
+    // Behind a mutex because transactions need exclusive access to the client
+    client: tokio::sync::Mutex<tokio_postgres::Client>,
+}
+
+#[cfg(feature = "postgres")]
+impl PostgresStore {
+    async fn connect(database_url: &str) -> Result<PostgresStore, tokio_postgres::Error> {
+        let (client, connection) = tokio_postgres::connect(database_url, tokio_postgres::NoTls).await?;
+        tokio::spawn(async move {
+            if let Err(e) = connection.await {
+                error!("Postgres connection error: {}", e);
+            }
+        });
+        Ok(PostgresStore {
+            client: tokio::sync::Mutex::new(client),
+        })
+    }
+}
+
+// Expects the columns in POSTGRES_METRICS_COLUMNS order
+#[cfg(feature = "postgres")]
+fn metrics_from_pg_row(row: &tokio_postgres::Row) -> Result<Metrics, tokio_postgres::Error> {
+    Ok(Metrics {
+        id: row.try_get(0)?,
+        block_height: row.try_get::<_, Option<i64>>(1)?.map(|height| height as u64),
+        btc_price: row.try_get(2)?,
+        prices: BTreeMap::new(),
+        timestamp: row.try_get(3)?,
+        asset: row.try_get(4)?,
+        source: row.try_get(5)?,
+        fetch_latency_ms: row.try_get::<_, Option<i64>>(6)?.map(|latency| latency as u64),
+        block_hash: row.try_get(7)?,
+        fees: FeeEstimates {
+            fee_1_block: row.try_get(8)?,
+            fee_6_blocks: row.try_get(9)?,
+            fee_144_blocks: row.try_get(10)?,
+        },
+        spread: PriceSpread {
+            price_spread: row.try_get(11)?,
+            source_prices: BTreeMap::new(),
+        },
+    })
+}
+
+// Fills in each row's per-currency prices with one query over all the ids
+#[cfg(feature = "postgres")]
+async fn attach_pg_prices<C: tokio_postgres::GenericClient>(
+    client: &C,
+    metrics: &mut [Metrics],
+) -> Result<(), tokio_postgres::Error> {
+    let ids: Vec<i64> = metrics.iter().map(|row| row.id).collect();
+    let rows = client
+        .query(
+            "SELECT metric_id, currency, value FROM prices WHERE metric_id = ANY($1)",
+            &[&ids],
+        )
+        .await?;
+
+    let mut by_id: HashMap<i64, BTreeMap<String, f64>> = HashMap::new();
+    for row in &rows {
+        by_id
+            .entry(row.try_get(0)?)
+            .or_default()
+            .insert(row.try_get(1)?, row.try_get(2)?);
+    }
+    for row in metrics.iter_mut() {
+        row.prices = by_id.remove(&row.id).unwrap_or_default();
+    }
+    Ok(())
+}
+
+#[cfg(feature = "postgres")]
+async fn save_pg_prices<C: tokio_postgres::GenericClient>(
+    client: &C,
+    metric_id: i64,
+    prices: &BTreeMap<String, f64>,
+) -> Result<(), tokio_postgres::Error> {
+    for (currency, value) in prices {
+        client
+            .execute(
+                "INSERT INTO prices (metric_id, currency, value) VALUES ($1, $2, $3)",
+                &[&metric_id, currency, value],
+            )
+            .await?;
+    }
+    Ok(())
+}
+
+#[cfg(feature = "postgres")]
+async fn save_pg_source_prices<C: tokio_postgres::GenericClient>(
+    client: &C,
+    metric_id: i64,
+    source_prices: &BTreeMap<String, f64>,
+) -> Result<(), tokio_postgres::Error> {
+    for (source, value) in source_prices {
+        client
+            .execute(
+                "INSERT INTO source_prices (metric_id, source, value) VALUES ($1, $2, $3)",
+                &[&metric_id, source, value],
+            )
+            .await?;
+    }
+    Ok(())
+}
+
+#[cfg(feature = "postgres")]
+#[async_trait]
+impl MetricsStore for PostgresStore {
+    async fn create_metrics_table(&self) -> Result<(), StoreError> {
+        let client = self.client.lock().await;
+        let prices_existed: bool = client
+            .query_one("SELECT to_regclass('prices') IS NOT NULL", &[])
+            .await?
+            .try_get(0)?;
+
+        client
+            .batch_execute(
+                "CREATE TABLE IF NOT EXISTS metrics (
+                    id BIGSERIAL PRIMARY KEY,
+                    block_height BIGINT,
+                    btc_price DOUBLE PRECISION,
+                    timestamp TIMESTAMP NOT NULL DEFAULT (NOW() AT TIME ZONE 'utc'),
+                    asset TEXT NOT NULL DEFAULT 'bitcoin',
+                    source TEXT NOT NULL DEFAULT 'coingecko'
+                );
+                ALTER TABLE metrics ADD COLUMN IF NOT EXISTS fetch_latency_ms BIGINT;
+                ALTER TABLE metrics ADD COLUMN IF NOT EXISTS block_hash TEXT;
+                ALTER TABLE metrics ADD COLUMN IF NOT EXISTS fee_1_block DOUBLE PRECISION;
+                ALTER TABLE metrics ADD COLUMN IF NOT EXISTS fee_6_blocks DOUBLE PRECISION;
+                ALTER TABLE metrics ADD COLUMN IF NOT EXISTS fee_144_blocks DOUBLE PRECISION;
+                ALTER TABLE metrics ADD COLUMN IF NOT EXISTS price_spread DOUBLE PRECISION;
+                CREATE INDEX IF NOT EXISTS idx_metrics_asset_id ON metrics (asset, id);
+                CREATE INDEX IF NOT EXISTS idx_metrics_asset_price ON metrics (asset, btc_price);
+                CREATE TABLE IF NOT EXISTS prices (
+                    metric_id BIGINT NOT NULL REFERENCES metrics (id) ON DELETE CASCADE,
+                    currency TEXT NOT NULL,
+                    value DOUBLE PRECISION NOT NULL,
+                    PRIMARY KEY (metric_id, currency)
+                );
+                CREATE TABLE IF NOT EXISTS source_prices (
+                    metric_id BIGINT NOT NULL REFERENCES metrics (id) ON DELETE CASCADE,
+                    source TEXT NOT NULL,
+                    value DOUBLE PRECISION NOT NULL,
+                    PRIMARY KEY (metric_id, source)
+                );
+                CREATE TABLE IF NOT EXISTS fetch_errors (
+                    id BIGSERIAL PRIMARY KEY,
+                    source TEXT NOT NULL,
+                    message TEXT NOT NULL,
+                    timestamp TIMESTAMP NOT NULL DEFAULT (NOW() AT TIME ZONE 'utc')
+                );
+                ALTER TABLE fetch_errors ADD COLUMN IF NOT EXISTS kind TEXT;",
+            )
+            .await?;
+
+        // Rows from before the prices table only had the USD price
+        if !prices_existed {
+            client
+                .execute(
+                    "INSERT INTO prices (metric_id, currency, value)
+                     SELECT id, 'usd', btc_price FROM metrics WHERE btc_price IS NOT NULL",
+                    &[],
+                )
+                .await?;
+        }
+        Ok(())
+    }
+
+    async fn save_metrics(&self, row: &MetricsRow) -> Result<Metrics, StoreError> {
+        let mut client = self.client.lock().await;
+        let tx = client.transaction().await?;
+        let inserted = tx
+            .query_one(
+                &format!(
+                    "INSERT INTO metrics (block_height, block_hash, fee_1_block, fee_6_blocks, fee_144_blocks, btc_price, price_spread, asset, source, fetch_latency_ms)
+                     VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10) RETURNING {}",
+                    POSTGRES_METRICS_COLUMNS
+                ),
+                &[
+                    &row.block_height.map(|height| height as i64),
+                    &row.block_hash,
+                    &row.fees.fee_1_block,
+                    &row.fees.fee_6_blocks,
+                    &row.fees.fee_144_blocks,
+                    &row.btc_price,
+                    &row.spread.price_spread,
+                    &row.asset,
+                    &row.source,
+                    &row.fetch_latency_ms.map(|latency| latency as i64),
+                ],
+            )
+            .await?;
+        let mut metrics = metrics_from_pg_row(&inserted)?;
+        save_pg_prices(&tx, metrics.id, &row.prices).await?;
+        save_pg_source_prices(&tx, metrics.id, &row.spread.source_prices).await?;
+        tx.commit().await?;
+
+        metrics.prices = row.prices.clone();
+        Ok(metrics)
+    }
+
+    async fn save_metrics_batch(&self, rows: &[Metrics]) -> Result<usize, StoreError> {
+        let mut client = self.client.lock().await;
+        let tx = client.transaction().await?;
+        let insert = tx
+            .prepare(
+                "INSERT INTO metrics (block_height, block_hash, fee_1_block, fee_6_blocks, fee_144_blocks, btc_price, price_spread, asset, source, fetch_latency_ms, timestamp)
+                 VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11::text::timestamp) RETURNING id",
+            )
+            .await?;
+        for row in rows {
+            let inserted = tx
+                .query_one(
+                    &insert,
+                    &[
+                        &row.block_height.map(|height| height as i64),
+                        &row.block_hash,
+                        &row.fees.fee_1_block,
+                        &row.fees.fee_6_blocks,
+                        &row.fees.fee_144_blocks,
+                        &row.btc_price,
+                        &row.spread.price_spread,
+                        &row.asset,
+                        &row.source,
+                        &row.fetch_latency_ms.map(|latency| latency as i64),
+                        &row.timestamp,
+                    ],
+                )
+                .await?;
+            let id: i64 = inserted.try_get(0)?;
+            save_pg_prices(&tx, id, &row.prices).await?;
+            save_pg_source_prices(&tx, id, &row.spread.source_prices).await?;
+        }
+        tx.commit().await?;
+
+        Ok(rows.len())
+    }
+
+    async fn save_fetch_error(&self, source: &str, kind: &str, message: &str) -> Result<(), StoreError> {
+        self.client
+            .lock()
+            .await
+            .execute(
+                "INSERT INTO fetch_errors (source, kind, message) VALUES ($1, $2, $3)",
+                &[&source, &kind, &message],
+            )
+            .await?;
+        Ok(())
+    }
+
+    async fn evict_oldest(&self, max_rows: u64) -> Result<usize, StoreError> {
+        let evicted = self
+            .client
+            .lock()
+            .await
+            .execute(
+                "DELETE FROM metrics WHERE id NOT IN (SELECT id FROM metrics ORDER BY id DESC LIMIT $1)",
+                &[&(max_rows as i64)],
+            )
+            .await?;
+        Ok(evicted as usize)
+    }
+
+    async fn get_metrics_history(
+        &self,
+        asset: &str,
+        limit: Option<u32>,
+        max_limit: u32,
+    ) -> Result<MetricsHistory, StoreError> {
+        let requested = limit.unwrap_or(DEFAULT_METRICS_LIMIT);
+        let limit = requested.min(max_limit);
+
+        let client = self.client.lock().await;
+        let rows = client
+            .query(
+                &format!(
+                    "SELECT {} FROM metrics WHERE asset = $1 ORDER BY id DESC LIMIT $2",
+                    POSTGRES_METRICS_COLUMNS
+                ),
+                &[&asset, &(limit as i64)],
+            )
+            .await?;
+
+        let mut metrics = Vec::new();
+        for row in &rows {
+            metrics.push(metrics_from_pg_row(row)?);
+        }
+        attach_pg_prices(&*client, &mut metrics).await?;
+
+        Ok(MetricsHistory {
+            metrics,
+            truncated: requested > limit,
+        })
+    }
+
+    async fn get_metrics_since(&self, asset: &str, after_id: i64, limit: u32) -> Result<Vec<Metrics>, StoreError> {
+        let client = self.client.lock().await;
+        let rows = client
+            .query(
+                &format!(
+                    "SELECT {} FROM metrics WHERE asset = $1 AND id > $2 ORDER BY id ASC LIMIT $3",
+                    POSTGRES_METRICS_COLUMNS
+                ),
+                &[&asset, &after_id, &(limit as i64)],
+            )
+            .await?;
+
+        let mut metrics = Vec::new();
+        for row in &rows {
+            metrics.push(metrics_from_pg_row(row)?);
+        }
+        attach_pg_prices(&*client, &mut metrics).await?;
+
+        Ok(metrics)
+    }
+
+    async fn get_metrics_before(&self, asset: &str, before_id: i64, limit: u32) -> Result<Vec<Metrics>, StoreError> {
+        let client = self.client.lock().await;
+        let rows = client
+            .query(
+                &format!(
+                    "SELECT {} FROM metrics WHERE asset = $1 AND id < $2 ORDER BY id DESC LIMIT $3",
+                    POSTGRES_METRICS_COLUMNS
+                ),
+                &[&asset, &before_id, &(limit as i64)],
+            )
+            .await?;
+
+        let mut metrics = Vec::new();
+        for row in &rows {
+            metrics.push(metrics_from_pg_row(row)?);
+        }
+        attach_pg_prices(&*client, &mut metrics).await?;
+
+        Ok(metrics)
+    }
+
+    async fn count_metrics(&self, asset: &str, from: Option<&str>, to: Option<&str>) -> Result<i64, StoreError> {
+        let client = self.client.lock().await;
+        let row = client
+            .query_one(
+                "SELECT COUNT(*) FROM metrics
+                 WHERE asset = $1
+                   AND ($2::text IS NULL OR timestamp >= $2::text::timestamp)
+                   AND ($3::text IS NULL OR timestamp <= $3::text::timestamp)",
+                &[&asset, &from, &to],
+            )
+            .await?;
+        Ok(row.get(0))
+    }
+
+    async fn get_currencies(&self, asset: &str) -> Result<Vec<CurrencyQuote>, StoreError> {
+        let rows = self
+            .client
+            .lock()
+            .await
+            .query(
+                "SELECT DISTINCT ON (p.currency) p.currency, p.value, to_char(m.timestamp, 'YYYY-MM-DD HH24:MI:SS')
+                 FROM prices p JOIN metrics m ON m.id = p.metric_id
+                 WHERE m.asset = $1
+                 ORDER BY p.currency, p.metric_id DESC",
+                &[&asset],
+            )
+            .await?;
+
+        let mut currencies = Vec::new();
+        for row in &rows {
+            currencies.push(CurrencyQuote {
+                currency: row.try_get(0)?,
+                value: row.try_get(1)?,
+                timestamp: row.try_get(2)?,
+            });
+        }
+        Ok(currencies)
+    }
+
+    async fn get_latest_metrics(&self) -> Result<Vec<Metrics>, StoreError> {
+        let client = self.client.lock().await;
+        let rows = client
+            .query(
+                &format!(
+                    "SELECT DISTINCT ON (asset) {} FROM metrics ORDER BY asset, id DESC",
+                    POSTGRES_METRICS_COLUMNS
+                ),
+                &[],
+            )
+            .await?;
+
+        let mut metrics = Vec::new();
+        for row in &rows {
+            metrics.push(metrics_from_pg_row(row)?);
+        }
+        attach_pg_prices(&*client, &mut metrics).await?;
+
+        Ok(metrics)
+    }
+
+    async fn last_block_height(&self) -> Result<Option<u64>, StoreError> {
+        let client = self.client.lock().await;
+        let row = client
+            .query_opt(
+                "SELECT block_height FROM metrics WHERE block_height IS NOT NULL ORDER BY id DESC LIMIT 1",
+                &[],
+            )
+            .await?;
+        Ok(row.map(|row| row.get::<_, i64>(0) as u64))
+    }
+}
+
+// Clone so one cycle's outcome can be handed to every trigger coalesced onto it
+#[derive(Debug, Clone)]
+enum CollectError {
+    Upstream(String),
+    Database(Arc<StoreError>),
+}
+
+impl std::fmt::Display for CollectError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CollectError::Upstream(e) => write!(f, "{}", e),
+            CollectError::Database(e) => write!(f, "Error saving metrics: {}", e),
+        }
+    }
+}
+
+// Batches of samples the write buffer holds while the store is failing, beyond which the
+// oldest are dropped rather than growing without bound through a long outage
+const WRITE_BUFFER_MAX_BATCHES: usize = 10;
+
+// Optional in-memory buffer so samples are written in batches rather than one insert per tick
+struct WriteBuffer {
+    // Flush once this many samples are queued...
+    max_samples: usize,
+    // ...or once the oldest queued sample is this old, whichever comes first
+    max_age: Duration,
+    pending: Mutex<PendingWrites>,
+}
+
+#[derive(Default)]
+struct PendingWrites {
+    rows: Vec<Metrics>,
+    since: Option<std::time::Instant>,
+}
+
+impl WriteBuffer {
+    // Buffering only applies to more than one sample; otherwise rows are written as collected
+    fn new(max_samples: usize, max_age: Duration) -> Option<WriteBuffer> {
+        (max_samples > 1).then(|| WriteBuffer {
+            max_samples,
+            max_age,
+            pending: Mutex::new(PendingWrites::default()),
+        })
+    }
+
+    fn push(&self, row: Metrics) {
+        let mut pending = lock_or_recover(&self.pending);
+        pending.since.get_or_insert_with(std::time::Instant::now);
+        pending.rows.push(row);
+        self.drop_excess(&mut pending);
+    }
+
+    fn drop_excess(&self, pending: &mut PendingWrites) {
+        let excess = pending.rows.len().saturating_sub(self.max_samples * WRITE_BUFFER_MAX_BATCHES);
+        if excess > 0 {
+            pending.rows.drain(..excess);
+            warn!("Write buffer full, dropped the {} oldest unsaved samples", excess);
+        }
+    }
+
+    fn is_due(&self) -> bool {
+        let pending = lock_or_recover(&self.pending);
+        pending.rows.len() >= self.max_samples
+            || pending.since.is_some_and(|since| since.elapsed() >= self.max_age)
+    }
+
+    // Takes up to max_samples of the oldest rows, so a backlog left by failed flushes is
+    // written in transactions no bigger than a normal batch
+    fn take_batch(&self) -> PendingWrites {
+        let mut pending = lock_or_recover(&self.pending);
+        let count = pending.rows.len().min(self.max_samples);
+        let rows: Vec<Metrics> = pending.rows.drain(..count).collect();
+        let since = if pending.rows.is_empty() {
+            pending.since.take()
+        } else {
+            pending.since
+        };
+        PendingWrites { rows, since }
+    }
+
+    // Puts a failed batch back in front of anything queued since, to retry on the next flush
+    fn restore(&self, failed: PendingWrites) {
+        let mut pending = lock_or_recover(&self.pending);
+        let newer = std::mem::replace(&mut pending.rows, failed.rows);
+        pending.rows.extend(newer);
+        pending.since = failed.since.or(pending.since);
+        self.drop_excess(&mut pending);
+    }
+}
+
+// Mainnet reorgs deeper than this are vanishingly rare, so a bigger drop points at a bad endpoint
+const DEFAULT_MAX_HEIGHT_REGRESSION: u64 = 6;
+
+fn check_height_regression(last_height: Option<u64>, height: u64, max_regression: u64) -> Result<(), String> {
+    match last_height {
+        Some(last_height) if last_height.saturating_sub(height) > max_regression => Err(format!(
+            "Block height regressed from {} to {}, more than the allowed {} blocks",
+            last_height, height, max_regression
+        )),
+        _ => Ok(()),
+    }
+}
+
+// Recent accepted prices per asset that SPIKE_FILTER_WINDOW keeps unless overridden
+const DEFAULT_SPIKE_FILTER_WINDOW: usize = 30;
+
+// The filter stays out of the way until the window has this many prices to judge against
+const SPIKE_FILTER_MIN_SAMPLES: usize = 5;
+
+// Floor on the spread, as a fraction of the median, so a perfectly flat window doesn't
+// turn a one-cent move into an outlier
+const SPIKE_FILTER_MIN_SPREAD: f64 = 0.001;
+
+// Consecutive rejected prices that agree with each other before they're taken as a real
+// level shift rather than a glitch, so the filter doesn't lock out a market that moved
+const SPIKE_FILTER_RECOVERY: usize = 3;
+
+// How close, as a fraction of their median, rejected prices must be to count as agreeing
+const SPIKE_FILTER_AGREEMENT: f64 = 0.02;
+
+// Rejects a price more than `max_deviations` standard deviations away from the rolling
+// median of recent accepted prices, catching upstream glitches that report 10x off
+struct SpikeFilter {
+    max_deviations: f64,
+    window: usize,
+    recent: Mutex<HashMap<String, SpikeWindow>>,
+}
+
+#[derive(Default)]
+struct SpikeWindow {
+    accepted: std::collections::VecDeque<f64>,
+    // The current run of rejected prices, cleared by any accepted one
+    rejected: Vec<f64>,
+}
+
+fn median_of(sorted: &[f64]) -> f64 {
+    (sorted[(sorted.len() - 1) / 2] + sorted[sorted.len() / 2]) / 2.0
+}
+
+impl SpikeFilter {
+    fn new(max_deviations: f64, window: usize) -> SpikeFilter {
+        SpikeFilter {
+            max_deviations,
+            window: window.max(SPIKE_FILTER_MIN_SAMPLES),
+            recent: Mutex::new(HashMap::new()),
+        }
+    }
+
+    // Accepted prices join the window; rejected ones don't, so a glitch can't drag the median.
+    // SPIKE_FILTER_RECOVERY agreeing rejections in a row restart the window at the new level.
+    fn check(&self, asset: &str, price: f64) -> Result<(), String> {
+        let mut recent = lock_or_recover(&self.recent);
+        let window = recent.entry(asset.to_string()).or_default();
+
+        if window.accepted.len() >= SPIKE_FILTER_MIN_SAMPLES {
+            let mut sorted: Vec<f64> = window.accepted.iter().copied().collect();
+            sorted.sort_by(|a, b| a.total_cmp(b));
+            let median = median_of(&sorted);
+            let mean = sorted.iter().sum::<f64>() / sorted.len() as f64;
+            let variance = sorted.iter().map(|p| (p - mean).powi(2)).sum::<f64>() / sorted.len() as f64;
+            let spread = variance.sqrt().max(median.abs() * SPIKE_FILTER_MIN_SPREAD);
+
+            let deviations = (price - median).abs() / spread;
+            if deviations > self.max_deviations {
+                window.rejected.push(price);
+                let mut run = window.rejected.clone();
+                run.sort_by(|a, b| a.total_cmp(b));
+                let run_median = median_of(&run);
+                if run.iter().any(|p| (p - run_median).abs() > run_median.abs() * SPIKE_FILTER_AGREEMENT) {
+                    window.rejected = vec![price];
+                } else if window.rejected.len() >= SPIKE_FILTER_RECOVERY {
+                    warn!(
+                        "Accepting {} price {} after {} agreeing rejections, restarting the spike filter at the new level",
+                        asset,
+                        price,
+                        window.rejected.len()
+                    );
+                    window.accepted = window.rejected.drain(..).collect();
+                    return Ok(());
+                }
+                return Err(format!(
+                    "Rejected {} price {} as a spike: {:.1} standard deviations from the rolling median {}",
+                    asset, price, deviations, median
+                ));
+            }
+        }
+
+        window.rejected.clear();
+        window.accepted.push_back(price);
+        if window.accepted.len() > self.window {
+            window.accepted.pop_front();
+        }
+        Ok(())
+    }
+}
+
+// Price moves smaller than this aren't pushed to /api/metrics/stream unless
+// BROADCAST_PRICE_THRESHOLD overrides it; zero pushes any change
+const DEFAULT_BROADCAST_PRICE_THRESHOLD: f64 = 0.0;
+
+// Samples queued per stream subscriber before a slow client starts skipping
+const BROADCAST_CAPACITY: usize = 64;
+
+// Most stored samples a stream client can ask to receive on connect via ?backlog=
+const MAX_STREAM_BACKLOG: u32 = 100;
+
+// Quiet markets often repeat the same sample; only a new height or a price move of at
+// least the threshold is worth a frame
+fn should_broadcast(last: Option<&Metrics>, next: &Metrics, price_threshold: f64) -> bool {
+    let last = match last {
+        Some(last) => last,
+        None => return true,
+    };
+    if last.block_height != next.block_height {
+        return true;
+    }
+    match (last.btc_price, next.btc_price) {
+        (Some(last_price), Some(price)) => {
+            let change = (price - last_price).abs();
+            change > 0.0 && change >= price_threshold
+        }
+        (last_price, price) => last_price.is_some() != price.is_some(),
+    }
+}
+
+// One tick's prices per asset and currency, with the value for the source column and,
+// under median aggregation, the spread behind each asset's price
+struct FetchedPrices {
+    source: &'static str,
+    prices: HashMap<String, BTreeMap<String, f64>>,
+    spreads: HashMap<String, PriceSpread>,
+}
+
+// Everything one fetch-and-save cycle needs, shared by the polling loop and the
+// manual refresh route
+struct Collector {
+    store: Arc<dyn MetricsStore>,
+    http: reqwest::Client,
+    api_bases: ApiBases,
+    fetch_status: Arc<Mutex<FetchStatus>>,
+    counters: Arc<FetchCounters>,
+    latest: LatestMetrics,
+    assets: Vec<String>,
+    currencies: Vec<String>,
+    // Tried in order each tick until one returns prices, or all asked under median
+    // aggregation; never empty
+    price_sources: Vec<PriceSource>,
+    price_aggregation: PriceAggregation,
+    alert_config: Option<Arc<AlertConfig>>,
+    last_price: Mutex<Option<f64>>,
+    price_decimals: Option<u32>,
+    // Deepest drop below the last stored height still accepted, to allow for reorgs
+    max_height_regression: u64,
+    breaker_config: BreakerConfig,
+    write_buffer: Option<WriteBuffer>,
+    spike_filter: Option<SpikeFilter>,
+    // Hard cap on stored rows, enforced after every tick. Changes on SIGHUP.
+    max_rows: Mutex<Option<u64>>,
+    // Opt-in, an extra request to the chain API every tick
+    fetch_fees: bool,
+    poll_strategy: PollStrategy,
+    height_schedule: Mutex<HeightSchedule>,
+    updates: broadcast::Sender<Metrics>,
+    // Last sample pushed per asset, which new samples are compared against
+    last_broadcast: Mutex<HashMap<String, Metrics>>,
+    // Handed every saved sample, unlike the stream which skips unchanged ones
+    sinks: Vec<Arc<dyn MetricsSink>>,
+    broadcast_price_threshold: f64,
+    // Held for a whole cycle so a refresh and a scheduled tick never interleave writes.
+    // Guards the outcome of the last finished cycle.
+    fetch_lock: tokio::sync::Mutex<Option<Result<Vec<Metrics>, CollectError>>>,
+    cycles_completed: AtomicU64,
+}
+
+impl Collector {
+    // With a write buffer the returned rows may not be written yet, and carry id 0 until flushed.
+    // A trigger arriving while a cycle is in flight waits for it and shares its outcome
+    // instead of fetching again and storing a near-duplicate row.
+    async fn collect(&self) -> Result<Vec<Metrics>, CollectError> {
+        let seen = self.cycles_completed.load(Ordering::Acquire);
+        let mut last_outcome = self.fetch_lock.lock().await;
+        if self.cycles_completed.load(Ordering::Acquire) != seen {
+            if let Some(outcome) = last_outcome.as_ref() {
+                return outcome.clone();
+            }
+        }
+
+        let outcome = self.run_cycle().await;
+        *last_outcome = Some(outcome.clone());
+        self.cycles_completed.fetch_add(1, Ordering::Release);
+        outcome
+    }
+
+    // Callers hold fetch_lock
+    async fn run_cycle(&self) -> Result<Vec<Metrics>, CollectError> {
+        // Time-based flushes happen even on ticks where every fetch fails
+        if self.write_buffer.as_ref().is_some_and(WriteBuffer::is_due) {
+            if let Err(e) = self.flush_writes().await {
+                error!("Error flushing buffered metrics: {}", e);
+            }
+        }
+
+        let reused_height = match self.poll_strategy {
+            PollStrategy::Adaptive => lock_or_recover(&self.height_schedule).reusable(std::time::Instant::now()),
+            PollStrategy::Fixed => None,
+        };
+        let (try_block_height, try_prices) = {
+            let mut fetch_status = lock_or_recover(&self.fetch_status);
+            (
+                reused_height.is_none() && fetch_status.block_height.breaker.allow("block_height"),
+                fetch_status.btc_price.breaker.allow("btc_price"),
+            )
+        };
+        // Both sources are fetched side by side and each keeps its own result, so the tick
+        // takes as long as the slower one and one failing doesn't stop the other. Fee
+        // estimates ride along when enabled.
+        let tick_started = std::time::Instant::now();
+        let (block_height, prices, fees) = tokio::join!(
+            async {
+                if let Some(height) = reused_height {
+                    return Ok(height);
+                }
+                if !try_block_height {
+                    return Err(FetchError::CircuitOpen);
+                }
+                let started = std::time::Instant::now();
+                let block_height = fetch_block_height(&self.http, &self.api_bases.chain).await;
+                self.counters
+                    .block_height_latency_ms
+                    .store(started.elapsed().as_millis() as u64, Ordering::Relaxed);
+                block_height
+            },
+            async {
+                if !try_prices {
+                    return Err(FetchError::CircuitOpen);
+                }
+                let started = std::time::Instant::now();
+                let prices = self.fetch_prices().await;
+                self.counters
+                    .btc_price_latency_ms
+                    .store(started.elapsed().as_millis() as u64, Ordering::Relaxed);
+                prices
+            },
+            self.fee_estimates(),
+        );
+        let fetch_latency_ms = Some(tick_started.elapsed().as_millis() as u64);
+
+        // Keep the latest outcome per source so /api/health can explain failures. Skipped
+        // fetches aren't outcomes and leave the status and counters alone.
+        {
+            let mut fetch_status = lock_or_recover(&self.fetch_status);
+            if try_block_height {
+                fetch_status.block_height.record("block_height", &block_height, &self.breaker_config);
+                self.counters.record(&block_height);
+            }
+            if try_prices {
+                fetch_status.btc_price.record("btc_price", &prices, &self.breaker_config);
+                self.counters.record(&prices);
+            }
+        }
+
+        // Nothing worth saving unless at least one source answered
+        if let (Err(height_error), Err(price_error)) = (&block_height, &prices) {
+            return Err(CollectError::Upstream(format!(
+                "Error fetching block height: {}; error fetching prices: {}",
+                height_error, price_error
+            )));
+        }
+
+        // A single failed source still leaves a row, with the missing field null and a note in fetch_errors
+        let mut failures = Vec::new();
+        let block_height = match block_height {
+            Ok(block_height) => {
+                // The store rather than the latest cache, which starts empty after a restart
+                let last_height = match self.store.last_block_height().await {
+                    Ok(last_height) => last_height,
+                    Err(e) => {
+                        warn!("Error reading the last stored block height, skipping the regression check: {}", e);
+                        None
+                    }
+                };
+                match check_height_regression(last_height, block_height, self.max_height_regression) {
+                    Ok(()) => {
+                        // Only a fresh height that passed the check is worth reusing
+                        if try_block_height {
+                            lock_or_recover(&self.height_schedule).record(block_height, std::time::Instant::now());
+                        }
+                        Some(block_height)
+                    }
+                    Err(message) => {
+                        failures.push(("block_height", "height_regression", message));
+                        None
+                    }
+                }
+            }
+            Err(e) => {
+                failures.push(("block_height", e.kind(), format!("Error fetching block height: {}", e)));
+                None
+            }
+        };
+        // A reused height keeps the hash stored with it rather than asking the chain API again
+        let block_hash = match block_height {
+            Some(block_height) if reused_height.is_some() => lock_or_recover(&self.latest)
+                .values()
+                .filter(|latest| latest.block_height == Some(block_height))
+                .find_map(|latest| latest.block_hash.clone()),
+            Some(block_height) => self.block_hash(block_height).await,
+            None => None,
+        };
+        let (price_source, prices, mut spreads) = match prices {
+            Ok(fetched) => (Some(fetched.source), Some(fetched.prices), fetched.spreads),
+            Err(e) => {
+                failures.push(("btc_price", e.kind(), format!("Error fetching prices: {}", e)));
+                (None, None, HashMap::new())
+            }
+        };
+        // Open breakers were already reported when they tripped
+        failures.retain(|(source, _, _)| {
+            (*source == "block_height" && try_block_height) || (*source == "btc_price" && try_prices)
+        });
+        for (source, kind, message) in &failures {
+            warn!("{}, saving partial metrics", message);
+            if let Err(e) = self.store.save_fetch_error(source, kind, message).await {
+                error!("Error recording fetch error: {}", e);
+            }
+        }
+        // A rejected height on top of a failed price fetch leaves nothing to save
+        if block_height.is_none() && prices.is_none() {
+            return Err(CollectError::Upstream("No usable block height or prices this tick".to_string()));
+        }
+
+        let mut stored = Vec::new();
+        for asset in &self.assets {
+            let quotes: BTreeMap<String, f64> = match &prices {
+                Some(prices) => match prices.get(asset) {
+                    Some(quotes) => quotes
+                        .iter()
+                        .map(|(currency, value)| {
+                            let value = self.price_decimals.map_or(*value, |decimals| round_price(*value, decimals));
+                            (currency.clone(), value)
+                        })
+                        .collect(),
+                    None => {
+                        warn!("No price returned for {}", asset);
+                        continue;
+                    }
+                },
+                None => BTreeMap::new(),
+            };
+            // A spiking price is dropped along with its other quotes and kept in fetch_errors for audit
+            let quotes = match (&self.spike_filter, quotes.get(BASE_CURRENCY)) {
+                (Some(spike_filter), Some(price)) => match spike_filter.check(asset, *price) {
+                    Ok(()) => quotes,
+                    Err(message) => {
+                        warn!("{}", message);
+                        if let Err(e) = self.store.save_fetch_error("btc_price", "price_spike", &message).await {
+                            error!("Error recording fetch error: {}", e);
+                        }
+                        if block_height.is_none() {
+                            continue;
+                        }
+                        BTreeMap::new()
+                    }
+                },
+                _ => quotes,
+            };
+            let price = quotes.get(BASE_CURRENCY).copied();
+            info!("Fetched block height and {} price: {:?}, {:?}", asset, block_height, price);
+            // Price alerts only apply to bitcoin, and only see the rounded price that passed
+            // the spike filter, so a rejected glitch can't fire one
+            if let (true, Some(price)) = (asset == DEFAULT_ASSET, price) {
+                self.check_price_alerts(price);
+            }
+
+            let source = price_source.filter(|_| price.is_some()).unwrap_or(PRICE_SOURCE_NONE);
+            let spread = match spreads.remove(asset) {
+                Some(spread) if price.is_some() => self.rounded_spread(spread),
+                _ => PriceSpread::default(),
+            };
+            let row = MetricsRow {
+                asset: asset.clone(),
+                block_height,
+                block_hash: block_hash.clone(),
+                fees: fees.clone(),
+                btc_price: price,
+                prices: quotes,
+                spread,
+                source: source.to_string(),
+                fetch_latency_ms,
+            };
+            if let Some(write_buffer) = &self.write_buffer {
+                let metrics = row.into_metrics(UNSAVED_ID, now_timestamp());
+                write_buffer.push(metrics.clone());
+                stored.push(metrics);
+                continue;
+            }
+
+            let metrics = self
+                .store
+                .save_metrics(&row)
+                .await
+                .map_err(|e| {
+                    self.counters.db_write_errors.fetch_add(1, Ordering::Relaxed);
+                    CollectError::Database(Arc::new(e))
+                })?;
+            stored.push(metrics);
+        }
+
+        if self.write_buffer.as_ref().is_some_and(WriteBuffer::is_due) {
+            self.flush_writes()
+                .await
+                .map_err(|e| CollectError::Database(Arc::new(e)))?;
+        }
+
+        let max_rows = *lock_or_recover(&self.max_rows);
+        if let Some(max_rows) = max_rows {
+            if let Err(e) = self.store.evict_oldest(max_rows).await {
+                error!("Error evicting rows beyond MAX_ROWS: {}", e);
+            }
+        }
+
+        {
+            let mut latest = lock_or_recover(&self.latest);
+            for metrics in &stored {
+                latest.insert(metrics.asset.clone(), metrics.clone());
+            }
+        }
+        self.broadcast(&stored);
+        for metrics in &stored {
+            for sink in &self.sinks {
+                sink.publish(metrics);
+            }
+        }
+
+        Ok(stored)
+    }
+
+    async fn fetch_prices(&self) -> Result<FetchedPrices, FetchError> {
+        match self.price_aggregation {
+            PriceAggregation::Single => self.fetch_single_source().await,
+            PriceAggregation::Median => self.fetch_median().await,
+        }
+    }
+
+    // Walks price_sources in order, counting a fallback whenever a later one answers. When
+    // all fail, the last source's error is the one reported.
+    async fn fetch_single_source(&self) -> Result<FetchedPrices, FetchError> {
+        let mut last_error = None;
+        for (index, source) in self.price_sources.iter().enumerate() {
+            match fetch_prices_from(&self.http, *source, &self.api_bases, &self.assets, &self.currencies).await {
+                Ok(prices) => {
+                    if index > 0 {
+                        self.counters.price_fallback_used.fetch_add(1, Ordering::Relaxed);
+                        info!("Prices taken from fallback source {}", source.name());
+                    }
+                    return Ok(FetchedPrices {
+                        source: source.name(),
+                        prices,
+                        spreads: HashMap::new(),
+                    });
+                }
+                Err(e) => {
+                    if index + 1 < self.price_sources.len() {
+                        warn!("Price source {} failed, trying the next: {}", source.name(), e);
+                    }
+                    last_error = Some(e);
+                }
+            }
+        }
+        Err(last_error.unwrap_or_else(|| FetchError::NoPrices {
+            url: self.api_bases.price.clone(),
+        }))
+    }
+
+    // Asks every source side by side. Sources that fail are logged and left out of the
+    // median as long as MIN_MEDIAN_SOURCES still answered.
+    async fn fetch_median(&self) -> Result<FetchedPrices, FetchError> {
+        let results = futures_util::future::join_all(self.price_sources.iter().map(|source| async move {
+            let prices = fetch_prices_from(&self.http, *source, &self.api_bases, &self.assets, &self.currencies).await;
+            (*source, prices)
+        }))
+        .await;
+
+        let mut quotes = Vec::new();
+        let mut errors = Vec::new();
+        for (source, result) in results {
+            match result {
+                Ok(prices) => quotes.push((source, prices)),
+                Err(e) => {
+                    warn!("Price source {} failed, leaving it out of the median: {}", source.name(), e);
+                    errors.push(format!("{}: {}", source.name(), e));
+                }
+            }
+        }
+        if quotes.len() < MIN_MEDIAN_SOURCES {
+            return Err(FetchError::TooFewSources {
+                answered: quotes.len(),
+                errors,
+            });
+        }
+
+        let (prices, spreads) = aggregate_median(&quotes);
+        Ok(FetchedPrices {
+            source: PRICE_SOURCE_MEDIAN,
+            prices,
+            spreads,
+        })
+    }
+
+    // PRICE_DECIMALS applies to the feeds' quotes and their spread like it does to prices
+    fn rounded_spread(&self, spread: PriceSpread) -> PriceSpread {
+        let decimals = match self.price_decimals {
+            Some(decimals) => decimals,
+            None => return spread,
+        };
+        PriceSpread {
+            price_spread: spread.price_spread.map(|value| round_price(value, decimals)),
+            source_prices: spread
+                .source_prices
+                .into_iter()
+                .map(|(source, value)| (source, round_price(value, decimals)))
+                .collect(),
+        }
+    }
+
+    fn broadcast(&self, stored: &[Metrics]) {
+        let mut last_broadcast = lock_or_recover(&self.last_broadcast);
+        for metrics in stored {
+            if !should_broadcast(last_broadcast.get(&metrics.asset), metrics, self.broadcast_price_threshold) {
+                continue;
+            }
+            // Fails only when nobody is subscribed, which is fine
+            let _ = self.updates.send(metrics.clone());
+            last_broadcast.insert(metrics.asset.clone(), metrics.clone());
+        }
+    }
+
+    // Callers hold fetch_lock so a flush never races a tick's pushes
+    async fn flush_writes(&self) -> Result<usize, StoreError> {
+        let write_buffer = match &self.write_buffer {
+            Some(write_buffer) => write_buffer,
+            None => return Ok(0),
+        };
+        let mut written = 0;
+        loop {
+            let batch = write_buffer.take_batch();
+            if batch.rows.is_empty() {
+                return Ok(written);
+            }
+            match self.store.save_metrics_batch(&batch.rows).await {
+                Ok(count) => written += count,
+                Err(e) => {
+                    self.counters.db_write_errors.fetch_add(1, Ordering::Relaxed);
+                    write_buffer.restore(batch);
+                    return Err(e);
+                }
+            }
+        }
+    }
+
+    // Writes out anything still buffered before the process exits
+    async fn shutdown(&self) {
+        let _fetching = self.fetch_lock.lock().await;
+        match self.flush_writes().await {
+            Ok(0) => {}
+            Ok(written) => info!("Flushed {} buffered samples before shutdown", written),
+            Err(e) => error!("Error flushing buffered metrics on shutdown: {}", e),
+        }
+    }
+
+    // Best effort: a failed lookup is logged and the row is saved without a hash. A hash
+    // differing from the one last stored at the same height means the tip was reorganized.
+    async fn block_hash(&self, block_height: u64) -> Option<String> {
+        let block_hash = match fetch_block_hash(&self.http, &self.api_bases.chain, block_height).await {
+            Ok(block_hash) => block_hash,
+            Err(e) => {
+                warn!("Error fetching hash for block {}: {}", block_height, e);
+                return None;
+            }
+        };
+
+        let reorged = lock_or_recover(&self.latest).values().find_map(|latest| {
+            latest
+                .block_hash
+                .as_ref()
+                .filter(|hash| latest.block_height == Some(block_height) && **hash != block_hash)
+                .cloned()
+        });
+        if let Some(previous) = reorged {
+            warn!("Block {} changed hash from {} to {}, the chain reorganized", block_height, previous, block_hash);
+        }
+        Some(block_hash)
+    }
+
+    // Best effort like the block hash: a failed fetch is logged and the row saved without fees
+    async fn fee_estimates(&self) -> FeeEstimates {
+        if !self.fetch_fees {
+            return FeeEstimates::default();
+        }
+        match fetch_fee_estimates(&self.http, &self.api_bases.chain).await {
+            Ok(fees) => fees,
+            Err(e) => {
+                warn!("Error fetching fee estimates: {}", e);
+                FeeEstimates::default()
+            }
+        }
+    }
+
+    fn check_price_alerts(&self, price: f64) {
+        let previous_price = lock_or_recover(&self.last_price).replace(price);
+
+        let (alert_config, previous_price) = match (&self.alert_config, previous_price) {
+            (Some(alert_config), Some(previous_price)) => (alert_config, previous_price),
+            _ => return,
+        };
+
+        for alert in detect_price_crossings(alert_config, previous_price, price) {
+            info!("BTC price crossed {} {}, sending alert", alert.direction, alert.threshold);
+            let alert_config = Arc::clone(alert_config);
+            let http = self.http.clone();
+            tokio::spawn(async move {
+                if let Err(e) = send_price_alert(&http, &alert_config.webhook_url, &alert).await {
+                    error!("Error sending price alert: {}", e);
+                }
+            });
+        }
+    }
+}
+
+// Takes the field case because a streamed body is past with_field_case's reach and renames
+// its own rows
+fn create_metrics_route(
+    store: Arc<dyn MetricsStore>,
+    default_limit: u32,
+    max_query_limit: u32,
+    field_case: FieldCase,
+    poll_interval: PollInterval,
+    auth: Option<Arc<ApiAuth>>,
+) -> impl Filter<Extract = (Response,), Error = warp::Rejection> + Clone {
+    let cache_scope = CacheScope::for_auth(&auth);
+    warp::path!("api" / "metrics")
+        .and(warp::get())
+        .and(with_auth(auth))
+        .and(warp::query::<MetricsQuery>())
+        .and(warp::header::optional::<String>("if-none-match"))
+        .and(warp::header::optional::<String>("accept"))
+        .then(move |query: MetricsQuery, if_none_match: Option<String>, accept: Option<String>| {
+            let store = Arc::clone(&store);
+            let format = accept.as_deref().map(preferred_format).unwrap_or(ResponseFormat::Json);
+            async move {
+                metrics_reply(store, query, if_none_match, format, default_limit, max_query_limit, field_case).await
+            }
+        })
+        .map(move |mut response: Response| {
+            set_poll_cache_control(&mut response, poll_interval.get(), cache_scope);
+            response
+        })
+}
+
+async fn metrics_reply(
+    store: Arc<dyn MetricsStore>,
+    query: MetricsQuery,
+    if_none_match: Option<String>,
+    format: ResponseFormat,
+    default_limit: u32,
+    max_query_limit: u32,
+    field_case: FieldCase,
+) -> Response {
+    let tz = match query.tz.as_deref().map(str::parse::<chrono_tz::Tz>).transpose() {
+        Ok(tz) => tz,
+        Err(_) => {
+            return error_reply(
+                StatusCode::BAD_REQUEST,
+                "Unknown time zone, expected an IANA name like America/New_York",
+            )
+        }
+    };
+    let asset = query.asset.unwrap_or_else(|| DEFAULT_ASSET.to_string());
+    let requested = query.limit.unwrap_or(default_limit);
+    let limit = requested.min(max_query_limit);
+
+    // Past one page the JSON array is streamed instead, which leaves it without an ETag
+    let (mut response, truncated) = if format == ResponseFormat::Json && i64::from(limit) > EXPORT_PAGE_SIZE {
+        let first_page = match store.get_metrics_before(&asset, i64::MAX, EXPORT_PAGE_SIZE as u32).await {
+            Ok(page) => page,
+            Err(e) => {
+                error!("Error fetching metrics history: {}", e);
+                return db_error_reply(&e);
+            }
+        };
+        let stream = metrics_json_stream(store, asset, first_page, limit, tz, field_case);
+        let mut response = Response::new(Body::wrap_stream(stream));
+        response
+            .headers_mut()
+            .insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
+        (response, requested > limit)
+    } else {
+        let mut history = match store.get_metrics_history(&asset, Some(requested), max_query_limit).await {
+            Ok(history) => history,
+            Err(e) => {
+                error!("Error fetching metrics history: {}", e);
+                return db_error_reply(&e);
+            }
+        };
+        if let Some(tz) = tz {
+            for row in &mut history.metrics {
+                row.timestamp = localize_timestamp(&row.timestamp, tz);
+            }
+        }
+        let response = match format {
+            ResponseFormat::Json => json_with_etag(&history.metrics, if_none_match.as_deref()),
+            ResponseFormat::Csv => body_with_etag(
+                metrics_to_csv(&history.metrics).into_bytes(),
+                "text/csv; charset=utf-8",
+                if_none_match.as_deref(),
+            ),
+        };
+        (response, history.truncated)
+    };
+
+    // Flag truncation in headers so the body stays a plain array for existing clients
+    response.headers_mut().append(VARY, HeaderValue::from_static("accept"));
+    if truncated {
+        response
+            .headers_mut()
+            .insert("x-truncated", HeaderValue::from_static("true"));
+        response
+            .headers_mut()
+            .insert("x-max-limit", HeaderValue::from(max_query_limit));
+    }
+    response
+}
+
+// Writes up to `limit` rows as one JSON array, newest first, reading EXPORT_PAGE_SIZE rows
+// at a time keyed on id so memory stays at one page however large the limit. The first page
+// is read by the caller, so a failing query still gets an error status rather than a cut-off
+// body; a later failure aborts the response mid-body. Rows are renamed to `field_case` here,
+// like stream_frame does, since with_field_case leaves streamed bodies alone.
+fn metrics_json_stream(
+    store: Arc<dyn MetricsStore>,
+    asset: String,
+    first_page: Vec<Metrics>,
+    limit: u32,
+    tz: Option<chrono_tz::Tz>,
+    field_case: FieldCase,
+) -> impl futures_util::Stream<Item = Result<Bytes, Box<dyn std::error::Error + Send + Sync>>> {
+    let open = futures_util::stream::once(async { Ok(Bytes::from_static(b"[")) });
+    let close = futures_util::stream::once(async { Ok(Bytes::from_static(b"]")) });
+    // (page already read, id to continue before, rows still wanted, nothing written yet)
+    let pages = futures_util::stream::unfold(Some((Some(first_page), i64::MAX, limit, true)), move |state| {
+        let store = Arc::clone(&store);
+        let asset = asset.clone();
+        async move {
+            let (pending, before_id, remaining, first) = state?;
+            let page_size = remaining.min(EXPORT_PAGE_SIZE as u32);
+            let mut page = match pending {
+                Some(page) => page,
+                None if remaining == 0 => return None,
+                None => match store.get_metrics_before(&asset, before_id, page_size).await {
+                    Ok(page) => page,
+                    Err(e) => {
+                        error!("Error reading metrics history: {}", e);
+                        return Some((Err(e.into()), None));
+                    }
+                },
+            };
+            page.truncate(page_size as usize);
+            let last_id = page.last()?.id;
+            // A short page means the history ran out
+            let remaining = if page.len() < page_size as usize { 0 } else { remaining - page.len() as u32 };
+
+            let mut chunk = Vec::new();
+            for (i, row) in page.iter_mut().enumerate() {
+                if let Some(tz) = tz {
+                    row.timestamp = localize_timestamp(&row.timestamp, tz);
+                }
+                if !first || i > 0 {
+                    chunk.push(b',');
+                }
+                let written = serde_json::to_value(&*row)
+                    .and_then(|value| serde_json::to_writer(&mut chunk, &field_case.apply(value)));
+                if let Err(e) = written {
+                    error!("Error serializing metrics history: {}", e);
+                    return Some((Err(e.into()), None));
+                }
+            }
+            Some((Ok(Bytes::from(chunk)), Some((None, last_id, remaining, false))))
+        }
+    });
+    open.chain(pages).chain(close)
+}
+
+// Stored timestamps are naive UTC; a converted one carries its offset, e.g.
+// 2024-01-01T07:00:00-05:00, so it can't be mistaken for UTC. Unparseable ones pass through.
+fn localize_timestamp(timestamp: &str, tz: chrono_tz::Tz) -> String {
+    match parse_timestamp(timestamp) {
+        Some(utc) => utc
+            .and_utc()
+            .with_timezone(&tz)
+            .format("%Y-%m-%dT%H:%M:%S%:z")
+            .to_string(),
+        None => timestamp.to_string(),
+    }
+}
+
+// Who may keep a cached response. A route behind auth is private, so a shared cache or
+// CDN doesn't hand one client's authorized response to another.
+#[derive(Clone, Copy)]
+enum CacheScope {
+    Public,
+    Private,
+}
+
+impl CacheScope {
+    fn for_auth(auth: &Option<Arc<ApiAuth>>) -> CacheScope {
+        match auth {
+            Some(_) => CacheScope::Private,
+            None => CacheScope::Public,
+        }
+    }
+
+    fn header_value(self, max_age: Duration) -> String {
+        let scope = match self {
+            CacheScope::Public => "public",
+            CacheScope::Private => "private",
+        };
+        format!("{}, max-age={}", scope, max_age.as_secs())
+    }
+}
+
+// New samples can't appear more often than once per poll, so caches may reuse a
+// response for that long. Errors are left uncached.
+fn set_poll_cache_control(response: &mut Response, poll_interval: Duration, scope: CacheScope) {
+    let cacheable = response.status().is_success() || response.status() == StatusCode::NOT_MODIFIED;
+    if let (true, Ok(value)) = (cacheable, HeaderValue::from_str(&scope.header_value(poll_interval))) {
+        response.headers_mut().insert(CACHE_CONTROL, value);
+    }
+}
+
+// Incremental polling: only rows newer than the id the client already has. At most
+// max_query_limit rows come back, with x-has-more set so a client that was away for a
+// while keeps asking from the last id it received.
+fn create_since_route(
+    store: Arc<dyn MetricsStore>,
+    max_query_limit: u32,
+    poll_interval: PollInterval,
+    auth: Option<Arc<ApiAuth>>,
+) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    let cache_scope = CacheScope::for_auth(&auth);
+    warp::path!("api" / "metrics" / "since" / i64)
+        .and(warp::get())
+        .and(with_auth(auth))
+        .and(warp::query::<AssetQuery>())
+        .then(move |after_id: i64, query: AssetQuery| {
+            let store = Arc::clone(&store);
+            let poll_interval = poll_interval.get();
+            async move {
+                let asset = query.asset.unwrap_or_else(|| DEFAULT_ASSET.to_string());
+                // One extra row tells whether anything is left beyond the cap
+                let mut metrics = match store.get_metrics_since(&asset, after_id, max_query_limit.saturating_add(1)).await {
+                    Ok(metrics) => metrics,
+                    Err(e) => {
+                        error!("Error fetching metrics since {}: {}", after_id, e);
+                        return db_error_reply(&e);
+                    }
+                };
+                let has_more = metrics.len() > max_query_limit as usize;
+                metrics.truncate(max_query_limit as usize);
+
+                let mut response = warp::reply::json(&metrics).into_response();
+                set_poll_cache_control(&mut response, poll_interval, cache_scope);
+                if has_more {
+                    response
+                        .headers_mut()
+                        .insert("x-has-more", HeaderValue::from_static("true"));
+                }
+                response
+            }
+        })
+}
+
+// Sample count for paging UIs, without fetching the rows themselves
+fn create_count_route(
+    store: Arc<dyn MetricsStore>,
+    poll_interval: PollInterval,
+    auth: Option<Arc<ApiAuth>>,
+) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    let cache_scope = CacheScope::for_auth(&auth);
+    warp::path!("api" / "metrics" / "count")
+        .and(warp::get())
+        .and(with_auth(auth))
+        .and(warp::query::<CountQuery>())
+        .then(move |query: CountQuery| {
+            let store = Arc::clone(&store);
+            let poll_interval = poll_interval.get();
+            async move {
+                let asset = query.asset.unwrap_or_else(|| DEFAULT_ASSET.to_string());
+                let from = match query.from.as_deref().map(normalize_timestamp) {
+                    Some(Some(from)) => Some(from),
+                    Some(None) => return error_reply(StatusCode::BAD_REQUEST, "Invalid 'from' timestamp"),
+                    None => None,
+                };
+                let to = match query.to.as_deref().map(normalize_timestamp) {
+                    Some(Some(to)) => Some(to),
+                    Some(None) => return error_reply(StatusCode::BAD_REQUEST, "Invalid 'to' timestamp"),
+                    None => None,
+                };
+
+                match store.count_metrics(&asset, from.as_deref(), to.as_deref()).await {
+                    Ok(count) => {
+                        let mut response = warp::reply::json(&serde_json::json!({ "count": count })).into_response();
+                        set_poll_cache_control(&mut response, poll_interval, cache_scope);
+                        response
+                    }
+                    Err(e) => {
+                        error!("Error counting metrics: {}", e);
+                        db_error_reply(&e)
+                    }
+                }
+            }
+        })
+}
+
+// Currencies present in stored data, so a UI can build its selector from what's there
+fn create_currencies_route(
+    store: Arc<dyn MetricsStore>,
+    poll_interval: PollInterval,
+    auth: Option<Arc<ApiAuth>>,
+) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    let cache_scope = CacheScope::for_auth(&auth);
+    warp::path!("api" / "currencies")
+        .and(warp::get())
+        .and(with_auth(auth))
+        .and(warp::query::<AssetQuery>())
+        .then(move |query: AssetQuery| {
+            let store = Arc::clone(&store);
+            let poll_interval = poll_interval.get();
+            async move {
+                let asset = query.asset.unwrap_or_else(|| DEFAULT_ASSET.to_string());
+                match store.get_currencies(&asset).await {
+                    Ok(currencies) => {
+                        let mut response = warp::reply::json(&currencies).into_response();
+                        set_poll_cache_control(&mut response, poll_interval, cache_scope);
+                        response
+                    }
+                    Err(e) => {
+                        error!("Error fetching currencies: {}", e);
+                        db_error_reply(&e)
+                    }
+                }
+            }
+        })
+}
+
+// A bare-bones page for eyeballing recent rows in a browser, not a replacement for the frontend
+fn create_view_route(
+    store: Arc<dyn MetricsStore>,
+    default_limit: u32,
+    max_query_limit: u32,
+    auth: Option<Arc<ApiAuth>>,
+) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::path!("api" / "metrics" / "view")
+        .and(warp::get())
+        .and(with_auth(auth))
+        .and(warp::query::<MetricsQuery>())
+        .then(move |query: MetricsQuery| {
+            let store = Arc::clone(&store);
+            async move {
+                let asset = query.asset.unwrap_or_else(|| DEFAULT_ASSET.to_string());
+                let limit = query.limit.unwrap_or(default_limit);
+                match store.get_metrics_history(&asset, Some(limit), max_query_limit).await {
+                    Ok(history) => warp::reply::html(metrics_to_html(&asset, &history.metrics)).into_response(),
+                    Err(e) => {
+                        error!("Error fetching metrics history: {}", e);
+                        db_error_reply(&e)
+                    }
+                }
+            }
+        })
+}
+
+fn html_escape(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+fn metrics_to_html(asset: &str, metrics: &[Metrics]) -> String {
+    let mut html = format!(
+        "<!DOCTYPE html>\n<html><head><meta charset=\"utf-8\"><title>{0} metrics</title>\n\
+         <style>body{{font-family:sans-serif;margin:2em}}table{{border-collapse:collapse}}\
+         th,td{{border:1px solid #ccc;padding:4px 8px;text-align:right}}th{{background:#f4f4f4}}</style>\n\
+         </head><body><h1>{0} metrics</h1>\n<table>\n\
+         <tr><th>id</th><th>timestamp</th><th>block_height</th><th>btc_price</th><th>source</th></tr>\n",
+        html_escape(asset)
+    );
+    for row in metrics {
+        html.push_str(&format!(
+            "<tr><td>{}</td><td>{}</td><td>{}</td><td>{}</td><td>{}</td></tr>\n",
+            row.id,
+            html_escape(&row.timestamp),
+            row.block_height.map(|height| height.to_string()).unwrap_or_default(),
+            row.btc_price.map(|price| price.to_string()).unwrap_or_default(),
+            html_escape(&row.source),
+        ));
+    }
+    html.push_str("</table>\n</body></html>\n");
+    html
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum ResponseFormat {
+    Json,
+    Csv,
+}
+
+// CSV only when the Accept header ranks text/csv above JSON; anything else, including */*,
+// keeps the JSON default
+fn preferred_format(accept: &str) -> ResponseFormat {
+    let mut csv_q = 0.0;
+    let mut json_q = 0.0;
+    for item in accept.split(',') {
+        let mut parts = item.split(';');
+        let media_type = match parts.next() {
+            Some(media_type) => media_type.trim().to_ascii_lowercase(),
+            None => continue,
+        };
+        let q = parts
+            .find_map(|param| param.trim().strip_prefix("q=").and_then(|q| q.trim().parse::<f32>().ok()))
+            .unwrap_or(1.0);
+
+        match media_type.as_str() {
+            "text/csv" => csv_q = f32::max(csv_q, q),
+            "application/json" | "application/*" | "*/*" => json_q = f32::max(json_q, q),
+            _ => {}
+        }
+    }
+
+    if csv_q > 0.0 && csv_q > json_q {
+        ResponseFormat::Csv
+    } else {
+        ResponseFormat::Json
+    }
+}
+
+// Quotes fields containing separators so asset/source values can't break the row
+fn csv_field(value: &str) -> String {
+    if value.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
+
+const METRICS_CSV_HEADER: &str =
+    "id,block_height,btc_price,timestamp,asset,source,fetch_latency_ms,block_hash,fee_1_block,fee_6_blocks,fee_144_blocks,price_spread\n";
+
+// Same columns as the JSON rows; missing heights and prices are left empty
+fn metrics_csv_row(row: &Metrics) -> String {
+    format!(
+        "{},{},{},{},{},{},{},{},{},{},{},{}\n",
+        row.id,
+        row.block_height.map(|height| height.to_string()).unwrap_or_default(),
+        row.btc_price.map(|price| price.to_string()).unwrap_or_default(),
+        csv_field(&row.timestamp),
+        csv_field(&row.asset),
+        csv_field(&row.source),
+        row.fetch_latency_ms.map(|latency| latency.to_string()).unwrap_or_default(),
+        row.block_hash.as_deref().map(csv_field).unwrap_or_default(),
+        row.fees.fee_1_block.map(|rate| rate.to_string()).unwrap_or_default(),
+        row.fees.fee_6_blocks.map(|rate| rate.to_string()).unwrap_or_default(),
+        row.fees.fee_144_blocks.map(|rate| rate.to_string()).unwrap_or_default(),
+        row.spread.price_spread.map(|spread| spread.to_string()).unwrap_or_default(),
+    )
+}
+
+fn metrics_to_csv(metrics: &[Metrics]) -> String {
+    let mut csv = String::from(METRICS_CSV_HEADER);
+    for row in metrics {
+        csv.push_str(&metrics_csv_row(row));
+    }
+    csv
+}
+
+// Weak, since the compression layer may re-encode the body after the tag is computed
+fn compute_etag(body: &[u8]) -> String {
+    let mut hasher = DefaultHasher::new();
+    body.hash(&mut hasher);
+    format!("W/\"{:016x}\"", hasher.finish())
+}
+
+fn etag_matches(if_none_match: &str, etag: &str) -> bool {
+    let opaque = |tag: &str| tag.trim().trim_start_matches("W/").to_string();
+    if_none_match
+        .split(',')
+        .any(|candidate| candidate.trim() == "*" || opaque(candidate) == opaque(etag))
+}
+
+// Serializes to JSON with an ETag, answering 304 when the client already has this body
+fn json_with_etag<T: Serialize>(value: &T, if_none_match: Option<&str>) -> Response {
+    match serde_json::to_vec(value) {
+        Ok(body) => body_with_etag(body, "application/json", if_none_match),
+        Err(e) => {
+            error!("Error serializing response: {}", e);
+            error_reply(StatusCode::INTERNAL_SERVER_ERROR, "Failed to serialize response")
+        }
+    }
+}
+
+fn body_with_etag(body: Vec<u8>, content_type: &'static str, if_none_match: Option<&str>) -> Response {
+    let etag = compute_etag(&body);
+
+    let mut response = if if_none_match.is_some_and(|value| etag_matches(value, &etag)) {
+        let mut response = Response::new(Body::empty());
+        *response.status_mut() = StatusCode::NOT_MODIFIED;
+        response
+    } else {
+        let mut response = Response::new(Body::from(body));
+        response
+            .headers_mut()
+            .insert(CONTENT_TYPE, HeaderValue::from_static(content_type));
+        response
+    };
+
+    if let Ok(etag) = HeaderValue::from_str(&etag) {
+        response.headers_mut().insert(ETAG, etag);
+    }
+    response
+}
+
+// Once every upstream is failing nothing new is saved, so the newest sample ages. Past
+// this many poll intervals it's flagged so clients can warn instead of showing it as current.
+const STALE_AFTER_POLLS: u64 = 3;
+
+#[derive(Serialize)]
+struct LatestReply<'a> {
+    #[serde(flatten)]
+    metrics: &'a Metrics,
+    is_stale: bool,
+    // Age of the sample; null when its timestamp can't be parsed
+    stale_secs: Option<i64>,
+}
+
+#[derive(Serialize)]
+struct LastBlock<'a> {
+    block_height: u64,
+    timestamp: &'a str,
+}
+
+fn staleness(timestamp: &str, now: chrono::NaiveDateTime, poll_interval: Duration) -> (bool, Option<i64>) {
+    let max_age_secs = (STALE_AFTER_POLLS * poll_interval.as_secs()) as i64;
+    match parse_timestamp(timestamp) {
+        Some(sampled_at) => {
+            let age_secs = (now - sampled_at).num_seconds().max(0);
+            (age_secs > max_age_secs, Some(age_secs))
+        }
+        None => (true, None),
+    }
+}
+
+fn create_latest_route(
+    latest: LatestMetrics,
+    poll_interval: PollInterval,
+    auth: Option<Arc<ApiAuth>>,
+) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    let cache_scope = CacheScope::for_auth(&auth);
+    warp::path!("api" / "metrics" / "latest")
+        .and(warp::get())
+        .and(with_auth(auth))
+        .and(warp::query::<AssetQuery>())
+        .map(move |query: AssetQuery| {
+            let asset = query.asset.unwrap_or_else(|| DEFAULT_ASSET.to_string());
+            let latest = lock_or_recover(&latest);
+
+            match latest.get(&asset) {
+                Some(metrics) => {
+                    let (is_stale, stale_secs) = staleness(&metrics.timestamp, chrono::Utc::now().naive_utc(), poll_interval.get());
+                    let mut response = warp::reply::json(&LatestReply {
+                        metrics,
+                        is_stale,
+                        stale_secs,
+                    })
+                    .into_response();
+                    set_poll_cache_control(&mut response, poll_interval.get(), cache_scope);
+                    response
+                }
+                None => error_reply(StatusCode::NOT_FOUND, "No metrics collected yet"),
+            }
+        })
+}
+
+// Just the chain height for lightweight clients, from the newest cached row that has one.
+// The height isn't per asset, so every tracked asset's row counts.
+fn create_last_block_route(
+    latest: LatestMetrics,
+    poll_interval: PollInterval,
+    auth: Option<Arc<ApiAuth>>,
+) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    let cache_scope = CacheScope::for_auth(&auth);
+    warp::path!("api" / "metrics" / "last-block")
+        .and(warp::get())
+        .and(with_auth(auth))
+        .map(move || {
+            let latest = lock_or_recover(&latest);
+            let newest = latest
+                .values()
+                .filter_map(|metrics| metrics.block_height.map(|height| (metrics, height)))
+                .max_by(|(a, _), (b, _)| (&a.timestamp, a.id).cmp(&(&b.timestamp, b.id)));
+
+            match newest {
+                Some((metrics, block_height)) => {
+                    let mut response = warp::reply::json(&LastBlock {
+                        block_height,
+                        timestamp: &metrics.timestamp,
+                    })
+                    .into_response();
+                    set_poll_cache_control(&mut response, poll_interval.get(), cache_scope);
+                    response
+                }
+                // Not a zero height, which clients could mistake for a real one
+                None => error_reply(StatusCode::SERVICE_UNAVAILABLE, "No block height collected yet"),
+            }
+        })
+}
+
+// Pushes each new sample for one asset as a JSON text frame, optionally preceded by the
+// last ?backlog= stored samples, oldest first
+fn create_stream_route(
+    store: Arc<dyn MetricsStore>,
+    updates: broadcast::Sender<Metrics>,
+    field_case: FieldCase,
+    auth: Option<Arc<ApiAuth>>,
+) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::path!("api" / "metrics" / "stream")
+        .and(warp::ws())
+        .and(with_auth(auth))
+        .and(warp::query::<StreamQuery>())
+        .map(move |ws: warp::ws::Ws, query: StreamQuery| {
+            let asset = query.asset.unwrap_or_else(|| DEFAULT_ASSET.to_string());
+            let backlog = query.backlog.unwrap_or(0).min(MAX_STREAM_BACKLOG);
+            // Subscribed before the backlog is read so nothing saved in between is missed
+            let receiver = updates.subscribe();
+            let store = Arc::clone(&store);
+            ws.on_upgrade(move |socket| stream_updates(socket, store, receiver, asset, backlog, field_case))
+        })
+}
+
+fn stream_frame(metrics: &Metrics, field_case: FieldCase) -> Option<Message> {
+    match serde_json::to_value(metrics).and_then(|value| serde_json::to_string(&field_case.apply(value))) {
+        Ok(frame) => Some(Message::text(frame)),
+        Err(e) => {
+            error!("Error serializing metrics for stream: {}", e);
+            None
+        }
+    }
+}
+
+async fn stream_updates(
+    mut socket: WebSocket,
+    store: Arc<dyn MetricsStore>,
+    mut receiver: broadcast::Receiver<Metrics>,
+    asset: String,
+    backlog: u32,
+    field_case: FieldCase,
+) {
+    // Live samples already covered by the backlog are skipped by id
+    let mut sent_up_to = 0;
+    if backlog > 0 {
+        let history = match store.get_metrics_history(&asset, Some(backlog), MAX_STREAM_BACKLOG).await {
+            Ok(history) => history.metrics,
+            Err(e) => {
+                error!("Error fetching stream backlog: {}", e);
+                Vec::new()
+            }
+        };
+        for metrics in history.iter().rev() {
+            sent_up_to = metrics.id;
+            let frame = match stream_frame(metrics, field_case) {
+                Some(frame) => frame,
+                None => continue,
+            };
+            if socket.send(frame).await.is_err() {
+                return;
+            }
+        }
+    }
+
+    loop {
+        tokio::select! {
+            update = receiver.recv() => {
+                let metrics = match update {
+                    Ok(metrics) => metrics,
+                    // A client too slow to keep up just misses the skipped samples
+                    Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                    Err(broadcast::error::RecvError::Closed) => break,
+                };
+                // Buffered writes go out unsaved and are never part of the backlog
+                if metrics.asset != asset || (!is_unsaved(&metrics.id) && metrics.id <= sent_up_to) {
+                    continue;
+                }
+                let frame = match stream_frame(&metrics, field_case) {
+                    Some(frame) => frame,
+                    None => continue,
+                };
+                if socket.send(frame).await.is_err() {
+                    break;
+                }
+            }
+            // Incoming frames are ignored; reading them notices closed connections
+            incoming = socket.next() => match incoming {
+                Some(Ok(message)) if !message.is_close() => {}
+                _ => break,
+            },
+        }
+    }
+}
+
+fn create_average_block_time_route(
+    conn: Arc<Mutex<Connection>>,
+    auth: Option<Arc<ApiAuth>>,
+) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::path!("api" / "metrics" / "average-block-time")
+        .and(warp::get())
+        .and(with_auth(auth))
+        .map(move || {
+            let first_seen = {
+                let conn = lock_or_recover(&conn);
+                get_block_first_seen(&conn)
+            };
+
+            match first_seen {
+                Ok(first_seen) => warp::reply::json(&compute_block_times(&first_seen)).into_response(),
+                Err(e) => {
+                    error!("Error fetching block heights: {}", e);
+                    db_error_reply(&StoreError::from(e))
+                }
+            }
+        })
+}
+
+// Next halving from the highest stored block, timed with the measured average block time
+fn create_halving_route(
+    conn: Arc<Mutex<Connection>>,
+    auth: Option<Arc<ApiAuth>>,
+) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::path!("api" / "halving")
+        .and(warp::get())
+        .and(with_auth(auth))
+        .map(move || {
+            let first_seen = {
+                let conn = lock_or_recover(&conn);
+
+                match get_block_first_seen(&conn) {
+                    Ok(first_seen) => first_seen,
+                    Err(e) => {
+                        error!("Error fetching block heights: {}", e);
+                        return error_reply(StatusCode::INTERNAL_SERVER_ERROR, "Failed to load block heights");
+                    }
+                }
+            };
+
+            match first_seen.last() {
+                Some((height, _)) => {
+                    let average_block_secs = compute_block_times(&first_seen).average_secs;
+                    warp::reply::json(&compute_halving(*height, average_block_secs, chrono::Utc::now().naive_utc()))
+                        .into_response()
+                }
+                None => error_reply(StatusCode::SERVICE_UNAVAILABLE, "No block height collected yet"),
+            }
+        })
+}
+
+// Gaps among the newest `limit` samples. Without a threshold anything over twice the
+// detected interval counts, which is also reported in x-expected-interval-secs.
+fn create_gaps_route(
+    conn: Arc<Mutex<Connection>>,
+    poll_interval: PollInterval,
+    auth: Option<Arc<ApiAuth>>,
+) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::path!("api" / "metrics" / "gaps")
+        .and(warp::get())
+        .and(with_auth(auth))
+        .and(warp::query::<GapsQuery>())
+        .map(move |query: GapsQuery| {
+            let threshold_secs = match query.threshold.as_deref().map(parse_duration_secs) {
+                Some(Some(secs)) if secs > 0 => Some(secs),
+                Some(_) => return error_reply(StatusCode::BAD_REQUEST, "threshold must be a duration such as 90s, 5m or 1h"),
+                None => None,
+            };
+            let limit = match query.limit {
+                Some(0) => return error_reply(StatusCode::BAD_REQUEST, "limit must be at least 1"),
+                Some(limit) => limit,
+                None => DEFAULT_GAP_SCAN_LIMIT,
+            };
+            let asset = query.asset.unwrap_or_else(|| DEFAULT_ASSET.to_string());
+
+            let timestamps = {
+                let conn = lock_or_recover(&conn);
+                get_sample_timestamps(&conn, &asset, limit)
+            };
+            let timestamps = match timestamps {
+                Ok(timestamps) => timestamps,
+                Err(e) => {
+                    error!("Error fetching sample timestamps: {}", e);
+                    return db_error_reply(&StoreError::from(e));
+                }
+            };
+
+            // Too few samples to tell the interval from, fall back to the staleness cutoff
+            let interval_secs = typical_interval_secs(&timestamps);
+            let threshold_secs = threshold_secs.unwrap_or_else(|| match interval_secs {
+                Some(interval_secs) => interval_secs * 2,
+                None => (STALE_AFTER_POLLS * poll_interval.get().as_secs()) as i64,
+            });
+
+            let mut response = warp::reply::json(&find_gaps(&timestamps, threshold_secs)).into_response();
+            if let Some(interval_secs) = interval_secs {
+                response
+                    .headers_mut()
+                    .insert("x-expected-interval-secs", HeaderValue::from(interval_secs));
+            }
+            response
+        })
+}
+
+// "Since yesterday" style deltas between the samples nearest two times, e.g.
+// ?a=2024-01-01T00:00:00Z&b=2024-01-02T00:00:00Z
+fn create_compare_route(
+    conn: Arc<Mutex<Connection>>,
+    auth: Option<Arc<ApiAuth>>,
+) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::path!("api" / "metrics" / "compare")
+        .and(warp::get())
+        .and(with_auth(auth))
+        .and(warp::query::<CompareQuery>())
+        .map(move |query: CompareQuery| {
+            let (a, b) = match (
+                query.a.as_deref().and_then(normalize_timestamp),
+                query.b.as_deref().and_then(normalize_timestamp),
+            ) {
+                (Some(a), Some(b)) => (a, b),
+                _ => return error_reply(StatusCode::BAD_REQUEST, "Both 'a' and 'b' must be valid timestamps"),
+            };
+            let tolerance_secs = match query.tolerance.as_deref().map(parse_duration_secs) {
+                Some(Some(secs)) => secs,
+                Some(None) => return error_reply(StatusCode::BAD_REQUEST, "tolerance must be a duration such as 90s, 15m or 1h"),
+                None => DEFAULT_COMPARE_TOLERANCE.as_secs() as i64,
+            };
+            let asset = query.asset.unwrap_or_else(|| DEFAULT_ASSET.to_string());
+
+            let nearest = {
+                let conn = lock_or_recover(&conn);
+                get_nearest_metrics(&conn, &asset, &a, tolerance_secs).and_then(|nearest_a| {
+                    get_nearest_metrics(&conn, &asset, &b, tolerance_secs).map(|nearest_b| (nearest_a, nearest_b))
+                })
+            };
+
+            match nearest {
+                Ok((Some(a), Some(b))) => warp::reply::json(&Comparison::new(a, b)).into_response(),
+                Ok(_) => error_reply(StatusCode::NOT_FOUND, "No samples near one or both of the requested times"),
+                Err(e) => {
+                    error!("Error fetching metrics to compare: {}", e);
+                    db_error_reply(&StoreError::from(e))
+                }
+            }
+        })
+}
+
+// All-time high within the stored history, not the asset's true ATH if it predates the data
+fn create_ath_route(
+    conn: Arc<Mutex<Connection>>,
+    poll_interval: PollInterval,
+    auth: Option<Arc<ApiAuth>>,
+) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    let cache_scope = CacheScope::for_auth(&auth);
+    warp::path!("api" / "metrics" / "ath")
+        .and(warp::get())
+        .and(with_auth(auth))
+        .and(warp::query::<AssetQuery>())
+        .map(move |query: AssetQuery| {
+            let asset = query.asset.unwrap_or_else(|| DEFAULT_ASSET.to_string());
+
+            let samples = {
+                let conn = lock_or_recover(&conn);
+                get_all_time_high(&conn, &asset)
+            };
+
+            match samples {
+                Ok(Some((ath, current))) => match AllTimeHigh::new(ath, &current) {
+                    Some(ath) => {
+                        let mut response = warp::reply::json(&ath).into_response();
+                        set_poll_cache_control(&mut response, poll_interval.get(), cache_scope);
+                        response
+                    }
+                    None => error_reply(StatusCode::NOT_FOUND, "No prices stored for this asset"),
+                },
+                Ok(None) => error_reply(StatusCode::NOT_FOUND, "No prices stored for this asset"),
+                Err(e) => {
+                    error!("Error fetching all-time high: {}", e);
+                    db_error_reply(&StoreError::from(e))
+                }
+            }
+        })
+}
+
+// Rolling high and low for tickers, e.g. ?window=24h
+fn create_high_low_route(
+    conn: Arc<Mutex<Connection>>,
+    poll_interval: PollInterval,
+    auth: Option<Arc<ApiAuth>>,
+) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    let cache_scope = CacheScope::for_auth(&auth);
+    warp::path!("api" / "metrics" / "high-low")
+        .and(warp::get())
+        .and(with_auth(auth))
+        .and(warp::query::<HighLowQuery>())
+        .map(move |query: HighLowQuery| {
+            let window_secs = match parse_duration_secs(query.window.as_deref().unwrap_or("24h")) {
+                Some(secs) if secs > 0 => secs,
+                _ => return error_reply(StatusCode::BAD_REQUEST, "window must be a duration such as 1h, 24h or 7d"),
+            };
+            let since = (chrono::Utc::now() - chrono::Duration::seconds(window_secs))
+                .format(TIMESTAMP_FORMAT)
+                .to_string();
+            let asset = query.asset.unwrap_or_else(|| DEFAULT_ASSET.to_string());
+
+            let high_low = {
+                let conn = lock_or_recover(&conn);
+                get_high_low(&conn, &asset, &since, window_secs)
+            };
+
+            match high_low {
+                Ok(high_low) => {
+                    let mut response = warp::reply::json(&high_low).into_response();
+                    set_poll_cache_control(&mut response, poll_interval.get(), cache_scope);
+                    response
+                }
+                Err(e) => {
+                    error!("Error fetching high and low prices: {}", e);
+                    db_error_reply(&StoreError::from(e))
+                }
+            }
+        })
+}
+
+fn create_buckets_route(
+    conn: Arc<Mutex<Connection>>,
+    auth: Option<Arc<ApiAuth>>,
+) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::path!("api" / "metrics" / "buckets")
+        .and(warp::get())
+        .and(with_auth(auth))
+        .and(warp::query::<BucketsQuery>())
+        .map(move |query: BucketsQuery| {
+            let interval_secs = match parse_duration_secs(query.interval.as_deref().unwrap_or("1h")) {
+                Some(secs) if secs >= 60 => secs,
+                _ => return error_reply(StatusCode::BAD_REQUEST, "interval must be a duration of at least 1m, e.g. 15m, 1h, 1d"),
+            };
+            let (from, to) = match parse_time_range(query.from.as_deref(), query.to.as_deref(), chrono::Duration::days(1)) {
+                Ok(range) => range,
+                Err(message) => return error_reply(StatusCode::BAD_REQUEST, message),
+            };
+            let asset = query.asset.unwrap_or_else(|| DEFAULT_ASSET.to_string());
+
+            let buckets = {
+                let conn = lock_or_recover(&conn);
+                get_price_buckets(&conn, &asset, interval_secs, &from, &to)
+            };
+
+            match buckets {
+                Ok(buckets) => warp::reply::json(&buckets).into_response(),
+                Err(e) => {
+                    error!("Error fetching price buckets: {}", e);
+                    db_error_reply(&StoreError::from(e))
+                }
+            }
+        })
+}
+
+fn create_downsample_route(
+    conn: Arc<Mutex<Connection>>,
+    auth: Option<Arc<ApiAuth>>,
+) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::path!("api" / "metrics" / "downsample")
+        .and(warp::get())
+        .and(with_auth(auth))
+        .and(warp::query::<DownsampleQuery>())
+        .map(move |query: DownsampleQuery| {
+            let points = query.points.unwrap_or(DEFAULT_DOWNSAMPLE_POINTS);
+            if !(3..=MAX_DOWNSAMPLE_POINTS).contains(&points) {
+                return error_reply(
+                    StatusCode::BAD_REQUEST,
+                    &format!("points must be between 3 and {}", MAX_DOWNSAMPLE_POINTS),
+                );
+            }
+            let (from, to) = match parse_time_range(query.from.as_deref(), query.to.as_deref(), chrono::Duration::days(30)) {
+                Ok(range) => range,
+                Err(message) => return error_reply(StatusCode::BAD_REQUEST, message),
+            };
+            let asset = query.asset.unwrap_or_else(|| DEFAULT_ASSET.to_string());
+
+            let series = {
+                let conn = lock_or_recover(&conn);
+                get_price_series(&conn, &asset, &from, &to)
+            };
+
+            match series {
+                Ok(series) => {
+                    let coords: Vec<(f64, f64)> = series
+                        .iter()
+                        .map(|point| {
+                            let x = parse_timestamp(&point.timestamp)
+                                .map(|ts| ts.and_utc().timestamp() as f64)
+                                .unwrap_or_default();
+                            (x, point.btc_price)
+                        })
+                        .collect();
+                    let sampled: Vec<&SeriesPoint> = lttb(&coords, points).into_iter().map(|i| &series[i]).collect();
+                    warp::reply::json(&sampled).into_response()
+                }
+                Err(e) => {
+                    error!("Error fetching price series: {}", e);
+                    db_error_reply(&StoreError::from(e))
+                }
+            }
+        })
+}
+
+// Hourly rollups of samples older than ROLLUP_AGE, for history the raw rows may no longer cover
+fn create_hourly_route(
+    conn: Arc<Mutex<Connection>>,
+    auth: Option<Arc<ApiAuth>>,
+) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::path!("api" / "metrics" / "hourly")
+        .and(warp::get())
+        .and(with_auth(auth))
+        .and(warp::query::<CountQuery>())
+        .map(move |query: CountQuery| {
+            let (from, to) = match parse_time_range(query.from.as_deref(), query.to.as_deref(), chrono::Duration::days(30)) {
+                Ok(range) => range,
+                Err(message) => return error_reply(StatusCode::BAD_REQUEST, message),
+            };
+            let asset = query.asset.unwrap_or_else(|| DEFAULT_ASSET.to_string());
+
+            let rollups = {
+                let conn = lock_or_recover(&conn);
+                get_hourly_rollups(&conn, &asset, &from, &to)
+            };
+
+            match rollups {
+                Ok(rollups) => warp::reply::json(&rollups).into_response(),
+                Err(e) => {
+                    error!("Error fetching hourly rollups: {}", e);
+                    db_error_reply(&StoreError::from(e))
+                }
+            }
+        })
+}
+
+// Prunes raw samples older than `older_than_days` on demand, rolling their hours up first as
+// the rollup task does so /api/metrics/hourly keeps covering them
+fn create_prune_route(
+    conn: Arc<Mutex<Connection>>,
+    auth: Option<Arc<ApiAuth>>,
+) -> impl Filter<Extract = (Response,), Error = warp::Rejection> + Clone {
+    warp::path!("api" / "admin" / "prune")
+        .and(warp::post())
+        .and(with_auth(auth))
+        .and(warp::query::<PruneQuery>())
+        .map(move |query: PruneQuery| {
+            let days = match query.older_than_days {
+                Some(days) if days > 0 => days,
+                _ => return error_reply(StatusCode::BAD_REQUEST, "'older_than_days' must be a positive number of days"),
+            };
+            let cutoff = rollup_cutoff(chrono::Utc::now(), Duration::from_secs(u64::from(days) * 86_400));
+
+            let result = {
+                let conn = lock_or_recover(&conn);
+                conn.unchecked_transaction().and_then(|tx| {
+                    let rolled_up = rollup_hourly(&tx, &cutoff)?;
+                    let deleted = prune_old_metrics(&tx, &cutoff)?;
+                    tx.commit()?;
+                    Ok((rolled_up, deleted))
+                })
+            };
+
+            match result {
+                Ok((rolled_up, deleted)) => {
+                    info!("Manual prune before {} deleted {} samples, rolled up {} hours", cutoff, deleted, rolled_up);
+                    warp::reply::json(&serde_json::json!({
+                        "deleted": deleted,
+                        "rolled_up_hours": rolled_up,
+                        "cutoff": cutoff,
+                    }))
+                    .into_response()
+                }
+                Err(e) => {
+                    error!("Error pruning old metrics: {}", e);
+                    db_error_reply(&StoreError::from(e))
+                }
+            }
+        })
+}
+
+// Missing bounds default to the `default_span` leading up to now
+fn parse_time_range(
+    from: Option<&str>,
+    to: Option<&str>,
+    default_span: chrono::Duration,
+) -> Result<(String, String), &'static str> {
+    let to = match to.map(normalize_timestamp) {
+        Some(Some(to)) => to,
+        Some(None) => return Err("Invalid 'to' timestamp"),
+        None => now_timestamp(),
+    };
+    let from = match from.map(normalize_timestamp) {
+        Some(Some(from)) => from,
+        Some(None) => return Err("Invalid 'from' timestamp"),
+        None => (chrono::Utc::now() - default_span).format(TIMESTAMP_FORMAT).to_string(),
+    };
+    Ok((from, to))
+}
+
+// Largest-Triangle-Three-Buckets: keeps the first and last points, then from each
+// bucket in between picks the point forming the largest triangle with the previously
+// kept point and the next bucket's average, so peaks and dips survive the reduction.
+// Returns the indices of the kept points in order.
+fn lttb(points: &[(f64, f64)], threshold: usize) -> Vec<usize> {
+    let len = points.len();
+    if threshold >= len || threshold < 3 {
+        return (0..len).collect();
+    }
+
+    let every = (len - 2) as f64 / (threshold - 2) as f64;
+    let mut sampled = Vec::with_capacity(threshold);
+    let mut anchor = 0;
+    sampled.push(anchor);
+
+    for bucket in 0..threshold - 2 {
+        let next_start = ((bucket + 1) as f64 * every) as usize + 1;
+        let next_end = (((bucket + 2) as f64 * every) as usize + 1).min(len);
+        let next = &points[next_start..next_end];
+        let avg_x = next.iter().map(|p| p.0).sum::<f64>() / next.len() as f64;
+        let avg_y = next.iter().map(|p| p.1).sum::<f64>() / next.len() as f64;
+
+        let start = (bucket as f64 * every) as usize + 1;
+        let (ax, ay) = points[anchor];
+        let mut best = start;
+        let mut best_area = -1.0;
+        for (i, &(x, y)) in points.iter().enumerate().take(next_start).skip(start) {
+            let area = ((ax - avg_x) * (y - ay) - (ax - x) * (avg_y - ay)).abs();
+            if area > best_area {
+                best_area = area;
+                best = i;
+            }
+        }
+
+        sampled.push(best);
+        anchor = best;
+    }
+
+    sampled.push(len - 1);
+    sampled
+}
+
+fn error_reply(status: StatusCode, message: &str) -> Response {
+    warp::reply::with_status(
+        warp::reply::json(&ErrorResponse {
+            error: message.to_string(),
+        }),
+        status,
+    )
+    .into_response()
+}
+
+fn is_db_busy(e: &rusqlite::Error) -> bool {
+    matches!(
+        e,
+        rusqlite::Error::SqliteFailure(err, _)
+            if err.code == rusqlite::ErrorCode::DatabaseBusy || err.code == rusqlite::ErrorCode::DatabaseLocked
+    )
+}
+
+// Lock contention is transient, so tell clients to retry rather than report "no data"
+fn db_error_reply(e: &StoreError) -> Response {
+    if e.is_busy() {
+        let mut response = error_reply(StatusCode::SERVICE_UNAVAILABLE, "Database is busy, retry shortly");
+        response
+            .headers_mut()
+            .insert(RETRY_AFTER, HeaderValue::from_static("1"));
+        return response;
+    }
+
+    error_reply(StatusCode::INTERNAL_SERVER_ERROR, "Failed to query metrics")
+}
+
+// Writes the table as one JSON array, a page at a time, so memory stays bounded
+// no matter how many rows are stored. The DB lock is only held per page.
+async fn stream_metrics_export(conn: Arc<Mutex<Connection>>, mut sender: warp::hyper::body::Sender) {
+    if sender.send_data(Bytes::from_static(b"[")).await.is_err() {
+        return;
+    }
+
+    let mut after_id = 0;
+    let mut first = true;
+    loop {
+        let page = {
+            let conn = lock_or_recover(&conn);
+            get_metrics_page(&conn, after_id, EXPORT_PAGE_SIZE)
+        };
+        let page = match page {
+            Ok(page) => page,
+            Err(e) => {
+                error!("Error reading metrics for export: {}", e);
+                sender.abort();
+                return;
+            }
+        };
+        let last_id = match page.last() {
+            Some(last) => last.id,
+            None => break,
+        };
+
+        let mut chunk = Vec::new();
+        for metric in &page {
+            if !first {
+                chunk.push(b',');
+            }
+            first = false;
+            if let Err(e) = serde_json::to_writer(&mut chunk, metric) {
+                error!("Error serializing metrics for export: {}", e);
+                sender.abort();
+                return;
+            }
+        }
+
+        // The client went away, stop reading
+        if sender.send_data(Bytes::from(chunk)).await.is_err() {
+            return;
+        }
+        after_id = last_id;
+    }
+
+    let _ = sender.send_data(Bytes::from_static(b"]")).await;
+}
+
+// Passes through when no credentials are configured. Place it after the path match
+// so unrelated paths still 404 rather than 401.
+fn with_auth(
+    auth: Option<Arc<ApiAuth>>,
+) -> impl Filter<Extract = (), Error = warp::Rejection> + Clone {
+    warp::header::optional::<String>("authorization")
+        .and_then(move |authorization: Option<String>| {
+            let auth = auth.clone();
+            async move {
+                match (&auth, authorization) {
+                    (None, _) => Ok(()),
+                    (Some(auth), Some(authorization)) if auth.matches(&authorization) => Ok(()),
+                    (Some(auth), _) => Err(warp::reject::custom(Unauthorized {
+                        challenge: auth.challenge(),
+                    })),
+                }
+            }
+        })
+        .untuple_one()
+}
+
+// CORS for the API. Origins are checked by with_allowed_origins, which a SIGHUP can change.
+// x-request-id goes both ways: a client may send its own and reads back the one used.
+fn api_cors() -> warp::cors::Builder {
+    warp::cors()
+        .allow_any_origin()
+        .allow_methods(vec!["GET", "POST"])
+        .allow_headers(vec!["content-type", "authorization", "x-export-token", "x-request-id"])
+        .expose_headers(vec!["etag", "x-truncated", "x-max-limit", "x-has-more", "x-request-id"])
+}
+
+// Matches the BASE_PATH prefix, e.g. "/btc" or "/btc/v1", one segment at a time so the
+// routes underneath keep their own "api/..." paths
+fn with_base_path(base_path: &str) -> BoxedFilter<()> {
+    base_path
+        .split('/')
+        .filter(|segment| !segment.is_empty())
+        .fold(warp::any().boxed(), |prefix, segment| {
+            prefix.and(warp::path(segment.to_string())).boxed()
+        })
+}
+
+async fn handle_rejection(err: warp::Rejection) -> Result<Response, warp::Rejection> {
+    if let Some(unauthorized) = err.find::<Unauthorized>() {
+        let mut response = error_reply(StatusCode::UNAUTHORIZED, "Authentication required");
+        response
+            .headers_mut()
+            .insert(WWW_AUTHENTICATE, HeaderValue::from_static(unauthorized.challenge));
+        return Ok(response);
+    }
+    if err.find::<Overloaded>().is_some() {
+        let mut response = error_reply(StatusCode::SERVICE_UNAVAILABLE, "Too many requests in flight, retry shortly");
+        response
+            .headers_mut()
+            .insert(RETRY_AFTER, HeaderValue::from_static("1"));
+        return Ok(response);
+    }
+
+    Err(err)
+}
+
+// Export and download both need EXPORT_TOKEN in an x-export-token header. Not a query
+// parameter, since URLs end up in access logs, proxies and browser history.
+// Returns the error reply when the request isn't allowed.
+fn export_token_rejection(expected: Option<&str>, provided: Option<String>) -> Option<Response> {
+    let expected = match expected {
+        Some(expected) => expected,
+        None => return Some(error_reply(StatusCode::FORBIDDEN, "Export is disabled, set EXPORT_TOKEN to enable it")),
+    };
+    if !provided.is_some_and(|provided| secrets_match(&provided, expected)) {
+        return Some(error_reply(StatusCode::FORBIDDEN, "Missing or invalid export token"));
+    }
+    None
+}
+
+fn create_export_route(
+    conn: Arc<Mutex<Connection>>,
+    export_token: Option<String>,
+    auth: Option<Arc<ApiAuth>>,
+) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::path!("api" / "metrics" / "export")
+        .and(warp::get())
+        .and(with_auth(auth))
+        .and(warp::header::optional::<String>("x-export-token"))
+        .map(move |token: Option<String>| {
+            if let Some(response) = export_token_rejection(export_token.as_deref(), token) {
+                return response;
+            }
+
+            let (sender, body) = Body::channel();
+            tokio::spawn(stream_metrics_export(Arc::clone(&conn), sender));
+
+            let mut response = Response::new(body);
+            response
+                .headers_mut()
+                .insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
+            response
+        })
+}
+
+// The whole table as CSV, read EXPORT_PAGE_SIZE rows at a time keyed on id. Hyper only
+// polls for the next page once the previous one is written out, so a slow client holds
+// back the reads and memory stays at one page however large the table is.
+fn metrics_csv_stream(
+    conn: Arc<Mutex<Connection>>,
+) -> impl futures_util::Stream<Item = Result<Bytes, rusqlite::Error>> {
+    let header = futures_util::stream::once(async { Ok(Bytes::from_static(METRICS_CSV_HEADER.as_bytes())) });
+    let pages = futures_util::stream::unfold(Some(0), move |after_id| {
+        let conn = Arc::clone(&conn);
+        async move {
+            let after_id = after_id?;
+            let page = {
+                let conn = lock_or_recover(&conn);
+                get_metrics_page(&conn, after_id, EXPORT_PAGE_SIZE)
+            };
+            match page {
+                Ok(page) => {
+                    let last_id = page.last()?.id;
+                    let chunk: String = page.iter().map(metrics_csv_row).collect();
+                    Some((Ok(Bytes::from(chunk)), Some(last_id)))
+                }
+                // Ends the stream after the error, which aborts the response mid-body
+                Err(e) => {
+                    error!("Error reading metrics for CSV export: {}", e);
+                    Some((Err(e), None))
+                }
+            }
+        }
+    });
+    header.chain(pages)
+}
+
+// Same EXPORT_TOKEN gate as the JSON export, since both hand out the entire table
+fn create_csv_export_route(
+    conn: Arc<Mutex<Connection>>,
+    export_token: Option<String>,
+    auth: Option<Arc<ApiAuth>>,
+) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::path!("api" / "metrics" / "csv")
+        .and(warp::get())
+        .and(with_auth(auth))
+        .and(warp::header::optional::<String>("x-export-token"))
+        .map(move |token: Option<String>| {
+            if let Some(response) = export_token_rejection(export_token.as_deref(), token) {
+                return response;
+            }
+
+            let mut response = Response::new(Body::wrap_stream(metrics_csv_stream(Arc::clone(&conn))));
+            let headers = response.headers_mut();
+            headers.insert(CONTENT_TYPE, HeaderValue::from_static("text/csv; charset=utf-8"));
+            headers.insert(
+                CONTENT_DISPOSITION,
+                HeaderValue::from_static("attachment; filename=\"metrics.csv\""),
+            );
+            response
+        })
+}
+
+// Copies the live database with SQLite's online backup API, so the snapshot is consistent
+// even while the collector keeps writing. Returns the temp file holding the copy.
+fn backup_database(conn: &Connection) -> Result<std::path::PathBuf> {
+    let nanos = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_nanos())
+        .unwrap_or(0);
+    let path = std::env::temp_dir().join(format!("bitcoin-explore-backup-{}-{}.db", std::process::id(), nanos));
+
+    if let Err(e) = conn.backup(rusqlite::DatabaseName::Main, &path, None) {
+        let _ = std::fs::remove_file(&path);
+        return Err(e);
+    }
+    Ok(path)
+}
+
+async fn stream_backup_file(path: std::path::PathBuf, mut file: tokio::fs::File, mut sender: warp::hyper::body::Sender) {
+    use tokio::io::AsyncReadExt;
+
+    let mut buf = vec![0; 64 * 1024];
+    loop {
+        match file.read(&mut buf).await {
+            Ok(0) => break,
+            Ok(read) => {
+                // The client went away, stop reading
+                if sender.send_data(Bytes::copy_from_slice(&buf[..read])).await.is_err() {
+                    break;
+                }
+            }
+            Err(e) => {
+                error!("Error reading database backup: {}", e);
+                sender.abort();
+                break;
+            }
+        }
+    }
+
+    if let Err(e) = tokio::fs::remove_file(&path).await {
+        error!("Error removing database backup {}: {}", path.display(), e);
+    }
+}
+
+async fn download_reply(conn: Arc<Mutex<Connection>>) -> Response {
+    let backup = tokio::task::spawn_blocking(move || backup_database(&lock_or_recover(&conn))).await;
+    let path = match backup {
+        Ok(Ok(path)) => path,
+        Ok(Err(e)) => {
+            error!("Error backing up database: {}", e);
+            return db_error_reply(&StoreError::from(e));
+        }
+        Err(e) => {
+            error!("Database backup task failed: {}", e);
+            return error_reply(StatusCode::INTERNAL_SERVER_ERROR, "Database backup failed");
+        }
+    };
+
+    let opened = match tokio::fs::File::open(&path).await {
+        Ok(file) => file.metadata().await.map(|metadata| (file, metadata.len())),
+        Err(e) => Err(e),
+    };
+    let (file, len) = match opened {
+        Ok(opened) => opened,
+        Err(e) => {
+            error!("Error opening database backup: {}", e);
+            let _ = tokio::fs::remove_file(&path).await;
+            return error_reply(StatusCode::INTERNAL_SERVER_ERROR, "Database backup failed");
+        }
+    };
+
+    let (sender, body) = Body::channel();
+    tokio::spawn(stream_backup_file(path, file, sender));
+
+    let mut response = Response::new(body);
+    let headers = response.headers_mut();
+    headers.insert(CONTENT_TYPE, HeaderValue::from_static("application/x-sqlite3"));
+    headers.insert(CONTENT_LENGTH, HeaderValue::from(len));
+    let disposition = format!(
+        "attachment; filename=\"metrics-{}.db\"",
+        chrono::Utc::now().format("%Y%m%d-%H%M%S")
+    );
+    if let Ok(disposition) = HeaderValue::from_str(&disposition) {
+        headers.insert(CONTENT_DISPOSITION, disposition);
+    }
+    response
+}
+
+fn create_download_route(
+    conn: Arc<Mutex<Connection>>,
+    export_token: Option<String>,
+    auth: Option<Arc<ApiAuth>>,
+) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::path!("api" / "metrics" / "download")
+        .and(warp::get())
+        .and(with_auth(auth))
+        .and(warp::header::optional::<String>("x-export-token"))
+        .then(move |token: Option<String>| {
+            let conn = Arc::clone(&conn);
+            let rejection = export_token_rejection(export_token.as_deref(), token);
+            async move {
+                match rejection {
+                    Some(response) => response,
+                    None => download_reply(conn).await,
+                }
+            }
+        })
+}
+
+// Runs one fetch-and-save cycle on demand, at /api/fetch or the older /api/metrics/refresh
+fn create_refresh_route(
+    collector: Arc<Collector>,
+    auth: Option<Arc<ApiAuth>>,
+) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::path!("api" / "fetch")
+        .or(warp::path!("api" / "metrics" / "refresh"))
+        .unify()
+        .and(warp::post())
+        .and(with_auth(auth))
+        .then(move || {
+            let collector = Arc::clone(&collector);
+            async move {
+                match collector.collect().await {
+                    Ok(stored) => warp::reply::json(&stored).into_response(),
+                    Err(e) => {
+                        error!("Manual refresh failed: {}", e);
+                        let status = match e {
+                            CollectError::Upstream(_) => StatusCode::BAD_GATEWAY,
+                            CollectError::Database(_) => StatusCode::INTERNAL_SERVER_ERROR,
+                        };
+                        error_reply(status, &e.to_string())
+                    }
+                }
+            }
+        })
+}
+
+fn create_health_route(
+    fetch_status: Arc<Mutex<FetchStatus>>,
+    counters: Arc<FetchCounters>,
+    latest: LatestMetrics,
+    chain_tip: Option<Arc<ChainTip>>,
+    auth: Option<Arc<ApiAuth>>,
+) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::path!("api" / "health")
+        .and(warp::get())
+        .and(with_auth(auth))
+        .then(move || {
+            let fetch_status = Arc::clone(&fetch_status);
+            let counters = Arc::clone(&counters);
+            let latest = Arc::clone(&latest);
+            let chain_tip = chain_tip.clone();
+
+            async move {
+                let chain_tip = match chain_tip {
+                    Some(chain_tip) => chain_tip.height().await,
+                    None => None,
+                };
+                let stored_height = lock_or_recover(&latest)
+                    .values()
+                    .filter_map(|metrics| metrics.block_height)
+                    .max();
+                let blocks_behind = match (chain_tip, stored_height) {
+                    (Some(tip), Some(stored)) => Some(tip.saturating_sub(stored)),
+                    _ => None,
+                };
+
+                let fetch_status = lock_or_recover(&fetch_status);
+                let failing = fetch_status.block_height.last_error.is_some()
+                    || fetch_status.btc_price.last_error.is_some()
+                    || blocks_behind.is_some_and(|behind| behind > MAX_BLOCKS_BEHIND);
+
+                // Health has to reflect the current state on every probe
+                warp::reply::with_header(
+                    warp::reply::json(&Health {
+                        status: if failing { "degraded" } else { "ok" },
+                        sources: &fetch_status,
+                        counters: counters.snapshot(),
+                        chain_tip,
+                        blocks_behind,
+                    }),
+                    CACHE_CONTROL,
+                    "no-store",
+                )
+            }
+        })
+}
+
+fn create_uptime_route(started: ProcessStart, auth: Option<Arc<ApiAuth>>) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::path!("api" / "uptime").and(warp::get()).and(with_auth(auth)).map(move || {
+        warp::reply::with_header(
+            warp::reply::json(&Uptime {
+                uptime_secs: started.instant.elapsed().as_secs(),
+                started_at: &started.timestamp,
+            }),
+            CACHE_CONTROL,
+            "no-store",
+        )
+    })
+}
+
+fn create_prometheus_route(
+    counters: Arc<FetchCounters>,
+    auth: Option<Arc<ApiAuth>>,
+) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::path!("metrics").and(warp::get()).and(with_auth(auth)).map(move || {
+        warp::reply::with_header(
+            counters.to_prometheus(),
+            CONTENT_TYPE,
+            "text/plain; version=0.0.4",
+        )
+    })
+}
+
+// Hand-written OpenAPI 3 description of the read endpoints, kept next to the routes it documents
+fn openapi_document() -> serde_json::Value {
+    let asset_param = serde_json::json!({
+        "name": "asset",
+        "in": "query",
+        "required": false,
+        "description": "CoinGecko asset id, defaults to bitcoin",
+        "schema": { "type": "string", "default": DEFAULT_ASSET }
+    });
+    let limit_param = serde_json::json!({
+        "name": "limit",
+        "in": "query",
+        "required": false,
+        "description": "Number of rows to return, capped at the server's MAX_QUERY_LIMIT. Defaults to DEFAULT_METRICS_LIMIT, 50 unless configured",
+        "schema": { "type": "integer", "minimum": 1 }
+    });
+    let tz_param = serde_json::json!({
+        "name": "tz",
+        "in": "query",
+        "required": false,
+        "description": "IANA time zone, e.g. America/New_York, to show timestamps in as RFC 3339 with offset. Stored times are UTC",
+        "schema": { "type": "string" }
+    });
+    let error_response = |description: &str| {
+        serde_json::json!({
+            "description": description,
+            "content": { "application/json": { "schema": { "$ref": "#/components/schemas/Error" } } }
+        })
+    };
+
+    serde_json::json!({
+        "openapi": "3.0.3",
+        "info": {
+            "title": "BitCoinExplore API",
+            "version": env!("CARGO_PKG_VERSION")
+        },
+        "paths": {
+            "/api/metrics": {
+                "get": {
+                    "summary": "Recent metrics samples, newest first",
+                    "parameters": [asset_param, limit_param, tz_param],
+                    "responses": {
+                        "200": {
+                            "description": "Metrics history",
+                            "headers": {
+                                "ETag": { "schema": { "type": "string" } },
+                                "x-truncated": {
+                                    "description": "Present when the requested limit was reduced",
+                                    "schema": { "type": "boolean" }
+                                },
+                                "x-max-limit": { "schema": { "type": "integer" } }
+                            },
+                            "content": {
+                                "application/json": {
+                                    "schema": { "type": "array", "items": { "$ref": "#/components/schemas/Metrics" } }
+                                },
+                                "text/csv": {
+                                    "schema": { "type": "string" },
+                                    "example": "id,block_height,btc_price,timestamp,asset,source,fetch_latency_ms,block_hash,fee_1_block,fee_6_blocks,fee_144_blocks,price_spread\n1,800000,60000.5,2024-01-01 00:00:00,bitcoin,median,412,00000000000000000002a7c4c1e48d76c5a37902165a270156b7a8d72728a054,25.2,12.1,3,41.5\n"
+                                }
+                            }
+                        },
+                        "304": { "description": "Not modified since the ETag in If-None-Match" },
+                        "400": error_response("Unknown time zone"),
+                        "503": error_response("Database busy, retry after the Retry-After delay")
+                    }
+                }
+            },
+            "/api/metrics/latest": {
+                "get": {
+                    "summary": "Most recently collected sample",
+                    "parameters": [asset_param],
+                    "responses": {
+                        "200": {
+                            "description": "Latest metrics, flagged stale once no sample has been saved for three poll intervals",
+                            "content": {
+                                "application/json": {
+                                    "schema": {
+                                        "allOf": [
+                                            { "$ref": "#/components/schemas/Metrics" },
+                                            {
+                                                "type": "object",
+                                                "required": ["is_stale"],
+                                                "properties": {
+                                                    "is_stale": { "type": "boolean" },
+                                                    "stale_secs": { "type": "integer", "nullable": true }
+                                                }
+                                            }
+                                        ]
+                                    }
+                                }
+                            }
+                        },
+                        "404": error_response("No metrics collected yet")
+                    }
+                }
+            },
+            "/api/metrics/last-block": {
+                "get": {
+                    "summary": "Current block height alone, from the newest sample that has one",
+                    "responses": {
+                        "200": {
+                            "description": "Block height and when it was sampled",
+                            "content": {
+                                "application/json": {
+                                    "schema": {
+                                        "type": "object",
+                                        "required": ["block_height", "timestamp"],
+                                        "properties": {
+                                            "block_height": { "type": "integer" },
+                                            "timestamp": { "type": "string" }
+                                        }
+                                    }
+                                }
+                            }
+                        },
+                        "503": error_response("No block height collected yet")
+                    }
+                }
+            },
+            "/api/metrics/since/{id}": {
+                "get": {
+                    "summary": "Rows newer than a known id, oldest first",
+                    "parameters": [
+                        {
+                            "name": "id",
+                            "in": "path",
+                            "required": true,
+                            "description": "Last row id the client has; 0 starts from the beginning",
+                            "schema": { "type": "integer" }
+                        },
+                        asset_param
+                    ],
+                    "responses": {
+                        "200": {
+                            "description": "Newer rows, at most MAX_QUERY_LIMIT of them",
+                            "headers": {
+                                "x-has-more": {
+                                    "description": "Present when more rows remain past the last one returned",
+                                    "schema": { "type": "boolean" }
+                                }
+                            },
+                            "content": {
+                                "application/json": {
+                                    "schema": { "type": "array", "items": { "$ref": "#/components/schemas/Metrics" } }
+                                }
+                            }
+                        }
+                    }
+                }
+            },
+            "/api/metrics/count": {
+                "get": {
+                    "summary": "Number of stored samples, optionally within a time window",
+                    "parameters": [
+                        asset_param,
+                        {
+                            "name": "from",
+                            "in": "query",
+                            "required": false,
+                            "description": "Window start, unbounded when omitted",
+                            "schema": { "type": "string" }
+                        },
+                        {
+                            "name": "to",
+                            "in": "query",
+                            "required": false,
+                            "description": "Window end, unbounded when omitted",
+                            "schema": { "type": "string" }
+                        }
+                    ],
+                    "responses": {
+                        "200": {
+                            "description": "Sample count",
+                            "content": {
+                                "application/json": {
+                                    "schema": {
+                                        "type": "object",
+                                        "properties": { "count": { "type": "integer" } }
+                                    }
+                                }
+                            }
+                        },
+                        "400": error_response("Invalid timestamp")
+                    }
+                }
+            },
+            "/api/currencies": {
+                "get": {
+                    "summary": "Quote currencies present in stored data, with the newest value of each",
+                    "parameters": [asset_param],
+                    "responses": {
+                        "200": {
+                            "description": "Currencies in alphabetical order",
+                            "content": {
+                                "application/json": {
+                                    "schema": { "type": "array", "items": { "$ref": "#/components/schemas/CurrencyQuote" } }
+                                }
+                            }
+                        }
+                    }
+                }
+            },
+            "/api/metrics/view": {
+                "get": {
+                    "summary": "Recent samples rendered as a plain HTML table",
+                    "parameters": [asset_param, limit_param],
+                    "responses": {
+                        "200": { "description": "HTML table, newest first", "content": { "text/html": {} } }
+                    }
+                }
+            },
+            "/api/metrics/stream": {
+                "get": {
+                    "summary": "WebSocket pushing each new sample as a JSON Metrics text frame, skipping unchanged ones",
+                    "parameters": [
+                        asset_param,
+                        {
+                            "name": "backlog",
+                            "in": "query",
+                            "required": false,
+                            "description": "Number of stored samples sent oldest first before the live ones, capped at the maximum",
+                            "schema": { "type": "integer", "minimum": 0, "maximum": MAX_STREAM_BACKLOG }
+                        }
+                    ],
+                    "responses": {
+                        "101": { "description": "Switching to the WebSocket protocol" }
+                    }
+                }
+            },
+            "/api/metrics/average-block-time": {
+                "get": {
+                    "summary": "Average and median time between observed blocks",
+                    "responses": {
+                        "200": {
+                            "description": "Block time statistics",
+                            "content": { "application/json": { "schema": { "$ref": "#/components/schemas/BlockTimeStats" } } }
+                        },
+                        "503": error_response("Database busy, retry after the Retry-After delay")
+                    }
+                }
+            },
+            "/api/halving": {
+                "get": {
+                    "summary": "Next subsidy halving estimated from the current block height",
+                    "responses": {
+                        "200": {
+                            "description": "Current reward era and the blocks and time left until the next halving",
+                            "content": { "application/json": { "schema": { "$ref": "#/components/schemas/HalvingEstimate" } } }
+                        },
+                        "500": error_response("Block heights could not be loaded"),
+                        "503": error_response("No block height collected yet")
+                    }
+                }
+            },
+            "/api/metrics/buckets": {
+                "get": {
+                    "summary": "OHLC price buckets over a time range",
+                    "parameters": [
+                        asset_param,
+                        {
+                            "name": "interval",
+                            "in": "query",
+                            "required": false,
+                            "description": "Bucket width such as 15m, 1h or 1d, at least 1m",
+                            "schema": { "type": "string", "default": "1h" }
+                        },
+                        {
+                            "name": "from",
+                            "in": "query",
+                            "required": false,
+                            "description": "Range start, defaults to 24 hours ago",
+                            "schema": { "type": "string" }
+                        },
+                        {
+                            "name": "to",
+                            "in": "query",
+                            "required": false,
+                            "description": "Range end, defaults to now",
+                            "schema": { "type": "string" }
+                        }
+                    ],
+                    "responses": {
+                        "200": {
+                            "description": "Price buckets, oldest first",
+                            "content": {
+                                "application/json": {
+                                    "schema": { "type": "array", "items": { "$ref": "#/components/schemas/PriceBucket" } }
+                                }
+                            }
+                        },
+                        "400": error_response("Invalid interval or timestamp")
+                    }
+                }
+            },
+            "/api/metrics/hourly": {
+                "get": {
+                    "summary": "Hourly OHLC rollups of samples older than ROLLUP_AGE, for history past the raw rows",
+                    "parameters": [
+                        asset_param,
+                        {
+                            "name": "from",
+                            "in": "query",
+                            "required": false,
+                            "description": "Range start, defaults to 30 days ago",
+                            "schema": { "type": "string" }
+                        },
+                        {
+                            "name": "to",
+                            "in": "query",
+                            "required": false,
+                            "description": "Range end, defaults to now",
+                            "schema": { "type": "string" }
+                        }
+                    ],
+                    "responses": {
+                        "200": {
+                            "description": "Hourly rollups, oldest first, with start as the hour",
+                            "content": {
+                                "application/json": {
+                                    "schema": { "type": "array", "items": { "$ref": "#/components/schemas/PriceBucket" } }
+                                }
+                            }
+                        },
+                        "400": error_response("Invalid timestamp")
+                    }
+                }
+            },
+            "/api/metrics/gaps": {
+                "get": {
+                    "summary": "Stretches without samples, usually from the collector being down",
+                    "parameters": [
+                        asset_param,
+                        {
+                            "name": "threshold",
+                            "in": "query",
+                            "required": false,
+                            "description": "Shortest interval reported as a gap, such as 90s, 5m or 1h. Defaults to twice the detected sample interval",
+                            "schema": { "type": "string" }
+                        },
+                        {
+                            "name": "limit",
+                            "in": "query",
+                            "required": false,
+                            "description": "Number of newest samples to scan",
+                            "schema": { "type": "integer", "minimum": 1, "default": DEFAULT_GAP_SCAN_LIMIT }
+                        }
+                    ],
+                    "responses": {
+                        "200": {
+                            "description": "Gaps, oldest first. The median sample interval is sent as x-expected-interval-secs",
+                            "content": {
+                                "application/json": {
+                                    "schema": { "type": "array", "items": { "$ref": "#/components/schemas/Gap" } }
+                                }
+                            }
+                        },
+                        "400": error_response("Invalid threshold")
+                    }
+                }
+            },
+            "/api/metrics/high-low": {
+                "get": {
+                    "summary": "Highest and lowest price within a rolling window",
+                    "parameters": [
+                        asset_param,
+                        {
+                            "name": "window",
+                            "in": "query",
+                            "required": false,
+                            "description": "How far back to look, such as 1h, 24h or 7d",
+                            "schema": { "type": "string", "default": "24h" }
+                        }
+                    ],
+                    "responses": {
+                        "200": {
+                            "description": "High and low with their timestamps, null when the window is empty",
+                            "content": { "application/json": { "schema": { "$ref": "#/components/schemas/HighLow" } } }
+                        },
+                        "400": error_response("Invalid window")
+                    }
+                }
+            },
+            "/api/metrics/ath": {
+                "get": {
+                    "summary": "Highest stored price and how far the current price is below it",
+                    "parameters": [asset_param],
+                    "responses": {
+                        "200": {
+                            "description": "The all-time high sample within the stored history and the gap to the newest price",
+                            "content": {
+                                "application/json": {
+                                    "schema": {
+                                        "type": "object",
+                                        "required": ["ath", "current_price", "below_ath", "below_ath_pct"],
+                                        "properties": {
+                                            "ath": { "$ref": "#/components/schemas/Metrics" },
+                                            "current_price": { "type": "number" },
+                                            "below_ath": { "type": "number" },
+                                            "below_ath_pct": { "type": "number" }
+                                        }
+                                    }
+                                }
+                            }
+                        },
+                        "404": error_response("No prices stored for this asset")
+                    }
+                }
+            },
+            "/api/metrics/compare": {
+                "get": {
+                    "summary": "Price and block height change between the samples nearest two times",
+                    "parameters": [
+                        asset_param,
+                        {
+                            "name": "a",
+                            "in": "query",
+                            "required": true,
+                            "description": "Earlier time to compare from",
+                            "schema": { "type": "string" }
+                        },
+                        {
+                            "name": "b",
+                            "in": "query",
+                            "required": true,
+                            "description": "Later time to compare to",
+                            "schema": { "type": "string" }
+                        },
+                        {
+                            "name": "tolerance",
+                            "in": "query",
+                            "required": false,
+                            "description": "How far from each time the nearest sample may be, such as 15m or 1h",
+                            "schema": { "type": "string", "default": "1h" }
+                        }
+                    ],
+                    "responses": {
+                        "200": {
+                            "description": "Both samples with the deltas from a to b, null when either lacks the field",
+                            "content": {
+                                "application/json": {
+                                    "schema": {
+                                        "type": "object",
+                                        "required": ["a", "b"],
+                                        "properties": {
+                                            "a": { "$ref": "#/components/schemas/Metrics" },
+                                            "b": { "$ref": "#/components/schemas/Metrics" },
+                                            "price_delta": { "type": "number", "nullable": true },
+                                            "price_delta_pct": { "type": "number", "nullable": true },
+                                            "block_delta": { "type": "integer", "nullable": true }
+                                        }
+                                    }
+                                }
+                            }
+                        },
+                        "400": error_response("Missing or invalid timestamp or tolerance"),
+                        "404": error_response("No samples near one or both of the requested times")
+                    }
+                }
+            },
+            "/api/metrics/downsample": {
+                "get": {
+                    "summary": "Price history reduced to a target number of points with LTTB",
+                    "parameters": [
+                        asset_param,
+                        {
+                            "name": "points",
+                            "in": "query",
+                            "required": false,
+                            "description": "Number of points to keep, between 3 and 5000",
+                            "schema": { "type": "integer", "default": DEFAULT_DOWNSAMPLE_POINTS }
+                        },
+                        {
+                            "name": "from",
+                            "in": "query",
+                            "required": false,
+                            "description": "Range start, defaults to 30 days ago",
+                            "schema": { "type": "string" }
+                        },
+                        {
+                            "name": "to",
+                            "in": "query",
+                            "required": false,
+                            "description": "Range end, defaults to now",
+                            "schema": { "type": "string" }
+                        }
+                    ],
+                    "responses": {
+                        "200": {
+                            "description": "Representative samples, oldest first",
+                            "content": {
+                                "application/json": {
+                                    "schema": { "type": "array", "items": { "$ref": "#/components/schemas/SeriesPoint" } }
+                                }
+                            }
+                        },
+                        "400": error_response("Invalid point count or timestamp")
+                    }
+                }
+            },
+            "/api/health": {
+                "get": {
+                    "summary": "Collector status and counters",
+                    "responses": {
+                        "200": {
+                            "description": "Health report",
+                            "content": { "application/json": { "schema": { "$ref": "#/components/schemas/Health" } } }
+                        }
+                    }
+                }
+            },
+            "/api/uptime": {
+                "get": {
+                    "summary": "Time since the process started",
+                    "responses": {
+                        "200": {
+                            "description": "Uptime in seconds and the start time",
+                            "content": {
+                                "application/json": {
+                                    "schema": {
+                                        "type": "object",
+                                        "required": ["uptime_secs", "started_at"],
+                                        "properties": {
+                                            "uptime_secs": { "type": "integer" },
+                                            "started_at": { "type": "string" }
+                                        }
+                                    }
+                                }
+                            }
+                        }
+                    }
+                }
+            }
+        },
+        "components": {
+            "schemas": {
+                "Metrics": {
+                    "type": "object",
+                    "required": ["timestamp", "asset", "source"],
+                    "properties": {
+                        "id": {
+                            "type": "integer",
+                            "format": "int64",
+                            "description": "Missing from samples pushed before a buffered write stores them"
+                        },
+                        "block_height": {
+                            "type": "integer",
+                            "format": "int64",
+                            "nullable": true,
+                            "description": "Null for backfilled rows and when the block height fetch failed"
+                        },
+                        "btc_price": {
+                            "type": "number",
+                            "format": "double",
+                            "nullable": true,
+                            "description": "Null when the price fetch failed"
+                        },
+                        "prices": {
+                            "type": "object",
+                            "additionalProperties": { "type": "number" },
+                            "description": "Price per quote currency from VS_CURRENCIES, e.g. {\"eur\": 58000.1, \"usd\": 63241.57}"
+                        },
+                        "timestamp": { "type": "string", "example": "2024-01-01 00:00:00" },
+                        "asset": { "type": "string" },
+                        "source": { "type": "string" },
+                        "fetch_latency_ms": {
+                            "type": "integer",
+                            "format": "int64",
+                            "nullable": true,
+                            "description": "Milliseconds spent on the tick's upstream fetches; null for backfilled rows"
+                        },
+                        "block_hash": {
+                            "type": "string",
+                            "nullable": true,
+                            "description": "Hash of the block at block_height; null when the hash fetch failed and for backfilled rows"
+                        },
+                        "fee_1_block": {
+                            "type": "number",
+                            "format": "double",
+                            "nullable": true,
+                            "description": "Recommended fee rate in sat/vB to confirm in the next block; null unless FETCH_FEE_ESTIMATES is on and the estimate was available"
+                        },
+                        "fee_6_blocks": {
+                            "type": "number",
+                            "format": "double",
+                            "nullable": true,
+                            "description": "Recommended fee rate in sat/vB to confirm within 6 blocks"
+                        },
+                        "fee_144_blocks": {
+                            "type": "number",
+                            "format": "double",
+                            "nullable": true,
+                            "description": "Recommended fee rate in sat/vB to confirm within 144 blocks, about a day"
+                        },
+                        "price_spread": {
+                            "type": "number",
+                            "format": "double",
+                            "nullable": true,
+                            "description": "Highest minus lowest source's USD price behind a median price; null unless PRICE_AGGREGATION=median"
+                        }
+                    }
+                },
+                "BlockTimeStats": {
+                    "type": "object",
+                    "required": ["blocks"],
+                    "properties": {
+                        "average_secs": { "type": "number", "nullable": true },
+                        "median_secs": { "type": "number", "nullable": true },
+                        "blocks": { "type": "integer" }
+                    }
+                },
+                "HalvingEstimate": {
+                    "type": "object",
+                    "required": [
+                        "block_height", "reward_era", "block_reward_btc", "next_halving_block",
+                        "blocks_remaining", "average_block_secs", "estimated_at"
+                    ],
+                    "properties": {
+                        "block_height": { "type": "integer" },
+                        "reward_era": { "type": "integer", "description": "1 for the original 50 BTC subsidy" },
+                        "block_reward_btc": { "type": "number" },
+                        "next_halving_block": { "type": "integer" },
+                        "blocks_remaining": { "type": "integer" },
+                        "average_block_secs": {
+                            "type": "number",
+                            "description": "Measured from stored heights, or the 600 second target without two of them"
+                        },
+                        "estimated_at": { "type": "string" }
+                    }
+                },
+                "PriceBucket": {
+                    "type": "object",
+                    "required": ["start", "open", "high", "low", "close", "avg", "samples"],
+                    "properties": {
+                        "start": { "type": "string" },
+                        "open": { "type": "number" },
+                        "high": { "type": "number" },
+                        "low": { "type": "number" },
+                        "close": { "type": "number" },
+                        "avg": { "type": "number" },
+                        "max_block_height": { "type": "integer", "nullable": true },
+                        "samples": { "type": "integer" }
+                    }
+                },
+                "Gap": {
+                    "type": "object",
+                    "required": ["start", "end", "duration_secs"],
+                    "properties": {
+                        "start": { "type": "string" },
+                        "end": { "type": "string" },
+                        "duration_secs": { "type": "integer" }
+                    }
+                },
+                "CurrencyQuote": {
+                    "type": "object",
+                    "required": ["currency", "value", "timestamp"],
+                    "properties": {
+                        "currency": { "type": "string", "example": "usd" },
+                        "value": { "type": "number", "format": "double" },
+                        "timestamp": { "type": "string" }
+                    }
+                },
+                "HighLow": {
+                    "type": "object",
+                    "required": ["window_secs"],
+                    "properties": {
+                        "window_secs": { "type": "integer" },
+                        "high": { "type": "number", "format": "double", "nullable": true },
+                        "high_at": { "type": "string", "nullable": true },
+                        "low": { "type": "number", "format": "double", "nullable": true },
+                        "low_at": { "type": "string", "nullable": true }
+                    }
+                },
+                "SeriesPoint": {
+                    "type": "object",
+                    "required": ["id", "timestamp", "btc_price"],
+                    "properties": {
+                        "id": { "type": "integer" },
+                        "timestamp": { "type": "string" },
+                        "btc_price": { "type": "number" },
+                        "block_height": { "type": "integer", "nullable": true }
+                    }
+                },
+                "SourceStatus": {
+                    "type": "object",
+                    "properties": {
+                        "last_success": { "type": "string", "nullable": true },
+                        "last_error": { "type": "string", "nullable": true },
+                        "last_error_at": { "type": "string", "nullable": true },
+                        "breaker": {
+                            "type": "object",
+                            "properties": {
+                                "state": { "type": "string", "enum": ["closed", "open", "half_open"] },
+                                "consecutive_failures": { "type": "integer" },
+                                "failed_probes": { "type": "integer" },
+                                "retry_at": { "type": "string", "nullable": true }
+                            }
+                        }
+                    }
+                },
+                "Health": {
+                    "type": "object",
+                    "required": ["status", "sources", "counters"],
+                    "properties": {
+                        "status": { "type": "string", "enum": ["ok", "degraded"] },
+                        "sources": {
+                            "type": "object",
+                            "properties": {
+                                "block_height": { "$ref": "#/components/schemas/SourceStatus" },
+                                "btc_price": { "$ref": "#/components/schemas/SourceStatus" }
+                            }
+                        },
+                        "counters": {
+                            "type": "object",
+                            "properties": {
+                                "fetch_success": { "type": "integer" },
+                                "fetch_failure": { "type": "integer" },
+                                "price_fallback_used": { "type": "integer" },
+                                "db_write_errors": { "type": "integer" },
+                                "poller_restarts": { "type": "integer" }
+                            }
+                        },
+                        "chain_tip": {
+                            "type": "integer",
+                            "description": "Live tip height, only present when HEALTH_CHECK_TIP is enabled"
+                        },
+                        "blocks_behind": {
+                            "type": "integer",
+                            "description": "Live tip minus the newest stored height"
+                        }
+                    }
+                },
+                "Error": {
+                    "type": "object",
+                    "required": ["error"],
+                    "properties": { "error": { "type": "string" } }
+                }
+            }
+        }
+    })
+}
+
+// Sets Cache-Control on every reply of the route it wraps, so only use it on routes that
+// can't fail; the others set it per response, like set_poll_cache_control
+fn with_cache_control(scope: CacheScope, max_age: Duration) -> warp::reply::with::WithHeader {
+    warp::reply::with::header(CACHE_CONTROL, scope.header_value(max_age))
+}
+
+fn create_version_route(auth: Option<Arc<ApiAuth>>) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    let version = Arc::new(VersionInfo::current());
+    let cache_scope = CacheScope::for_auth(&auth);
+
+    warp::path!("api" / "version")
+        .and(warp::get())
+        .and(with_auth(auth))
+        .map(move || warp::reply::json(&*version))
+        .with(with_cache_control(cache_scope, STATIC_DOC_MAX_AGE))
+}
+
+fn create_openapi_route(auth: Option<Arc<ApiAuth>>) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    let document = Arc::new(openapi_document());
+    let cache_scope = CacheScope::for_auth(&auth);
+
+    warp::path!("api" / "openapi.json")
+        .and(warp::get())
+        .and(with_auth(auth))
+        .map(move || warp::reply::json(&*document))
+        .with(with_cache_control(cache_scope, STATIC_DOC_MAX_AGE))
+}
+
+#[derive(Clone, Copy)]
+enum ContentEncoding {
+    Gzip,
+    Deflate,
+}
+
+impl ContentEncoding {
+    fn as_str(self) -> &'static str {
+        match self {
+            ContentEncoding::Gzip => "gzip",
+            ContentEncoding::Deflate => "deflate",
+        }
+    }
+}
+
+// Picks gzip over deflate when both are accepted, skipping codings disabled with q=0
+fn preferred_encoding(accept_encoding: &str) -> Option<ContentEncoding> {
+    let accepted: Vec<&str> = accept_encoding
+        .split(',')
+        .filter_map(|item| {
+            let mut parts = item.split(';');
+            let coding = parts.next()?.trim();
+            let disabled = parts.any(|param| {
+                param
+                    .trim()
+                    .strip_prefix("q=")
+                    .and_then(|q| q.trim().parse::<f32>().ok())
+                    .is_some_and(|q| q == 0.0)
+            });
+            if disabled {
+                None
+            } else {
+                Some(coding)
+            }
+        })
+        .collect();
+
+    if accepted.iter().any(|c| c.eq_ignore_ascii_case("gzip")) {
+        Some(ContentEncoding::Gzip)
+    } else if accepted.iter().any(|c| c.eq_ignore_ascii_case("deflate")) {
+        Some(ContentEncoding::Deflate)
+    } else {
+        None
+    }
+}
+
+fn is_compressible(response: &Response) -> bool {
+    response
+        .headers()
+        .get(CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|ct| ct.starts_with("application/json") || ct.starts_with("text/csv"))
+}
+
+fn encode_body(encoding: ContentEncoding, bytes: &[u8]) -> std::io::Result<Vec<u8>> {
+    match encoding {
+        ContentEncoding::Gzip => {
+            let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+            encoder.write_all(bytes)?;
+            encoder.finish()
+        }
+        ContentEncoding::Deflate => {
+            let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+            encoder.write_all(bytes)?;
+            encoder.finish()
+        }
+    }
+}
+
+// Encoder for a streamed body. Flushed after every chunk so each one reaches the client as
+// it's produced rather than once the encoder's buffer fills.
+enum StreamEncoder {
+    Gzip(GzEncoder<Vec<u8>>),
+    Deflate(ZlibEncoder<Vec<u8>>),
+}
+
+impl StreamEncoder {
+    fn new(encoding: ContentEncoding) -> StreamEncoder {
+        match encoding {
+            ContentEncoding::Gzip => StreamEncoder::Gzip(GzEncoder::new(Vec::new(), Compression::default())),
+            ContentEncoding::Deflate => StreamEncoder::Deflate(ZlibEncoder::new(Vec::new(), Compression::default())),
+        }
+    }
+
+    // Compressed output for `bytes` that's ready so far
+    fn write(&mut self, bytes: &[u8]) -> std::io::Result<Bytes> {
+        let output = match self {
+            StreamEncoder::Gzip(encoder) => {
+                encoder.write_all(bytes)?;
+                encoder.flush()?;
+                encoder.get_mut()
+            }
+            StreamEncoder::Deflate(encoder) => {
+                encoder.write_all(bytes)?;
+                encoder.flush()?;
+                encoder.get_mut()
+            }
+        };
+        Ok(Bytes::from(std::mem::take(output)))
+    }
+
+    fn finish(self) -> std::io::Result<Bytes> {
+        match self {
+            StreamEncoder::Gzip(encoder) => encoder.finish(),
+            StreamEncoder::Deflate(encoder) => encoder.finish(),
+        }
+        .map(Bytes::from)
+    }
+}
+
+fn compress_stream(body: Body, encoding: ContentEncoding) -> Body {
+    let chunks = futures_util::stream::unfold(Some((body, StreamEncoder::new(encoding))), |state| async move {
+        let (mut body, mut encoder) = state?;
+        let item: Result<Bytes, Box<dyn std::error::Error + Send + Sync>> = match body.next().await {
+            Some(Ok(bytes)) => match encoder.write(&bytes) {
+                Ok(compressed) => return Some((Ok(compressed), Some((body, encoder)))),
+                Err(e) => Err(e.into()),
+            },
+            Some(Err(e)) => Err(e.into()),
+            None => encoder.finish().map_err(Into::into),
+        };
+        Some((item, None))
+    });
+    Body::wrap_stream(chunks)
+}
+
+async fn compress_response(response: Response, accept_encoding: Option<String>) -> Response {
+    let encoding = match accept_encoding.as_deref().and_then(preferred_encoding) {
+        Some(encoding) => encoding,
+        None => return response,
+    };
+    if !is_compressible(&response) || response.headers().contains_key(CONTENT_ENCODING) {
+        return response;
+    }
+    // Streaming bodies have no known length; buffering them here would defeat the point, so
+    // they're compressed chunk by chunk instead
+    if response.body().size_hint().exact().is_none() {
+        let (mut parts, body) = response.into_parts();
+        parts.headers.remove(CONTENT_LENGTH);
+        parts
+            .headers
+            .insert(CONTENT_ENCODING, HeaderValue::from_static(encoding.as_str()));
+        parts.headers.append(VARY, HeaderValue::from_static("accept-encoding"));
+        return Response::from_parts(parts, compress_stream(body, encoding));
+    }
+
+    let (mut parts, body) = response.into_parts();
+    let bytes = match warp::hyper::body::to_bytes(body).await {
+        Ok(bytes) => bytes,
+        Err(e) => {
+            error!("Error reading response body for compression: {}", e);
+            parts.status = warp::http::StatusCode::INTERNAL_SERVER_ERROR;
+            return Response::from_parts(parts, Body::empty());
+        }
+    };
+
+    if bytes.len() < COMPRESSION_MIN_BYTES {
+        return Response::from_parts(parts, Body::from(bytes));
+    }
+
+    match encode_body(encoding, &bytes) {
+        Ok(compressed) => {
+            parts.headers.remove(CONTENT_LENGTH);
+            parts
+                .headers
+                .insert(CONTENT_ENCODING, HeaderValue::from_static(encoding.as_str()));
+            parts.headers.append(VARY, HeaderValue::from_static("accept-encoding"));
+            Response::from_parts(parts, Body::from(compressed))
+        }
+        Err(e) => {
+            error!("Error compressing response: {}", e);
+            Response::from_parts(parts, Body::from(bytes))
+        }
+    }
+}
+
+// Wraps the routes so JSON/CSV replies are gzip/deflate encoded for clients that ask for it
+fn with_compression<F, R>(
+    routes: F,
+) -> impl Filter<Extract = (Response,), Error = warp::Rejection> + Clone
+where
+    F: Filter<Extract = (R,), Error = warp::Rejection> + Clone + Send + Sync + 'static,
+    R: Reply + Send,
+{
+    warp::header::optional::<String>("accept-encoding")
+        .and(routes)
+        .then(|accept_encoding: Option<String>, reply: R| async move {
+            compress_response(reply.into_response(), accept_encoding).await
+        })
+}
+
+// Key style for JSON bodies. The structs serialize as snake_case; JSON_FIELD_CASE=camel
+// rewrites keys on the way out for clients that expect blockHeight and btcPrice.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FieldCase {
+    Snake,
+    Camel,
+}
+
+impl FieldCase {
+    pub fn from_env() -> FieldCase {
+        match std::env::var("JSON_FIELD_CASE").as_deref().map(str::trim) {
+            Ok("camel") | Ok("camelCase") => FieldCase::Camel,
+            Ok("snake") | Ok("snake_case") | Ok("") | Err(_) => FieldCase::Snake,
+            Ok(other) => {
+                warn!("Ignoring unknown JSON_FIELD_CASE {:?}, using snake_case", other);
+                FieldCase::Snake
+            }
+        }
+    }
+
+    fn apply(self, value: serde_json::Value) -> serde_json::Value {
+        match self {
+            FieldCase::Snake => value,
+            FieldCase::Camel => camel_case_keys(value),
+        }
+    }
+}
+
+fn to_camel_case(key: &str) -> String {
+    let mut camel = String::with_capacity(key.len());
+    let mut upper_next = false;
+    for c in key.chars() {
+        if c == '_' && !camel.is_empty() {
+            upper_next = true;
+        } else if upper_next {
+            camel.extend(c.to_uppercase());
+            upper_next = false;
+        } else {
+            camel.push(c);
+        }
+    }
+    camel
+}
+
+fn camel_case_keys(value: serde_json::Value) -> serde_json::Value {
+    match value {
+        serde_json::Value::Object(map) => map
+            .into_iter()
+            .map(|(key, value)| (to_camel_case(&key), camel_case_keys(value)))
+            .collect(),
+        serde_json::Value::Array(items) => items.into_iter().map(camel_case_keys).collect(),
+        other => other,
+    }
+}
+
+// Rewrites JSON bodies into the configured key style. Runs inside the compression layer
+// so it sees plain bodies; streamed bodies are left as they are, so routes that stream JSON
+// rename their own rows (see metrics_json_stream). The exports keep the stored key names.
+fn with_field_case<F, R>(
+    field_case: FieldCase,
+    routes: F,
+) -> impl Filter<Extract = (Response,), Error = warp::Rejection> + Clone
+where
+    F: Filter<Extract = (R,), Error = warp::Rejection> + Clone + Send + Sync + 'static,
+    R: Reply + Send,
+{
+    routes.then(move |reply: R| async move { rename_response_fields(reply.into_response(), field_case).await })
+}
+
+// Caps the requests handled at once so a burst gets quick 503s instead of queueing on the
+// SQLite connection mutex and tying up runtime threads. Passes through when no limit is set.
+// The permit is released once the reply is built, so a streamed body stops counting when
+// its headers go out. Boxed, as wrapping the whole route tree unboxed blows up codegen.
+fn with_concurrency_limit<F, R>(limit: Option<Arc<tokio::sync::Semaphore>>, routes: F) -> BoxedFilter<(Response,)>
+where
+    F: Filter<Extract = (R,), Error = warp::Rejection> + Clone + Send + Sync + 'static,
+    R: Reply + Send,
+{
+    warp::any()
+        .and_then(move || {
+            let limit = limit.clone();
+            async move {
+                match limit {
+                    Some(semaphore) => semaphore
+                        .try_acquire_owned()
+                        .map(Some)
+                        .map_err(|_| warp::reject::custom(Overloaded)),
+                    None => Ok(None),
+                }
+            }
+        })
+        .and(routes)
+        .map(|_permit: Option<tokio::sync::OwnedSemaphorePermit>, reply: R| reply.into_response())
+        .boxed()
+}
+
+// Longest incoming X-Request-Id reused as is; anything longer or unprintable gets a fresh id
+const MAX_REQUEST_ID_LEN: usize = 128;
+
+// Tags every request with an id, taken from X-Request-Id or generated as a UUID, which is
+// recorded on the request's tracing span and echoed back in the response. Takes routes with
+// rejections already recovered so our error replies carry the id too; rejections left for
+// warp to answer, like a bare 404, go out without it.
+fn with_request_id<F, R>(routes: F) -> impl Filter<Extract = (Response,), Error = warp::Rejection> + Clone
+where
+    F: Filter<Extract = (R,), Error = warp::Rejection> + Clone + Send + Sync + 'static,
+    R: Reply + Send,
+{
+    warp::header::optional::<String>("x-request-id")
+        .map(|incoming: Option<String>| {
+            let request_id = incoming.filter(|id| is_valid_request_id(id)).unwrap_or_else(new_request_id);
+            tracing::Span::current().record("request_id", request_id.as_str());
+            request_id
+        })
+        .and(routes)
+        .map(|request_id: String, reply: R| {
+            let mut response = reply.into_response();
+            if let Ok(value) = HeaderValue::from_str(&request_id) {
+                response.headers_mut().insert("x-request-id", value);
+            }
+            response
+        })
+        .with(warp::trace(|info| {
+            tracing::info_span!(
+                "request",
+                method = %info.method(),
+                path = %info.path(),
+                request_id = tracing::field::Empty
+            )
+        }))
+        .map(Reply::into_response)
+}
+
+fn is_valid_request_id(id: &str) -> bool {
+    !id.is_empty() && id.len() <= MAX_REQUEST_ID_LEN && id.bytes().all(|b| b.is_ascii_graphic())
+}
+
+// Random (version 4) UUID
+fn new_request_id() -> String {
+    let mut bytes: [u8; 16] = rand::random();
+    bytes[6] = (bytes[6] & 0x0f) | 0x40;
+    bytes[8] = (bytes[8] & 0x3f) | 0x80;
+    let hex: String = bytes.iter().map(|b| format!("{:02x}", b)).collect();
+    format!("{}-{}-{}-{}-{}", &hex[..8], &hex[8..12], &hex[12..16], &hex[16..20], &hex[20..])
+}
+
+async fn rename_response_fields(response: Response, field_case: FieldCase) -> Response {
+    let is_json = response
+        .headers()
+        .get(CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|ct| ct.starts_with("application/json"));
+    if field_case == FieldCase::Snake || !is_json || response.body().size_hint().exact().is_none() {
+        return response;
+    }
+
+    let (mut parts, body) = response.into_parts();
+    let bytes = match warp::hyper::body::to_bytes(body).await {
+        Ok(bytes) => bytes,
+        Err(e) => {
+            error!("Error reading response body for renaming: {}", e);
+            parts.status = StatusCode::INTERNAL_SERVER_ERROR;
+            return Response::from_parts(parts, Body::empty());
+        }
+    };
+    let renamed = serde_json::from_slice(&bytes)
+        .and_then(|value| serde_json::to_vec(&field_case.apply(value)));
+
+    match renamed {
+        Ok(renamed) => {
+            parts.headers.remove(CONTENT_LENGTH);
+            Response::from_parts(parts, Body::from(renamed))
+        }
+        Err(_) => Response::from_parts(parts, Body::from(bytes)),
+    }
+}
+
+// Pause after a panicked poll cycle, so a panic that recurs every tick doesn't spin
+const POLLER_RESTART_DELAY: Duration = Duration::from_secs(5);
+
+// Runs a poll cycle on its own task so a panic in fetch or save code unwinds only that
+// task, leaving the loop and the API server running. Returns the panic as an error.
+async fn run_contained<F>(cycle: F) -> Result<(), tokio::task::JoinError>
+where
+    F: std::future::Future<Output = ()> + Send + 'static,
+{
+    tokio::spawn(cycle).await
+}
+
+// Upper bound on each startup connectivity check, so a hanging connection can't stall startup
+const SELF_TEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+// One fetch from each upstream before polling starts, so broken networking shows up straight
+// away instead of on the first tick
+async fn upstream_self_test(
+    client: &reqwest::Client,
+    api_bases: &ApiBases,
+    price_source: PriceSource,
+    assets: &[String],
+) -> Result<(), String> {
+    let mut problems = Vec::new();
+
+    match time::timeout(SELF_TEST_TIMEOUT, fetch_block_height(client, &api_bases.chain)).await {
+        Ok(Ok(height)) => info!("Self-test: Blockstream reachable, tip height {}", height),
+        Ok(Err(e)) => problems.push(format!("Blockstream block height fetch failed: {}", e)),
+        Err(_) => problems.push(format!("Blockstream did not answer within {}s", SELF_TEST_TIMEOUT.as_secs())),
+    }
+    let currencies = [BASE_CURRENCY.to_string()];
+    let source = price_source.name();
+    match time::timeout(SELF_TEST_TIMEOUT, fetch_prices_from(client, price_source, api_bases, assets, &currencies)).await {
+        Ok(Ok(prices)) => info!("Self-test: {} reachable, {} price(s) returned", source, prices.len()),
+        Ok(Err(e)) => problems.push(format!("{} price fetch failed: {}", source, e)),
+        Err(_) => problems.push(format!("{} did not answer within {}s", source, SELF_TEST_TIMEOUT.as_secs())),
+    }
+
+    if problems.is_empty() {
+        Ok(())
+    } else {
+        Err(format!(
+            "{}. Check DNS, outbound HTTPS (port 443), proxy settings and firewall rules for this host",
+            problems.join("; ")
+        ))
+    }
+}
+
+// Hours are only rolled up once they end before now minus `age`, so each is complete
+fn rollup_cutoff(now: chrono::DateTime<chrono::Utc>, age: Duration) -> String {
+    let cutoff = now - chrono::Duration::from_std(age).unwrap_or_else(|_| chrono::Duration::zero());
+    cutoff.format("%Y-%m-%d %H:00:00").to_string()
+}
+
+// Every `interval`, starting at startup, rolls samples older than `age` into metrics_hourly
+// and with `prune_raw` deletes them, in one transaction so no hour is lost in between
+fn spawn_rollups(conn: Arc<Mutex<Connection>>, age: Duration, interval: Duration, prune_raw: bool) {
+    tokio::spawn(async move {
+        let mut ticks = time::interval(interval);
+        loop {
+            ticks.tick().await;
+            let cutoff = rollup_cutoff(chrono::Utc::now(), age);
+            let result = {
+                let conn = lock_or_recover(&conn);
+                conn.unchecked_transaction().and_then(|tx| {
+                    let rolled_up = rollup_hourly(&tx, &cutoff)?;
+                    let pruned = if prune_raw { prune_old_metrics(&tx, &cutoff)? } else { 0 };
+                    tx.commit()?;
+                    Ok((rolled_up, pruned))
+                })
+            };
+            match result {
+                Ok((0, 0)) => {}
+                Ok((rolled_up, pruned)) => {
+                    info!("Rolled up {} hours before {}, pruned {} raw samples", rolled_up, cutoff, pruned)
+                }
+                Err(e) => error!("Error rolling up hourly metrics: {}", e),
+            }
+        }
+    });
+}
+
+// Seeds an empty history with daily prices so charts aren't barren on a fresh DB. Assets
+// that already have rows are skipped: history is ordered by id, so older rows can only be
+// inserted before any live samples exist.
+async fn backfill_history(
+    conn: &Arc<Mutex<Connection>>,
+    client: &reqwest::Client,
+    price_api_base: &str,
+    assets: &[String],
+    days: u32,
+    price_decimals: Option<u32>,
+) {
+    // Daily points, so history starting within a day of the window counts as covering it
+    let covered_from = (chrono::Utc::now() - chrono::Duration::days(i64::from(days) - 1))
+        .format(TIMESTAMP_FORMAT)
+        .to_string();
+    for asset in assets {
+        let oldest = match oldest_asset_timestamp(&lock_or_recover(conn), asset) {
+            Ok(Some(oldest)) if oldest <= covered_from => {
+                info!("Skipping backfill for {}, history already covers the last {} days", asset, days);
+                continue;
+            }
+            Ok(oldest) => oldest,
+            Err(e) => {
+                error!("Error checking history before backfill: {}", e);
+                continue;
+            }
+        };
+
+        let points = match fetch_price_history(client, price_api_base, asset, days).await {
+            Ok(points) => points,
+            Err(e) => {
+                error!("Error fetching price history for {}: {}", asset, e);
+                continue;
+            }
+        };
+
+        // Historical points have a price and timestamp but no block height. Only the part of
+        // the window before the oldest stored row is filled, so nothing is stored twice.
+        let rows: Vec<Metrics> = points
+            .into_iter()
+            .filter(|(timestamp, _)| oldest.as_ref().map_or(true, |oldest| timestamp < oldest))
+            .map(|(timestamp, price)| {
+                let price = price_decimals.map_or(price, |decimals| round_price(price, decimals));
+                Metrics {
+                    id: UNSAVED_ID,
+                    block_height: None,
+                    block_hash: None,
+                    fees: FeeEstimates::default(),
+                    btc_price: Some(price),
+                    prices: BTreeMap::from([(BASE_CURRENCY.to_string(), price)]),
+                    spread: PriceSpread::default(),
+                    timestamp,
+                    asset: asset.clone(),
+                    source: PRICE_SOURCE_COINGECKO.to_string(),
+                    fetch_latency_ms: None,
+                }
+            })
+            .collect();
+
+        match save_metrics_batch(&mut lock_or_recover(conn), &rows) {
+            Ok(inserted) => info!("Backfilled {} daily prices for {}", inserted, asset),
+            Err(e) => error!("Error saving backfilled metrics for {}: {}", asset, e),
+        }
+    }
+}
+
+// Picks the store from DATABASE_URL: postgres:// URLs need the `postgres` feature, anything
+// else is a SQLite path (metrics.db by default). The raw SQLite connection is returned too
+// for the routes that query SQLite directly.
+pub async fn open_store(
+    database_url: Option<&str>,
+    journal_mode: &str,
+    synchronous: Option<&str>,
+    read_connections: usize,
+    busy_timeout: Duration,
+    on_corrupt: OnCorrupt,
+    integrity_check_on_corrupt: bool,
+) -> Result<(Arc<dyn MetricsStore>, Option<SqliteConnections>), String> {
+    let database_url = database_url.unwrap_or("metrics.db");
+
+    if database_url.starts_with("postgres://") || database_url.starts_with("postgresql://") {
+        #[cfg(feature = "postgres")]
+        {
+            let store = PostgresStore::connect(database_url)
+                .await
+                .map_err(|e| format!("Failed to connect to Postgres: {}", e))?;
+            info!("Using Postgres metrics store");
+            return Ok((Arc::new(store), None));
+        }
+        #[cfg(not(feature = "postgres"))]
+        return Err("DATABASE_URL points at Postgres but this build lacks the `postgres` feature".to_string());
+    }
+
+    let path = database_url.strip_prefix("sqlite://").unwrap_or(database_url);
+    let conn = Connection::open(path).map_err(|e| format!("Failed to open database: {}", e))?;
+
+    // Otherwise a damaged file opens fine and every later query fails. Reads the whole
+    // file, so startup takes longer on a large database.
+    let problems = integrity_problems(&conn).map_err(|e| format!("Failed to check database integrity: {}", e))?;
+    let conn = match (problems, on_corrupt) {
+        (None, _) => conn,
+        (Some(problems), OnCorrupt::Fail) => {
+            return Err(format!(
+                "Database {} failed its integrity check: {}. Restore it from a backup, or set ON_CORRUPT=reset to move it aside and start empty",
+                path, problems
+            ))
+        }
+        (Some(problems), OnCorrupt::Reset) => {
+            drop(conn);
+            let aside = move_corrupt_database(path).map_err(|e| format!("Failed to move corrupt database aside: {}", e))?;
+            warn!("Database {} failed its integrity check ({}), moved it to {} and starting empty", path, problems, aside);
+            Connection::open(path).map_err(|e| format!("Failed to open database: {}", e))?
+        }
+    };
+
+    let journal_mode = journal_mode.trim().to_uppercase();
+    if !SQLITE_JOURNAL_MODES.contains(&journal_mode.as_str()) {
+        return Err(format!(
+            "Invalid SQLITE_JOURNAL_MODE {:?}, expected one of {}",
+            journal_mode,
+            SQLITE_JOURNAL_MODES.join(", ")
+        ));
+    }
+    let synchronous = synchronous.map(|synchronous| synchronous.trim().to_uppercase());
+    if let Some(synchronous) = synchronous.as_deref().filter(|synchronous| !SQLITE_SYNCHRONOUS_MODES.contains(synchronous)) {
+        return Err(format!(
+            "Invalid SQLITE_SYNCHRONOUS {:?}, expected one of {}",
+            synchronous,
+            SQLITE_SYNCHRONOUS_MODES.join(", ")
+        ));
+    }
+    configure_connection(&conn, &journal_mode, synchronous.as_deref(), busy_timeout)
+        .map_err(|e| format!("Failed to configure database: {}", e))?;
+
+    let conn = Arc::new(Mutex::new(conn));
+    info!("Using SQLite metrics store at {}", path);
+
+    // Without WAL a reader would block the writer anyway, and an in-memory database
+    // can't be opened a second time
+    let mut store = SqliteStore::new(Arc::clone(&conn));
+    if path != ":memory:" {
+        store = store.with_reopen(SqliteReopen {
+            path: path.to_string(),
+            journal_mode: journal_mode.clone(),
+            synchronous: synchronous.clone(),
+            busy_timeout,
+            integrity_check: integrity_check_on_corrupt,
+        });
+    }
+    if read_connections == 0 || journal_mode != "WAL" || path == ":memory:" {
+        let connections = SqliteConnections {
+            writer: Arc::clone(&conn),
+            reader: conn,
+        };
+        return Ok((Arc::new(store), Some(connections)));
+    }
+
+    let readers = ReadPool::open(path, read_connections, busy_timeout).map_err(|e| format!("Failed to open read connections: {}", e))?;
+    info!("Serving reads from {} read-only SQLite connection(s)", readers.conns.len());
+    let connections = SqliteConnections {
+        writer: conn,
+        reader: Arc::clone(&readers.conns[0]),
+    };
+    Ok((Arc::new(store.with_readers(Arc::new(readers))), Some(connections)))
+}
+
+// Routes whose queries are written against SQLite directly. With another store they
+// aren't mounted and fall through to a 404. Exports always need `auth`; the read routes
+// only need `read_auth`, which is set under REQUIRE_AUTH_ALL.
+fn create_sqlite_routes(
+    conn: Option<Arc<Mutex<Connection>>>,
+    export_token: Option<String>,
+    auth: Option<Arc<ApiAuth>>,
+    read_auth: Option<Arc<ApiAuth>>,
+    poll_interval: PollInterval,
+) -> BoxedFilter<(Response,)> {
+    match conn {
+        Some(conn) => create_average_block_time_route(Arc::clone(&conn), read_auth.clone())
+            .or(create_buckets_route(Arc::clone(&conn), read_auth.clone()))
+            .or(create_downsample_route(Arc::clone(&conn), read_auth.clone()))
+            .or(create_gaps_route(Arc::clone(&conn), poll_interval.clone(), read_auth.clone()))
+            .or(create_high_low_route(Arc::clone(&conn), poll_interval.clone(), read_auth.clone()))
+            .or(create_ath_route(Arc::clone(&conn), poll_interval, read_auth.clone()))
+            .or(create_compare_route(Arc::clone(&conn), read_auth.clone()))
+            .or(create_halving_route(Arc::clone(&conn), read_auth.clone()))
+            .or(create_hourly_route(Arc::clone(&conn), read_auth))
+            .or(create_export_route(Arc::clone(&conn), export_token.clone(), auth.clone()))
+            .or(create_csv_export_route(Arc::clone(&conn), export_token.clone(), auth.clone()))
+            .or(create_download_route(conn, export_token, auth))
+            .map(Reply::into_response)
+            .boxed(),
+        None => warp::any()
+            .and_then(|| async { Err::<Response, _>(warp::reject::not_found()) })
+            .boxed(),
+    }
+}
+
+// SQLite routes that write, mounted on the writer connection since the reader handed to
+// create_sqlite_routes may be a read-only pool connection
+fn create_sqlite_admin_routes(conn: Option<Arc<Mutex<Connection>>>, auth: Option<Arc<ApiAuth>>) -> BoxedFilter<(Response,)> {
+    match conn {
+        Some(conn) => create_prune_route(conn, auth).boxed(),
+        None => warp::any()
+            .and_then(|| async { Err::<Response, _>(warp::reject::not_found()) })
+            .boxed(),
+    }
+}
+
+// Ctrl-C, or SIGTERM as sent by `docker stop`
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        let mut terminate = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+            .expect("Failed to install SIGTERM handler");
+        tokio::select! {
+            _ = tokio::signal::ctrl_c() => {}
+            _ = terminate.recv() => {}
+        }
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = tokio::signal::ctrl_c().await;
+    }
+}
+
+// SIGHUP, which reloads .env. Never fires where there's no SIGHUP or the handler can't be installed.
+struct Hangups {
+    #[cfg(unix)]
+    signal: Option<tokio::signal::unix::Signal>,
+}
+
+impl Hangups {
+    fn new() -> Hangups {
+        #[cfg(unix)]
+        {
+            let signal = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup())
+                .map_err(|e| warn!("Failed to install SIGHUP handler, config reload is off: {}", e))
+                .ok();
+            Hangups { signal }
+        }
+        #[cfg(not(unix))]
+        Hangups {}
+    }
+
+    async fn recv(&mut self) {
+        #[cfg(unix)]
+        {
+            if let Some(signal) = &mut self.signal {
+                if signal.recv().await.is_some() {
+                    return;
+                }
+            }
+        }
+        std::future::pending::<()>().await
+    }
+}
+
+// Applies the settings that can change while running and names the rest, which need a restart
+fn reload_settings(
+    path: &str,
+    collector: &Collector,
+    poll_interval: &PollInterval,
+    jitter_pct: &mut f64,
+    allowed_origins: &AllowedOrigins,
+) {
+    let changed = match reload_dotenv(path) {
+        Ok(changed) => changed,
+        Err(e) => {
+            error!("Error reloading {}: {}", path, e);
+            return;
+        }
+    };
+    if changed.is_empty() {
+        info!("Reloaded {}, nothing changed", path);
+        return;
+    }
+    let settings = {
+        let dotenv = lock_or_recover(&DOTENV);
+        ReloadableSettings::from_lookup(|key| dotenv.get(key, |key| std::env::var(key).ok()))
+    };
+
+    let rows = |max_rows: Option<u64>| max_rows.map_or("unlimited".to_string(), |rows| rows.to_string());
+    let origins = |origins: &Option<Vec<String>>| origins.as_ref().map_or("any".to_string(), |origins| origins.join(","));
+    for key in &changed {
+        match key.as_str() {
+            "POLL_INTERVAL" => {
+                info!(
+                    "POLL_INTERVAL changed from {}s to {}s",
+                    poll_interval.get().as_secs(),
+                    settings.poll_interval.as_secs()
+                );
+                poll_interval.set(settings.poll_interval);
+            }
+            "FETCH_JITTER_PCT" => {
+                info!("FETCH_JITTER_PCT changed from {} to {}", jitter_pct, settings.jitter_pct);
+                *jitter_pct = settings.jitter_pct;
+            }
+            "MAX_ROWS" => {
+                let mut max_rows = lock_or_recover(&collector.max_rows);
+                info!("MAX_ROWS changed from {} to {}", rows(*max_rows), rows(settings.max_rows));
+                *max_rows = settings.max_rows;
+            }
+            "ALLOWED_ORIGINS" => {
+                info!("ALLOWED_ORIGINS changed to {}", origins(&settings.allowed_origins));
+                allowed_origins.set(settings.allowed_origins.clone());
+            }
+            "RUST_LOG" => match LOG_FILTER.get().map(|handle| handle.reload(log_filter(settings.log_filter.as_deref()))) {
+                Some(Err(e)) => error!("Error applying the new RUST_LOG: {}", e),
+                _ => info!("RUST_LOG changed to {}", settings.log_filter.as_deref().unwrap_or_default()),
+            },
+            // Only the key, since .env usually holds secrets
+            _ => warn!("{} changed, restart to apply it", key),
+        }
+    }
+}
+
+// Serves a bundled dashboard from STATIC_DIR. Unknown non-API paths fall back to index.html for
+// client-side routing; unknown /api paths still 404.
+fn create_static_route(static_dir: Option<String>, auth: Option<Arc<ApiAuth>>) -> BoxedFilter<(Response,)> {
+    let static_dir = match static_dir {
+        Some(static_dir) => static_dir,
+        None => {
+            return warp::any()
+                .and_then(|| async { Err::<Response, _>(warp::reject::not_found()) })
+                .boxed()
+        }
+    };
+    let index = std::path::Path::new(&static_dir).join("index.html");
+
+    let outside_api = warp::path::peek()
+        .and_then(|peek: warp::path::Peek| async move {
+            if peek.segments().next() == Some("api") {
+                Err(warp::reject::not_found())
+            } else {
+                Ok(())
+            }
+        })
+        .untuple_one();
+
+    warp::get()
+        .and(outside_api)
+        .and(with_auth(auth))
+        .and(warp::fs::dir(static_dir).or(warp::fs::file(index)).unify())
+        .map(Reply::into_response)
+        .boxed()
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum LogFormat {
+    Pretty,
+    Json,
+}
+
+impl LogFormat {
+    // LOG_FORMAT=pretty|json; without it, pretty on a terminal and JSON for aggregators
+    pub fn from_env() -> LogFormat {
+        match std::env::var("LOG_FORMAT").ok().as_deref().map(str::trim) {
+            Some(format) if format.eq_ignore_ascii_case("json") => LogFormat::Json,
+            Some(format) if format.eq_ignore_ascii_case("pretty") => LogFormat::Pretty,
+            other => {
+                if let Some(other) = other {
+                    eprintln!("Ignoring unknown LOG_FORMAT {:?}", other);
+                }
+                if std::io::stdout().is_terminal() {
+                    LogFormat::Pretty
+                } else {
+                    LogFormat::Json
+                }
+            }
+        }
+    }
+}
+
+// Set by init_logging so a SIGHUP can swap in the filter from a changed RUST_LOG
+static LOG_FILTER: OnceLock<
+    tracing_subscriber::reload::Handle<tracing_subscriber::EnvFilter, tracing_subscriber::Registry>,
+> = OnceLock::new();
+
+// RUST_LOG filters as usual and defaults to info
+fn log_filter(directives: Option<&str>) -> tracing_subscriber::EnvFilter {
+    directives
+        .and_then(|directives| tracing_subscriber::EnvFilter::try_new(directives).ok())
+        .unwrap_or_else(|| tracing_subscriber::EnvFilter::new("info"))
+}
+
+pub fn init_logging(format: LogFormat) {
+    use tracing_subscriber::layer::SubscriberExt;
+    use tracing_subscriber::util::SubscriberInitExt;
+
+    let (filter, handle) = tracing_subscriber::reload::Layer::new(log_filter(std::env::var("RUST_LOG").ok().as_deref()));
+    let subscriber = tracing_subscriber::registry().with(filter);
+    match format {
+        LogFormat::Pretty => subscriber.with(tracing_subscriber::fmt::layer()).init(),
+        LogFormat::Json => subscriber.with(tracing_subscriber::fmt::layer().json()).init(),
+    }
+    let _ = LOG_FILTER.set(handle);
+}
+
+// What was taken from .env. Only load_dotenv, which runs before anything else is started,
+// writes the file into the process environment; a reload keeps the new values here, since
+// changing the environment while other threads may read it isn't safe.
+struct Dotenv {
+    // Keys load_dotenv set in the environment, as opposed to ones set there for real
+    loaded: std::collections::BTreeSet<String>,
+    // The file's current value for every key the real environment doesn't set
+    values: BTreeMap<String, String>,
+}
+
+static DOTENV: Mutex<Dotenv> = Mutex::new(Dotenv::new());
+
+impl Dotenv {
+    const fn new() -> Dotenv {
+        Dotenv {
+            loaded: std::collections::BTreeSet::new(),
+            values: BTreeMap::new(),
+        }
+    }
+
+    // Records the entries for keys `is_real` says the real environment doesn't set, returning
+    // them for load_dotenv to set there
+    fn load(&mut self, entries: Vec<(String, String)>, is_real: impl Fn(&str) -> bool) -> Vec<(String, String)> {
+        let loaded: Vec<(String, String)> = entries.into_iter().filter(|(key, _)| !is_real(key)).collect();
+        for (key, value) in &loaded {
+            self.loaded.insert(key.clone());
+            self.values.insert(key.clone(), value.clone());
+        }
+        loaded
+    }
+
+    // Takes the file's entries as the new values, skipping keys `is_real` says the real
+    // environment sets. Returns the keys whose values changed, including removed ones.
+    fn reload(&mut self, entries: Vec<(String, String)>, is_real: impl Fn(&str) -> bool) -> Vec<String> {
+        let mut values = BTreeMap::new();
+        for (key, value) in entries {
+            if !is_real(&key) || self.loaded.contains(&key) {
+                values.insert(key, value);
+            }
+        }
+        let mut changed: Vec<String> = values
+            .iter()
+            .filter(|(key, value)| self.values.get(*key) != Some(*value))
+            .map(|(key, _)| key.clone())
+            .collect();
+        changed.extend(self.values.keys().filter(|key| !values.contains_key(*key)).cloned());
+        changed.sort();
+        self.values = values;
+        changed
+    }
+
+    // `key` as of the last reload: the file's value when it sets it, otherwise the real
+    // environment's. A key dropped from the file reads as unset, even though the value
+    // load_dotenv put in the environment is still there.
+    fn get(&self, key: &str, env: impl Fn(&str) -> Option<String>) -> Option<String> {
+        match self.values.get(key) {
+            Some(value) => Some(value.clone()),
+            None if self.loaded.contains(key) => None,
+            None => env(key),
+        }
+    }
+}
+
+// Fills in configuration from ./.env when present. Variables already set in the environment
+// win. Runs before logging is set up so .env can configure it, hence printing problems
+// directly; nothing from the file is printed, since it usually holds secrets. Returns the
+// number of variables it set.
+pub fn load_dotenv() -> usize {
+    let (entries, skipped) = match read_dotenv(".env") {
+        Ok(read) => read,
+        Err(e) => {
+            eprintln!("Error reading .env: {}", e);
+            return 0;
+        }
+    };
+    if skipped > 0 {
+        eprintln!("Skipped {} invalid .env line(s)", skipped);
+    }
+
+    let loaded = lock_or_recover(&DOTENV).load(entries, |key| std::env::var_os(key).is_some());
+    for (key, value) in &loaded {
+        std::env::set_var(key, value);
+    }
+    loaded.len()
+}
+
+// The file's entries and how many invalid lines were skipped; no entries when it doesn't
+// exist. Parse errors quote the line, so only their number is kept.
+fn read_dotenv(path: &str) -> Result<(Vec<(String, String)>, usize), String> {
+    let entries = match dotenvy::from_path_iter(path) {
+        Ok(entries) => entries,
+        Err(e) if e.not_found() => return Ok((Vec::new(), 0)),
+        Err(e) => return Err(e.to_string()),
+    };
+    let mut parsed = Vec::new();
+    let mut skipped = 0;
+    for entry in entries {
+        match entry {
+            Ok(entry) => parsed.push(entry),
+            Err(_) => skipped += 1,
+        }
+    }
+    Ok((parsed, skipped))
+}
+
+// Re-reads the env file on SIGHUP under the same rules as load_dotenv, into DOTENV rather
+// than the environment. Returns the keys whose values changed.
+fn reload_dotenv(path: &str) -> Result<Vec<String>, String> {
+    let (entries, skipped) = read_dotenv(path)?;
+    if skipped > 0 {
+        warn!("Skipped {} invalid line(s) in {}", skipped, path);
+    }
+    Ok(lock_or_recover(&DOTENV).reload(entries, |key| std::env::var_os(key).is_some()))
+}
+
+// The settings a SIGHUP can change without a restart. Read through `lookup` so a reload can
+// take them from the re-read .env; empty values count as unset, as in Config::from_env.
+struct ReloadableSettings {
+    poll_interval: Duration,
+    jitter_pct: f64,
+    max_rows: Option<u64>,
+    allowed_origins: Option<Vec<String>>,
+    log_filter: Option<String>,
+}
+
+impl ReloadableSettings {
+    fn from_lookup(lookup: impl Fn(&str) -> Option<String>) -> ReloadableSettings {
+        let lookup = |key: &str| lookup(key).filter(|value| !value.is_empty());
+        ReloadableSettings {
+            poll_interval: lookup("POLL_INTERVAL")
+                .and_then(|interval| parse_duration_secs(&interval))
+                .filter(|secs| *secs > 0)
+                .map_or(POLL_INTERVAL, |secs| Duration::from_secs(secs as u64)),
+            jitter_pct: parse_setting::<f64>("FETCH_JITTER_PCT", lookup("FETCH_JITTER_PCT"))
+                .unwrap_or(0.0)
+                .clamp(0.0, 50.0),
+            max_rows: parse_setting::<u64>("MAX_ROWS", lookup("MAX_ROWS")).filter(|rows| *rows > 0),
+            allowed_origins: parse_allowed_origins(lookup("ALLOWED_ORIGINS").as_deref()),
+            log_filter: lookup("RUST_LOG"),
+        }
+    }
+}
+
+// Splits ALLOWED_ORIGINS on commas, dropping trailing slashes so they compare equal to the
+// Origin header. None, allowing any origin, when unset or *.
+fn parse_allowed_origins(raw: Option<&str>) -> Option<Vec<String>> {
+    let origins: Vec<String> = raw?
+        .split(',')
+        .map(|origin| origin.trim().trim_end_matches('/').to_string())
+        .filter(|origin| !origin.is_empty())
+        .collect();
+    if origins.is_empty() || origins.iter().any(|origin| origin == "*") {
+        return None;
+    }
+    Some(origins)
+}
+
+// The live ALLOWED_ORIGINS, checked on every request so a SIGHUP can change it
+#[derive(Clone, Default)]
+struct AllowedOrigins(Arc<Mutex<Option<Vec<String>>>>);
+
+impl AllowedOrigins {
+    fn new(origins: Option<Vec<String>>) -> AllowedOrigins {
+        AllowedOrigins(Arc::new(Mutex::new(origins)))
+    }
+
+    fn set(&self, origins: Option<Vec<String>>) {
+        *lock_or_recover(&self.0) = origins;
+    }
+
+    fn allows(&self, origin: &str) -> bool {
+        lock_or_recover(&self.0)
+            .as_ref()
+            .map_or(true, |origins| origins.iter().any(|allowed| allowed.eq_ignore_ascii_case(origin)))
+    }
+}
+
+// Answers 403 to a browser request whose Origin isn't allowed, preflights included, before it
+// reaches the routes. Requests without an Origin header, like curl's, always pass.
+fn with_allowed_origins<F, R>(origins: AllowedOrigins, routes: F) -> BoxedFilter<(Response,)>
+where
+    F: Filter<Extract = (R,), Error = warp::Rejection> + Clone + Send + Sync + 'static,
+    R: warp::Reply,
+{
+    let forbidden = warp::header::optional::<String>("origin").and_then(move |origin: Option<String>| {
+        let origins = origins.clone();
+        async move {
+            match origin {
+                Some(origin) if !origins.allows(&origin) => {
+                    Ok(error_reply(StatusCode::FORBIDDEN, "Origin not allowed"))
+                }
+                _ => Err(warp::reject::not_found()),
+            }
+        }
+    });
+    forbidden.or(routes.map(|reply: R| reply.into_response())).unify().boxed()
+}
+
+// Everything the service reads from the environment, gathered up front so the collector
+// and server can also be started from another binary or a test with explicit settings
+pub struct Config {
+    // A SQLite path or a postgres:// URL; metrics.db when unset
+    pub database_url: Option<String>,
+    pub sqlite_journal_mode: String,
+    // OFF, NORMAL or FULL; unset keeps NORMAL under WAL and SQLite's FULL otherwise
+    pub sqlite_synchronous: Option<String>,
+    // Size of the read-only connection pool for SQLite in WAL mode; 0 reads through the writer
+    pub sqlite_read_connections: usize,
+    // Wait on a locked database this long before failing with SQLITE_BUSY
+    pub sqlite_busy_timeout: Duration,
+    pub on_corrupt: OnCorrupt,
+    // Runs PRAGMA integrity_check when a corruption error makes the store reopen the file
+    pub integrity_check_on_corrupt: bool,
+    pub listen_addr: std::net::SocketAddr,
+    pub tls: Option<TlsConfig>,
+    // Set when running behind a reverse proxy that forwards a sub-path, e.g. /btc
+    pub base_path: String,
+    pub auth: Option<BasicAuth>,
+    // Bearer token accepted by the protected routes, alongside or instead of Basic Auth
+    pub api_token: Option<String>,
+    // Read endpoints stay public unless this is set
+    pub require_auth_all: bool,
+    pub export_token: Option<String>,
+    pub max_query_limit: u32,
+    pub default_limit: u32,
+    pub static_dir: Option<String>,
+    pub field_case: FieldCase,
+    // Opt-in, since it makes /api/health depend on reaching Blockstream
+    pub health_check_tip: bool,
+    // Stores recommended fee rates with every sample
+    pub fetch_fee_estimates: bool,
+    pub assets: Vec<String>,
+    pub currencies: Vec<String>,
+    pub user_agent: String,
+    pub price_api_base: String,
+    pub chain_api_base: String,
+    pub coinbase_api_base: String,
+    pub kraken_api_base: String,
+    // Price feeds in the order they're tried, from PRICE_SOURCES; CoinGecko alone by default,
+    // or all of them under median aggregation
+    pub price_sources: Vec<PriceSource>,
+    pub price_aggregation: PriceAggregation,
+    pub alerts: Option<AlertConfig>,
+    pub mqtt: Option<MqttConfig>,
+    // Rounds prices to this many decimals before they're written, so it changes the stored
+    // series and not just how it's displayed; unset keeps full upstream precision
+    pub price_decimals: Option<u32>,
+    // Deepest drop below the last stored height still accepted, to allow for reorgs
+    pub max_height_regression: u64,
+    pub breaker: BreakerConfig,
+    // Buffering is on for more than one sample; the flush age caps how long samples wait
+    pub write_buffer_samples: Option<usize>,
+    pub write_buffer_flush: Duration,
+    pub broadcast_price_threshold: f64,
+    // Standard deviations from the rolling median past which a price is rejected; off when unset
+    pub spike_filter_stddev: Option<f64>,
+    pub spike_filter_window: usize,
+    // Oldest rows are deleted once the table holds more than this; unset keeps everything
+    pub max_rows: Option<u64>,
+    // Requests handled at once before the rest get a 503; unlimited when unset
+    pub max_concurrent_requests: Option<usize>,
+    // Turns a failed upstream self-test into a startup failure for deploy pipelines
+    pub fail_fast: bool,
+    // SERVE_API=0 skips warp entirely and leaves just the fetch loop writing to the store
+    pub serve_api: bool,
+    pub backfill_days: Option<u32>,
+    // Samples older than this are rolled up into hourly rows; rollups are off when unset
+    pub rollup_age: Option<Duration>,
+    pub rollup_interval: Duration,
+    // Deletes raw samples once rolled up, leaving only the hourly rows for older history
+    pub rollup_prune_raw: bool,
+    // Time between scheduled fetches, which cache lifetimes and staleness also follow
+    pub poll_interval: Duration,
+    pub poll_strategy: PollStrategy,
+    // Optional ±% jitter so a fleet started together doesn't hit the APIs in lockstep
+    pub jitter_pct: f64,
+    // Browser origins allowed to call the API, from a comma-separated ALLOWED_ORIGINS; any
+    // origin when unset or *
+    pub allowed_origins: Option<Vec<String>>,
+}
+
+impl Config {
+    pub fn from_env() -> Result<Config, String> {
+        let max_query_limit = env_parse::<u32>("MAX_QUERY_LIMIT")
+            .filter(|limit| *limit > 0)
+            .unwrap_or(DEFAULT_MAX_QUERY_LIMIT);
+        // Rows served when no ?limit= is given, kept within the cap so defaults are never flagged as truncated
+        let default_limit = match env_parse::<u32>("DEFAULT_METRICS_LIMIT").filter(|limit| *limit > 0) {
+            Some(limit) if limit > max_query_limit => {
+                warn!(
+                    "DEFAULT_METRICS_LIMIT {} exceeds MAX_QUERY_LIMIT {}, using {}",
+                    limit, max_query_limit, max_query_limit
+                );
+                max_query_limit
+            }
+            Some(limit) => limit,
+            None => DEFAULT_METRICS_LIMIT.min(max_query_limit),
+        };
+        let tls = TlsConfig::from_env().map_err(|e| format!("Invalid TLS configuration: {}", e))?;
+        let non_empty = |key: &str| std::env::var(key).ok().filter(|v| !v.is_empty());
+        let flag = |key: &str| std::env::var(key).is_ok_and(|v| v == "1");
+        let price_aggregation = PriceAggregation::from_env();
+        let price_sources = match (non_empty("PRICE_SOURCES"), price_aggregation) {
+            (Some(raw), _) => parse_price_sources(&raw),
+            (None, PriceAggregation::Median) => vec![PriceSource::CoinGecko, PriceSource::Coinbase, PriceSource::Kraken],
+            (None, PriceAggregation::Single) => vec![PriceSource::CoinGecko],
+        };
+        let reloadable = ReloadableSettings::from_lookup(|key| std::env::var(key).ok());
+        if price_aggregation == PriceAggregation::Median && price_sources.len() < MIN_MEDIAN_SOURCES {
+            return Err(format!(
+                "PRICE_AGGREGATION=median needs at least {} PRICE_SOURCES, got {}",
+                MIN_MEDIAN_SOURCES,
+                price_sources.len()
+            ));
+        }
+
+        Ok(Config {
+            database_url: non_empty("DATABASE_URL"),
+            sqlite_journal_mode: non_empty("SQLITE_JOURNAL_MODE").unwrap_or_else(|| "WAL".to_string()),
+            sqlite_synchronous: non_empty("SQLITE_SYNCHRONOUS"),
+            sqlite_read_connections: env_parse::<usize>("SQLITE_READ_CONNECTIONS").unwrap_or(DEFAULT_SQLITE_READ_CONNECTIONS),
+            sqlite_busy_timeout: env_parse::<u64>("SQLITE_BUSY_TIMEOUT_MS")
+                .map(Duration::from_millis)
+                .unwrap_or(DEFAULT_SQLITE_BUSY_TIMEOUT),
+            on_corrupt: OnCorrupt::from_env(),
+            integrity_check_on_corrupt: flag("INTEGRITY_CHECK_ON_CORRUPT"),
+            listen_addr: ([0, 0, 0, 0], 8080).into(),
+            tls,
+            base_path: std::env::var("BASE_PATH").unwrap_or_default(),
+            auth: BasicAuth::from_env(),
+            api_token: non_empty("API_TOKEN"),
+            require_auth_all: flag("REQUIRE_AUTH_ALL"),
+            export_token: non_empty("EXPORT_TOKEN"),
+            max_query_limit,
+            default_limit,
+            static_dir: non_empty("STATIC_DIR"),
+            field_case: FieldCase::from_env(),
+            health_check_tip: flag("HEALTH_CHECK_TIP"),
+            fetch_fee_estimates: flag("FETCH_FEE_ESTIMATES"),
+            assets: tracked_assets_from_env(),
+            currencies: quote_currencies_from_env(),
+            user_agent: non_empty("HTTP_USER_AGENT").unwrap_or_else(|| DEFAULT_USER_AGENT.to_string()),
+            price_api_base: non_empty("PRICE_API_BASE").unwrap_or_else(|| DEFAULT_PRICE_API_BASE.to_string()),
+            chain_api_base: non_empty("CHAIN_API_BASE").unwrap_or_else(|| DEFAULT_CHAIN_API_BASE.to_string()),
+            coinbase_api_base: non_empty("COINBASE_API_BASE").unwrap_or_else(|| DEFAULT_COINBASE_API_BASE.to_string()),
+            kraken_api_base: non_empty("KRAKEN_API_BASE").unwrap_or_else(|| DEFAULT_KRAKEN_API_BASE.to_string()),
+            price_sources,
+            price_aggregation,
+            alerts: AlertConfig::from_env(),
+            mqtt: MqttConfig::from_env(),
+            price_decimals: env_parse::<u32>("PRICE_DECIMALS").map(|decimals| decimals.min(MAX_PRICE_DECIMALS)),
+            max_height_regression: env_parse::<u64>("MAX_HEIGHT_REGRESSION").unwrap_or(DEFAULT_MAX_HEIGHT_REGRESSION),
+            breaker: BreakerConfig::from_env(),
+            write_buffer_samples: env_parse::<usize>("WRITE_BUFFER_SAMPLES"),
+            write_buffer_flush: Duration::from_secs(
+                non_empty("WRITE_BUFFER_FLUSH")
+                    .and_then(|flush| parse_duration_secs(&flush))
+                    .unwrap_or(60) as u64,
+            ),
+            broadcast_price_threshold: env_parse::<f64>("BROADCAST_PRICE_THRESHOLD")
+                .filter(|threshold| *threshold >= 0.0)
+                .unwrap_or(DEFAULT_BROADCAST_PRICE_THRESHOLD),
+            spike_filter_stddev: env_parse::<f64>("SPIKE_FILTER_STDDEV").filter(|stddev| *stddev > 0.0),
+            spike_filter_window: env_parse::<usize>("SPIKE_FILTER_WINDOW").unwrap_or(DEFAULT_SPIKE_FILTER_WINDOW),
+            max_rows: reloadable.max_rows,
+            max_concurrent_requests: env_parse::<usize>("MAX_CONCURRENT_REQUESTS").filter(|limit| *limit > 0),
+            fail_fast: flag("FAIL_FAST"),
+            serve_api: std::env::var("SERVE_API").map_or(true, |v| v != "0"),
+            backfill_days: env_parse::<u32>("BACKFILL_DAYS").filter(|days| *days > 0),
+            rollup_age: non_empty("ROLLUP_AGE")
+                .and_then(|age| parse_duration_secs(&age))
+                .map(|secs| Duration::from_secs(secs as u64)),
+            rollup_interval: Duration::from_secs(
+                non_empty("ROLLUP_INTERVAL")
+                    .and_then(|interval| parse_duration_secs(&interval))
+                    .unwrap_or(60 * 60) as u64,
+            ),
+            rollup_prune_raw: flag("ROLLUP_PRUNE_RAW"),
+            poll_interval: reloadable.poll_interval,
+            poll_strategy: PollStrategy::from_env(),
+            jitter_pct: reloadable.jitter_pct,
+            allowed_origins: reloadable.allowed_origins,
+        })
+    }
+}
+
+// Opens the store, serves the API and polls until Ctrl-C or SIGTERM
+pub async fn run(config: Config) -> anyhow::Result<()> {
+    let started = ProcessStart::now();
+    // Installed first, since SIGHUP's default action would otherwise end the process
+    let mut hangups = Hangups::new();
+
+    if let (true, Some(tls)) = (config.serve_api, &config.tls) {
+        tls.check_readable()
+            .map_err(anyhow::Error::msg)
+            .context("Invalid TLS configuration")?;
+    }
+
+    let (store, sqlite_conns) = open_store(
+        config.database_url.as_deref(),
+        &config.sqlite_journal_mode,
+        config.sqlite_synchronous.as_deref(),
+        config.sqlite_read_connections,
+        config.sqlite_busy_timeout,
+        config.on_corrupt,
+        config.integrity_check_on_corrupt,
+    )
+    .await
+    .map_err(anyhow::Error::msg)
+    .context("Error opening the metrics store")?;
+
+    // Create the metrics table at startup if it doesn't exist
+    if let Err(e) = store.create_metrics_table().await {
+        error!("Error creating metrics table: {}", e);
+    }
+
+    let auth = ApiAuth::new(config.auth, config.api_token).map(Arc::new);
+    // Passed to every read route rather than wrapped around them all, so the check runs after
+    // a route's path matched and unknown paths still 404
+    let read_auth = if config.require_auth_all { auth.clone() } else { None };
+
+    let fetch_status = Arc::new(Mutex::new(FetchStatus::default()));
+
+    // Create the metrics route with CORS enabled
+    let max_query_limit = config.max_query_limit;
+    let default_limit = config.default_limit;
+    let poll_interval = PollInterval::new(config.poll_interval);
+    let metrics_route = create_metrics_route(
+        Arc::clone(&store),
+        default_limit,
+        max_query_limit,
+        config.field_case,
+        poll_interval.clone(),
+        read_auth.clone(),
+    );
+    let since_route = create_since_route(Arc::clone(&store), max_query_limit, poll_interval.clone(), read_auth.clone());
+    let count_route = create_count_route(Arc::clone(&store), poll_interval.clone(), read_auth.clone());
+    let currencies_route = create_currencies_route(Arc::clone(&store), poll_interval.clone(), read_auth.clone());
+    let view_route = create_view_route(Arc::clone(&store), default_limit, max_query_limit, read_auth.clone());
+    let sqlite_routes = create_sqlite_routes(
+        sqlite_conns.as_ref().map(|conns| Arc::clone(&conns.reader)),
+        config.export_token,
+        auth.clone(),
+        read_auth.clone(),
+        poll_interval.clone(),
+    );
+    let sqlite_admin_routes = create_sqlite_admin_routes(sqlite_conns.as_ref().map(|conns| Arc::clone(&conns.writer)), auth.clone());
+    let counters = Arc::new(FetchCounters::default());
+    let prometheus_route = create_prometheus_route(Arc::clone(&counters), read_auth.clone());
+    let openapi_route = create_openapi_route(read_auth.clone());
+    let version_route = create_version_route(read_auth.clone());
+    let uptime_route = create_uptime_route(started, read_auth.clone());
+    let static_route = create_static_route(config.static_dir, read_auth.clone());
+
+    // Prime the cache from the newest stored rows so /latest has data straight after a restart
+    let latest: LatestMetrics = Arc::new(Mutex::new(HashMap::new()));
+    match store.get_latest_metrics().await {
+        Ok(rows) => {
+            let mut latest = lock_or_recover(&latest);
+            for metrics in rows {
+                latest.insert(metrics.asset.clone(), metrics);
+            }
+        }
+        Err(e) => error!("Error loading latest metrics: {}", e),
+    }
+    let latest_route = create_latest_route(Arc::clone(&latest), poll_interval.clone(), read_auth.clone());
+    let last_block_route = create_last_block_route(Arc::clone(&latest), poll_interval.clone(), read_auth.clone());
+
+    info!("Tracking assets: {}", config.assets.join(", "));
+
+    let collector = Arc::new(Collector {
+        store: Arc::clone(&store),
+        http: build_http_client(&config.user_agent),
+        api_bases: ApiBases {
+            price: config.price_api_base.trim_end_matches('/').to_string(),
+            chain: config.chain_api_base.trim_end_matches('/').to_string(),
+            coinbase: config.coinbase_api_base.trim_end_matches('/').to_string(),
+            kraken: config.kraken_api_base.trim_end_matches('/').to_string(),
+        },
+        fetch_status: Arc::clone(&fetch_status),
+        counters,
+        latest,
+        assets: config.assets,
+        currencies: config.currencies,
+        price_sources: config.price_sources,
+        price_aggregation: config.price_aggregation,
+        alert_config: config.alerts.map(Arc::new),
+        last_price: Mutex::new(None),
+        price_decimals: config.price_decimals,
+        max_height_regression: config.max_height_regression,
+        breaker_config: config.breaker,
+        write_buffer: config
+            .write_buffer_samples
+            .and_then(|samples| WriteBuffer::new(samples, config.write_buffer_flush)),
+        spike_filter: config
+            .spike_filter_stddev
+            .map(|stddev| SpikeFilter::new(stddev, config.spike_filter_window)),
+        max_rows: Mutex::new(config.max_rows),
+        fetch_fees: config.fetch_fee_estimates,
+        poll_strategy: config.poll_strategy,
+        height_schedule: Mutex::new(HeightSchedule::default()),
+        updates: broadcast::channel(BROADCAST_CAPACITY).0,
+        last_broadcast: Mutex::new(HashMap::new()),
+        broadcast_price_threshold: config.broadcast_price_threshold,
+        sinks: connect_sinks(config.mqtt.as_ref())
+            .map_err(anyhow::Error::msg)
+            .context("Error connecting the metrics sinks")?,
+        fetch_lock: tokio::sync::Mutex::new(None),
+        cycles_completed: AtomicU64::new(0),
+    });
+    let refresh_route = create_refresh_route(Arc::clone(&collector), auth.clone());
+    let field_case = config.field_case;
+    let stream_route = create_stream_route(Arc::clone(&store), collector.updates.clone(), field_case, read_auth.clone());
+
+    let chain_tip = config
+        .health_check_tip
+        .then(|| Arc::new(ChainTip::new(collector.http.clone(), collector.api_bases.chain.clone())));
+    let health_route = create_health_route(
+        Arc::clone(&fetch_status),
+        Arc::clone(&collector.counters),
+        Arc::clone(&collector.latest),
+        chain_tip,
+        read_auth,
+    );
+
+    let cors = api_cors();
+
+    let request_limit = config
+        .max_concurrent_requests
+        .map(|limit| Arc::new(tokio::sync::Semaphore::new(limit)));
+
+    let tls_config = config.tls;
+    let listen_addr = config.listen_addr;
+    let base_path = config.base_path;
+    let allowed_origins = AllowedOrigins::new(config.allowed_origins);
+    if !base_path.trim_matches('/').is_empty() {
+        info!("Serving routes under {}", base_path);
+    }
+
+    // Start the warp server unless running as a pure collector
+    if config.serve_api {
+        let allowed_origins = allowed_origins.clone();
+        tokio::spawn(async move {
+            let api = with_base_path(&base_path)
+                .and(with_concurrency_limit(request_limit, with_compression(with_field_case(
+                    field_case,
+                    metrics_route
+                        .or(latest_route)
+                        .or(last_block_route)
+                        .or(since_route)
+                        .or(count_route)
+                        .or(currencies_route)
+                        .or(view_route)
+                        .or(stream_route)
+                        .or(sqlite_routes)
+                        .or(sqlite_admin_routes)
+                        .or(refresh_route)
+                        .or(health_route)
+                        .or(uptime_route)
+                        .or(prometheus_route)
+                        .or(openapi_route)
+                        .or(version_route)
+                        .or(static_route),
+                ))))
+                .recover(handle_rejection);
+            let routes = with_allowed_origins(allowed_origins, with_request_id(api).with(cors));
+
+            match tls_config {
+                Some(tls_config) => {
+                    info!("Starting the Warp server with TLS on {}...", listen_addr);
+                    warp::serve(routes)
+                        .tls()
+                        .cert_path(&tls_config.cert_path)
+                        .key_path(&tls_config.key_path)
+                        .run(listen_addr)
+                        .await;
+                }
+                None => {
+                    info!("Starting the Warp server on {}...", listen_addr);
+                    warp::serve(routes).run(listen_addr).await;
+                }
+            }
+        });
+    } else {
+        info!("SERVE_API=0, running as a collector without the HTTP server");
+    }
+
+    // Only a warning unless fail_fast is set
+    // Only the first price source is checked, as the one expected to answer every tick
+    if let Err(e) = upstream_self_test(&collector.http, &collector.api_bases, collector.price_sources[0], &collector.assets).await {
+        if config.fail_fast {
+            anyhow::bail!("Upstream self-test failed: {}", e);
+        }
+        warn!("Upstream self-test failed: {}", e);
+    }
+
+    if let Some(days) = config.backfill_days {
+        match &sqlite_conns {
+            Some(SqliteConnections { writer: conn, .. }) => {
+                backfill_history(
+                    conn,
+                    &collector.http,
+                    &collector.api_bases.price,
+                    &collector.assets,
+                    days.min(365),
+                    collector.price_decimals,
+                )
+                .await
+            }
+            None => warn!("BACKFILL_DAYS is only supported with the SQLite store, skipping backfill"),
+        }
+    }
+
+    if let Some(age) = config.rollup_age {
+        match &sqlite_conns {
+            Some(SqliteConnections { writer: conn, .. }) => {
+                spawn_rollups(Arc::clone(conn), age, config.rollup_interval, config.rollup_prune_raw)
+            }
+            None => warn!("ROLLUP_AGE is only supported with the SQLite store, skipping rollups"),
+        }
+    }
+
+    let mut jitter_pct = config.jitter_pct;
+    let mut next_tick = time::Instant::now() + startup_jitter(poll_interval.get(), jitter_pct);
+
+    // Created once so a signal that arrives mid-collect is still seen at the next wait
+    let shutdown = shutdown_signal();
+    tokio::pin!(shutdown);
+
+    loop {
+        tokio::select! {
+            _ = time::sleep_until(next_tick) => {}
+            _ = hangups.recv() => {
+                reload_settings(".env", &collector, &poll_interval, &mut jitter_pct, &allowed_origins);
+                // A shorter interval shouldn't wait out the rest of the old one
+                next_tick = next_tick.min(time::Instant::now() + poll_interval.get());
+                continue;
+            }
+            _ = &mut shutdown => {
+                info!("Shutting down");
+                collector.shutdown().await;
+                return Ok(());
+            }
+        }
+        next_tick += jittered_interval(poll_interval.get(), jitter_pct);
+
+        let cycle = {
+            let collector = Arc::clone(&collector);
+            async move {
+                if let Err(e) = collector.collect().await {
+                    error!("{}", e);
+                }
+            }
+        };
+        if let Err(e) = run_contained(cycle).await {
+            collector.counters.poller_restarts.fetch_add(1, Ordering::Relaxed);
+            error!("{}, restarting the poller in {}s", e, POLLER_RESTART_DELAY.as_secs());
+            next_tick = next_tick.max(time::Instant::now() + POLLER_RESTART_DELAY);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_coingecko_price_payload() {
+        let body = r#"{"bitcoin":{"usd":63241.57}}"#;
+        let parsed: SimplePriceResponse = serde_json::from_str(body).unwrap();
+        assert_eq!(parsed["bitcoin"].usd, Some(63241.57));
+    }
+
+    #[test]
+    fn parses_coingecko_multi_asset_payload() {
+        let body = r#"{"bitcoin":{"usd":63241.57},"ethereum":{"usd":3120.4}}"#;
+        let parsed: SimplePriceResponse = serde_json::from_str(body).unwrap();
+        assert_eq!(parsed["bitcoin"].usd, Some(63241.57));
+        assert_eq!(parsed["ethereum"].usd, Some(3120.4));
+    }
+
+    #[test]
+    fn parses_coingecko_integer_price() {
+        let body = r#"{"bitcoin":{"usd":63241}}"#;
+        let parsed: SimplePriceResponse = serde_json::from_str(body).unwrap();
+        assert_eq!(parsed["bitcoin"].usd, Some(63241.0));
+    }
+
+    #[test]
+    fn ignores_extra_coingecko_fields() {
+        let body = r#"{"bitcoin":{"usd":63241.57,"eur":58000.1,"usd_24h_change":-1.2}}"#;
+        let parsed: SimplePriceResponse = serde_json::from_str(body).unwrap();
+        assert_eq!(parsed["bitcoin"].usd, Some(63241.57));
+    }
+
+    #[test]
+    fn keeps_partial_coingecko_payload() {
+        let body = r#"{"bitcoin":{"eur":58000.1,"gbp":null},"ethereum":{}}"#;
+        let parsed: SimplePriceResponse = serde_json::from_str(body).unwrap();
+        let currencies = vec!["usd".to_string(), "eur".to_string(), "gbp".to_string()];
+
+        let prices = requested_prices(parsed, &currencies).unwrap();
+        assert_eq!(prices.len(), 1);
+        assert_eq!(prices["bitcoin"], BTreeMap::from([("eur".to_string(), 58000.1)]));
+    }
+
+    #[test]
+    fn rejects_coingecko_error_payload() {
+        let body = r#"{"status":{"error_code":429,"error_message":"You've exceeded the Rate Limit"}}"#;
+        let parsed: SimplePriceResponse = serde_json::from_str(body).unwrap();
+        assert!(requested_prices(parsed, &[BASE_CURRENCY.to_string()]).is_none());
+    }
+
+    #[test]
+    fn computes_block_time_across_multi_block_jumps() {
+        let first_seen = vec![
+            (100, "2024-01-01 00:00:00".to_string()),
+            (101, "2024-01-01 00:10:00".to_string()),
+            (103, "2024-01-01 00:30:00".to_string()),
+        ];
+        let stats = compute_block_times(&first_seen);
+        assert_eq!(stats.blocks, 3);
+        assert_eq!(stats.average_secs, Some(600.0));
+        assert_eq!(stats.median_secs, Some(600.0));
+    }
+
+    #[test]
+    fn block_time_is_null_without_two_heights() {
+        let first_seen = vec![(100, "2024-01-01 00:00:00".to_string())];
+        assert_eq!(compute_block_times(&first_seen), BlockTimeStats::default());
+        assert_eq!(compute_block_times(&[]), BlockTimeStats::default());
+    }
+
+    #[test]
+    fn estimates_next_halving_from_height() {
+        let now = parse_timestamp("2024-01-01 00:00:00").unwrap();
+        let estimate = compute_halving(839_000, Some(300.0), now);
+        assert_eq!(estimate.reward_era, 4);
+        assert_eq!(estimate.block_reward_btc, 6.25);
+        assert_eq!(estimate.next_halving_block, 840_000);
+        assert_eq!(estimate.blocks_remaining, 1_000);
+        assert_eq!(estimate.estimated_at, "2024-01-04 11:20:00");
+
+        // The halving block starts the new era, and no measured average falls back to the target
+        let estimate = compute_halving(840_000, None, now);
+        assert_eq!(estimate.reward_era, 5);
+        assert_eq!(estimate.block_reward_btc, 3.125);
+        assert_eq!(estimate.next_halving_block, 1_050_000);
+        assert_eq!(estimate.average_block_secs, TARGET_BLOCK_SECS);
+    }
+
+    #[tokio::test]
+    async fn halving_route_uses_highest_stored_block() {
+        let route = create_halving_route(seeded_conn(&[]), None);
+        let res = warp::test::request().path("/api/halving").reply(&route).await;
+        assert_eq!(res.status(), StatusCode::SERVICE_UNAVAILABLE);
+
+        let route = create_halving_route(seeded_conn(&[(DEFAULT_ASSET, 839_998, 60_000.0), (DEFAULT_ASSET, 839_999, 60_000.0)]), None);
+        let res = warp::test::request().path("/api/halving").reply(&route).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        let body: serde_json::Value = serde_json::from_slice(res.body()).unwrap();
+        assert_eq!(body["block_height"], 839_999);
+        assert_eq!(body["next_halving_block"], 840_000);
+        assert_eq!(body["blocks_remaining"], 1);
+        assert!(body["estimated_at"].is_string());
+    }
+
+    #[test]
+    fn matches_weak_and_listed_etags() {
+        let etag = compute_etag(b"[]");
+        assert!(etag_matches(&etag, &etag));
+        assert!(etag_matches(etag.trim_start_matches("W/"), &etag));
+        assert!(etag_matches(&format!("W/\"other\", {}", etag), &etag));
+        assert!(etag_matches("*", &etag));
+        assert!(!etag_matches("W/\"other\"", &etag));
+    }
+
+    #[test]
+    fn history_limit_is_capped_server_side() {
+        let conn = Connection::open_in_memory().unwrap();
+        create_metrics_table(&conn).unwrap();
+        for i in 0..5 {
+            let prices = BTreeMap::from([(BASE_CURRENCY.to_string(), 60_000.0)]);
+            save_metrics(&conn, &MetricsRow {
+                asset: DEFAULT_ASSET.to_string(),
+                block_height: Some(800_000 + i),
+                btc_price: Some(60_000.0),
+                prices,
+                source: PRICE_SOURCE_COINGECKO.to_string(),
+                ..Default::default()
+            })
+            .unwrap();
+        }
+
+        let history = get_metrics_history(&conn, DEFAULT_ASSET, Some(10), 3).unwrap();
+        assert_eq!(history.metrics.len(), 3);
+        assert!(history.truncated);
+
+        let history = get_metrics_history(&conn, DEFAULT_ASSET, Some(2), 3).unwrap();
+        assert_eq!(history.metrics.len(), 2);
+        assert!(!history.truncated);
+    }
+
+    #[test]
+    fn jitter_stays_within_bounds() {
+        let base = Duration::from_secs(20);
+        assert_eq!(jittered_interval(base, 0.0), base);
+        for _ in 0..100 {
+            let interval = jittered_interval(base, 10.0);
+            assert!(interval >= Duration::from_secs(18) && interval <= Duration::from_secs(22));
+            assert!(startup_jitter(base, 10.0) <= Duration::from_secs(2));
+        }
+        assert_eq!(startup_jitter(base, 0.0), Duration::ZERO);
+    }
+
+    #[test]
+    fn parses_blockstream_tip_height() {
+        let parsed: u64 = serde_json::from_str("868123").unwrap();
+        assert_eq!(parsed, 868123);
+    }
+
+    #[test]
+    fn rejects_malformed_blockstream_tip_height() {
+        assert!(serde_json::from_str::<u64>("<html>502 Bad Gateway</html>").is_err());
+        assert!(serde_json::from_str::<u64>("").is_err());
+        assert!(serde_json::from_str::<u64>("-1").is_err());
+        assert!(serde_json::from_str::<u64>("868123.5").is_err());
+    }
+
+    fn seeded_conn(rows: &[(&str, u64, f64)]) -> Arc<Mutex<Connection>> {
+        let conn = Connection::open_in_memory().unwrap();
+        create_metrics_table(&conn).unwrap();
+        for (asset, block_height, btc_price) in rows {
+            let prices = BTreeMap::from([(BASE_CURRENCY.to_string(), *btc_price)]);
+            save_metrics(&conn, &MetricsRow {
+                asset: asset.to_string(),
+                block_height: Some(*block_height),
+                btc_price: Some(*btc_price),
+                prices,
+                source: PRICE_SOURCE_COINGECKO.to_string(),
+                ..Default::default()
+            })
+            .unwrap();
+        }
+        Arc::new(Mutex::new(conn))
+    }
+
+    fn sqlite_store(conn: Arc<Mutex<Connection>>) -> Arc<dyn MetricsStore> {
+        Arc::new(SqliteStore::new(conn))
+    }
+
+    // Tracks bitcoin in USD against a mock upstream serving /prices and /chain under `upstream`
+    fn test_collector(store: Arc<dyn MetricsStore>, upstream: &str) -> Collector {
+        Collector {
+            store,
+            http: reqwest::Client::new(),
+            api_bases: ApiBases {
+                price: format!("{}/prices", upstream),
+                chain: format!("{}/chain", upstream),
+                coinbase: format!("{}/coinbase", upstream),
+                kraken: format!("{}/kraken", upstream),
+            },
+            fetch_status: Arc::new(Mutex::new(FetchStatus::default())),
+            counters: Arc::new(FetchCounters::default()),
+            latest: Arc::new(Mutex::new(HashMap::new())),
+            assets: vec![DEFAULT_ASSET.to_string()],
+            currencies: vec![BASE_CURRENCY.to_string()],
+            price_sources: vec![PriceSource::CoinGecko],
+            price_aggregation: PriceAggregation::Single,
+            alert_config: None,
+            last_price: Mutex::new(None),
+            price_decimals: None,
+            max_height_regression: DEFAULT_MAX_HEIGHT_REGRESSION,
+            breaker_config: BreakerConfig {
+                failure_threshold: DEFAULT_BREAKER_THRESHOLD,
+                cooldown: Duration::from_secs(DEFAULT_BREAKER_COOLDOWN_SECS as u64),
+            },
+            write_buffer: None,
+            spike_filter: None,
+            max_rows: Mutex::new(None),
+            fetch_fees: false,
+            poll_strategy: PollStrategy::Fixed,
+            height_schedule: Mutex::new(HeightSchedule::default()),
+            updates: broadcast::channel(BROADCAST_CAPACITY).0,
+            last_broadcast: Mutex::new(HashMap::new()),
+            broadcast_price_threshold: DEFAULT_BROADCAST_PRICE_THRESHOLD,
+            sinks: Vec::new(),
+            fetch_lock: tokio::sync::Mutex::new(None),
+            cycles_completed: AtomicU64::new(0),
+        }
+    }
+
+    #[tokio::test]
+    async fn metrics_route_returns_newest_rows_first() {
+        let conn = seeded_conn(&[
+            (DEFAULT_ASSET, 800_000, 60_000.0),
+            (DEFAULT_ASSET, 800_001, 60_500.5),
+            ("ethereum", 800_001, 3_100.0),
+        ]);
+        let route = create_metrics_route(sqlite_store(conn), DEFAULT_METRICS_LIMIT, DEFAULT_MAX_QUERY_LIMIT, FieldCase::Snake, PollInterval::default(), None);
+
+        let res = warp::test::request().path("/api/metrics").reply(&route).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(res.headers()[CACHE_CONTROL], format!("public, max-age={}", POLL_INTERVAL.as_secs()));
+
+        let body: serde_json::Value = serde_json::from_slice(res.body()).unwrap();
+        let rows = body.as_array().unwrap();
+        assert_eq!(rows.len(), 2);
+        assert_eq!(rows[0]["block_height"], 800_001);
+        assert_eq!(rows[0]["btc_price"], 60_500.5);
+        assert_eq!(rows[0]["asset"], DEFAULT_ASSET);
+        assert_eq!(rows[0]["source"], PRICE_SOURCE_COINGECKO);
+        assert_eq!(rows[1]["block_height"], 800_000);
+    }
+
+    #[tokio::test]
+    async fn metrics_route_filters_by_asset() {
+        let conn = seeded_conn(&[(DEFAULT_ASSET, 800_000, 60_000.0), ("ethereum", 800_000, 3_100.0)]);
+        let route = create_metrics_route(sqlite_store(conn), DEFAULT_METRICS_LIMIT, DEFAULT_MAX_QUERY_LIMIT, FieldCase::Snake, PollInterval::default(), None);
+
+        let res = warp::test::request()
+            .path("/api/metrics?asset=ethereum")
+            .reply(&route)
+            .await;
+        assert_eq!(res.status(), StatusCode::OK);
+
+        let body: serde_json::Value = serde_json::from_slice(res.body()).unwrap();
+        assert_eq!(body.as_array().unwrap().len(), 1);
+        assert_eq!(body[0]["asset"], "ethereum");
+    }
+
+    #[tokio::test]
+    async fn metrics_route_returns_empty_array_for_empty_table() {
+        let route =
+            create_metrics_route(sqlite_store(seeded_conn(&[])), DEFAULT_METRICS_LIMIT, DEFAULT_MAX_QUERY_LIMIT, FieldCase::Snake, PollInterval::default(), None);
+
+        let res = warp::test::request().path("/api/metrics").reply(&route).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(res.body().as_ref(), b"[]");
+    }
+
+    #[tokio::test]
+    async fn metrics_route_flags_truncated_limit() {
+        let conn = seeded_conn(&[
+            (DEFAULT_ASSET, 800_000, 60_000.0),
+            (DEFAULT_ASSET, 800_001, 60_100.0),
+            (DEFAULT_ASSET, 800_002, 60_200.0),
+        ]);
+        let route = create_metrics_route(sqlite_store(conn), DEFAULT_METRICS_LIMIT, 2, FieldCase::Snake, PollInterval::default(), None);
+
+        let res = warp::test::request()
+            .path("/api/metrics?limit=3")
+            .reply(&route)
+            .await;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(res.headers()["x-truncated"], "true");
+
+        let body: serde_json::Value = serde_json::from_slice(res.body()).unwrap();
+        assert_eq!(body.as_array().unwrap().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn metrics_route_rejects_other_methods() {
+        let route =
+            create_metrics_route(sqlite_store(seeded_conn(&[])), DEFAULT_METRICS_LIMIT, DEFAULT_MAX_QUERY_LIMIT, FieldCase::Snake, PollInterval::default(), None);
+
+        let res = warp::test::request()
+            .method("POST")
+            .path("/api/metrics")
+            .reply(&route)
+            .await;
+        assert_eq!(res.status(), StatusCode::METHOD_NOT_ALLOWED);
+    }
+
+    #[tokio::test]
+    async fn metrics_route_returns_500_on_query_error() {
+        let conn = seeded_conn(&[]);
+        lock_or_recover(&conn).execute("DROP TABLE metrics", []).unwrap();
+        let route = create_metrics_route(sqlite_store(conn), DEFAULT_METRICS_LIMIT, DEFAULT_MAX_QUERY_LIMIT, FieldCase::Snake, PollInterval::default(), None);
+
+        let res = warp::test::request().path("/api/metrics").reply(&route).await;
+        assert_eq!(res.status(), StatusCode::INTERNAL_SERVER_ERROR);
+        assert!(res.headers().get(RETRY_AFTER).is_none());
+        assert!(res.headers().get(CACHE_CONTROL).is_none());
+    }
+
+    #[tokio::test]
+    async fn average_block_time_route_reports_query_errors() {
+        let conn = seeded_conn(&[]);
+        let route = create_average_block_time_route(Arc::clone(&conn), None);
+
+        let res = warp::test::request().path("/api/metrics/average-block-time").reply(&route).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        let body: serde_json::Value = serde_json::from_slice(res.body()).unwrap();
+        assert_eq!(body["blocks"], 0);
+
+        // A failed query isn't passed off as an empty history
+        lock_or_recover(&conn).execute("DROP TABLE metrics", []).unwrap();
+        let res = warp::test::request().path("/api/metrics/average-block-time").reply(&route).await;
+        assert_eq!(res.status(), StatusCode::INTERNAL_SERVER_ERROR);
+    }
+
+    #[test]
+    fn busy_database_maps_to_503_with_retry_after() {
+        for code in [rusqlite::ffi::SQLITE_BUSY, rusqlite::ffi::SQLITE_LOCKED] {
+            let e = StoreError::Sqlite(rusqlite::Error::SqliteFailure(rusqlite::ffi::Error::new(code), None));
+            let response = db_error_reply(&e);
+            assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+            assert_eq!(response.headers()[RETRY_AFTER], "1");
+        }
+    }
+
+    #[tokio::test]
+    async fn latest_route_serves_primed_value_and_404s_when_empty() {
+        let conn = seeded_conn(&[(DEFAULT_ASSET, 800_000, 60_000.0), (DEFAULT_ASSET, 800_001, 61_000.0)]);
+        let latest: LatestMetrics = Arc::new(Mutex::new(HashMap::new()));
+        for metrics in get_latest_metrics(&lock_or_recover(&conn)).unwrap() {
+            lock_or_recover(&latest).insert(metrics.asset.clone(), metrics);
+        }
+        let route = create_latest_route(latest, PollInterval::default(), None);
+
+        let res = warp::test::request().path("/api/metrics/latest").reply(&route).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        let body: serde_json::Value = serde_json::from_slice(res.body()).unwrap();
+        assert_eq!(body["block_height"], 800_001);
+        assert_eq!(body["is_stale"], false);
+        assert!(body["stale_secs"].is_i64());
+
+        let res = warp::test::request()
+            .path("/api/metrics/latest?asset=ethereum")
+            .reply(&route)
+            .await;
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn last_block_route_serves_newest_height_and_503s_when_empty() {
+        let latest: LatestMetrics = Arc::new(Mutex::new(HashMap::new()));
+        let route = create_last_block_route(Arc::clone(&latest), PollInterval::default(), None);
+
+        let res = warp::test::request().path("/api/metrics/last-block").reply(&route).await;
+        assert_eq!(res.status(), StatusCode::SERVICE_UNAVAILABLE);
+
+        let conn = seeded_conn(&[(DEFAULT_ASSET, 800_000, 60_000.0), ("ethereum", 800_001, 3_000.0)]);
+        for metrics in get_latest_metrics(&lock_or_recover(&conn)).unwrap() {
+            lock_or_recover(&latest).insert(metrics.asset.clone(), metrics);
+        }
+        let res = warp::test::request().path("/api/metrics/last-block").reply(&route).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        let body: serde_json::Value = serde_json::from_slice(res.body()).unwrap();
+        assert_eq!(body["block_height"], 800_001);
+        assert!(body["timestamp"].is_string());
+        assert_eq!(body.as_object().unwrap().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn uptime_route_counts_from_process_start() {
+        let started = ProcessStart {
+            instant: std::time::Instant::now() - Duration::from_secs(90),
+            timestamp: "2024-01-01 00:00:00".to_string(),
+        };
+        let res = warp::test::request().path("/api/uptime").reply(&create_uptime_route(started, None)).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(res.headers()[CACHE_CONTROL], "no-store");
+        let body: serde_json::Value = serde_json::from_slice(res.body()).unwrap();
+        assert_eq!(body["uptime_secs"], 90);
+        assert_eq!(body["started_at"], "2024-01-01 00:00:00");
+    }
+
+    #[test]
+    fn prometheus_output_lists_every_counter() {
+        let counters = FetchCounters::default();
+        counters.record::<(), ()>(&Ok(()));
+        counters.record::<(), ()>(&Err(()));
+        counters.record::<(), ()>(&Err(()));
+
+        let out = counters.to_prometheus();
+        assert!(out.contains("bitcoin_explore_fetch_success_total 1\n"));
+        assert!(out.contains("bitcoin_explore_fetch_failure_total 2\n"));
+        assert!(out.contains("bitcoin_explore_price_fallback_used_total 0\n"));
+        assert!(out.contains("# TYPE bitcoin_explore_db_write_errors_total counter\n"));
+        assert!(out.contains("bitcoin_explore_poller_restarts_total 0\n"));
+
+        counters.btc_price_latency_ms.store(412, Ordering::Relaxed);
+        let out = counters.to_prometheus();
+        assert!(out.contains("# TYPE bitcoin_explore_fetch_latency_ms gauge\n"));
+        assert!(out.contains("bitcoin_explore_fetch_latency_ms{source=\"btc_price\"} 412\n"));
+    }
+
+    #[test]
+    fn parses_duration_units() {
+        assert_eq!(parse_duration_secs("90s"), Some(90));
+        assert_eq!(parse_duration_secs("15m"), Some(900));
+        assert_eq!(parse_duration_secs("1h"), Some(3600));
+        assert_eq!(parse_duration_secs("7d"), Some(604_800));
+        assert_eq!(parse_duration_secs("0h"), None);
+        assert_eq!(parse_duration_secs("h"), None);
+        assert_eq!(parse_duration_secs("1w"), None);
+        assert_eq!(parse_duration_secs("1é"), None);
+        assert_eq!(parse_duration_secs(""), None);
+    }
+
+    #[test]
+    fn normalizes_timestamp_formats() {
+        assert_eq!(normalize_timestamp("2024-01-01 12:30:00").as_deref(), Some("2024-01-01 12:30:00"));
+        assert_eq!(normalize_timestamp("2024-01-01T12:30:00+02:00").as_deref(), Some("2024-01-01 10:30:00"));
+        assert_eq!(normalize_timestamp("2024-01-01").as_deref(), Some("2024-01-01 00:00:00"));
+        assert_eq!(normalize_timestamp("yesterday"), None);
+    }
+
+    #[tokio::test]
+    async fn buckets_route_aggregates_ohlc() {
+        let conn = seeded_conn(&[]);
+        {
+            let conn = lock_or_recover(&conn);
+            for (ts, height, price) in [
+                ("2024-01-01 00:05:00", 100, 10.0),
+                ("2024-01-01 00:20:00", 100, 30.0),
+                ("2024-01-01 00:50:00", 101, 5.0),
+                ("2024-01-01 00:55:00", 101, 20.0),
+                ("2024-01-01 01:10:00", 102, 40.0),
+            ] {
+                conn.execute(
+                    "INSERT INTO metrics (block_height, btc_price, timestamp) VALUES (?1, ?2, ?3)",
+                    params![height, price, ts],
+                )
+                .unwrap();
+            }
+        }
+        let route = create_buckets_route(conn, None);
+
+        let res = warp::test::request()
+            .path("/api/metrics/buckets?interval=1h&from=2024-01-01&to=2024-01-02")
+            .reply(&route)
+            .await;
+        assert_eq!(res.status(), StatusCode::OK);
+
+        let body: serde_json::Value = serde_json::from_slice(res.body()).unwrap();
+        let buckets = body.as_array().unwrap();
+        assert_eq!(buckets.len(), 2);
+        assert_eq!(buckets[0]["start"], "2024-01-01 00:00:00");
+        assert_eq!(buckets[0]["open"], 10.0);
+        assert_eq!(buckets[0]["high"], 30.0);
+        assert_eq!(buckets[0]["low"], 5.0);
+        assert_eq!(buckets[0]["close"], 20.0);
+        assert_eq!(buckets[0]["avg"], 16.25);
+        assert_eq!(buckets[0]["max_block_height"], 101);
+        assert_eq!(buckets[0]["samples"], 4);
+        assert_eq!(buckets[1]["open"], 40.0);
+
+        let res = warp::test::request()
+            .path("/api/metrics/buckets?interval=soon")
+            .reply(&route)
+            .await;
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[test]
+    fn parses_coingecko_market_chart() {
+        let body = r#"{"prices":[[1704067200000,42280.23],[1704153600000,44187.14]],"market_caps":[],"total_volumes":[]}"#;
+        let chart: MarketChart = serde_json::from_str(body).unwrap();
+        assert_eq!(chart.prices, vec![(1704067200000.0, 42280.23), (1704153600000.0, 44187.14)]);
+    }
+
+    #[test]
+    fn batch_insert_keeps_row_timestamps() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        create_metrics_table(&conn).unwrap();
+        let rows: Vec<Metrics> = ["2024-01-01 00:00:00", "2024-01-02 00:00:00"]
+            .iter()
+            .map(|timestamp| Metrics {
+                id: 0,
+                block_height: None,
+                block_hash: None,
+                fees: FeeEstimates::default(),
+                btc_price: Some(42_280.23),
+                prices: BTreeMap::new(),
+                spread: PriceSpread::default(),
+                timestamp: timestamp.to_string(),
+                asset: DEFAULT_ASSET.to_string(),
+                source: PRICE_SOURCE_COINGECKO.to_string(),
+                fetch_latency_ms: None,
+            })
+            .collect();
+
+        assert_eq!(save_metrics_batch(&mut conn, &rows).unwrap(), 2);
+        let metrics = get_metrics_by_id(&conn, 2).unwrap();
+        assert_eq!(metrics.block_height, None);
+        assert_eq!(metrics.timestamp, "2024-01-02 00:00:00");
+        assert_eq!(count_metrics(&conn, DEFAULT_ASSET, None, None).unwrap(), 2);
+        assert!(get_block_first_seen(&conn).unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn openapi_document_describes_public_endpoints() {
+        let res = warp::test::request().path("/api/openapi.json").reply(&create_openapi_route(None)).await;
+        assert_eq!(res.status(), 200);
+        assert_eq!(
+            res.headers()[CACHE_CONTROL],
+            format!("public, max-age={}", STATIC_DOC_MAX_AGE.as_secs())
+        );
+
+        let document: serde_json::Value = serde_json::from_slice(res.body()).unwrap();
+        assert_eq!(document["openapi"], "3.0.3");
+        for path in ["/api/metrics", "/api/metrics/latest", "/api/metrics/average-block-time", "/api/health"] {
+            assert!(document["paths"][path]["get"].is_object(), "missing {}", path);
+        }
+        assert!(document["components"]["schemas"]["Metrics"]["properties"]["block_height"].is_object());
+    }
+
+    // The document is hand-written, so this keeps the Metrics schema in step with the struct
+    #[test]
+    fn openapi_metrics_schema_matches_struct_fields() {
+        let sample = Metrics {
+            id: 1,
+            block_height: Some(800_000),
+            block_hash: None,
+            fees: FeeEstimates::default(),
+            btc_price: Some(60_000.0),
+            prices: BTreeMap::from([(BASE_CURRENCY.to_string(), 60_000.0)]),
+            spread: PriceSpread::default(),
+            timestamp: "2024-01-01 00:00:00".to_string(),
+            asset: DEFAULT_ASSET.to_string(),
+            source: PRICE_SOURCE_COINGECKO.to_string(),
+            fetch_latency_ms: Some(412),
+        };
+        let serialized = serde_json::to_value(&sample).unwrap();
+        let fields: Vec<&String> = serialized.as_object().unwrap().keys().collect();
+
+        let document = openapi_document();
+        let schema = &document["components"]["schemas"]["Metrics"];
+        let properties: Vec<&String> = schema["properties"].as_object().unwrap().keys().collect();
+        assert_eq!(fields, properties);
+        for required in schema["required"].as_array().unwrap() {
+            assert!(fields.iter().any(|field| *field == required), "unknown required field {}", required);
+        }
+    }
+
+    // Wire format fixtures for the snapshot tests below: one fully populated sample and one
+    // with every optional field null
+    fn snapshot_samples() -> Vec<Metrics> {
+        vec![
+            Metrics {
+                id: 2,
+                block_height: Some(800_001),
+                block_hash: Some("00000000000000000002a7c4c1e48d76c5a37902165a270156b7a8d72728a054".to_string()),
+                fees: FeeEstimates {
+                    fee_1_block: Some(24.5),
+                    fee_6_blocks: Some(12.0),
+                    fee_144_blocks: Some(3.1),
+                },
+                btc_price: Some(60_123.45),
+                prices: BTreeMap::from([("eur".to_string(), 55_010.2), (BASE_CURRENCY.to_string(), 60_123.45)]),
+                spread: PriceSpread::default(),
+                timestamp: "2024-01-01 00:10:00".to_string(),
+                asset: DEFAULT_ASSET.to_string(),
+                source: PRICE_SOURCE_COINGECKO.to_string(),
+                fetch_latency_ms: Some(412),
+            },
+            Metrics {
+                id: 1,
+                block_height: None,
+                block_hash: None,
+                fees: FeeEstimates::default(),
+                btc_price: None,
+                prices: BTreeMap::new(),
+                spread: PriceSpread::default(),
+                timestamp: "2024-01-01 00:00:00".to_string(),
+                asset: DEFAULT_ASSET.to_string(),
+                source: PRICE_SOURCE_NONE.to_string(),
+                fetch_latency_ms: None,
+            },
+        ]
+    }
+
+    // A failing snapshot means a client-visible change to the JSON; review it with
+    // `cargo insta review` and commit the updated .snap only if the change is intended
+    #[test]
+    fn metrics_wire_format_snapshot() {
+        insta::assert_json_snapshot!("metrics", snapshot_samples()[0]);
+        insta::assert_json_snapshot!("metrics_list", snapshot_samples());
+    }
+
+    #[test]
+    fn latest_wire_format_snapshot() {
+        let samples = snapshot_samples();
+        let reply = LatestReply {
+            metrics: &samples[0],
+            is_stale: false,
+            stale_secs: Some(20),
+        };
+        insta::assert_json_snapshot!("latest", reply);
+    }
+
+    #[test]
+    fn stats_wire_format_snapshot() {
+        let first_seen = vec![
+            (800_000, "2024-01-01 00:00:00".to_string()),
+            (800_001, "2024-01-01 00:10:00".to_string()),
+            (800_003, "2024-01-01 00:25:00".to_string()),
+        ];
+        insta::assert_json_snapshot!("block_time_stats", compute_block_times(&first_seen));
+        insta::assert_json_snapshot!("block_time_stats_empty", compute_block_times(&[]));
+    }
+
+    #[tokio::test]
+    async fn error_wire_format_snapshot() {
+        let response = error_reply(StatusCode::BAD_REQUEST, "limit must be at least 1");
+        let body = warp::hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        insta::assert_json_snapshot!("error", body);
+    }
+
+    #[test]
+    fn response_snippet_truncates_long_bodies() {
+        let html = format!("  <html>{}</html>", "é".repeat(300));
+        let snippet = response_snippet(&html);
+        assert!(snippet.starts_with("<html>"));
+        assert!(snippet.ends_with("..."));
+        assert_eq!(snippet.chars().count(), RESPONSE_SNIPPET_CHARS + 3);
+
+        assert_eq!(response_snippet("Too Many Requests\n"), "Too Many Requests");
+    }
+
+    #[tokio::test]
+    async fn metrics_route_uses_configured_default_limit() {
+        let conn = seeded_conn(&[
+            (DEFAULT_ASSET, 800_000, 60_000.0),
+            (DEFAULT_ASSET, 800_001, 60_100.0),
+            (DEFAULT_ASSET, 800_002, 60_200.0),
+        ]);
+        let route = create_metrics_route(sqlite_store(conn), 2, DEFAULT_MAX_QUERY_LIMIT, FieldCase::Snake, PollInterval::default(), None);
+
+        let res = warp::test::request().path("/api/metrics").reply(&route).await;
+        let body: serde_json::Value = serde_json::from_slice(res.body()).unwrap();
+        assert_eq!(body.as_array().unwrap().len(), 2);
+        assert!(res.headers().get("x-truncated").is_none());
+
+        let res = warp::test::request().path("/api/metrics?limit=3").reply(&route).await;
+        let body: serde_json::Value = serde_json::from_slice(res.body()).unwrap();
+        assert_eq!(body.as_array().unwrap().len(), 3);
+    }
+
+    #[tokio::test]
+    async fn partial_metrics_keep_the_field_that_was_fetched() {
+        let conn = seeded_conn(&[]);
+        let store = sqlite_store(Arc::clone(&conn));
+
+        let metrics = store
+            .save_metrics(&MetricsRow {
+                asset: DEFAULT_ASSET.to_string(),
+                block_height: Some(800_000),
+                source: PRICE_SOURCE_NONE.to_string(),
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+        assert_eq!(metrics.block_height, Some(800_000));
+        assert_eq!(metrics.btc_price, None);
+        store
+            .save_fetch_error("btc_price", "timeout", "Error fetching prices: timed out")
+            .await
+            .unwrap();
+
+        let conn = lock_or_recover(&conn);
+        let (source, kind, message): (String, String, String) = conn
+            .query_row("SELECT source, kind, message FROM fetch_errors", [], |row| {
+                Ok((row.get(0)?, row.get(1)?, row.get(2)?))
+            })
+            .unwrap();
+        assert_eq!(source, "btc_price");
+        assert_eq!(kind, "timeout");
+        assert_eq!(message, "Error fetching prices: timed out");
+    }
+
+    #[tokio::test]
+    async fn version_route_reports_crate_version() {
+        let res = warp::test::request().path("/api/version").reply(&create_version_route(None)).await;
+        assert_eq!(res.status(), StatusCode::OK);
+
+        let body: serde_json::Value = serde_json::from_slice(res.body()).unwrap();
+        assert_eq!(body["version"], env!("CARGO_PKG_VERSION"));
+        assert!(body["built_at"].is_string());
+    }
+
+    #[test]
+    fn round_price_strips_float_noise() {
+        assert_eq!(round_price(63_241.999_999_98, 2), 63_242.0);
+        assert_eq!(round_price(63_241.234_9, 2), 63_241.23);
+        assert_eq!(round_price(3_120.456, 0), 3_120.0);
+        assert_eq!(round_price(0.123_456_789, 8), 0.123_456_79);
+    }
+
+    #[tokio::test]
+    async fn health_route_reports_blocks_behind_live_tip() {
+        let mut latest = HashMap::new();
+        latest.insert(
+            DEFAULT_ASSET.to_string(),
+            Metrics {
+                id: 1,
+                block_height: Some(800_000),
+                block_hash: None,
+                fees: FeeEstimates::default(),
+                btc_price: Some(60_000.0),
+                prices: BTreeMap::new(),
+                spread: PriceSpread::default(),
+                timestamp: "2024-01-01 00:00:00".to_string(),
+                asset: DEFAULT_ASSET.to_string(),
+                source: PRICE_SOURCE_COINGECKO.to_string(),
+                fetch_latency_ms: None,
+            },
+        );
+        // A fresh cache entry means the route never goes to the network
+        let chain_tip = ChainTip::new(reqwest::Client::new(), DEFAULT_CHAIN_API_BASE.to_string());
+        *lock_or_recover(&chain_tip.cached) = Some((std::time::Instant::now(), 800_005));
+
+        let route = create_health_route(
+            Arc::new(Mutex::new(FetchStatus::default())),
+            Arc::new(FetchCounters::default()),
+            Arc::new(Mutex::new(latest)),
+            Some(Arc::new(chain_tip)),
+            None,
+        );
+        let res = warp::test::request().path("/api/health").reply(&route).await;
+        let body: serde_json::Value = serde_json::from_slice(res.body()).unwrap();
+        assert_eq!(body["status"], "degraded");
+        assert_eq!(body["chain_tip"], 800_005);
+        assert_eq!(body["blocks_behind"], 5);
+    }
+
+    #[tokio::test]
+    async fn health_route_skips_tip_check_when_disabled() {
+        let route = create_health_route(
+            Arc::new(Mutex::new(FetchStatus::default())),
+            Arc::new(FetchCounters::default()),
+            Arc::new(Mutex::new(HashMap::new())),
+            None,
+            None,
+        );
+        let res = warp::test::request().path("/api/health").reply(&route).await;
+        assert_eq!(res.headers()[CACHE_CONTROL], "no-store");
+        let body: serde_json::Value = serde_json::from_slice(res.body()).unwrap();
+        assert_eq!(body["status"], "ok");
+        assert!(body.get("blocks_behind").is_none());
+    }
+
+    #[tokio::test]
+    async fn base_path_prefixes_routes() {
+        let version = || warp::path!("api" / "version").map(|| "0.1.0");
+        let route = with_base_path("/btc/v1/").and(version());
+
+        let res = warp::test::request().path("/btc/v1/api/version").reply(&route).await;
+        assert_eq!(res.status(), StatusCode::OK);
+
+        let res = warp::test::request().path("/api/version").reply(&route).await;
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+
+        let route = with_base_path("").and(version());
+        let res = warp::test::request().path("/api/version").reply(&route).await;
+        assert_eq!(res.status(), StatusCode::OK);
+    }
+
+    #[test]
+    fn preferred_format_honors_accept_quality() {
+        assert_eq!(preferred_format("text/csv"), ResponseFormat::Csv);
+        assert_eq!(preferred_format("text/csv, application/json;q=0.5"), ResponseFormat::Csv);
+        assert_eq!(preferred_format("application/json, text/csv;q=0.9"), ResponseFormat::Json);
+        assert_eq!(preferred_format("*/*"), ResponseFormat::Json);
+        assert_eq!(preferred_format("text/csv;q=0"), ResponseFormat::Json);
+    }
+
+    #[tokio::test]
+    async fn metrics_route_returns_csv_when_accepted() {
+        let conn = seeded_conn(&[(DEFAULT_ASSET, 800_000, 60_000.5)]);
+        let route = create_metrics_route(sqlite_store(conn), DEFAULT_METRICS_LIMIT, DEFAULT_MAX_QUERY_LIMIT, FieldCase::Snake, PollInterval::default(), None);
+
+        let res = warp::test::request()
+            .path("/api/metrics")
+            .header("accept", "text/csv")
+            .reply(&route)
+            .await;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(res.headers()[CONTENT_TYPE], "text/csv; charset=utf-8");
+
+        let body = std::str::from_utf8(res.body()).unwrap();
+        let mut lines = body.lines();
+        assert_eq!(
+            lines.next(),
+            Some("id,block_height,btc_price,timestamp,asset,source,fetch_latency_ms,block_hash,fee_1_block,fee_6_blocks,fee_144_blocks,price_spread")
+        );
+        let row: Vec<&str> = lines.next().unwrap().split(',').collect();
+        assert_eq!(&row[..3], ["1", "800000", "60000.5"]);
+        assert_eq!(&row[4..], ["bitcoin", "coingecko", "", "", "", "", "", ""]);
+    }
+
+    #[tokio::test]
+    async fn metrics_route_converts_timestamps_to_requested_zone() {
+        let conn = seeded_conn(&[(DEFAULT_ASSET, 800_000, 60_000.0)]);
+        lock_or_recover(&conn)
+            .execute("UPDATE metrics SET timestamp = '2024-07-01 12:00:00'", [])
+            .unwrap();
+        let route = create_metrics_route(sqlite_store(conn), DEFAULT_METRICS_LIMIT, DEFAULT_MAX_QUERY_LIMIT, FieldCase::Snake, PollInterval::default(), None);
+
+        let res = warp::test::request().path("/api/metrics?tz=America/New_York").reply(&route).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        let body: serde_json::Value = serde_json::from_slice(res.body()).unwrap();
+        assert_eq!(body[0]["timestamp"], "2024-07-01T08:00:00-04:00");
+
+        let res = warp::test::request().path("/api/metrics").reply(&route).await;
+        let body: serde_json::Value = serde_json::from_slice(res.body()).unwrap();
+        assert_eq!(body[0]["timestamp"], "2024-07-01 12:00:00");
+
+        let res = warp::test::request().path("/api/metrics?tz=Mars/Olympus").reply(&route).await;
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[test]
+    fn circuit_breaker_opens_after_threshold_and_probes_after_cooldown() {
+        let config = BreakerConfig {
+            failure_threshold: 2,
+            cooldown: Duration::from_secs(60),
+        };
+        let mut breaker = CircuitBreaker::default();
+
+        breaker.record("test", false, &config);
+        assert!(breaker.allow("test"));
+        breaker.record("test", false, &config);
+        assert_eq!(breaker.state, BreakerState::Open);
+        assert!(!breaker.allow("test"));
+
+        // Pretend the cooldown has passed: one probe goes through, and a failed probe reopens
+        breaker.open_until = Some(std::time::Instant::now());
+        assert!(breaker.allow("test"));
+        assert_eq!(breaker.state, BreakerState::HalfOpen);
+        breaker.record("test", false, &config);
+        assert_eq!(breaker.state, BreakerState::Open);
+        // A failed probe doubles the cooldown
+        assert_eq!(breaker.failed_probes, 1);
+        assert!(breaker.open_until.unwrap() > std::time::Instant::now() + Duration::from_secs(90));
+
+        breaker.open_until = Some(std::time::Instant::now());
+        assert!(breaker.allow("test"));
+        breaker.record("test", true, &config);
+        assert_eq!(breaker.state, BreakerState::Closed);
+        assert_eq!(breaker.consecutive_failures, 0);
+        assert_eq!(breaker.failed_probes, 0);
+    }
+
+    #[tokio::test]
+    async fn static_route_falls_back_to_index_outside_api() {
+        let dir = std::env::temp_dir().join(format!("bitcoin-explore-static-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("index.html"), "<h1>dashboard</h1>").unwrap();
+        std::fs::write(dir.join("app.js"), "console.log(1)").unwrap();
+        let route = create_static_route(Some(dir.to_string_lossy().into_owned()), None);
+
+        let res = warp::test::request().path("/app.js").reply(&route).await;
+        assert_eq!(res.body().as_ref(), b"console.log(1)");
+
+        let res = warp::test::request().path("/charts/daily").reply(&route).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(res.body().as_ref(), b"<h1>dashboard</h1>");
+
+        let res = warp::test::request().path("/api/unknown").reply(&route).await;
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn write_buffer_flushes_in_bounded_batches_and_retries_failures() {
+        let buffer = WriteBuffer {
+            max_samples: 2,
+            max_age: Duration::from_secs(3600),
+            pending: Mutex::new(PendingWrites::default()),
+        };
+        let sample = |height: u64| Metrics {
+            id: 0,
+            block_height: Some(height),
+            block_hash: None,
+            fees: FeeEstimates::default(),
+            btc_price: Some(60_000.0),
+            prices: BTreeMap::new(),
+            spread: PriceSpread::default(),
+            timestamp: "2024-01-01 00:00:00".to_string(),
+            asset: DEFAULT_ASSET.to_string(),
+            source: PRICE_SOURCE_COINGECKO.to_string(),
+            fetch_latency_ms: None,
+        };
+
+        buffer.push(sample(800_000));
+        assert!(!buffer.is_due());
+        buffer.push(sample(800_001));
+        assert!(buffer.is_due());
+        // Unsaved rows are served without an id
+        assert!(serde_json::to_value(sample(800_000)).unwrap().get("id").is_none());
+
+        // A failed flush goes back ahead of newer samples
+        let failed = buffer.take_batch();
+        buffer.push(sample(800_002));
+        buffer.restore(failed);
+        let heights = |rows: &[Metrics]| rows.iter().map(|row| row.block_height.unwrap()).collect::<Vec<_>>();
+        // and the backlog is written back a batch at a time
+        let first = buffer.take_batch();
+        assert_eq!(heights(&first.rows), [800_000, 800_001]);
+        let second = buffer.take_batch();
+        assert_eq!(heights(&second.rows), [800_002]);
+        assert!(buffer.take_batch().rows.is_empty());
+
+        let conn = seeded_conn(&[]);
+        let store = sqlite_store(Arc::clone(&conn));
+        assert_eq!(store.save_metrics_batch(&first.rows).await.unwrap(), 2);
+        assert_eq!(store.save_metrics_batch(&second.rows).await.unwrap(), 1);
+        assert_eq!(count_metrics(&lock_or_recover(&conn), DEFAULT_ASSET, None, None).unwrap(), 3);
+
+        // Through a long outage only the newest WRITE_BUFFER_MAX_BATCHES batches are kept
+        let limit = 2 * WRITE_BUFFER_MAX_BATCHES as u64;
+        for height in 0..limit + 3 {
+            buffer.push(sample(height));
+        }
+        let failed = buffer.take_batch();
+        assert_eq!(heights(&failed.rows), [3, 4]);
+        buffer.push(sample(limit + 3));
+        buffer.restore(failed);
+        let mut kept = Vec::new();
+        loop {
+            let batch = buffer.take_batch();
+            if batch.rows.is_empty() {
+                break;
+            }
+            kept.extend(heights(&batch.rows));
+        }
+        assert_eq!(kept, (4..=limit + 3).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn extracts_requested_quote_currencies() {
+        let body = r#"{"bitcoin":{"usd":63241.57,"eur":58000.1,"usd_24h_change":-1.2}}"#;
+        let parsed: SimplePriceResponse = serde_json::from_str(body).unwrap();
+        let currencies = vec!["usd".to_string(), "eur".to_string(), "gbp".to_string()];
+
+        let prices = parsed["bitcoin"].prices(&currencies);
+        assert_eq!(prices, BTreeMap::from([("eur".to_string(), 58000.1), ("usd".to_string(), 63241.57)]));
+    }
+
+    #[tokio::test]
+    async fn history_includes_per_currency_prices() {
+        let conn = seeded_conn(&[]);
+        let store = sqlite_store(Arc::clone(&conn));
+        let prices = BTreeMap::from([("eur".to_string(), 55_000.0), ("usd".to_string(), 60_000.0)]);
+        store
+            .save_metrics(&MetricsRow {
+                asset: DEFAULT_ASSET.to_string(),
+                block_height: Some(800_000),
+                btc_price: Some(60_000.0),
+                prices: prices.clone(),
+                source: PRICE_SOURCE_COINGECKO.to_string(),
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+        store
+            .save_metrics(&MetricsRow {
+                asset: DEFAULT_ASSET.to_string(),
+                source: PRICE_SOURCE_NONE.to_string(),
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+
+        let history = store.get_metrics_history(DEFAULT_ASSET, None, DEFAULT_MAX_QUERY_LIMIT).await.unwrap();
+        assert_eq!(history.metrics.len(), 2);
+        assert!(history.metrics[0].prices.is_empty());
+        assert_eq!(history.metrics[1].prices, prices);
+
+        let latest = store.get_latest_metrics().await.unwrap();
+        assert_eq!(latest.len(), 1);
+        assert_eq!(latest[0].id, history.metrics[0].id);
+    }
+
+    #[tokio::test]
+    async fn download_route_returns_a_sqlite_snapshot() {
+        let conn = seeded_conn(&[(DEFAULT_ASSET, 800_000, 60_000.0), (DEFAULT_ASSET, 800_001, 60_100.0)]);
+        let route = create_download_route(conn, Some("secret".to_string()), None);
+
+        let res = warp::test::request().path("/api/metrics/download").reply(&route).await;
+        assert_eq!(res.status(), StatusCode::FORBIDDEN);
+
+        let res = warp::test::request()
+            .path("/api/metrics/download")
+            .header("x-export-token", "secret")
+            .reply(&route)
+            .await;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(res.headers()[CONTENT_TYPE], "application/x-sqlite3");
+        assert!(res.body().starts_with(b"SQLite format 3\0"));
+
+        let path = std::env::temp_dir().join(format!("bitcoin-explore-download-test-{}.db", std::process::id()));
+        std::fs::write(&path, res.body()).unwrap();
+        let snapshot = Connection::open(&path).unwrap();
+        assert_eq!(count_metrics(&snapshot, DEFAULT_ASSET, None, None).unwrap(), 2);
+        drop(snapshot);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn csv_route_streams_every_page() {
+        // Spans three export pages so the id cursor has to carry across chunks
+        let rows: Vec<_> = (0..EXPORT_PAGE_SIZE as u64 * 2 + 7)
+            .map(|i| (DEFAULT_ASSET, 800_000 + i, 60_000.0 + i as f64))
+            .collect();
+        let route = create_csv_export_route(seeded_conn(&rows), Some("secret".to_string()), None);
+
+        let res = warp::test::request().path("/api/metrics/csv").reply(&route).await;
+        assert_eq!(res.status(), StatusCode::FORBIDDEN);
+
+        // The token is only taken from the header, never the URL
+        let res = warp::test::request().path("/api/metrics/csv?token=secret").reply(&route).await;
+        assert_eq!(res.status(), StatusCode::FORBIDDEN);
+        let res = warp::test::request()
+            .path("/api/metrics/csv")
+            .header("x-export-token", "secre")
+            .reply(&route)
+            .await;
+        assert_eq!(res.status(), StatusCode::FORBIDDEN);
+
+        let res = warp::test::request()
+            .path("/api/metrics/csv")
+            .header("x-export-token", "secret")
+            .reply(&route)
+            .await;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(res.headers()[CONTENT_TYPE], "text/csv; charset=utf-8");
+        let body = std::str::from_utf8(res.body()).unwrap();
+        let lines: Vec<&str> = body.lines().collect();
+        assert_eq!(lines[0], METRICS_CSV_HEADER.trim_end());
+        assert_eq!(lines.len(), rows.len() + 1);
+        assert!(lines[1].starts_with("1,800000,60000,"));
+        assert!(lines[rows.len()].starts_with(&format!("{},", rows.len())));
+    }
+
+    #[tokio::test]
+    async fn metrics_route_streams_limits_past_one_page() {
+        let mut rows: Vec<_> = (0..EXPORT_PAGE_SIZE as u64 * 2 + 7)
+            .map(|i| (DEFAULT_ASSET, 800_000 + i, 60_000.0 + i as f64))
+            .collect();
+        rows.insert(10, ("ethereum", 800_010, 3_000.0));
+        let route = create_metrics_route(sqlite_store(seeded_conn(&rows)), DEFAULT_METRICS_LIMIT, 1_100, FieldCase::Snake, PollInterval::default(), None);
+
+        let res = warp::test::request().path("/api/metrics?limit=600").reply(&route).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert!(res.headers().get(ETAG).is_none());
+        let body: Vec<serde_json::Value> = serde_json::from_slice(res.body()).unwrap();
+        assert_eq!(body.len(), 600);
+        assert_eq!(body[0]["id"], rows.len());
+        assert!(body.windows(2).all(|pair| pair[0]["id"].as_i64() > pair[1]["id"].as_i64()));
+
+        // Every bitcoin row, across three pages and skipping the other asset's
+        let res = warp::test::request().path("/api/metrics?limit=5000").reply(&route).await;
+        assert_eq!(res.headers()["x-truncated"], "true");
+        let body: Vec<serde_json::Value> = serde_json::from_slice(res.body()).unwrap();
+        assert_eq!(body.len(), rows.len() - 1);
+        assert!(body.iter().all(|row| row["asset"] == DEFAULT_ASSET));
+        assert_eq!(body.last().unwrap()["id"], 1);
+    }
+
+    #[tokio::test]
+    async fn streamed_metrics_follow_field_case_and_compression() {
+        let rows: Vec<_> = (0..EXPORT_PAGE_SIZE as u64 + 5)
+            .map(|i| (DEFAULT_ASSET, 800_000 + i, 60_000.0))
+            .collect();
+        let metrics = create_metrics_route(sqlite_store(seeded_conn(&rows)), DEFAULT_METRICS_LIMIT, 1_000, FieldCase::Camel, PollInterval::default(), None);
+        let route = with_compression(with_field_case(FieldCase::Camel, metrics));
+
+        // Buffered and streamed replies come out in the same key style
+        for limit in [10, 600] {
+            let res = warp::test::request().path(&format!("/api/metrics?limit={}", limit)).reply(&route).await;
+            let body: Vec<serde_json::Value> = serde_json::from_slice(res.body()).unwrap();
+            assert_eq!(body[0]["blockHeight"], 800_000 + rows.len() as u64 - 1, "limit {}", limit);
+            assert!(body[0].get("block_height").is_none(), "limit {}", limit);
+        }
+
+        let res = warp::test::request()
+            .path("/api/metrics?limit=600")
+            .header("accept-encoding", "gzip")
+            .reply(&route)
+            .await;
+        assert_eq!(res.headers()[CONTENT_ENCODING], "gzip");
+        let mut json = String::new();
+        std::io::Read::read_to_string(&mut flate2::read::GzDecoder::new(&res.body()[..]), &mut json).unwrap();
+        let body: Vec<serde_json::Value> = serde_json::from_str(&json).unwrap();
+        assert_eq!(body.len(), rows.len());
+        assert!(body.iter().all(|row| row.get("btcPrice").is_some()));
+    }
+
+    fn alert_config(above: Option<f64>, below: Option<f64>) -> AlertConfig {
+        AlertConfig { above, below, webhook_url: "http://alerts.invalid/hook".to_string() }
+    }
+
+    fn crossings(config: &AlertConfig, previous_price: f64, price: f64) -> Vec<(&'static str, f64)> {
+        detect_price_crossings(config, previous_price, price)
+            .iter()
+            .map(|alert| (alert.direction, alert.threshold))
+            .collect()
+    }
+
+    #[test]
+    fn price_alerts_fire_only_when_a_threshold_is_crossed() {
+        let above = alert_config(Some(70_000.0), None);
+        assert_eq!(crossings(&above, 69_000.0, 71_000.0), vec![("above", 70_000.0)]);
+        // Already above, or falling back under, isn't a crossing up
+        assert!(crossings(&above, 71_000.0, 72_000.0).is_empty());
+        assert!(crossings(&above, 71_000.0, 69_000.0).is_empty());
+        assert!(crossings(&above, 68_000.0, 69_000.0).is_empty());
+
+        let below = alert_config(None, Some(50_000.0));
+        assert_eq!(crossings(&below, 51_000.0, 49_000.0), vec![("below", 50_000.0)]);
+        assert!(crossings(&below, 49_000.0, 48_000.0).is_empty());
+        assert!(crossings(&below, 49_000.0, 51_000.0).is_empty());
+        assert!(crossings(&below, 52_000.0, 51_000.0).is_empty());
+
+        let alert = &detect_price_crossings(&below, 51_000.0, 49_000.0)[0];
+        assert_eq!((alert.previous_price, alert.price), (51_000.0, 49_000.0));
+    }
+
+    #[test]
+    fn price_alert_thresholds_count_as_the_above_side() {
+        // Reaching `above` exactly is a crossing up
+        let above = alert_config(Some(70_000.0), None);
+        assert_eq!(crossings(&above, 69_999.0, 70_000.0), vec![("above", 70_000.0)]);
+        assert!(crossings(&above, 70_000.0, 70_500.0).is_empty());
+
+        // Reaching `below` exactly isn't yet a crossing down, dropping past it is
+        let below = alert_config(None, Some(50_000.0));
+        assert!(crossings(&below, 50_001.0, 50_000.0).is_empty());
+        assert_eq!(crossings(&below, 50_000.0, 49_999.0), vec![("below", 50_000.0)]);
+    }
+
+    #[test]
+    fn price_alerts_check_both_thresholds() {
+        let both = alert_config(Some(70_000.0), Some(50_000.0));
+        assert_eq!(crossings(&both, 60_000.0, 71_000.0), vec![("above", 70_000.0)]);
+        assert_eq!(crossings(&both, 60_000.0, 49_000.0), vec![("below", 50_000.0)]);
+        assert!(crossings(&both, 60_000.0, 65_000.0).is_empty());
+        // A jump across the whole band only leaves from above, so only the below alert fires
+        assert_eq!(crossings(&both, 71_000.0, 49_000.0), vec![("below", 50_000.0)]);
+        assert_eq!(crossings(&both, 49_000.0, 71_000.0), vec![("above", 70_000.0)]);
+    }
+
+    #[test]
+    fn rejects_block_height_regressions_beyond_reorg_depth() {
+        assert!(check_height_regression(None, 800_000, 6).is_ok());
+        assert!(check_height_regression(Some(800_000), 800_001, 6).is_ok());
+        assert!(check_height_regression(Some(800_000), 799_994, 6).is_ok());
+        assert!(check_height_regression(Some(800_000), 799_993, 6).is_err());
+        assert!(check_height_regression(Some(800_000), 799_999, 0).is_err());
+    }
+
+    #[test]
+    fn lttb_keeps_endpoints_and_extremes() {
+        let points: Vec<(f64, f64)> = (0..100)
+            .map(|i| (i as f64, if i == 37 { 500.0 } else if i == 71 { -500.0 } else { (i % 5) as f64 }))
+            .collect();
+
+        let sampled = lttb(&points, 10);
+        assert_eq!(sampled.len(), 10);
+        assert_eq!(sampled[0], 0);
+        assert_eq!(sampled[9], 99);
+        assert!(sampled.contains(&37));
+        assert!(sampled.contains(&71));
+        assert!(sampled.windows(2).all(|pair| pair[0] < pair[1]));
+
+        assert_eq!(lttb(&points[..5], 10), vec![0, 1, 2, 3, 4]);
+    }
+
+    #[tokio::test]
+    async fn downsample_route_reduces_series() {
+        let conn = seeded_conn(&[]);
+        {
+            let conn = lock_or_recover(&conn);
+            for minute in 0..60 {
+                conn.execute(
+                    "INSERT INTO metrics (block_height, btc_price, timestamp) VALUES (?1, ?2, ?3)",
+                    params![100 + minute / 10, 1000.0 + minute as f64, format!("2024-01-01 00:{:02}:00", minute)],
+                )
+                .unwrap();
+            }
+        }
+        let route = create_downsample_route(conn, None);
+
+        let res = warp::test::request()
+            .path("/api/metrics/downsample?points=12&from=2024-01-01&to=2024-01-02")
+            .reply(&route)
+            .await;
+        assert_eq!(res.status(), StatusCode::OK);
+
+        let body: serde_json::Value = serde_json::from_slice(res.body()).unwrap();
+        let points = body.as_array().unwrap();
+        assert_eq!(points.len(), 12);
+        assert_eq!(points[0]["timestamp"], "2024-01-01 00:00:00");
+        assert_eq!(points[11]["timestamp"], "2024-01-01 00:59:00");
+        assert_eq!(points[11]["btc_price"], 1059.0);
+
+        let res = warp::test::request()
+            .path("/api/metrics/downsample?points=2")
+            .reply(&route)
+            .await;
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[test]
+    fn broadcasts_only_changed_samples() {
+        let sample = |height: u64, price: Option<f64>| Metrics {
+            id: 0,
+            block_height: Some(height),
+            block_hash: None,
+            fees: FeeEstimates::default(),
+            btc_price: price,
+            prices: BTreeMap::new(),
+            spread: PriceSpread::default(),
+            timestamp: "2024-01-01 00:00:00".to_string(),
+            asset: DEFAULT_ASSET.to_string(),
+            source: PRICE_SOURCE_COINGECKO.to_string(),
+            fetch_latency_ms: None,
+        };
+        let last = sample(800_000, Some(60_000.0));
+
+        assert!(should_broadcast(None, &last, 0.0));
+        assert!(!should_broadcast(Some(&last), &sample(800_000, Some(60_000.0)), 0.0));
+        assert!(should_broadcast(Some(&last), &sample(800_000, Some(60_000.01)), 0.0));
+        assert!(!should_broadcast(Some(&last), &sample(800_000, Some(60_000.004)), 0.01));
+        assert!(should_broadcast(Some(&last), &sample(800_000, Some(59_999.0)), 0.01));
+        assert!(should_broadcast(Some(&last), &sample(800_001, Some(60_000.0)), 0.01));
+        assert!(should_broadcast(Some(&last), &sample(800_000, None), 0.01));
+    }
+
+    #[tokio::test]
+    async fn stream_route_pushes_samples_for_requested_asset() {
+        let (updates, _) = broadcast::channel(BROADCAST_CAPACITY);
+        let route = create_stream_route(sqlite_store(seeded_conn(&[])), updates.clone(), FieldCase::Snake, None);
+
+        let mut client = warp::test::ws()
+            .path("/api/metrics/stream?asset=ethereum")
+            .handshake(route)
+            .await
+            .unwrap();
+
+        let sample = |asset: &str, price: f64| Metrics {
+            id: 1,
+            block_height: Some(800_000),
+            block_hash: None,
+            fees: FeeEstimates::default(),
+            btc_price: Some(price),
+            prices: BTreeMap::new(),
+            spread: PriceSpread::default(),
+            timestamp: "2024-01-01 00:00:00".to_string(),
+            asset: asset.to_string(),
+            source: PRICE_SOURCE_COINGECKO.to_string(),
+            fetch_latency_ms: None,
+        };
+        assert!(updates.send(sample(DEFAULT_ASSET, 60_000.0)).is_ok());
+        assert!(updates.send(sample("ethereum", 3_000.0)).is_ok());
+
+        let frame = client.recv().await.unwrap();
+        let body: serde_json::Value = serde_json::from_str(frame.to_str().unwrap()).unwrap();
+        assert_eq!(body["asset"], "ethereum");
+        assert_eq!(body["btc_price"], 3_000.0);
+    }
+
+    #[tokio::test]
+    async fn stream_route_sends_backlog_before_live_samples() {
+        let conn = seeded_conn(&[
+            (DEFAULT_ASSET, 800_000, 60_000.0),
+            (DEFAULT_ASSET, 800_001, 60_100.0),
+            (DEFAULT_ASSET, 800_002, 60_200.0),
+        ]);
+        let (updates, _) = broadcast::channel(BROADCAST_CAPACITY);
+        let route = create_stream_route(sqlite_store(Arc::clone(&conn)), updates.clone(), FieldCase::Snake, None);
+
+        let mut client = warp::test::ws()
+            .path("/api/metrics/stream?backlog=2")
+            .handshake(route)
+            .await
+            .unwrap();
+        let frame_id = |frame: Message| {
+            let body: serde_json::Value = serde_json::from_str(frame.to_str().unwrap()).unwrap();
+            body["id"].as_i64().unwrap()
+        };
+        assert_eq!(frame_id(client.recv().await.unwrap()), 2);
+        assert_eq!(frame_id(client.recv().await.unwrap()), 3);
+
+        // A live sample the backlog already covered isn't sent twice
+        let latest = get_metrics_by_id(&lock_or_recover(&conn), 3).unwrap();
+        assert!(updates.send(latest.clone()).is_ok());
+        assert!(updates.send(Metrics { id: 4, ..latest }).is_ok());
+        assert_eq!(frame_id(client.recv().await.unwrap()), 4);
+    }
+
+    #[tokio::test]
+    async fn view_route_renders_escaped_html_table() {
+        let conn = seeded_conn(&[(DEFAULT_ASSET, 800_000, 60_000.0)]);
+        lock_or_recover(&conn)
+            .execute("UPDATE metrics SET source = '<script>x</script>'", [])
+            .unwrap();
+        let route = create_view_route(sqlite_store(conn), DEFAULT_METRICS_LIMIT, DEFAULT_MAX_QUERY_LIMIT, None);
+
+        let res = warp::test::request().path("/api/metrics/view").reply(&route).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert!(res.headers()[CONTENT_TYPE].to_str().unwrap().starts_with("text/html"));
+
+        let body = std::str::from_utf8(res.body()).unwrap();
+        assert!(body.contains("<td>800000</td>"));
+        assert!(body.contains("<td>60000</td>"));
+        assert!(body.contains("&lt;script&gt;x&lt;/script&gt;"));
+        assert!(!body.contains("<script>"));
+    }
+
+    #[test]
+    fn finds_gaps_longer_than_threshold() {
+        let timestamps: Vec<String> = [
+            "2024-01-01 00:00:00",
+            "2024-01-01 00:00:20",
+            "2024-01-01 00:00:40",
+            "2024-01-01 01:00:40",
+            "2024-01-01 01:01:00",
+            "2024-01-01 01:02:00",
+        ]
+        .iter()
+        .map(|ts| ts.to_string())
+        .collect();
+
+        assert_eq!(
+            find_gaps(&timestamps, 60),
+            vec![Gap {
+                start: "2024-01-01 00:00:40".to_string(),
+                end: "2024-01-01 01:00:40".to_string(),
+                duration_secs: 3600,
+            }]
+        );
+        assert_eq!(find_gaps(&timestamps, 30).len(), 2);
+        assert!(find_gaps(&timestamps[..3], 30).is_empty());
+        assert_eq!(typical_interval_secs(&timestamps), Some(20));
+        assert_eq!(typical_interval_secs(&timestamps[..1]), None);
+    }
+
+    #[tokio::test]
+    async fn gaps_route_scans_newest_samples_against_detected_interval() {
+        let conn = seeded_conn(&(0..6).map(|i| (DEFAULT_ASSET, 800_000 + i, 60_000.0)).collect::<Vec<_>>());
+        lock_or_recover(&conn)
+            .execute(
+                "UPDATE metrics SET timestamp = CASE id
+                     WHEN 1 THEN '2024-01-01 00:00:00' WHEN 2 THEN '2024-01-01 00:30:00'
+                     WHEN 3 THEN '2024-01-01 00:30:20' WHEN 4 THEN '2024-01-01 00:30:40'
+                     WHEN 5 THEN '2024-01-01 00:31:30' ELSE '2024-01-01 00:31:50' END",
+                [],
+            )
+            .unwrap();
+        let route = create_gaps_route(conn, PollInterval::default(), None);
+
+        // 50s is over twice the 20s interval; the half hour before it is outside the scan
+        let res = warp::test::request().path("/api/metrics/gaps?limit=5").reply(&route).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(res.headers()["x-expected-interval-secs"], "20");
+        let body: serde_json::Value = serde_json::from_slice(res.body()).unwrap();
+        assert_eq!(body.as_array().unwrap().len(), 1);
+        assert_eq!(body[0]["start"], "2024-01-01 00:30:40");
+        assert_eq!(body[0]["duration_secs"], 50);
+
+        let res = warp::test::request().path("/api/metrics/gaps").reply(&route).await;
+        let body: serde_json::Value = serde_json::from_slice(res.body()).unwrap();
+        assert_eq!(body.as_array().unwrap().len(), 2);
+
+        let res = warp::test::request().path("/api/metrics/gaps?threshold=1m").reply(&route).await;
+        let body: serde_json::Value = serde_json::from_slice(res.body()).unwrap();
+        assert_eq!(body.as_array().unwrap().len(), 1);
+
+        let res = warp::test::request().path("/api/metrics/gaps?limit=0").reply(&route).await;
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn since_route_returns_newer_rows_up_to_the_cap() {
+        let conn = seeded_conn(&[
+            (DEFAULT_ASSET, 800_000, 60_000.0),
+            (DEFAULT_ASSET, 800_001, 60_100.0),
+            ("ethereum", 800_001, 3_100.0),
+            (DEFAULT_ASSET, 800_002, 60_200.0),
+            (DEFAULT_ASSET, 800_003, 60_300.0),
+        ]);
+        let route = create_since_route(sqlite_store(conn), 2, PollInterval::default(), None);
+
+        let res = warp::test::request().path("/api/metrics/since/1").reply(&route).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(res.headers()["x-has-more"], "true");
+        let body: serde_json::Value = serde_json::from_slice(res.body()).unwrap();
+        let ids: Vec<i64> = body.as_array().unwrap().iter().map(|row| row["id"].as_i64().unwrap()).collect();
+        assert_eq!(ids, vec![2, 4]);
+
+        let res = warp::test::request().path("/api/metrics/since/4").reply(&route).await;
+        assert!(res.headers().get("x-has-more").is_none());
+        let body: serde_json::Value = serde_json::from_slice(res.body()).unwrap();
+        assert_eq!(body.as_array().unwrap().len(), 1);
+        assert_eq!(body[0]["block_height"], 800_003);
+
+        let res = warp::test::request().path("/api/metrics/since/abc").reply(&route).await;
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[test]
+    fn tls_config_requires_readable_files() {
+        let dir = std::env::temp_dir().join(format!("tls-check-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let cert_path = dir.join("cert.pem");
+        std::fs::write(&cert_path, "cert").unwrap();
+
+        let config = TlsConfig {
+            cert_path: cert_path.to_string_lossy().into_owned(),
+            key_path: dir.join("missing.pem").to_string_lossy().into_owned(),
+        };
+        let error = config.check_readable().unwrap_err();
+        assert!(error.contains("missing.pem"));
+
+        let config = TlsConfig {
+            key_path: config.cert_path.clone(),
+            ..config
+        };
+        assert!(config.check_readable().is_ok());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn field_case_renames_json_keys() {
+        assert_eq!(to_camel_case("block_height"), "blockHeight");
+        assert_eq!(to_camel_case("last_error_at"), "lastErrorAt");
+        assert_eq!(to_camel_case("asset"), "asset");
+
+        let metrics = warp::path!("api" / "metrics").map(|| {
+            warp::reply::json(&serde_json::json!([
+                { "block_height": 800_000, "btc_price": 60_000.0, "prices": { "usd": 60_000.0 } }
+            ]))
+        });
+
+        let route = with_field_case(FieldCase::Camel, metrics);
+        let res = warp::test::request().path("/api/metrics").reply(&route).await;
+        let body: serde_json::Value = serde_json::from_slice(res.body()).unwrap();
+        assert_eq!(body[0]["blockHeight"], 800_000);
+        assert_eq!(body[0]["btcPrice"], 60_000.0);
+        assert_eq!(body[0]["prices"]["usd"], 60_000.0);
+        assert!(body[0].get("block_height").is_none());
+
+        let route = with_field_case(FieldCase::Snake, metrics);
+        let res = warp::test::request().path("/api/metrics").reply(&route).await;
+        let body: serde_json::Value = serde_json::from_slice(res.body()).unwrap();
+        assert_eq!(body[0]["block_height"], 800_000);
+    }
+
+    #[tokio::test]
+    async fn contained_cycle_reports_panics() {
+        assert!(run_contained(async {}).await.is_ok());
+
+        let error = run_contained(async { panic!("bad upstream payload") }).await.unwrap_err();
+        assert!(error.is_panic());
+    }
+
+    #[tokio::test]
+    async fn fetchers_use_configured_api_bases() {
+        let upstream = warp::path!("chain" / "blocks" / "tip" / "height")
+            .map(|| "800123")
+            .or(warp::path!("prices" / "simple" / "price").map(|| {
+                warp::reply::json(&serde_json::json!({ "bitcoin": { "usd": 60_000.5, "eur": 55_000.0 } }))
+            }));
+        let (addr, server) = warp::serve(upstream).bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(server);
+
+        let client = reqwest::Client::new();
+        let height = fetch_block_height(&client, &format!("http://{}/chain", addr)).await.unwrap();
+        assert_eq!(height, 800_123);
+
+        let currencies = vec![BASE_CURRENCY.to_string(), "eur".to_string()];
+        let prices = fetch_prices(&client, &format!("http://{}/prices", addr), &[DEFAULT_ASSET.to_string()], &currencies)
+            .await
+            .unwrap();
+        assert_eq!(prices[DEFAULT_ASSET]["usd"], 60_000.5);
+        assert_eq!(prices[DEFAULT_ASSET]["eur"], 55_000.0);
+    }
+
+    #[tokio::test]
+    async fn fetch_retries_transient_upstream_errors() {
+        let requests = Arc::new(AtomicUsize::new(0));
+        let upstream = warp::path!("chain" / "blocks" / "tip" / "height").map({
+            let requests = Arc::clone(&requests);
+            move || match requests.fetch_add(1, Ordering::SeqCst) {
+                0 => warp::reply::with_status("busy", StatusCode::SERVICE_UNAVAILABLE).into_response(),
+                _ => "800123".into_response(),
+            }
+        });
+        let upstream = upstream.or(warp::path!("missing").map(|| StatusCode::NOT_FOUND));
+        let (addr, server) = warp::serve(upstream).bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(server);
+
+        let client = build_http_client(DEFAULT_USER_AGENT);
+        let height = fetch_block_height(&client, &format!("http://{}/chain", addr)).await.unwrap();
+        assert_eq!(height, 800_123);
+        assert_eq!(requests.load(Ordering::SeqCst), 2);
+
+        // A 404 won't change on a retry, so it is reported straight away
+        let started = std::time::Instant::now();
+        let missing = fetch_with_retry::<u64>(&client, &format!("http://{}/missing", addr)).await;
+        assert!(matches!(missing, Err(FetchError::Status { status: StatusCode::NOT_FOUND, .. })));
+        assert!(started.elapsed() < FETCH_RETRY_BACKOFF);
+    }
+
+    #[test]
+    fn flags_samples_older_than_the_stale_cutoff() {
+        let now = parse_timestamp("2024-01-01 12:00:00").unwrap();
+        assert_eq!(staleness("2024-01-01 11:59:40", now, POLL_INTERVAL), (false, Some(20)));
+        assert_eq!(staleness("2024-01-01 11:00:00", now, POLL_INTERVAL), (true, Some(3600)));
+        assert_eq!(staleness("garbage", now, POLL_INTERVAL), (true, None));
+    }
+
+    #[tokio::test]
+    async fn applies_configured_synchronous_level() {
+        let dir = std::env::temp_dir().join(format!("synchronous-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = |name: &str| dir.join(name).to_str().unwrap().to_string();
+        let synchronous = |conns: &SqliteConnections| -> i64 {
+            lock_or_recover(&conns.writer)
+                .query_row("PRAGMA synchronous", [], |row| row.get(0))
+                .unwrap()
+        };
+
+        // 1 is NORMAL, 2 is FULL and 0 is OFF. WAL needs a file, since :memory: stays in memory mode.
+        let (_, conns) = open_store(Some(&path("wal.db")), "WAL", None, 0, DEFAULT_SQLITE_BUSY_TIMEOUT, OnCorrupt::Fail, false).await.unwrap();
+        let conns = conns.unwrap();
+        let journal_mode: String = lock_or_recover(&conns.writer)
+            .query_row("PRAGMA journal_mode", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(journal_mode, "wal");
+        assert_eq!(synchronous(&conns), 1);
+        let (_, conns) = open_store(Some(&path("delete.db")), "DELETE", None, 0, DEFAULT_SQLITE_BUSY_TIMEOUT, OnCorrupt::Fail, false).await.unwrap();
+        assert_eq!(synchronous(&conns.unwrap()), 2);
+        let (_, conns) = open_store(Some(&path("off.db")), "DELETE", Some("off"), 0, DEFAULT_SQLITE_BUSY_TIMEOUT, OnCorrupt::Fail, false).await.unwrap();
+        assert_eq!(synchronous(&conns.unwrap()), 0);
+        let (_, conns) = open_store(Some(&path("normal.db")), "DELETE", Some("NORMAL"), 0, DEFAULT_SQLITE_BUSY_TIMEOUT, OnCorrupt::Fail, false).await.unwrap();
+        assert_eq!(synchronous(&conns.unwrap()), 1);
+
+        let err = open_store(Some(&path("wal.db")), "WAL", Some("EXTRA-SAFE"), 0, DEFAULT_SQLITE_BUSY_TIMEOUT, OnCorrupt::Fail, false).await.err().unwrap();
+        assert!(err.contains("SQLITE_SYNCHRONOUS"));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn corrupt_database_fails_or_is_moved_aside() {
+        let dir = std::env::temp_dir().join(format!("corrupt-db-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("metrics.db");
+        let path = path.to_str().unwrap();
+        std::fs::write(path, vec![0x5a; 8192]).unwrap();
+
+        let err = open_store(Some(path), "WAL", None, 0, DEFAULT_SQLITE_BUSY_TIMEOUT, OnCorrupt::Fail, false).await.err().unwrap();
+        assert!(err.contains("integrity check"), "{}", err);
+        assert_eq!(std::fs::read(path).unwrap(), vec![0x5a; 8192]);
+
+        let (store, _) = open_store(Some(path), "WAL", None, 0, DEFAULT_SQLITE_BUSY_TIMEOUT, OnCorrupt::Reset, false).await.unwrap();
+        store.create_metrics_table().await.unwrap();
+        let moved: Vec<String> = std::fs::read_dir(&dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .filter(|name| name.starts_with("metrics.db.corrupt-"))
+            .collect();
+        assert_eq!(moved.len(), 1);
+        assert_eq!(std::fs::read(dir.join(&moved[0])).unwrap(), vec![0x5a; 8192]);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn reopens_the_database_after_a_corruption_error() {
+        let dir = std::env::temp_dir().join(format!("reopen-db-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("metrics.db");
+        let path = path.to_str().unwrap();
+        let on_disk = Connection::open(path).unwrap();
+        create_metrics_table(&on_disk).unwrap();
+        save_metrics(&on_disk, &MetricsRow {
+            asset: DEFAULT_ASSET.to_string(),
+            block_height: Some(800_000),
+            btc_price: Some(60_000.0),
+            source: PRICE_SOURCE_COINGECKO.to_string(),
+            ..Default::default()
+        })
+        .unwrap();
+        drop(on_disk);
+
+        // Stands in for a connection whose file went bad: empty until it's reopened from disk
+        let stale = Connection::open_in_memory().unwrap();
+        create_metrics_table(&stale).unwrap();
+        let store = SqliteStore::new(Arc::new(Mutex::new(stale))).with_reopen(SqliteReopen {
+            path: path.to_string(),
+            journal_mode: "DELETE".to_string(),
+            synchronous: None,
+            busy_timeout: DEFAULT_SQLITE_BUSY_TIMEOUT,
+            integrity_check: true,
+        });
+        assert!(store.get_latest_metrics().await.unwrap().is_empty());
+
+        let busy = rusqlite::Error::SqliteFailure(rusqlite::ffi::Error::new(rusqlite::ffi::SQLITE_BUSY), None);
+        assert!(store.recover::<()>(Err(busy)).is_err());
+        assert!(store.get_latest_metrics().await.unwrap().is_empty());
+
+        let corrupt = rusqlite::Error::SqliteFailure(rusqlite::ffi::Error::new(rusqlite::ffi::SQLITE_CORRUPT), None);
+        assert!(store.recover::<()>(Err(corrupt)).is_err());
+        let latest = store.get_latest_metrics().await.unwrap();
+        assert_eq!(latest[0].block_height, Some(800_000));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn wal_store_reads_through_read_only_pool() {
+        let dir = std::env::temp_dir().join(format!("read-pool-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("metrics.db");
+        let (store, conns) = open_store(Some(path.to_str().unwrap()), "WAL", None, 2, Duration::from_millis(1234), OnCorrupt::Fail, false)
+            .await
+            .unwrap();
+        let conns = conns.unwrap();
+        assert!(!Arc::ptr_eq(&conns.writer, &conns.reader));
+        for conn in [&conns.writer, &conns.reader] {
+            let busy_timeout: i64 = lock_or_recover(conn)
+                .query_row("PRAGMA busy_timeout", [], |row| row.get(0))
+                .unwrap();
+            assert_eq!(busy_timeout, 1234);
+        }
+
+        store.create_metrics_table().await.unwrap();
+        let prices = BTreeMap::from([(BASE_CURRENCY.to_string(), 60_000.0)]);
+        store
+            .save_metrics(&MetricsRow {
+                asset: DEFAULT_ASSET.to_string(),
+                block_height: Some(800_000),
+                btc_price: Some(60_000.0),
+                prices,
+                source: PRICE_SOURCE_COINGECKO.to_string(),
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+
+        // Readers see the last commit while the writer is mid-transaction
+        lock_or_recover(&conns.writer)
+            .execute_batch("BEGIN IMMEDIATE; INSERT INTO metrics (block_height, asset) VALUES (800001, 'bitcoin');")
+            .unwrap();
+        let history = store.get_metrics_history(DEFAULT_ASSET, None, DEFAULT_MAX_QUERY_LIMIT).await.unwrap();
+        assert_eq!(history.metrics.len(), 1);
+        assert_eq!(history.metrics[0].prices[BASE_CURRENCY], 60_000.0);
+        lock_or_recover(&conns.writer).execute_batch("COMMIT").unwrap();
+
+        let readonly = lock_or_recover(&conns.reader).execute("DELETE FROM metrics", []);
+        assert!(readonly.is_err());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn spike_filter_rejects_outliers_after_warmup() {
+        let filter = SpikeFilter::new(4.0, 10);
+        // Too few samples to judge, so even wild values are accepted at first
+        for price in [60_000.0, 60_050.0, 59_980.0, 60_020.0, 60_010.0] {
+            assert!(filter.check(DEFAULT_ASSET, price).is_ok());
+        }
+
+        assert!(filter.check(DEFAULT_ASSET, 60_040.0).is_ok());
+        let error = filter.check(DEFAULT_ASSET, 600_000.0).unwrap_err();
+        assert!(error.contains("spike"));
+        // The rejected value didn't shift the window
+        assert!(filter.check(DEFAULT_ASSET, 59_990.0).is_ok());
+        // Windows are per asset
+        assert!(filter.check("ethereum", 3_000.0).is_ok());
+    }
+
+    #[test]
+    fn spike_filter_follows_a_sustained_step_change() {
+        let filter = SpikeFilter::new(4.0, 10);
+        for price in [60_000.0, 60_050.0, 59_980.0, 60_020.0, 60_010.0, 60_040.0] {
+            assert!(filter.check(DEFAULT_ASSET, price).is_ok());
+        }
+
+        // A lone glitch between normal prices resets the run
+        assert!(filter.check(DEFAULT_ASSET, 45_000.0).is_err());
+        assert!(filter.check(DEFAULT_ASSET, 60_030.0).is_ok());
+        // Rejections that disagree don't add up to a new level either
+        assert!(filter.check(DEFAULT_ASSET, 45_000.0).is_err());
+        assert!(filter.check(DEFAULT_ASSET, 600_000.0).is_err());
+        assert!(filter.check(DEFAULT_ASSET, 45_100.0).is_err());
+
+        // The market dropped 25% and stays there
+        assert!(filter.check(DEFAULT_ASSET, 45_050.0).is_err());
+        assert!(filter.check(DEFAULT_ASSET, 44_980.0).is_ok());
+        // The window now sits at the new level, judging prices there as normal
+        assert!(filter.check(DEFAULT_ASSET, 45_020.0).is_ok());
+        assert!(filter.check(DEFAULT_ASSET, 45_010.0).is_ok());
+        assert!(filter.check(DEFAULT_ASSET, 44_990.0).is_ok());
+        assert!(filter.check(DEFAULT_ASSET, 60_000.0).is_err());
+    }
+
+    #[tokio::test]
+    async fn rejected_price_spikes_send_no_alerts() {
+        let price = Arc::new(Mutex::new(60_000.0));
+        let alerts = Arc::new(Mutex::new(Vec::new()));
+        let prices = warp::path!("prices" / "simple" / "price").map({
+            let price = Arc::clone(&price);
+            move || warp::reply::json(&serde_json::json!({ "bitcoin": { "usd": *lock_or_recover(&price) } }))
+        });
+        let hook = warp::path!("hook").and(warp::post()).and(warp::body::json()).map({
+            let alerts = Arc::clone(&alerts);
+            move |alert: serde_json::Value| {
+                lock_or_recover(&alerts).push(alert);
+                warp::reply()
+            }
+        });
+        let (addr, server) = warp::serve(prices.or(hook)).bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(server);
+
+        let mut collector = test_collector(sqlite_store(seeded_conn(&[])), &format!("http://{}", addr));
+        collector.alert_config = Some(Arc::new(AlertConfig {
+            above: Some(60_045.0),
+            below: None,
+            webhook_url: format!("http://{}/hook", addr),
+        }));
+        collector.spike_filter = Some(SpikeFilter::new(4.0, 10));
+        collector.price_decimals = Some(0);
+
+        for tick in [60_000.0, 60_010.0, 59_990.0, 60_020.0, 60_000.0, 60_010.0, 600_000.0, 60_000.0] {
+            *lock_or_recover(&price) = tick;
+            collector.collect().await.unwrap();
+        }
+        time::sleep(Duration::from_millis(100)).await;
+        assert!(lock_or_recover(&alerts).is_empty());
+
+        // A real crossing still alerts, with the price as stored
+        *lock_or_recover(&price) = 60_049.6;
+        let stored = collector.collect().await.unwrap();
+        assert_eq!(stored[0].btc_price, Some(60_050.0));
+        for _ in 0..50 {
+            if !lock_or_recover(&alerts).is_empty() {
+                break;
+            }
+            time::sleep(Duration::from_millis(10)).await;
+        }
+        let alerts = lock_or_recover(&alerts);
+        assert_eq!(alerts.len(), 1);
+        assert_eq!(alerts[0]["previous_price"], 60_000.0);
+        assert_eq!(alerts[0]["price"], 60_050.0);
+    }
+
+    #[tokio::test]
+    async fn count_route_counts_samples_in_window() {
+        let conn = seeded_conn(&[
+            (DEFAULT_ASSET, 800_000, 60_000.0),
+            (DEFAULT_ASSET, 800_001, 60_100.0),
+            ("ethereum", 800_001, 3_100.0),
+        ]);
+        lock_or_recover(&conn)
+            .execute("UPDATE metrics SET timestamp = '2024-01-01 00:00:00' WHERE id = 1", [])
+            .unwrap();
+        let route = create_count_route(sqlite_store(conn), PollInterval::default(), None);
+
+        let res = warp::test::request().path("/api/metrics/count").reply(&route).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        let body: serde_json::Value = serde_json::from_slice(res.body()).unwrap();
+        assert_eq!(body["count"], 2);
+
+        let res = warp::test::request()
+            .path("/api/metrics/count?from=2024-06-01T00:00:00Z")
+            .reply(&route)
+            .await;
+        let body: serde_json::Value = serde_json::from_slice(res.body()).unwrap();
+        assert_eq!(body["count"], 1);
+
+        let res = warp::test::request().path("/api/metrics/count?to=yesterday").reply(&route).await;
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn concurrent_collects_share_one_fetch() {
+        let price_requests = Arc::new(AtomicUsize::new(0));
+        let prices = warp::path!("prices" / "simple" / "price").then({
+            let price_requests = Arc::clone(&price_requests);
+            move || {
+                price_requests.fetch_add(1, Ordering::SeqCst);
+                async {
+                    // Slow enough that the second trigger arrives while this one is in flight
+                    time::sleep(Duration::from_millis(100)).await;
+                    warp::reply::json(&serde_json::json!({ "bitcoin": { "usd": 60_000.0 } }))
+                }
+            }
+        });
+        let upstream = warp::path!("chain" / "blocks" / "tip" / "height").map(|| "800123").or(prices);
+        let (addr, server) = warp::serve(upstream).bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(server);
+
+        let conn = seeded_conn(&[]);
+        let collector = test_collector(sqlite_store(Arc::clone(&conn)), &format!("http://{}", addr));
+
+        let (first, second) = tokio::join!(collector.collect(), collector.collect());
+        let (first, second) = (first.unwrap(), second.unwrap());
+        assert_eq!(first.len(), 1);
+        assert_eq!(first[0].id, second[0].id);
+        assert!(first[0].fetch_latency_ms.is_some());
+        // The mock has no block-height route, so the best-effort hash is left null
+        assert!(first[0].block_hash.is_none());
+        assert_eq!(price_requests.load(Ordering::SeqCst), 1);
+        assert_eq!(count_metrics(&lock_or_recover(&conn), DEFAULT_ASSET, None, None).unwrap(), 1);
+
+        // Once nothing is in flight, the next trigger fetches again
+        collector.collect().await.unwrap();
+        assert_eq!(price_requests.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn collect_fetches_height_and_prices_concurrently() {
+        let slow = Duration::from_millis(300);
+        let upstream = warp::path!("chain" / "blocks" / "tip" / "height")
+            .then(move || async move {
+                time::sleep(slow).await;
+                "800123"
+            })
+            .or(warp::path!("prices" / "simple" / "price").then(move || async move {
+                time::sleep(slow).await;
+                warp::reply::with_status("down", StatusCode::NOT_FOUND)
+            }));
+        let (addr, server) = warp::serve(upstream).bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(server);
+
+        let conn = seeded_conn(&[]);
+        let collector = test_collector(sqlite_store(conn), &format!("http://{}", addr));
+
+        // The failed price fetch still leaves the height to save
+        let stored = collector.collect().await.unwrap();
+        assert_eq!(stored[0].block_height, Some(800_123));
+        assert_eq!(stored[0].btc_price, None);
+        assert!(stored[0].fetch_latency_ms.unwrap() < 2 * slow.as_millis() as u64);
+    }
+
+    #[tokio::test]
+    async fn height_regression_is_checked_against_the_stored_rows() {
+        let upstream = warp::path!("chain" / "blocks" / "tip" / "height")
+            .map(|| "799000")
+            .or(warp::path!("prices" / "simple" / "price")
+                .map(|| warp::reply::json(&serde_json::json!({ "bitcoin": { "usd": 60_000.0 } }))));
+        let (addr, server) = warp::serve(upstream).bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(server);
+
+        // Stored before a restart, so the collector's latest cache is still empty
+        let conn = seeded_conn(&[(DEFAULT_ASSET, 800_000, 59_000.0)]);
+        lock_or_recover(&conn)
+            .execute("INSERT INTO metrics (btc_price, asset) VALUES (59500.0, ?1)", params![DEFAULT_ASSET])
+            .unwrap();
+        assert_eq!(last_block_height(&lock_or_recover(&conn)).unwrap(), Some(800_000));
+        let collector = test_collector(sqlite_store(Arc::clone(&conn)), &format!("http://{}", addr));
+        assert!(lock_or_recover(&collector.latest).is_empty());
+
+        let stored = collector.collect().await.unwrap();
+        assert_eq!(stored[0].block_height, None);
+        assert_eq!(stored[0].btc_price, Some(60_000.0));
+        let kind: String = lock_or_recover(&conn)
+            .query_row("SELECT kind FROM fetch_errors ORDER BY id DESC LIMIT 1", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(kind, "height_regression");
+    }
+
+    #[test]
+    fn adaptive_height_refetch_backs_off_after_a_change() {
+        let start = std::time::Instant::now();
+        let mut schedule = HeightSchedule::default();
+        assert_eq!(schedule.reusable(start), None);
+
+        schedule.record(800_000, start);
+        // A minute after the block, a fetch 20s ago is still within the 30s wait
+        schedule.last_fetch = Some(start + Duration::from_secs(40));
+        assert_eq!(schedule.reusable(start + Duration::from_secs(60)), Some(800_000));
+        assert_eq!(schedule.reusable(start + Duration::from_secs(80)), None);
+
+        // Long after the block the wait is capped
+        schedule.last_fetch = Some(start + Duration::from_secs(3600));
+        assert_eq!(schedule.reusable(start + Duration::from_secs(3600 + 119)), Some(800_000));
+        assert_eq!(schedule.reusable(start + Duration::from_secs(3600 + 120)), None);
+
+        // An unchanged height keeps the original change time
+        schedule.record(800_000, start + Duration::from_secs(3720));
+        assert_eq!(schedule.last_change, Some(start));
+    }
+
+    #[tokio::test]
+    async fn adaptive_collect_reuses_a_recent_height() {
+        let height_requests = Arc::new(AtomicUsize::new(0));
+        let upstream = warp::path!("chain" / "blocks" / "tip" / "height")
+            .map({
+                let height_requests = Arc::clone(&height_requests);
+                move || {
+                    height_requests.fetch_add(1, Ordering::SeqCst);
+                    "800123"
+                }
+            })
+            .or(warp::path!("prices" / "simple" / "price")
+                .map(|| warp::reply::json(&serde_json::json!({ "bitcoin": { "usd": 60_000.0 } }))));
+        let (addr, server) = warp::serve(upstream).bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(server);
+
+        let mut collector = test_collector(sqlite_store(seeded_conn(&[])), &format!("http://{}", addr));
+        collector.poll_strategy = PollStrategy::Adaptive;
+
+        let stored = collector.collect().await.unwrap();
+        assert_eq!(stored[0].block_height, Some(800_123));
+        assert_eq!(height_requests.load(Ordering::SeqCst), 1);
+
+        // As if the height had been unchanged for ten minutes
+        lock_or_recover(&collector.height_schedule).last_change = std::time::Instant::now().checked_sub(Duration::from_secs(600));
+        let stored = collector.collect().await.unwrap();
+        assert_eq!(stored[0].block_height, Some(800_123));
+        assert_eq!(stored[0].btc_price, Some(60_000.0));
+        assert_eq!(height_requests.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn adaptive_collect_refetches_a_regressed_height() {
+        let height_requests = Arc::new(AtomicUsize::new(0));
+        let upstream = warp::path!("chain" / "blocks" / "tip" / "height")
+            .map({
+                let height_requests = Arc::clone(&height_requests);
+                move || {
+                    height_requests.fetch_add(1, Ordering::SeqCst);
+                    "799000"
+                }
+            })
+            .or(warp::path!("prices" / "simple" / "price")
+                .map(|| warp::reply::json(&serde_json::json!({ "bitcoin": { "usd": 60_000.0 } }))));
+        let (addr, server) = warp::serve(upstream).bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(server);
+
+        let conn = seeded_conn(&[(DEFAULT_ASSET, 800_000, 59_000.0)]);
+        let mut collector = test_collector(sqlite_store(Arc::clone(&conn)), &format!("http://{}", addr));
+        collector.poll_strategy = PollStrategy::Adaptive;
+
+        let stored = collector.collect().await.unwrap();
+        assert_eq!(stored[0].block_height, None);
+        assert_eq!(lock_or_recover(&collector.height_schedule).last_height, None);
+
+        // Even long after the last change, the rejected height isn't reused, so the next tick
+        // asks again and reports the regression again
+        lock_or_recover(&collector.height_schedule).last_change = std::time::Instant::now().checked_sub(Duration::from_secs(600));
+        let stored = collector.collect().await.unwrap();
+        assert_eq!(stored[0].block_height, None);
+        assert_eq!(height_requests.load(Ordering::SeqCst), 2);
+        let regressions: i64 = lock_or_recover(&conn)
+            .query_row("SELECT COUNT(*) FROM fetch_errors WHERE kind = 'height_regression'", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(regressions, 2);
+    }
+
+    #[tokio::test]
+    async fn backfill_fills_the_window_before_the_oldest_row() {
+        let requests = Arc::new(AtomicUsize::new(0));
+        let upstream = {
+            let requests = Arc::clone(&requests);
+            warp::path!("coins" / String / "market_chart").map(move |_asset: String| {
+                requests.fetch_add(1, Ordering::SeqCst);
+                let now = chrono::Utc::now();
+                let prices: Vec<(f64, f64)> = (0..=3)
+                    .rev()
+                    .map(|days| ((now - chrono::Duration::days(days)).timestamp_millis() as f64, 50_000.0 + days as f64))
+                    .collect();
+                warp::reply::json(&serde_json::json!({ "prices": prices }))
+            })
+        };
+        let (addr, server) = warp::serve(upstream).bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(server);
+        let base = format!("http://{}", addr);
+        let client = reqwest::Client::new();
+        let assets = vec![DEFAULT_ASSET.to_string()];
+
+        // A row from just now doesn't cover the last three days, so the days before it are filled
+        let conn = seeded_conn(&[(DEFAULT_ASSET, 800_000, 60_000.0)]);
+        backfill_history(&conn, &client, &base, &assets, 3, None).await;
+        assert_eq!(requests.load(Ordering::SeqCst), 1);
+        let history = get_metrics_history(&lock_or_recover(&conn), DEFAULT_ASSET, Some(10), 10).unwrap();
+        let mut prices: Vec<f64> = history.metrics.iter().filter_map(|row| row.btc_price).collect();
+        prices.sort_by(|a, b| a.total_cmp(b));
+        assert_eq!(prices, [50_001.0, 50_002.0, 50_003.0, 60_000.0]);
+
+        // Now it does
+        backfill_history(&conn, &client, &base, &assets, 3, None).await;
+        assert_eq!(requests.load(Ordering::SeqCst), 1);
+        assert_eq!(count_metrics(&lock_or_recover(&conn), DEFAULT_ASSET, None, None).unwrap(), 4);
+    }
+
+    #[test]
+    fn parses_price_sources_in_order() {
+        assert_eq!(
+            parse_price_sources("kraken, Coinbase,coingecko"),
+            [PriceSource::Kraken, PriceSource::Coinbase, PriceSource::CoinGecko]
+        );
+        assert_eq!(parse_price_sources("coinbase,bitstamp,coinbase"), [PriceSource::Coinbase]);
+        assert_eq!(parse_price_sources(""), [PriceSource::CoinGecko]);
+        assert_eq!(parse_price_sources("bitstamp"), [PriceSource::CoinGecko]);
+    }
+
+    #[tokio::test]
+    async fn collect_falls_back_through_price_sources() {
+        let upstream = warp::path!("chain" / "blocks" / "tip" / "height")
+            .map(|| "800123")
+            .or(warp::path!("prices" / "simple" / "price").map(|| warp::reply::with_status("down", StatusCode::NOT_FOUND)))
+            .or(warp::path!("coinbase" / "prices" / "BTC-USD" / "spot")
+                .map(|| warp::reply::with_status("down", StatusCode::NOT_FOUND)))
+            .or(warp::path!("kraken" / "Ticker").map(|| {
+                warp::reply::json(&serde_json::json!({
+                    "error": [],
+                    "result": { "XXBTZUSD": { "c": ["60123.4", "0.01"] } }
+                }))
+            }));
+        let (addr, server) = warp::serve(upstream).bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(server);
+
+        let mut collector = test_collector(sqlite_store(seeded_conn(&[])), &format!("http://{}", addr));
+        collector.price_sources = vec![PriceSource::CoinGecko, PriceSource::Coinbase, PriceSource::Kraken];
+
+        let stored = collector.collect().await.unwrap();
+        assert_eq!(stored[0].btc_price, Some(60_123.4));
+        assert_eq!(stored[0].source, PRICE_SOURCE_KRAKEN);
+        assert_eq!(collector.counters.snapshot().price_fallback_used, 1);
+        assert!(collector
+            .counters
+            .to_prometheus()
+            .contains("bitcoin_explore_price_fallback_used_total 1\n"));
+
+        // An answer from the first source isn't a fallback
+        collector.price_sources = vec![PriceSource::Kraken, PriceSource::CoinGecko];
+        collector.collect().await.unwrap();
+        assert_eq!(collector.counters.snapshot().price_fallback_used, 1);
+        collector.price_sources = vec![PriceSource::Coinbase, PriceSource::Kraken];
+        collector.collect().await.unwrap();
+        assert_eq!(collector.counters.snapshot().price_fallback_used, 2);
+    }
+
+    #[test]
+    fn aggregates_the_median_of_source_quotes() {
+        let quotes = |usd: f64, eur: Option<f64>| {
+            let mut currencies = BTreeMap::from([(BASE_CURRENCY.to_string(), usd)]);
+            currencies.extend(eur.map(|eur| ("eur".to_string(), eur)));
+            HashMap::from([(DEFAULT_ASSET.to_string(), currencies)])
+        };
+        let (prices, spreads) = aggregate_median(&[
+            (PriceSource::CoinGecko, quotes(60_000.0, Some(55_000.0))),
+            (PriceSource::Coinbase, quotes(60_400.0, None)),
+            (PriceSource::Kraken, quotes(60_100.0, None)),
+        ]);
+        // eur only came from one source, too few for a median
+        assert_eq!(prices[DEFAULT_ASSET], BTreeMap::from([(BASE_CURRENCY.to_string(), 60_100.0)]));
+        assert_eq!(spreads[DEFAULT_ASSET].price_spread, Some(400.0));
+        assert_eq!(spreads[DEFAULT_ASSET].source_prices.len(), 3);
+
+        assert_eq!(median(&mut [3.0, 1.0, 4.0, 2.0]), Some(2.5));
+        assert_eq!(median(&mut []), None);
+    }
+
+    #[tokio::test]
+    async fn collect_stores_the_median_price_and_each_source() {
+        let upstream = warp::path!("chain" / "blocks" / "tip" / "height")
+            .map(|| "800123")
+            .or(warp::path!("prices" / "simple" / "price")
+                .map(|| warp::reply::json(&serde_json::json!({ "bitcoin": { "usd": 60_000.0 } }))))
+            .or(warp::path!("coinbase" / "prices" / "BTC-USD" / "spot").map(|| {
+                warp::reply::json(&serde_json::json!({ "data": { "amount": "60400", "base": "BTC", "currency": "USD" } }))
+            }))
+            .or(warp::path!("kraken" / "Ticker").map(|| {
+                warp::reply::json(&serde_json::json!({
+                    "error": [],
+                    "result": { "XXBTZUSD": { "c": ["60100", "0.01"] } }
+                }))
+            }));
+        let (addr, server) = warp::serve(upstream).bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(server);
+
+        let conn = seeded_conn(&[]);
+        let mut collector = test_collector(sqlite_store(Arc::clone(&conn)), &format!("http://{}", addr));
+        collector.price_sources = vec![PriceSource::CoinGecko, PriceSource::Coinbase, PriceSource::Kraken];
+        collector.price_aggregation = PriceAggregation::Median;
+
+        let stored = collector.collect().await.unwrap();
+        assert_eq!(stored[0].btc_price, Some(60_100.0));
+        assert_eq!(stored[0].source, PRICE_SOURCE_MEDIAN);
+        assert_eq!(stored[0].spread.price_spread, Some(400.0));
+
+        let conn = lock_or_recover(&conn);
+        let mut stmt = conn.prepare("SELECT source, value FROM source_prices ORDER BY source").unwrap();
+        let source_prices: Vec<(String, f64)> = stmt
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))
+            .unwrap()
+            .collect::<Result<_>>()
+            .unwrap();
+        assert_eq!(
+            source_prices,
+            [
+                (PRICE_SOURCE_COINBASE.to_string(), 60_400.0),
+                (PRICE_SOURCE_COINGECKO.to_string(), 60_000.0),
+                (PRICE_SOURCE_KRAKEN.to_string(), 60_100.0),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn collect_skips_the_median_price_when_one_source_answers() {
+        let upstream = warp::path!("chain" / "blocks" / "tip" / "height")
+            .map(|| "800123")
+            .or(warp::path!("prices" / "simple" / "price")
+                .map(|| warp::reply::json(&serde_json::json!({ "bitcoin": { "usd": 60_000.0 } }))))
+            .or(warp::path!("kraken" / "Ticker").map(|| warp::reply::with_status("down", StatusCode::SERVICE_UNAVAILABLE)));
+        let (addr, server) = warp::serve(upstream).bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(server);
+
+        let conn = seeded_conn(&[]);
+        let mut collector = test_collector(sqlite_store(Arc::clone(&conn)), &format!("http://{}", addr));
+        collector.price_sources = vec![PriceSource::CoinGecko, PriceSource::Kraken];
+        collector.price_aggregation = PriceAggregation::Median;
+
+        // The height is still saved, without a price
+        let stored = collector.collect().await.unwrap();
+        assert_eq!(stored[0].block_height, Some(800_123));
+        assert_eq!(stored[0].btc_price, None);
+        assert_eq!(stored[0].source, PRICE_SOURCE_NONE);
+        assert_eq!(stored[0].spread, PriceSpread::default());
+        let kind: String = lock_or_recover(&conn)
+            .query_row("SELECT kind FROM fetch_errors", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(kind, "too_few_sources");
+    }
+
+    #[tokio::test]
+    async fn reads_coinbase_spot_prices() {
+        let upstream = warp::path!("coinbase" / "prices" / "BTC-USD" / "spot").map(|| {
+            warp::reply::json(&serde_json::json!({ "data": { "amount": "60000.55", "base": "BTC", "currency": "USD" } }))
+        });
+        let (addr, server) = warp::serve(upstream).bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(server);
+
+        let collector = test_collector(sqlite_store(seeded_conn(&[])), &format!("http://{}", addr));
+        let assets = [DEFAULT_ASSET.to_string(), "dogecoin".to_string()];
+        let prices = fetch_prices_from(&collector.http, PriceSource::Coinbase, &collector.api_bases, &assets, &collector.currencies)
+            .await
+            .unwrap();
+        // dogecoin has no exchange symbol, so it's skipped rather than failing the fetch
+        assert_eq!(prices.len(), 1);
+        assert_eq!(prices[DEFAULT_ASSET][BASE_CURRENCY], 60_000.55);
+    }
+
+    #[derive(Default)]
+    struct RecordingSink(Mutex<Vec<Metrics>>);
+
+    impl MetricsSink for RecordingSink {
+        fn publish(&self, metrics: &Metrics) {
+            lock_or_recover(&self.0).push(metrics.clone());
+        }
+    }
+
+    #[tokio::test]
+    async fn collect_publishes_every_saved_sample_to_sinks() {
+        let upstream = warp::path!("chain" / "blocks" / "tip" / "height")
+            .map(|| "800123")
+            .or(warp::path!("prices" / "simple" / "price")
+                .map(|| warp::reply::json(&serde_json::json!({ "bitcoin": { "usd": 60_000.0 } }))));
+        let (addr, server) = warp::serve(upstream).bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(server);
+
+        let sink = Arc::new(RecordingSink::default());
+        let mut collector = test_collector(sqlite_store(seeded_conn(&[])), &format!("http://{}", addr));
+        collector.sinks.push(Arc::clone(&sink) as Arc<dyn MetricsSink>);
+        let mut stream = collector.updates.subscribe();
+
+        collector.collect().await.unwrap();
+        collector.collect().await.unwrap();
+
+        // The unchanged second sample is skipped on the stream but still published
+        let published = lock_or_recover(&sink.0);
+        assert_eq!(published.iter().map(|metrics| metrics.id).collect::<Vec<_>>(), [1, 2]);
+        assert_eq!(stream.try_recv().unwrap().id, 1);
+        assert!(stream.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn tells_failed_status_from_unparseable_height() {
+        let upstream = warp::path!("empty" / "blocks" / "tip" / "height")
+            .map(|| "")
+            .or(warp::path!("gone" / "blocks" / "tip" / "height")
+                .map(|| warp::reply::with_status("<html>Not Found</html>", StatusCode::NOT_FOUND)))
+            .or(warp::path!("prices" / "simple" / "price")
+                .map(|| warp::reply::json(&serde_json::json!({ "bitcoin": { "usd": 60_000.0 } }))));
+        let (addr, server) = warp::serve(upstream).bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(server);
+
+        let client = reqwest::Client::new();
+        let empty = fetch_block_height(&client, &format!("http://{}/empty", addr)).await.unwrap_err();
+        assert_eq!(empty.kind(), "invalid_body");
+        assert!(empty.to_string().contains("expected an integer block height"), "{}", empty);
+        let gone = fetch_block_height(&client, &format!("http://{}/gone", addr)).await.unwrap_err();
+        assert_eq!(gone.kind(), "http_status");
+        assert!(gone.to_string().contains("returned HTTP 404"), "{}", gone);
+
+        // The price still gets saved and the height failure is recorded with its kind
+        let conn = seeded_conn(&[]);
+        let mut collector = test_collector(sqlite_store(Arc::clone(&conn)), &format!("http://{}", addr));
+        collector.api_bases.chain = format!("http://{}/empty", addr);
+        let stored = collector.collect().await.unwrap();
+        assert_eq!(stored[0].block_height, None);
+        let (source, kind): (String, String) = lock_or_recover(&conn)
+            .query_row("SELECT source, kind FROM fetch_errors", [], |row| Ok((row.get(0)?, row.get(1)?)))
+            .unwrap();
+        assert_eq!((source.as_str(), kind.as_str()), ("block_height", "invalid_body"));
+    }
+
+    #[tokio::test]
+    async fn collect_stores_the_hash_of_the_fetched_height() {
+        let hash = "00000000000000000002a7c4c1e48d76c5a37902165a270156b7a8d72728a054";
+        let upstream = warp::path!("chain" / "blocks" / "tip" / "height")
+            .map(|| "800123")
+            .or(warp::path!("chain" / "block-height" / u64).map(move |height: u64| {
+                assert_eq!(height, 800_123);
+                hash
+            }))
+            .or(warp::path!("prices" / "simple" / "price")
+                .map(|| warp::reply::json(&serde_json::json!({ "bitcoin": { "usd": 60_000.0 } }))));
+        let (addr, server) = warp::serve(upstream).bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(server);
+
+        let conn = seeded_conn(&[]);
+        let collector = test_collector(sqlite_store(Arc::clone(&conn)), &format!("http://{}", addr));
+
+        let stored = collector.collect().await.unwrap();
+        assert_eq!(stored[0].block_height, Some(800_123));
+        assert_eq!(stored[0].block_hash.as_deref(), Some(hash));
+        let history = get_metrics_history(&lock_or_recover(&conn), DEFAULT_ASSET, None, DEFAULT_MAX_QUERY_LIMIT).unwrap();
+        assert_eq!(history.metrics[0].block_hash.as_deref(), Some(hash));
+    }
+
+    #[tokio::test]
+    async fn collect_stores_available_fee_estimates() {
+        // 6 isn't a number and 144 is missing, neither should cost the 1-block estimate
+        let upstream = warp::path!("chain" / "blocks" / "tip" / "height")
+            .map(|| "800123")
+            .or(warp::path!("chain" / "fee-estimates")
+                .map(|| warp::reply::json(&serde_json::json!({ "1": 25.2, "3": 20.0, "6": "12.1" }))))
+            .or(warp::path!("prices" / "simple" / "price")
+                .map(|| warp::reply::json(&serde_json::json!({ "bitcoin": { "usd": 60_000.0 } }))));
+        let (addr, server) = warp::serve(upstream).bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(server);
+
+        let conn = seeded_conn(&[]);
+        let mut collector = test_collector(sqlite_store(Arc::clone(&conn)), &format!("http://{}", addr));
+        collector.fetch_fees = true;
+
+        let expected = FeeEstimates {
+            fee_1_block: Some(25.2),
+            fee_6_blocks: None,
+            fee_144_blocks: None,
+        };
+        let stored = collector.collect().await.unwrap();
+        assert_eq!(stored[0].fees, expected);
+        let history = get_metrics_history(&lock_or_recover(&conn), DEFAULT_ASSET, None, DEFAULT_MAX_QUERY_LIMIT).unwrap();
+        assert_eq!(history.metrics[0].fees, expected);
+        let served = serde_json::to_value(&history.metrics[0]).unwrap();
+        assert_eq!(served["fee_1_block"], 25.2);
+        assert!(served["fee_144_blocks"].is_null());
+    }
+
+    #[tokio::test]
+    async fn rolls_up_complete_hours_and_serves_them() {
+        let conn = seeded_conn(&[
+            (DEFAULT_ASSET, 800_000, 60_000.0),
+            (DEFAULT_ASSET, 800_001, 61_000.0),
+            (DEFAULT_ASSET, 800_002, 59_000.0),
+            (DEFAULT_ASSET, 800_003, 60_500.0),
+            (DEFAULT_ASSET, 800_004, 62_000.0),
+        ]);
+        let now = parse_timestamp("2024-01-01 12:40:00").unwrap().and_utc();
+        let cutoff = rollup_cutoff(now, Duration::from_secs(30 * 60));
+        assert_eq!(cutoff, "2024-01-01 12:00:00");
+        {
+            let conn = lock_or_recover(&conn);
+            conn.execute(
+                "UPDATE metrics SET timestamp = CASE id
+                     WHEN 1 THEN '2024-01-01 10:05:00' WHEN 2 THEN '2024-01-01 10:20:00'
+                     WHEN 3 THEN '2024-01-01 10:50:00' WHEN 4 THEN '2024-01-01 11:10:00'
+                     ELSE '2024-01-01 12:30:00' END",
+                [],
+            )
+            .unwrap();
+
+            assert_eq!(rollup_hourly(&conn, &cutoff).unwrap(), 2);
+            assert_eq!(prune_old_metrics(&conn, &cutoff).unwrap(), 4);
+            // The still-open 12:00 hour stays raw, and a rerun can't clobber the rolled-up hours
+            assert_eq!(rollup_hourly(&conn, &cutoff).unwrap(), 0);
+            assert_eq!(count_metrics(&conn, DEFAULT_ASSET, None, None).unwrap(), 1);
+        }
+
+        let route = create_hourly_route(conn, None);
+        let res = warp::test::request()
+            .path("/api/metrics/hourly?from=2024-01-01&to=2024-01-02")
+            .reply(&route)
+            .await;
+        assert_eq!(res.status(), StatusCode::OK);
+        let body: serde_json::Value = serde_json::from_slice(res.body()).unwrap();
+        assert_eq!(body.as_array().unwrap().len(), 2);
+        assert_eq!(body[0]["start"], "2024-01-01 10:00:00");
+        assert_eq!(body[0]["open"], 60_000.0);
+        assert_eq!(body[0]["high"], 61_000.0);
+        assert_eq!(body[0]["low"], 59_000.0);
+        assert_eq!(body[0]["close"], 59_000.0);
+        assert_eq!(body[0]["samples"], 3);
+        assert_eq!(body[1]["samples"], 1);
+    }
+
+    #[tokio::test]
+    async fn ath_route_reports_distance_below_the_high() {
+        let conn = seeded_conn(&[
+            (DEFAULT_ASSET, 800_000, 60_000.0),
+            (DEFAULT_ASSET, 800_001, 80_000.0),
+            (DEFAULT_ASSET, 800_002, 60_000.0),
+            ("ethereum", 800_002, 3_000.0),
+        ]);
+        let route = create_ath_route(conn, PollInterval::default(), None);
+
+        let res = warp::test::request().path("/api/metrics/ath").reply(&route).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        let body: serde_json::Value = serde_json::from_slice(res.body()).unwrap();
+        assert_eq!(body["ath"]["btc_price"], 80_000.0);
+        assert_eq!(body["ath"]["block_height"], 800_001);
+        assert!(body["ath"]["timestamp"].is_string());
+        assert_eq!(body["current_price"], 60_000.0);
+        assert_eq!(body["below_ath"], 20_000.0);
+        assert_eq!(body["below_ath_pct"], 25.0);
+
+        let res = warp::test::request().path("/api/metrics/ath?asset=ethereum").reply(&route).await;
+        let body: serde_json::Value = serde_json::from_slice(res.body()).unwrap();
+        assert_eq!(body["below_ath"], 0.0);
+
+        let res = warp::test::request().path("/api/metrics/ath?asset=dogecoin").reply(&route).await;
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn high_low_route_reports_extremes_in_window() {
+        let conn = seeded_conn(&[
+            (DEFAULT_ASSET, 800_000, 70_000.0),
+            (DEFAULT_ASSET, 800_001, 61_000.0),
+            (DEFAULT_ASSET, 800_002, 59_500.0),
+            (DEFAULT_ASSET, 800_003, 60_200.0),
+        ]);
+        // The 70k sample is older than the window
+        lock_or_recover(&conn)
+            .execute("UPDATE metrics SET timestamp = '2024-01-01 00:00:00' WHERE id = 1", [])
+            .unwrap();
+        let route = create_high_low_route(conn, PollInterval::default(), None);
+
+        let res = warp::test::request().path("/api/metrics/high-low?window=24h").reply(&route).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        let body: serde_json::Value = serde_json::from_slice(res.body()).unwrap();
+        assert_eq!(body["window_secs"], 86_400);
+        assert_eq!(body["high"], 61_000.0);
+        assert_eq!(body["low"], 59_500.0);
+        assert!(body["high_at"].is_string());
+
+        let res = warp::test::request()
+            .path("/api/metrics/high-low?asset=ethereum")
+            .reply(&route)
+            .await;
+        let body: serde_json::Value = serde_json::from_slice(res.body()).unwrap();
+        assert!(body["high"].is_null());
+        assert!(body["low_at"].is_null());
+
+        let res = warp::test::request().path("/api/metrics/high-low?window=soon").reply(&route).await;
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[test]
+    fn loads_dotenv_entries_the_environment_doesnt_set() {
+        let path = std::env::temp_dir().join(format!("bitcoin-explore-load-{}.env", std::process::id()));
+        std::fs::write(&path, "API_TOKEN=s3cret\nREAL=from-file\nnot a valid line\nPOLL_INTERVAL=30s\n").unwrap();
+        let (entries, skipped) = read_dotenv(path.to_str().unwrap()).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(entries.len(), 3);
+        assert_eq!(skipped, 1);
+
+        let mut dotenv = Dotenv::new();
+        let loaded = dotenv.load(entries, |key| key == "REAL");
+        let keys: Vec<&str> = loaded.iter().map(|(key, _)| key.as_str()).collect();
+        assert_eq!(keys, ["API_TOKEN", "POLL_INTERVAL"]);
+        assert!(dotenv.loaded.contains("API_TOKEN"));
+        assert_eq!(dotenv.get("POLL_INTERVAL", |_| None).as_deref(), Some("30s"));
+        assert_eq!(dotenv.get("REAL", |_| Some("from-env".to_string())).as_deref(), Some("from-env"));
+
+        assert_eq!(read_dotenv("/nonexistent/.env").unwrap(), (Vec::new(), 0));
+    }
+
+    #[test]
+    fn reload_updates_dotenv_keys_but_not_the_real_environment() {
+        let entries = |pairs: &[(&str, &str)]| -> Vec<(String, String)> {
+            pairs.iter().map(|(key, value)| (key.to_string(), value.to_string())).collect()
+        };
+        let env = |key: &str| match key {
+            "REAL" => Some("from-env".to_string()),
+            "LOADED" => Some("at-startup".to_string()),
+            _ => None,
+        };
+        let is_real = |key: &str| env(key).is_some();
+        let mut dotenv = Dotenv::new();
+        dotenv.loaded.insert("LOADED".to_string());
+        dotenv.values.insert("LOADED".to_string(), "at-startup".to_string());
+
+        let changed = dotenv.reload(entries(&[("A", "1"), ("B", "1"), ("REAL", "from-file"), ("LOADED", "at-startup")]), is_real);
+        assert_eq!(changed, vec!["A", "B"]);
+        assert!(dotenv.reload(entries(&[("A", "1"), ("B", "1"), ("LOADED", "at-startup")]), is_real).is_empty());
+
+        let changed = dotenv.reload(entries(&[("A", "2"), ("REAL", "from-file"), ("LOADED", "edited")]), is_real);
+        assert_eq!(changed, vec!["A", "B", "LOADED"]);
+        assert_eq!(dotenv.get("A", env).as_deref(), Some("2"));
+        assert_eq!(dotenv.get("B", env), None);
+        assert_eq!(dotenv.get("REAL", env).as_deref(), Some("from-env"));
+        assert_eq!(dotenv.get("LOADED", env).as_deref(), Some("edited"));
+
+        assert_eq!(dotenv.reload(Vec::new(), is_real), vec!["A", "LOADED"]);
+        assert_eq!(dotenv.get("A", env), None);
+        assert_eq!(dotenv.get("LOADED", env), None);
+    }
+
+    #[test]
+    fn reloadable_settings_parse_like_the_environment() {
+        let file: HashMap<&str, &str> = [
+            ("POLL_INTERVAL", "2m"),
+            ("FETCH_JITTER_PCT", "80"),
+            ("MAX_ROWS", "0"),
+            ("ALLOWED_ORIGINS", "https://a.example/, https://b.example"),
+            ("RUST_LOG", ""),
+        ]
+        .into_iter()
+        .collect();
+        let settings = ReloadableSettings::from_lookup(|key| file.get(key).map(|value| value.to_string()));
+        assert_eq!(settings.poll_interval, Duration::from_secs(120));
+        assert_eq!(settings.jitter_pct, 50.0);
+        assert_eq!(settings.max_rows, None);
+        assert_eq!(
+            settings.allowed_origins,
+            Some(vec!["https://a.example".to_string(), "https://b.example".to_string()])
+        );
+        assert_eq!(settings.log_filter, None);
+
+        let settings = ReloadableSettings::from_lookup(|_| None);
+        assert_eq!(settings.poll_interval, POLL_INTERVAL);
+        assert_eq!(parse_allowed_origins(Some("*")), None);
+    }
+
+    #[tokio::test]
+    async fn cache_lifetimes_follow_a_reloaded_poll_interval() {
+        let conn = seeded_conn(&[(DEFAULT_ASSET, 800_000, 60_000.0)]);
+        let poll_interval = PollInterval::new(Duration::from_secs(30));
+        let route = create_metrics_route(
+            sqlite_store(conn),
+            DEFAULT_METRICS_LIMIT,
+            DEFAULT_MAX_QUERY_LIMIT,
+            FieldCase::Snake,
+            poll_interval.clone(),
+            None,
+        );
+
+        let res = warp::test::request().path("/api/metrics").reply(&route).await;
+        assert_eq!(res.headers()[CACHE_CONTROL], "public, max-age=30");
+
+        poll_interval.set(Duration::from_secs(300));
+        let res = warp::test::request().path("/api/metrics").reply(&route).await;
+        assert_eq!(res.headers()[CACHE_CONTROL], "public, max-age=300");
+    }
+
+    #[tokio::test]
+    async fn read_auth_keeps_poll_cached_responses_private() {
+        // What REQUIRE_AUTH_ALL passes to every read route
+        let read_auth = ApiAuth::new(None, Some("s3cret".to_string())).map(Arc::new);
+        let conn = seeded_conn(&[(DEFAULT_ASSET, 800_000, 60_000.0)]);
+        let poll_interval = PollInterval::new(Duration::from_secs(30));
+        let route = create_metrics_route(
+            sqlite_store(Arc::clone(&conn)),
+            DEFAULT_METRICS_LIMIT,
+            DEFAULT_MAX_QUERY_LIMIT,
+            FieldCase::Snake,
+            poll_interval.clone(),
+            read_auth.clone(),
+        )
+        .or(create_since_route(sqlite_store(conn), DEFAULT_MAX_QUERY_LIMIT, poll_interval, read_auth));
+
+        for path in ["/api/metrics", "/api/metrics/since/0"] {
+            let res = warp::test::request()
+                .path(path)
+                .header("authorization", "Bearer s3cret")
+                .reply(&route)
+                .await;
+            assert_eq!(res.status(), StatusCode::OK, "{}", path);
+            assert_eq!(res.headers()[CACHE_CONTROL], "private, max-age=30", "{}", path);
+        }
+    }
+
+    #[tokio::test]
+    async fn allowed_origins_are_checked_per_request() {
+        let origins = AllowedOrigins::new(Some(vec!["https://a.example".to_string()]));
+        let route = with_allowed_origins(origins.clone(), warp::path("ping").map(|| "pong"));
+
+        let res = warp::test::request().path("/ping").header("origin", "https://a.example").reply(&route).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        let res = warp::test::request().path("/ping").header("origin", "https://b.example").reply(&route).await;
+        assert_eq!(res.status(), StatusCode::FORBIDDEN);
+        let res = warp::test::request().path("/ping").reply(&route).await;
+        assert_eq!(res.status(), StatusCode::OK);
+
+        origins.set(Some(vec!["https://b.example".to_string()]));
+        let res = warp::test::request().path("/ping").header("origin", "https://b.example").reply(&route).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        let res = warp::test::request().path("/ping").header("origin", "https://a.example").reply(&route).await;
+        assert_eq!(res.status(), StatusCode::FORBIDDEN);
+
+        origins.set(None);
+        let res = warp::test::request().path("/ping").header("origin", "https://c.example").reply(&route).await;
+        assert_eq!(res.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn compare_route_diffs_the_samples_nearest_each_time() {
+        let conn = seeded_conn(&[
+            (DEFAULT_ASSET, 800_000, 50_000.0),
+            (DEFAULT_ASSET, 800_100, 55_000.0),
+            (DEFAULT_ASSET, 800_144, 60_000.0),
+        ]);
+        lock_or_recover(&conn)
+            .execute(
+                "UPDATE metrics SET timestamp = CASE id
+                     WHEN 1 THEN '2024-01-01 00:10:00' WHEN 2 THEN '2024-01-01 20:00:00'
+                     ELSE '2024-01-02 00:05:00' END",
+                [],
+            )
+            .unwrap();
+        let route = create_compare_route(conn, None);
+
+        let res = warp::test::request()
+            .path("/api/metrics/compare?a=2024-01-01&b=2024-01-02T00:00:00Z")
+            .reply(&route)
+            .await;
+        assert_eq!(res.status(), StatusCode::OK);
+        let body: serde_json::Value = serde_json::from_slice(res.body()).unwrap();
+        assert_eq!(body["a"]["id"], 1);
+        assert_eq!(body["b"]["id"], 3);
+        assert_eq!(body["price_delta"], 10_000.0);
+        assert_eq!(body["price_delta_pct"], 20.0);
+        assert_eq!(body["block_delta"], 144);
+
+        // Nothing within five minutes of midnight on the 1st
+        let res = warp::test::request()
+            .path("/api/metrics/compare?a=2024-01-01&b=2024-01-02&tolerance=5m")
+            .reply(&route)
+            .await;
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+
+        let res = warp::test::request().path("/api/metrics/compare?a=2024-01-01").reply(&route).await;
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn concurrency_limit_rejects_requests_past_the_cap() {
+        let entered = Arc::new(tokio::sync::Notify::new());
+        let release = Arc::new(tokio::sync::Notify::new());
+        let slow = warp::path!("slow").then({
+            let (entered, release) = (Arc::clone(&entered), Arc::clone(&release));
+            move || {
+                let (entered, release) = (Arc::clone(&entered), Arc::clone(&release));
+                async move {
+                    entered.notify_one();
+                    release.notified().await;
+                    "done"
+                }
+            }
+        });
+        let route = with_concurrency_limit(Some(Arc::new(tokio::sync::Semaphore::new(1))), slow)
+            .recover(handle_rejection);
+
+        let first = tokio::spawn({
+            let route = route.clone();
+            async move { warp::test::request().path("/slow").reply(&route).await }
+        });
+        entered.notified().await;
+
+        let res = warp::test::request().path("/slow").reply(&route).await;
+        assert_eq!(res.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(res.headers()[RETRY_AFTER], "1");
+
+        release.notify_one();
+        assert_eq!(first.await.unwrap().status(), StatusCode::OK);
+
+        // The permit went back with the first reply
+        let second = tokio::spawn({
+            let route = route.clone();
+            async move { warp::test::request().path("/slow").reply(&route).await }
+        });
+        entered.notified().await;
+        release.notify_one();
+        assert_eq!(second.await.unwrap().status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn evicts_oldest_rows_beyond_max_rows() {
+        let conn = seeded_conn(&[
+            (DEFAULT_ASSET, 800_000, 60_000.0),
+            (DEFAULT_ASSET, 800_001, 60_100.0),
+            ("ethereum", 800_001, 3_100.0),
+            (DEFAULT_ASSET, 800_002, 60_200.0),
+        ]);
+        lock_or_recover(&conn).execute_batch("PRAGMA foreign_keys = ON").unwrap();
+        let store = sqlite_store(Arc::clone(&conn));
+
+        assert_eq!(store.evict_oldest(2).await.unwrap(), 2);
+        assert_eq!(store.evict_oldest(2).await.unwrap(), 0);
+
+        let conn = lock_or_recover(&conn);
+        let ids: Vec<i64> = conn
+            .prepare("SELECT id FROM metrics ORDER BY id")
+            .unwrap()
+            .query_map([], |row| row.get(0))
+            .unwrap()
+            .collect::<Result<_>>()
+            .unwrap();
+        assert_eq!(ids, vec![3, 4]);
+        let prices: i64 = conn.query_row("SELECT COUNT(*) FROM prices", [], |row| row.get(0)).unwrap();
+        assert_eq!(prices, 2);
+    }
+
+    #[tokio::test]
+    async fn currencies_route_lists_newest_value_per_currency() {
+        let conn = seeded_conn(&[(DEFAULT_ASSET, 800_000, 60_000.0), ("ethereum", 800_000, 3_100.0)]);
+        {
+            let conn = lock_or_recover(&conn);
+            let prices = BTreeMap::from([("usd".to_string(), 61_000.0)]);
+            save_metrics(&conn, &MetricsRow {
+                asset: DEFAULT_ASSET.to_string(),
+                block_height: Some(800_001),
+                btc_price: Some(61_000.0),
+                prices,
+                source: PRICE_SOURCE_COINGECKO.to_string(),
+                ..Default::default()
+            })
+            .unwrap();
+            // EUR only showed up in the first sample
+            conn.execute("INSERT INTO prices (metric_id, currency, value) VALUES (1, 'eur', 55000.0)", [])
+                .unwrap();
+        }
+        let route = create_currencies_route(sqlite_store(conn), PollInterval::default(), None);
+
+        let res = warp::test::request().path("/api/currencies").reply(&route).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        let body: serde_json::Value = serde_json::from_slice(res.body()).unwrap();
+        let currencies: Vec<(&str, f64)> = body
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|quote| (quote["currency"].as_str().unwrap(), quote["value"].as_f64().unwrap()))
+            .collect();
+        assert_eq!(currencies, vec![("eur", 55_000.0), ("usd", 61_000.0)]);
+
+        let res = warp::test::request().path("/api/currencies?asset=solana").reply(&route).await;
+        assert_eq!(res.body().as_ref(), b"[]");
+    }
+
+    #[tokio::test]
+    async fn request_id_is_echoed_or_generated() {
+        let route = with_request_id(warp::path!("ping").map(|| "pong").recover(handle_rejection));
+
+        let res = warp::test::request()
+            .path("/ping")
+            .header("x-request-id", "abc-123")
+            .reply(&route)
+            .await;
+        assert_eq!(res.headers()["x-request-id"], "abc-123");
+
+        let res = warp::test::request().path("/ping").reply(&route).await;
+        let generated = res.headers()["x-request-id"].to_str().unwrap();
+        assert_eq!(generated.len(), 36);
+        assert_eq!(generated.as_bytes()[14], b'4');
+
+        let res = warp::test::request()
+            .path("/ping")
+            .header("x-request-id", "has spaces")
+            .reply(&route)
+            .await;
+        assert_ne!(res.headers()["x-request-id"], "has spaces");
+    }
+
+    #[tokio::test]
+    async fn read_auth_protects_matched_routes_and_leaves_unknown_paths_404() {
+        let read_auth = ApiAuth::new(None, Some("s3cret".to_string())).map(Arc::new);
+        let latest: LatestMetrics = Arc::new(Mutex::new(HashMap::new()));
+        let route = create_version_route(read_auth.clone())
+            .or(create_latest_route(latest, PollInterval::default(), read_auth.clone()))
+            .or(create_sqlite_routes(Some(seeded_conn(&[])), None, None, read_auth, PollInterval::default()))
+            .recover(handle_rejection);
+
+        for path in ["/api/version", "/api/metrics/latest", "/api/halving"] {
+            let res = warp::test::request().path(path).reply(&route).await;
+            assert_eq!(res.status(), StatusCode::UNAUTHORIZED, "{}", path);
+            let res = warp::test::request()
+                .path(path)
+                .header("authorization", "Bearer s3cret")
+                .reply(&route)
+                .await;
+            assert_ne!(res.status(), StatusCode::UNAUTHORIZED, "{}", path);
+        }
+
+        let res = warp::test::request().path("/api/nope").reply(&route).await;
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn read_auth_keeps_static_documents_private() {
+        let read_auth = ApiAuth::new(None, Some("s3cret".to_string())).map(Arc::new);
+        let route = create_version_route(read_auth.clone()).or(create_openapi_route(read_auth));
+
+        for path in ["/api/version", "/api/openapi.json"] {
+            let res = warp::test::request()
+                .path(path)
+                .header("authorization", "Bearer s3cret")
+                .reply(&route)
+                .await;
+            assert_eq!(res.status(), StatusCode::OK, "{}", path);
+            assert_eq!(
+                res.headers()[CACHE_CONTROL],
+                format!("private, max-age={}", STATIC_DOC_MAX_AGE.as_secs()),
+                "{}",
+                path
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn cors_lets_browsers_send_and_read_the_request_id() {
+        let route = with_request_id(warp::path("ping").map(|| "pong")).with(api_cors());
+
+        let res = warp::test::request()
+            .method("OPTIONS")
+            .path("/ping")
+            .header("origin", "https://dashboard.example")
+            .header("access-control-request-method", "GET")
+            .header("access-control-request-headers", "x-request-id")
+            .reply(&route)
+            .await;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert!(res.headers()["access-control-allow-headers"].to_str().unwrap().contains("x-request-id"));
+
+        let res = warp::test::request()
+            .path("/ping")
+            .header("origin", "https://dashboard.example")
+            .header("x-request-id", "abc-123")
+            .reply(&route)
+            .await;
+        assert_eq!(res.headers()["x-request-id"], "abc-123");
+        assert!(res.headers()["access-control-expose-headers"].to_str().unwrap().contains("x-request-id"));
+    }
+
+    #[tokio::test]
+    async fn protected_routes_accept_bearer_token() {
+        let auth = ApiAuth::new(None, Some("s3cret".to_string())).map(Arc::new);
+        let route = warp::path!("admin")
+            .and(with_auth(auth))
+            .map(|| "ok")
+            .recover(handle_rejection);
+
+        let res = warp::test::request()
+            .path("/admin")
+            .header("authorization", "Bearer s3cret")
+            .reply(&route)
+            .await;
+        assert_eq!(res.status(), StatusCode::OK);
+
+        for authorization in [None, Some("Bearer wrong"), Some("Basic czNjcmV0")] {
+            let mut request = warp::test::request().path("/admin");
+            if let Some(authorization) = authorization {
+                request = request.header("authorization", authorization);
+            }
+            let res = request.reply(&route).await;
+            assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+            assert_eq!(res.headers()[WWW_AUTHENTICATE], "Bearer realm=\"bitcoin-explore\"");
+        }
+
+        // With both configured, either credential works
+        let basic = BasicAuth {
+            username: "admin".to_string(),
+            password: "pw".to_string(),
+        };
+        let auth = ApiAuth::new(Some(basic), Some("s3cret".to_string())).unwrap();
+        assert!(auth.matches("Bearer s3cret"));
+        assert!(auth.matches("Basic YWRtaW46cHc="));
+        assert!(ApiAuth::new(None, None).is_none());
+    }
+
+    #[test]
+    fn credentials_must_match_exactly() {
+        assert!(secrets_match("s3cret", "s3cret"));
+        assert!(!secrets_match("s3cre", "s3cret"));
+        assert!(!secrets_match("s3cret!", "s3cret"));
+        assert!(!secrets_match("", "s3cret"));
+
+        let basic = BasicAuth {
+            username: "admin".to_string(),
+            password: "pw".to_string(),
+        };
+        let auth = ApiAuth::new(Some(basic), Some("s3cret".to_string())).unwrap();
+        // admin:pX, adminX:pw and admin: respectively
+        for authorization in ["Basic YWRtaW46cFg=", "Basic YWRtaW5YOnB3", "Basic YWRtaW46", "Bearer s3cre"] {
+            assert!(!auth.matches(authorization), "{}", authorization);
+        }
+    }
+
+    #[tokio::test]
+    async fn prune_route_deletes_old_samples_behind_auth() {
+        let conn = seeded_conn(&[
+            (DEFAULT_ASSET, 800_000, 60_000.0),
+            (DEFAULT_ASSET, 800_001, 61_000.0),
+            (DEFAULT_ASSET, 800_002, 62_000.0),
+        ]);
+        lock_or_recover(&conn)
+            .execute("UPDATE metrics SET timestamp = '2024-01-01 10:05:00' WHERE id < 3", [])
+            .unwrap();
+        let auth = ApiAuth::new(None, Some("s3cret".to_string())).map(Arc::new);
+        let route = create_prune_route(Arc::clone(&conn), auth).recover(handle_rejection);
+
+        let res = warp::test::request()
+            .method("POST")
+            .path("/api/admin/prune?older_than_days=7")
+            .reply(&route)
+            .await;
+        assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+
+        let res = warp::test::request()
+            .method("POST")
+            .path("/api/admin/prune?older_than_days=0")
+            .header("authorization", "Bearer s3cret")
+            .reply(&route)
+            .await;
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+
+        let res = warp::test::request()
+            .method("POST")
+            .path("/api/admin/prune?older_than_days=7")
+            .header("authorization", "Bearer s3cret")
+            .reply(&route)
+            .await;
+        assert_eq!(res.status(), StatusCode::OK);
+        let body: serde_json::Value = serde_json::from_slice(res.body()).unwrap();
+        assert_eq!(body["deleted"], 2);
+        assert_eq!(body["rolled_up_hours"], 1);
+
+        let conn = lock_or_recover(&conn);
+        assert_eq!(count_metrics(&conn, DEFAULT_ASSET, None, None).unwrap(), 1);
+        let rollups = get_hourly_rollups(&conn, DEFAULT_ASSET, "2024-01-01", "2024-01-02").unwrap();
+        assert_eq!(rollups.len(), 1);
+        assert_eq!(rollups[0].samples, 2);
+    }
+
+    #[tokio::test]
+    async fn prune_route_writes_through_the_writer_beside_a_read_pool() {
+        let dir = std::env::temp_dir().join(format!("prune-pool-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("metrics.db");
+        let (store, conns) = open_store(Some(path.to_str().unwrap()), "WAL", None, 2, DEFAULT_SQLITE_BUSY_TIMEOUT, OnCorrupt::Fail, false)
+            .await
+            .unwrap();
+        let conns = conns.unwrap();
+        store.create_metrics_table().await.unwrap();
+        for height in [800_000, 800_001] {
+            save_metrics(&lock_or_recover(&conns.writer), &MetricsRow {
+                asset: DEFAULT_ASSET.to_string(),
+                block_height: Some(height),
+                btc_price: Some(60_000.0),
+                source: PRICE_SOURCE_COINGECKO.to_string(),
+                ..Default::default()
+            })
+            .unwrap();
+        }
+        lock_or_recover(&conns.writer)
+            .execute("UPDATE metrics SET timestamp = '2024-01-01 10:05:00' WHERE id = 1", [])
+            .unwrap();
+
+        // Mounted the way run() does it
+        let auth = ApiAuth::new(None, Some("s3cret".to_string())).map(Arc::new);
+        let route = create_sqlite_routes(Some(Arc::clone(&conns.reader)), None, auth.clone(), None, PollInterval::default())
+            .or(create_sqlite_admin_routes(Some(Arc::clone(&conns.writer)), auth))
+            .recover(handle_rejection);
+        let res = warp::test::request()
+            .method("POST")
+            .path("/api/admin/prune?older_than_days=7")
+            .header("authorization", "Bearer s3cret")
+            .reply(&route)
+            .await;
+        assert_eq!(res.status(), StatusCode::OK);
+        let body: serde_json::Value = serde_json::from_slice(res.body()).unwrap();
+        assert_eq!(body["deleted"], 1);
+        assert_eq!(count_metrics(&lock_or_recover(&conns.writer), DEFAULT_ASSET, None, None).unwrap(), 1);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn fetch_route_runs_a_cycle_behind_auth() {
+        let upstream = warp::path!("chain" / "blocks" / "tip" / "height")
+            .map(|| "800123")
+            .or(warp::path!("prices" / "simple" / "price")
+                .map(|| warp::reply::json(&serde_json::json!({ "bitcoin": { "usd": 60_000.0 } }))));
+        let (addr, server) = warp::serve(upstream).bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(server);
+
+        let collector = Arc::new(test_collector(sqlite_store(seeded_conn(&[])), &format!("http://{}", addr)));
+        let auth = ApiAuth::new(None, Some("s3cret".to_string())).map(Arc::new);
+        let route = create_refresh_route(collector, auth).recover(handle_rejection);
+
+        let res = warp::test::request().method("POST").path("/api/fetch").reply(&route).await;
+        assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+
+        let res = warp::test::request()
+            .method("POST")
+            .path("/api/fetch")
+            .header("authorization", "Bearer s3cret")
+            .reply(&route)
+            .await;
+        assert_eq!(res.status(), StatusCode::OK);
+        let body: serde_json::Value = serde_json::from_slice(res.body()).unwrap();
+        assert_eq!(body[0]["block_height"], 800_123);
+        assert_eq!(body[0]["btc_price"], 60_000.0);
+    }
+}
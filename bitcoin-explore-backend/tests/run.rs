@@ -0,0 +1,67 @@
+use bitcoin_explore_backend::{run, Config, TlsConfig};
+
+// Settings from the defaults with no HTTP server, so each test only drives the startup path
+fn collector_config() -> Config {
+    let mut config = Config::from_env().expect("default config");
+    config.serve_api = false;
+    config
+}
+
+#[tokio::test]
+async fn run_reports_which_startup_step_failed() {
+    let mut config = collector_config();
+    let missing = std::env::temp_dir().join(format!("bitcoin-explore-missing-{}", std::process::id()));
+    config.database_url = Some(missing.join("metrics.db").to_str().unwrap().to_string());
+
+    let error = run(config).await.unwrap_err();
+    assert_eq!(error.to_string(), "Error opening the metrics store");
+    assert!(format!("{:#}", error).starts_with("Error opening the metrics store: "));
+}
+
+#[tokio::test]
+async fn run_fails_fast_when_the_upstream_self_test_fails() {
+    let mut config = collector_config();
+    let db = std::env::temp_dir().join(format!("bitcoin-explore-run-{}.db", std::process::id()));
+    config.database_url = Some(db.to_str().unwrap().to_string());
+    // Nothing listens on port 1, so the self-test is refused straight away
+    config.price_api_base = "http://127.0.0.1:1".to_string();
+    config.chain_api_base = "http://127.0.0.1:1".to_string();
+    config.fail_fast = true;
+
+    let error = run(config).await.unwrap_err();
+    assert!(error.to_string().starts_with("Upstream self-test failed"), "{}", error);
+    for suffix in ["", "-wal", "-shm"] {
+        let _ = std::fs::remove_file(format!("{}{}", db.display(), suffix));
+    }
+}
+
+fn missing_tls_files() -> Option<TlsConfig> {
+    let missing = std::env::temp_dir().join(format!("bitcoin-explore-no-tls-{}", std::process::id()));
+    Some(TlsConfig {
+        cert_path: missing.join("cert.pem").to_str().unwrap().to_string(),
+        key_path: missing.join("key.pem").to_str().unwrap().to_string(),
+    })
+}
+
+#[tokio::test]
+async fn run_rejects_unreadable_tls_files_before_serving() {
+    let mut config = collector_config();
+    config.serve_api = true;
+    config.tls = missing_tls_files();
+
+    let error = run(config).await.unwrap_err();
+    assert_eq!(error.to_string(), "Invalid TLS configuration");
+    assert!(format!("{:#}", error).contains("cert.pem"), "{:#}", error);
+}
+
+#[tokio::test]
+async fn run_ignores_tls_files_without_the_api_server() {
+    let mut config = collector_config();
+    config.tls = missing_tls_files();
+    // The next step fails instead, which shows the TLS check let startup carry on
+    let missing = std::env::temp_dir().join(format!("bitcoin-explore-missing-{}", std::process::id()));
+    config.database_url = Some(missing.join("metrics.db").to_str().unwrap().to_string());
+
+    let error = run(config).await.unwrap_err();
+    assert_eq!(error.to_string(), "Error opening the metrics store");
+}
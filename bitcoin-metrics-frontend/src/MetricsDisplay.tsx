@@ -16,8 +16,8 @@ import { Line } from 'react-chartjs-2';
 ChartJS.register(CategoryScale, LinearScale, PointElement, LineElement, Title, Tooltip, Legend);
 
 interface Metrics {
-  block_height: number;
-  btc_price: number;
+  block_height: number | null;
+  btc_price: number | null;
   timestamp: string;
 }
 